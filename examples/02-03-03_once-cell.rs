@@ -0,0 +1,206 @@
+//! # `OnceCell<T>`
+//!
+//! 02-03-02の遅延初期化は`AtomicU64`の値そのものを0かどうかで判定していたため、`u64`にしか使用できず、
+//! 競合した場合は複数のスレッドが`generate_random_key`を実行していた。
+//! `OnceCell<T>`は任意の型の値を1回だけ初期化し、初期化関数を実行するスレッドも1つだけにする。
+//!
+//! 状態は次の3つであり、`compare_exchange`で`EMPTY`から`INITIALIZING`に遷移できたスレッドだけが
+//! 初期化関数を実行する。
+//! 競合に負けたスレッドは、状態が`READY`になるまでスピンする。
+//! したがって、初期化関数が短時間で完了する場合に適している。
+use std::cell::UnsafeCell;
+use std::convert::Infallible;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// 値が初期化されていない状態
+const EMPTY: u8 = 0;
+/// いずれかのスレッドが値を初期化している状態
+const INITIALIZING: u8 = 1;
+/// 値が初期化された状態
+const READY: u8 = 2;
+
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// 初期化された値は複数のスレッドから`&T`で参照されるため`T: Sync`が必要であり、
+/// 初期化関数を実行したスレッドとは別のスレッドでドロップされる可能性があるため`T: Send`が必要である。
+unsafe impl<T> Sync for OnceCell<T> where T: Send + Sync {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// 値が初期化されていれば、その値への参照を返す。
+    pub fn get(&self) -> Option<&T> {
+        // Acquireロードにより、`READY`を観測した場合は、初期化された値を観測できる。
+        if self.state.load(Ordering::Acquire) == READY {
+            // 安全性: `READY`の場合は値が初期化されており、以降変更されない。
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// 値が初期化されていなければ`f`で初期化し、値への参照を返す。
+    ///
+    /// 複数のスレッドが同時に呼び出した場合でも、`f`を実行するのは1つのスレッドだけである。
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.get_or_try_init(|| Ok::<T, Infallible>(f())) {
+            Ok(value) => value,
+        }
+    }
+
+    /// `get_or_init`と同様であるが、`f`がエラーを返した場合は値を初期化せずに、そのエラーを返す。
+    ///
+    /// `f`がエラーを返した場合やパニックした場合は、状態を`EMPTY`に戻すため、
+    /// 待機していたスレッドのいずれかが、改めて初期化を試みる。
+    pub fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        loop {
+            match self.state.compare_exchange(
+                EMPTY,
+                INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                // 競合に勝ったため、このスレッドが初期化する。
+                Ok(_) => break,
+                Err(READY) => return Ok(self.get().unwrap()),
+                // 他のスレッドが初期化しているため、完了するまでスピンする。
+                Err(_) => std::hint::spin_loop(),
+            }
+        }
+
+        let reset = ResetOnDrop(&self.state);
+        let value = f()?;
+        std::mem::forget(reset);
+        // 安全性: `INITIALIZING`に遷移したスレッドだけが値にアクセスする。
+        let value = unsafe { (*self.value.get()).write(value) };
+        // Releaseストアにより、`READY`を観測したスレッドは、書き込んだ値を観測できる。
+        self.state.store(READY, Ordering::Release);
+        Ok(value)
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        // 初期化された値だけをドロップする。
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+/// 初期化関数がエラーを返した場合やパニックした場合に、状態を`EMPTY`に戻す。
+struct ResetOnDrop<'a>(&'a AtomicU8);
+
+impl Drop for ResetOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(EMPTY, Ordering::Release);
+    }
+}
+
+fn get_key() -> u64 {
+    static KEY: OnceCell<u64> = OnceCell::new();
+    *KEY.get_or_init(generate_random_key)
+}
+
+fn generate_random_key() -> u64 {
+    42
+}
+
+fn main() {
+    println!("key: {}", get_key());
+
+    let config: OnceCell<String> = OnceCell::new();
+    let result = config.get_or_try_init(|| "".parse::<u32>().map(|n| n.to_string()));
+    println!("failed to initialize: {result:?}");
+    let value = config.get_or_init(|| String::from("default"));
+    println!("initialized: {value}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn racing_initializers_run_once() {
+        let cell = OnceCell::new();
+        let calls = AtomicUsize::new(0);
+        let barrier = std::sync::Barrier::new(100);
+
+        std::thread::scope(|s| {
+            for i in 0..100 {
+                let (cell, calls, barrier) = (&cell, &calls, &barrier);
+                s.spawn(move || {
+                    barrier.wait();
+                    let value = cell.get_or_init(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        i
+                    });
+                    // すべてのスレッドが同じ値を観測するはず。
+                    assert_eq!(Some(value), cell.get());
+                });
+            }
+        });
+
+        // `f`は1回だけ呼び出されるはず。
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn failed_initialization_can_be_retried() {
+        let cell = OnceCell::new();
+        assert_eq!(cell.get_or_try_init(|| Err("failed")), Err("failed"));
+        assert_eq!(cell.get(), None);
+
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(1)), Ok(&1));
+        // 初期化された後は、`f`を実行しないはず。
+        assert_eq!(cell.get_or_try_init(|| Err(())), Ok(&1));
+    }
+
+    #[test]
+    fn panicking_initializer_resets_state() {
+        let cell = OnceCell::new();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            cell.get_or_init(|| panic!("initialization failed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+    }
+
+    #[test]
+    fn drops_value_only_when_ready() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // 初期化されていない場合は、何もドロップしないはず。
+        drop(OnceCell::<DetectDrop>::new());
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+
+        let cell = OnceCell::new();
+        cell.get_or_init(|| DetectDrop);
+        drop(cell);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,37 +1,84 @@
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 
-static mut DATA: String = String::new();
-static LOCKED: AtomicBool = AtomicBool::new(false);
+/// 04-03のスピンロックの最小限のコピー
+///
+/// `new`は`const fn`であるため、`static`を初期化できる。
+/// ロックを`static mut`とアトミック変数で自作する代わりに、保護するデータとロックを1つの`static`にまとめることで、
+/// `unsafe`なしで`DATA`にアクセスできる。
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
 
-fn f() {
-    // LOCKED変数の値をcompare_exchangeで読み取り、falseであればtrueに変更する。
-    // 成功時はAcquireオーダリング、失敗時はRelaxedオーダリングを使用する。
-    //
-    // Acquireオーダリングにより、compare_exchange成功時に以下が保証される。
-    // - このcompare_exchangeより前に他のスレッドがLOCKEDへReleaseオーダリング
-    //   でfalseを書き込んだ場合、そのReleaseより前のメモリ操作（DATAへの変更など）が
-    //   このスレッドで観測可能になる。
-    //
-    // LOCKED変数の書き換えに失敗した場合、DATA変数へのアクセスはない。
-    //
-    // したがって、このスレッドがLOCKEDをtrueに設定した後は、他のスレッドが同時にDATAに
-    // アクセスすることはない（ロックが取得された状態）。
-    //
-    // ReleaseストアとAcquireロード間で確立する「先行発生関係（happens-before）は、
-    // アトミックでない通常の変数にも影響することに注意すること。
-    if LOCKED
-        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-        .is_ok()
-    {
-        unsafe {
-            #[allow(static_mut_refs)]
-            DATA.push('!');
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
         }
+    }
+
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        // locked変数の値をcompare_exchangeで読み取り、falseであればtrueに変更する。
+        // 成功時はAcquireオーダリング、失敗時はRelaxedオーダリングを使用する。
+        //
+        // Acquireオーダリングにより、compare_exchange成功時に以下が保証される。
+        // - このcompare_exchangeより前に他のスレッドがlockedへReleaseオーダリング
+        //   でfalseを書き込んだ場合、そのReleaseより前のメモリ操作（valueへの変更など）が
+        //   このスレッドで観測可能になる。
+        //
+        // locked変数の書き換えに失敗した場合、value変数へのアクセスはない。
+        //
+        // したがって、このスレッドがlockedをtrueに設定した後は、他のスレッドが同時にvalueに
+        // アクセスすることはない（ロックが取得された状態）。
+        //
+        // ReleaseストアとAcquireロード間で確立する「先行発生関係（happens-before）は、
+        // アトミックでない通常の変数にも影響することに注意すること。
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Guard { lock: self })
+    }
+}
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
         // ロックを解放する。Releaseオーダリングにより、このstoreより前に
-        // 行われたメモリ操作（DATAへの書き込み）が、次にこのLOCKED変数を
+        // 行われたメモリ操作（valueへの書き込み）が、次にこのlocked変数を
         // Acquireオーダリングで読み取るスレッドから観測可能になる。
-        LOCKED.store(false, Ordering::Release);
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+static DATA: SpinLock<String> = SpinLock::new(String::new());
+
+fn f() {
+    // ロックを獲得できた場合のみ、DATAに書き込む。
+    if let Some(mut data) = DATA.try_lock() {
+        data.push('!');
     }
 }
 
@@ -40,5 +87,6 @@ fn main() {
         for _ in 0..100 {
             s.spawn(f);
         }
-    })
+    });
+    println!("{}", *DATA.try_lock().unwrap());
 }
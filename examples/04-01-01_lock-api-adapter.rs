@@ -0,0 +1,122 @@
+//! 04-01のスピンロックを`lock_api`に適合させる。
+//!
+//! `lock_api`は、ロックの獲得と解放だけを行う`RawMutex`トレイトを実装すれば、
+//! `lock_api::Mutex<R, T>`として値を保護するミューテックスとガード（ガードの変換や`const`な初期化を含む）
+//! を提供するクレートである。
+//! したがって、04-01の値を保護しないスピンロックを`RawSpinLock`として実装するだけで、
+//! 04-03のようにガードを自作する必要がなくなる。
+//!
+//! `lock_api`フィーチャーを有効にして、`cargo run --features lock_api --example 04-01-01_lock-api-adapter`
+//! で実行すること。
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lock_api::{GuardSend, RawMutex};
+
+/// 04-01の`SpinLock`から抽出した、値を保護しないスピンロック
+pub struct RawSpinLock {
+    locked: AtomicBool,
+}
+
+/// `lock_api::Mutex`で値を保護するスピンロック
+pub type SpinLock<T> = lock_api::Mutex<RawSpinLock, T>;
+pub type SpinLockGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinLock, T>;
+
+/// 安全性: `lock`は`compare_exchange_weak`のAcquireでロックを獲得し、`unlock`はReleaseストアで
+/// ロックを解放するため、同時に1つのスレッドだけがロックを保持し、ロックを保持していたスレッドの
+/// 操作は次にロックを獲得したスレッドから観測できる。
+unsafe impl RawMutex for RawSpinLock {
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+    };
+
+    /// ロックを獲得したスレッドとは別のスレッドでロックを解放しても問題ないため、
+    /// ガードは`Send`である。
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// `lock_api::Mutex::new`は`const fn`であるため、静的変数を初期化できる。
+static NAMES: SpinLock<Vec<&str>> = SpinLock::new(Vec::new());
+
+fn main() {
+    std::thread::scope(|s| {
+        s.spawn(|| NAMES.lock().push("alice"));
+        s.spawn(|| NAMES.lock().push("bob"));
+    });
+
+    // ガードを変換して、先頭要素だけを公開する。
+    let first = SpinLockGuard::map(NAMES.lock(), |names| &mut names[0]);
+    println!("first: {}", *first);
+    drop(first);
+    println!("names: {:?}", *NAMES.lock());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_keeps_count_exact() {
+        let lock = SpinLock::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.into_inner(), 80_000);
+    }
+
+    #[test]
+    fn try_lock_fails_while_locked() {
+        let lock = SpinLock::new(0);
+        let guard = lock.lock();
+        assert!(lock.is_locked());
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(lock.try_lock().is_none()));
+        });
+
+        drop(guard);
+        assert!(!lock.is_locked());
+        assert!(lock.try_lock().is_some());
+    }
+
+    #[test]
+    fn mapped_guard_holds_lock() {
+        let lock = SpinLock::new((0, String::from("spin")));
+        let mut name = SpinLockGuard::map(lock.lock(), |(_, name)| name);
+        name.push_str("lock");
+        // 変換後のガードがロックを保持しているはず。
+        assert!(lock.is_locked());
+        drop(name);
+
+        assert!(!lock.is_locked());
+        assert_eq!(lock.lock().1, "spinlock");
+    }
+}
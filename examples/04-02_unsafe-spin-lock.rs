@@ -1,6 +1,11 @@
 use std::cell::UnsafeCell;
 use std::sync::atomic::{AtomicBool, Ordering};
 
+#[path = "shared/backoff.rs"]
+mod backoff;
+
+use backoff::Backoff;
+
 pub struct SpinLock<T> {
     locked: AtomicBool,
     value: UnsafeCell<T>,
@@ -30,8 +35,10 @@ impl<T> SpinLock<T> {
     /// 省略規則により、ライフタイム注釈は不要である。
     #[allow(clippy::mut_from_ref)]
     pub fn lock(&self) -> &mut T {
+        // 競合している場合は、04-03と同じ`Backoff`でスピンしてから、CPUを他のスレッドに譲る。
+        let mut backoff = Backoff::new();
         while self.locked.swap(true, Ordering::Acquire) {
-            std::hint::spin_loop();
+            backoff.snooze();
         }
         // `UnsafeCell::get`は`*mut T`、つまり可変な`T`へのポインタを返す。
         // したがって、`*`を使用して参照外しをした後、その可変参照を返す。
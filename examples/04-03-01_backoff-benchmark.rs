@@ -0,0 +1,127 @@
+//! 04-03のスピンロックに導入した指数バックオフの効果を計測する。
+//!
+//! 8スレッドが同じカウンタをロックしてインクリメントし、毎回`spin_loop`だけで再試行する場合と、
+//! 指数バックオフで再試行する場合の所要時間を比較する。
+//! 指数バックオフは、以前の04-02のように`lock`の中に直接書いたもの（スピン回数を64回まで倍増させた後は
+//! `yield_now`する）と、現在の04-03の`Backoff`（1、2、4、8回スピンし、8回`yield_now`した後は一時停止する）の
+//! 両方を計測する。
+//! 例えば、CPUコアが1つのLinux環境では、`spin_loop`だけの場合は約400ms、どちらの指数バックオフも約100msであった。
+//! 計測する場合は`cargo run --release --example 04-03-01_backoff-benchmark`で実行すること。
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+#[path = "shared/backoff.rs"]
+mod backoff;
+
+use backoff::Backoff;
+
+const THREADS: usize = 8;
+const ITERATIONS: usize = 1_000_000;
+
+/// `lock_with_backoff`で、スピン回数を`2^INLINE_SPIN_LIMIT`回まで倍増させた後、`yield_now`に切り替える。
+const INLINE_SPIN_LIMIT: u32 = 6;
+
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// 変更前の`lock`: 失敗するたびに1回だけ`spin_loop`して再試行する。
+    pub fn lock_with_spin_loop(&self) -> Guard<'_, T> {
+        while self.locked.swap(true, Ordering::Acquire) {
+            std::hint::spin_loop();
+        }
+        Guard { lock: self }
+    }
+
+    /// 以前の04-02の`lock`: `lock`の中に直接書いた指数バックオフで再試行する。
+    pub fn lock_with_backoff(&self) -> Guard<'_, T> {
+        let mut step = 0;
+        while self.locked.swap(true, Ordering::Acquire) {
+            if step <= INLINE_SPIN_LIMIT {
+                for _ in 0..1 << step {
+                    std::hint::spin_loop();
+                }
+                step += 1;
+            } else {
+                std::thread::yield_now();
+            }
+        }
+        Guard { lock: self }
+    }
+
+    /// 現在の04-03の`lock`: `Backoff`で再試行する。
+    pub fn lock_with_backoff_struct(&self) -> Guard<'_, T> {
+        let mut backoff = Backoff::new();
+        while self.locked.swap(true, Ordering::Acquire) {
+            backoff.snooze();
+        }
+        Guard { lock: self }
+    }
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+fn bench(lock: impl Fn(&SpinLock<usize>) -> Guard<'_, usize> + Sync) -> Duration {
+    let counter = SpinLock::new(0);
+    std::hint::black_box(&counter);
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    *lock(&counter) += 1;
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    // どちらの方法でも、カウンタの値は正確でなければならない。
+    assert_eq!(*counter.lock_with_spin_loop(), THREADS * ITERATIONS);
+    duration
+}
+
+fn main() {
+    let duration = bench(SpinLock::lock_with_spin_loop);
+    println!("spin_loop:      locked {THREADS}x{ITERATIONS} times in {duration:?}");
+
+    let duration = bench(SpinLock::lock_with_backoff);
+    println!("inline backoff: locked {THREADS}x{ITERATIONS} times in {duration:?}");
+
+    let duration = bench(SpinLock::lock_with_backoff_struct);
+    println!("Backoff:        locked {THREADS}x{ITERATIONS} times in {duration:?}");
+}
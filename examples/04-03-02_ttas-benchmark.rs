@@ -0,0 +1,110 @@
+//! 04-03のスピンロックに導入したtest-and-test-and-set（TTAS）の効果を計測する。
+//!
+//! スレッドが同じカウンタをロックしてインクリメントし、待機中も毎回`swap`を試みる場合（TAS）と、
+//! ロックが解放されたことを`Relaxed`のロードで確認してから`swap`を試みる場合（TTAS）の所要時間を比較する。
+//! どちらも04-03と同じ指数バックオフで待機する。
+//! TASでは、待機中のスレッドの`swap`がキャッシュラインの排他的な所有権を奪い合うため、
+//! ロックを保持しているスレッドの解放も遅くなる。
+//!
+//! 効果はCPUコアの数に依存し、コアが1つしかない環境では差が出ない。
+//! 計測する場合は`cargo run --release --example 04-03-02_ttas-benchmark`で実行すること。
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+#[path = "shared/backoff.rs"]
+mod backoff;
+
+use backoff::Backoff;
+
+const ITERATIONS: usize = 200_000;
+
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// 変更前の`lock`: 待機中も`swap`を繰り返す。
+    pub fn lock_tas(&self) -> Guard<'_, T> {
+        let mut backoff = Backoff::new();
+        while self.locked.swap(true, Ordering::Acquire) {
+            backoff.snooze();
+        }
+        Guard { lock: self }
+    }
+
+    /// 変更後の`lock`: ロックが解放されたことを確認してから`swap`を試みる。
+    pub fn lock_ttas(&self) -> Guard<'_, T> {
+        let mut backoff = Backoff::new();
+        while self.locked.swap(true, Ordering::Acquire) {
+            while self.locked.load(Ordering::Relaxed) {
+                backoff.snooze();
+            }
+        }
+        Guard { lock: self }
+    }
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+fn bench(threads: usize, lock: impl Fn(&SpinLock<usize>) -> Guard<'_, usize> + Sync) -> Duration {
+    let counter = SpinLock::new(0);
+    std::hint::black_box(&counter);
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    *lock(&counter) += 1;
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    // どちらの方法でも、カウンタの値は正確でなければならない。
+    assert_eq!(*counter.lock_tas(), threads * ITERATIONS);
+    duration
+}
+
+fn main() {
+    for threads in [8, 16] {
+        println!(
+            "{threads:>2} threads: TAS {:>12?}, TTAS {:>12?}",
+            bench(threads, SpinLock::lock_tas),
+            bench(threads, SpinLock::lock_ttas),
+        );
+    }
+}
@@ -1,47 +1,438 @@
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LockResult, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
-pub struct SpinLock<T> {
+#[path = "shared/backoff.rs"]
+mod backoff;
+
+use backoff::Backoff;
+
+pub struct SpinLock<T: ?Sized> {
     locked: AtomicBool,
+    /// ロックを保持したスレッドがパニックしたか（毒状態）
+    ///
+    /// パニックしたスレッドは値を更新している途中であった可能性があるため、`std::sync::Mutex`と同様に
+    /// 次にロックを獲得するスレッドがそれを検知できるようにする。
+    poisoned: AtomicBool,
+    /// ロックを保持しているスレッドの識別子（ロックされていない場合は0）
+    ///
+    /// 同じスレッドから`lock`を2回呼び出すと、自分自身の解放を待ち続けてデッドロックするため、
+    /// デバッグビルドでのみ所有者を記録し、再入を検出してパニックする。
+    /// リリースビルドではフィールド自体が存在しないため、オーバーヘッドはない。
+    #[cfg(debug_assertions)]
+    owner: AtomicU64,
+    /// 競合の統計情報（`stats`フィーチャーを有効にした場合のみ）
+    ///
+    /// カウンタを更新するたびに`locked`と同じキャッシュラインが無効化されないように、
+    /// 別のキャッシュラインに配置する。
+    #[cfg(feature = "stats")]
+    stats: CachePadded<Stats>,
     value: UnsafeCell<T>,
 }
 
+/// 07-02-02と同様に、値を64バイトにアラインして、他のフィールドとキャッシュラインを共有しないようにするラッパー
+#[cfg(feature = "stats")]
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+#[cfg(feature = "stats")]
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// ロックの競合を計測するカウンタ
+///
+/// 統計情報であり、他のメモリ操作との順序関係は必要ないため、すべて`Relaxed`で更新する。
+#[cfg(feature = "stats")]
+struct Stats {
+    acquisitions: AtomicU64,
+    contended_acquisitions: AtomicU64,
+    spin_iterations: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl Stats {
+    const fn new() -> Self {
+        Self {
+            acquisitions: AtomicU64::new(0),
+            contended_acquisitions: AtomicU64::new(0),
+            spin_iterations: AtomicU64::new(0),
+        }
+    }
+}
+
+/// `SpinLock::stats`が返す、統計情報のスナップショット
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpinLockStats {
+    /// ロックを獲得した回数
+    pub acquisitions: u64,
+    /// 最初の試行でロックを獲得できず、スピンした回数
+    pub contended_acquisitions: u64,
+    /// ロックの獲得を再試行した回数の合計
+    pub spin_iterations: u64,
+}
+
 /// Guard
 ///
 /// GuardはSpinLockよりも長生きできない。
 /// Guardは`Deref`と`DerefMut`を実装しているため、ロック保持中に`T`への不変参照および可変参照を提供する。
 /// Guard自体をスレッド間で送受信・共有できるようにするため、 別途`Send`および`Sync`のunsafe実装により`T`への制約を課している。
-pub struct Guard<'a, T> {
+pub struct Guard<'a, T: ?Sized> {
     lock: &'a SpinLock<T>,
+    /// ガードを作成した時点で、このスレッドがパニック中だったか
+    ///
+    /// 巻き戻し中のデストラクタでロックを獲得した場合に、誤って毒状態にしないために記録する。
+    panicking: bool,
+    /// ロックを獲得した時刻（デバッグビルドのみ）
+    #[cfg(debug_assertions)]
+    acquired_at: Instant,
 }
 
 /// `UnsafeCell<T>`は`Sync`でないため、コンパイラは`SpinLock<T>`を動的に`Sync`であることを判断できない。
 /// しかし、`SpinLock<T>`は内部可変性がスピンロックによって適切に同期されており、`T: Send`である限り、
 /// 複数スレッドから`SpinLock<T>`にアクセスしても安全である。
 /// その安全性をプログラマが保証して、それをコンパイラーに伝えるために、`unsafe impl`を使用して`Sync`を実装する。
-unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+unsafe impl<T: ?Sized> Sync for SpinLock<T> where T: Send {}
 
 impl<T> SpinLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
             locked: AtomicBool::new(false),
+            poisoned: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            owner: AtomicU64::new(0),
+            #[cfg(feature = "stats")]
+            stats: CachePadded(Stats::new()),
             value: UnsafeCell::new(value),
         }
     }
 
+    /// ロックを消費して、保護している値を返す。
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// ロックを獲得して値を`value`に置き換え、ロックを解放してから元の値を返す。
+    ///
+    /// `std::sync::Mutex::replace`と同様に、毒状態の場合は値を置き換えず、`value`を`PoisonError`で返す。
+    pub fn replace(&self, value: T) -> LockResult<T> {
+        match self.lock_checked() {
+            Ok(mut guard) => Ok(std::mem::replace(&mut *guard, value)),
+            Err(_) => Err(PoisonError::new(value)),
+        }
+    }
+
+    /// ロックを獲得して値を`T::default()`に置き換え、ロックを解放してから元の値を返す。
+    ///
+    /// 毒状態の場合は値を置き換えず、現在の値の代わりに`T::default()`を`PoisonError`で返す。
+    pub fn take(&self) -> LockResult<T>
+    where
+        T: Default,
+    {
+        self.replace(T::default())
+    }
+}
+
+/// `T`が動的サイズ型（`dyn Trait`やスライス）の場合も、`value`は最後のフィールドであるため、
+/// `Box<SpinLock<[u8; 4]>>`から`Box<SpinLock<[u8]>>`のように、サイズの決まった型から型強制（unsizing）できる。
+impl<T: ?Sized> SpinLock<T> {
+    /// 可変参照から、ロックを獲得せずに値への可変参照を返す。
+    ///
+    /// 可変参照を持っている場合は、他に参照が存在しないため、ロックを獲得する必要はない。
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// ロックを獲得する。
+    ///
+    /// 毒状態を確認しないため、毒状態を検知する必要がある場合は`lock_checked`を使用すること。
+    ///
+    /// デバッグビルドでは、ロックを保持しているスレッドが再度`lock`を呼び出すと、
+    /// デッドロックする代わりにパニックする。
+    /// ただし、ガードを他のスレッドに送った場合は、ロックを獲得したスレッドを所有者とみなす。
     pub fn lock(&self) -> Guard<'_, T> {
-        while self.locked.swap(true, Ordering::Acquire) {
+        #[cfg(debug_assertions)]
+        assert_ne!(
+            self.owner.load(Ordering::Relaxed),
+            current_thread_token(),
+            "SpinLock::lock called by the thread that already holds the lock (this would deadlock)"
+        );
+        // ロックを獲得できた場合は、そのままガードを返す（ファストパス）。
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        #[cfg(feature = "stats")]
+        self.stats
+            .contended_acquisitions
+            .fetch_add(1, Ordering::Relaxed);
+        let mut backoff = Backoff::new();
+        loop {
+            // 競合している場合は、失敗するたびに待機時間を指数的に増やし、
+            // 上限に達した後はCPUを他のスレッドに譲る。
+            //
+            // ロックが解放されたことを`Relaxed`のロードで確認してから、`compare_exchange`を試みる
+            // （test-and-test-and-set）。
+            // ロードはキャッシュラインを共有したまま読み込めるため、ロックが保持されている間に
+            // `compare_exchange`を繰り返して、キャッシュラインの排他的な所有権を奪い合うことを避けられる。
+            loop {
+                backoff.snooze();
+                #[cfg(feature = "stats")]
+                self.stats.spin_iterations.fetch_add(1, Ordering::Relaxed);
+                if !self.locked.load(Ordering::Relaxed) {
+                    break;
+                }
+            }
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+        }
+    }
+
+    /// ロックの獲得を1回だけ試みる。
+    ///
+    /// ロックが他のスレッドに保持されている場合はスピンせずに`None`を返す。
+    /// ロックの獲得に失敗した場合は、値を更新せず、他のメモリ操作との順序関係も必要ないため、
+    /// 失敗時のオーダリングは`Relaxed`で十分である。
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| {
+                #[cfg(debug_assertions)]
+                self.owner.store(current_thread_token(), Ordering::Relaxed);
+                #[cfg(feature = "stats")]
+                self.stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+                Guard {
+                    lock: self,
+                    panicking: std::thread::panicking(),
+                    #[cfg(debug_assertions)]
+                    acquired_at: Instant::now(),
+                }
+            })
+    }
+
+    /// ロックを獲得し、毒状態の場合は`PoisonError`でガードを返す。
+    ///
+    /// 毒状態であってもロックは獲得されているため、呼び出し側は`PoisonError::into_inner`で
+    /// ガードを取り出し、値を検査、修復できる。
+    pub fn lock_checked(&self) -> Result<Guard<'_, T>, PoisonError<Guard<'_, T>>> {
+        let guard = self.lock();
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// ロックを保持したスレッドがパニックしたかを返す。
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// 毒状態を解除する。
+    ///
+    /// 値を修復した後など、値が正しい状態であることを確認してから呼び出すこと。
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    /// `budget`の範囲内でスピンしながらロックの獲得を試みる。
+    ///
+    /// 予算を使い切った場合は`None`を返す。
+    /// 純粋なスピンロックであるため、`yield_now`などのシステムコールは呼び出さない。
+    /// 期限の確認に使用する`Instant::now`も、`DEADLINE_CHECK_INTERVAL`回に1回だけ呼び出す。
+    pub fn try_lock_for(&self, budget: SpinBudget) -> Option<Guard<'_, T>> {
+        let deadline = budget.timeout.map(|timeout| Instant::now() + timeout);
+        let mut spins: u32 = 0;
+        loop {
+            // `lock`と同様に、2回目以降はロックが解放されたことを確認してから`compare_exchange`を試みる。
+            if (spins == 0 || !self.locked.load(Ordering::Relaxed))
+                && let Some(guard) = self.try_lock()
+            {
+                return Some(guard);
+            }
+            #[cfg(feature = "stats")]
+            if spins == 0 {
+                self.stats
+                    .contended_acquisitions
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            spins = spins.saturating_add(1);
+            if budget.max_spins.is_some_and(|max| max <= spins) {
+                return None;
+            }
+            if let Some(deadline) = deadline
+                && spins.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+                && deadline <= Instant::now()
+            {
+                return None;
+            }
             std::hint::spin_loop();
+            #[cfg(feature = "stats")]
+            self.stats.spin_iterations.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 競合の統計情報のスナップショットを返す（`stats`フィーチャーを有効にした場合のみ）。
+    ///
+    /// 各カウンタは個別に読み込むため、他のスレッドがロックを獲得している最中は、
+    /// カウンタ同士が厳密に一致しない場合がある。
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> SpinLockStats {
+        SpinLockStats {
+            acquisitions: self.stats.acquisitions.load(Ordering::Relaxed),
+            contended_acquisitions: self.stats.contended_acquisitions.load(Ordering::Relaxed),
+            spin_iterations: self.stats.spin_iterations.load(Ordering::Relaxed),
         }
-        Guard { lock: self }
+    }
+
+    /// 競合の統計情報を0に戻す（`stats`フィーチャーを有効にした場合のみ）。
+    #[cfg(feature = "stats")]
+    pub fn reset_stats(&self) {
+        self.stats.acquisitions.store(0, Ordering::Relaxed);
+        self.stats
+            .contended_acquisitions
+            .store(0, Ordering::Relaxed);
+        self.stats.spin_iterations.store(0, Ordering::Relaxed);
+    }
+
+    /// 最初の試行でロックを獲得できなかった回数を返す（`stats`フィーチャーを有効にした場合のみ）。
+    ///
+    /// `stats().contended_acquisitions`と同じ値である。
+    #[cfg(feature = "stats")]
+    pub fn contention_count(&self) -> u64 {
+        self.stats.contended_acquisitions.load(Ordering::Relaxed)
+    }
+
+    /// 最初の試行でロックを獲得できなかった回数を0に戻す（`stats`フィーチャーを有効にした場合のみ）。
+    #[cfg(feature = "stats")]
+    pub fn reset_contention_count(&self) {
+        self.stats
+            .contended_acquisitions
+            .store(0, Ordering::Relaxed);
+    }
+
+    /// ロックを獲得して`f`を実行し、`f`から戻った時点でロックを解放する。
+    ///
+    /// ガードがクロージャーの外に出ないため、意図せずロックを長く保持することを防げる。
+    /// `f`がパニックした場合でも、巻き戻し（unwind）の過程でガードがドロップされるため、
+    /// ロックは解放される（ただし、毒状態になる）。
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    /// ロックを獲得できた場合のみ`f`を実行する。
+    /// ロックが他のスレッドに保持されている場合は、`f`を実行せずに`None`を返す。
+    pub fn try_with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.try_lock().map(|mut guard| f(&mut guard))
+    }
+
+    /// `Arc`で共有しているロックを獲得し、`Arc`を保持するガードを返す。
+    ///
+    /// `Guard`はロックを借用するため、`Arc<SpinLock<T>>`を所有する関数から返したり、構造体に格納したり
+    /// できないが、`OwnedGuard`はライフタイムを持たないため、ロックを獲得したスコープの外に持ち出せる。
+    pub fn lock_owned(self: &Arc<Self>) -> OwnedGuard<T> {
+        OwnedGuard::new(Arc::clone(self), self.lock())
+    }
+
+    /// ロックの獲得を1回だけ試み、獲得できた場合は`Arc`を保持するガードを返す。
+    pub fn try_lock_owned(self: &Arc<Self>) -> Option<OwnedGuard<T>> {
+        self.try_lock()
+            .map(|guard| OwnedGuard::new(Arc::clone(self), guard))
+    }
+
+    /// ロックが獲得されているかを返す。
+    ///
+    /// ログ出力やアサーション用の診断目的の関数であり、戻り値は呼び出した時点のスナップショットに過ぎない。
+    /// 戻り値を利用して`value`にアクセスするわけではないため、`Relaxed`で十分である。
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl<A: ?Sized> SpinLock<A> {
+    /// 2つのロックを、デッドロックしない順序で獲得する。
+    ///
+    /// 2つのスレッドが`a`、`b`と`b`、`a`の逆の順序でロックを獲得すると、お互いに相手が保持している
+    /// ロックを待ち続けてデッドロックする。
+    /// 引数の順序に関わらず、アドレスの小さいロックから獲得することで、獲得する順序を統一する。
+    ///
+    /// `a`と`b`が同じロックの場合は、自分自身を待ち続けることになるためパニックする。
+    pub fn lock_both<'a, B: ?Sized>(
+        a: &'a SpinLock<A>,
+        b: &'a SpinLock<B>,
+    ) -> (Guard<'a, A>, Guard<'a, B>) {
+        if lock_order(a, b) {
+            let a = a.lock();
+            (a, b.lock())
+        } else {
+            let b = b.lock();
+            (a.lock(), b)
+        }
+    }
+
+    /// 2つのロックの獲得を1回だけ試みる。
+    ///
+    /// どちらかのロックを獲得できなかった場合は、獲得したロックを解放して`None`を返す。
+    pub fn try_lock_both<'a, B: ?Sized>(
+        a: &'a SpinLock<A>,
+        b: &'a SpinLock<B>,
+    ) -> Option<(Guard<'a, A>, Guard<'a, B>)> {
+        if lock_order(a, b) {
+            let a = a.try_lock()?;
+            Some((a, b.try_lock()?))
+        } else {
+            let b = b.try_lock()?;
+            Some((a.try_lock()?, b))
+        }
+    }
+}
+
+/// `a`を`b`より先に獲得する場合に`true`を返す。
+fn lock_order<A: ?Sized, B: ?Sized>(a: &SpinLock<A>, b: &SpinLock<B>) -> bool {
+    let a = std::ptr::from_ref(a).addr();
+    let b = std::ptr::from_ref(b).addr();
+    assert_ne!(a, b, "SpinLock::lock_both called with the same lock twice");
+    a < b
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// `Debug`の実装はロックの獲得を待機しない。
+/// ロックを獲得できた場合は値を出力し、他のスレッドがロックを保持している場合は`<locked>`を出力する。
+impl<T> fmt::Debug for SpinLock<T>
+where
+    T: fmt::Debug + ?Sized,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("SpinLock");
+        match self.try_lock() {
+            Some(guard) => d.field("value", &&*guard),
+            None => d.field("value", &format_args!("<locked>")),
+        };
+        d.finish()
     }
 }
 
 /// `'_`は、この実装がGuardのライフタイム引数に依存せず、`'static`を含めてすべてのライフタイムに
 /// 対して同一に成立することを示す。
 /// これは `impl<'a, T> Deref for Guard<'a, T>` と等価である。
-impl<T> Deref for Guard<'_, T> {
+impl<T: ?Sized> Deref for Guard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -54,22 +445,368 @@ impl<T> Deref for Guard<'_, T> {
 
 /// `DerefMut`は`Deref`を継承するトレイトであり、`Target`関連型は`Deref`側で定義されたものをそのまま使用する。
 /// そのため、`DerefMut`を実装する型は必ず`Deref`も実装している必要がある。
-impl<T> DerefMut for Guard<'_, T> {
+impl<T: ?Sized> DerefMut for Guard<'_, T> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.value.get() }
     }
 }
 
-unsafe impl<T> Send for Guard<'_, T> where T: Send {}
-unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
+/// ガードはロックを保持しているため、`std::sync::MutexGuard`と同様に保護している値をそのまま出力する。
+impl<T: fmt::Debug + ?Sized> fmt::Debug for Guard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+unsafe impl<T: ?Sized> Send for Guard<'_, T> where T: Send {}
+unsafe impl<T: ?Sized> Sync for Guard<'_, T> where T: Sync {}
 
-impl<T> Drop for Guard<'_, T> {
+impl<T: ?Sized> Drop for Guard<'_, T> {
     fn drop(&mut self) {
-        self.lock.locked.store(false, Ordering::Release);
+        // ガードが存在する間は、ロックが獲得されたままでなければならない。
+        debug_assert!(
+            self.lock.is_locked(),
+            "guard dropped while the lock is not held"
+        );
+        #[cfg(debug_assertions)]
+        let held = {
+            self.lock.owner.store(0, Ordering::Relaxed);
+            self.acquired_at.elapsed()
+        };
+        unlock(&self.lock.locked, &self.lock.poisoned, self.panicking);
+        #[cfg(debug_assertions)]
+        check_hold_time(held);
+    }
+}
+
+/// ロックを解放する。
+///
+/// ガードを保持している間にパニックが発生した場合は、ロックを解放する前に毒状態にする。
+/// `poisoned`へのストアは、`locked`へのReleaseストアより前に行うため、次にロックを獲得したスレッドは
+/// 毒状態を観測できる。
+fn unlock(locked: &AtomicBool, poisoned: &AtomicBool, panicking: bool) {
+    if !panicking && std::thread::panicking() {
+        poisoned.store(true, Ordering::Relaxed);
+    }
+    locked.store(false, Ordering::Release);
+}
+
+/// 現在のスレッドを識別する0以外の値を返す。
+///
+/// `ThreadId::as_u64`は安定化されていないため、スレッドごとに連番を割り当てる。
+/// 所有者はロックを獲得したスレッド自身が書き込み、解放する前に0に戻すため、
+/// 他のスレッドが`owner`と自分の値の一致を観測することはない。
+#[cfg(debug_assertions)]
+fn current_thread_token() -> u64 {
+    static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static TOKEN: u64 = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+    }
+    TOKEN.with(|token| *token)
+}
+
+/// ロックを保持した時間が、この時間（ナノ秒）を超えた場合に通知する。
+static HOLD_WARN_THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(5_000_000);
+
+/// ロックを保持した時間が閾値を超えた場合に呼び出す関数
+static VIOLATION_HOOK: RwLock<fn(Duration)> = RwLock::new(warn_long_hold);
+
+/// ロックを保持した時間の閾値を設定する（既定値は5ミリ秒）。
+///
+/// スピンロックを長時間保持すると、他のスレッドがCPUを浪費し続けるが、本番環境で問題になるまで
+/// 気付きにくい。
+/// そこで、デバッグビルドではガードをドロップしたときに保持していた時間を計測し、閾値を超えた場合は
+/// `set_violation_hook`で設定した関数に通知する。
+/// リリースビルドでは時間を計測しないため、オーバーヘッドはない。
+pub fn set_hold_warn_threshold(threshold: Duration) {
+    let nanos = u64::try_from(threshold.as_nanos()).unwrap_or(u64::MAX);
+    HOLD_WARN_THRESHOLD_NANOS.store(nanos, Ordering::Relaxed);
+}
+
+/// ロックを保持した時間が閾値を超えた場合に呼び出す関数を設定する。
+///
+/// 関数はガードをドロップしたスレッドで、保持していた時間を引数に呼び出される。
+/// 既定では標準エラー出力に警告を出力する。
+pub fn set_violation_hook(hook: fn(Duration)) {
+    // 読み込む側と同じく、毒状態を無視する（関数ポインタを書き換えるだけであり、不整合な状態にはならない）。
+    *VIOLATION_HOOK
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = hook;
+}
+
+fn warn_long_hold(held: Duration) {
+    eprintln!("warning: SpinLock was held for {held:?}");
+}
+
+/// ロックを保持した時間`held`が閾値を超えた場合に、フックを呼び出す。
+///
+/// ガードはロックを解放してから呼び出す。
+/// フックがパニックしてもロックが保持されたままにならず、フックの実行時間がクリティカルセクションに含まれない。
+#[cfg(debug_assertions)]
+fn check_hold_time(held: Duration) {
+    let threshold = Duration::from_nanos(HOLD_WARN_THRESHOLD_NANOS.load(Ordering::Relaxed));
+    if threshold < held {
+        // 呼び出し中にフックが変更されても問題ないように、コピーしてから呼び出す。
+        let hook = *VIOLATION_HOOK
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        hook(held);
+    }
+}
+
+/// `try_lock_for`で期限を確認する間隔（スピン回数）
+const DEADLINE_CHECK_INTERVAL: u32 = 64;
+
+/// `SpinLock::try_lock_for`でスピンする予算
+///
+/// 最大スピン回数と期限（`Duration`）の両方を指定した場合は、どちらか一方に達した時点で終了する。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpinBudget {
+    max_spins: Option<u32>,
+    timeout: Option<Duration>,
+}
+
+impl SpinBudget {
+    /// 最大`n`回だけロックの獲得を試みる予算
+    pub const fn spins(n: u32) -> Self {
+        Self {
+            max_spins: Some(n),
+            timeout: None,
+        }
+    }
+
+    /// `timeout`が経過するまでロックの獲得を試みる予算
+    pub const fn timeout(timeout: Duration) -> Self {
+        Self {
+            max_spins: None,
+            timeout: Some(timeout),
+        }
+    }
+
+    /// 最大スピン回数を追加する。
+    pub const fn with_spins(self, n: u32) -> Self {
+        Self {
+            max_spins: Some(n),
+            ..self
+        }
+    }
+
+    /// 期限を追加する。
+    pub const fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Guard<'a, T> {
+    /// ロックを解放する。
+    ///
+    /// `drop(guard)`と同じ動作であるが、ロックを解放する箇所を明示し、検索しやすくするために使用する。
+    /// ガードを消費して`Drop`に解放を委ねるため、ロックが2回解放されることはない。
+    /// `map`と同様に、`T`のメソッドと衝突しないように関連関数としている。
+    pub fn unlock(guard: Self) {
+        drop(guard);
+    }
+
+    /// ロックを解放せずにガードを破棄し、ロックと同じライフタイムを持つ可変参照を返す。
+    ///
+    /// 初期化した後は変更しない値などで使用する（`std::sync::MutexGuard::leak`と同様）。
+    /// ロックは二度と解放されないため、以降`try_lock`は常に`None`を返し、`lock`は永遠にスピンする。
+    /// これは意図した動作である（デバッグビルドでは、リークしたスレッドが`lock`を呼び出すと、
+    /// 再入として検出されてパニックする）。
+    pub fn leak(guard: Self) -> &'a mut T {
+        let lock = guard.lock;
+        // `Drop`を実行しないため、ロックは解放されない。
+        std::mem::forget(guard);
+        // 安全性: ロックは解放されないため、他に`value`へアクセスする者は存在しない。
+        unsafe { &mut *lock.value.get() }
+    }
+
+    /// ロックを保持したまま、保護している値の一部（フィールドなど）だけを公開するガードに変換する。
+    ///
+    /// `Guard`は`T`に参照外しされるため、`T`が同名のメソッド（`Option::map`など）を持つ場合に
+    /// 衝突しないように、`std::sync::MutexGuard::map`と同様にメソッドではなく関連関数としている。
+    /// したがって、`Guard::map(guard, |v| &mut v.field)`のように呼び出す。
+    ///
+    /// `f`がパニックした場合、元のガードは通常通りドロップされるため、ロックは解放される。
+    pub fn map<U: ?Sized>(mut guard: Self, f: impl FnOnce(&mut T) -> &mut U) -> MappedGuard<'a, U> {
+        let value = NonNull::from(f(&mut *guard));
+        let (lock, panicking) = (guard.lock, guard.panicking);
+        #[cfg(debug_assertions)]
+        let acquired_at = guard.acquired_at;
+        // ロックの解放は`MappedGuard`に引き継ぐため、元のガードの`Drop`を実行しない。
+        // これにより、ロックが2回解放されることを防ぐ。
+        std::mem::forget(guard);
+        MappedGuard {
+            locked: &lock.locked,
+            poisoned: &lock.poisoned,
+            #[cfg(debug_assertions)]
+            owner: &lock.owner,
+            panicking,
+            #[cfg(debug_assertions)]
+            acquired_at,
+            value,
+            _marker: PhantomData,
+        }
+    }
+
+    /// `f`が`Some`を返した場合は`map`と同様に変換し、`None`を返した場合は元のガードを返す。
+    pub fn try_map<U: ?Sized>(
+        mut guard: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedGuard<'a, U>, Self> {
+        let Some(value) = f(&mut *guard).map(NonNull::from) else {
+            return Err(guard);
+        };
+        let (lock, panicking) = (guard.lock, guard.panicking);
+        #[cfg(debug_assertions)]
+        let acquired_at = guard.acquired_at;
+        std::mem::forget(guard);
+        Ok(MappedGuard {
+            locked: &lock.locked,
+            poisoned: &lock.poisoned,
+            #[cfg(debug_assertions)]
+            owner: &lock.owner,
+            panicking,
+            #[cfg(debug_assertions)]
+            acquired_at,
+            value,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// 保護している値の一部のみを公開するガード
+///
+/// `Guard::map`または`Guard::try_map`からのみ作成でき、ドロップされたときにロックを解放する。
+/// `value`はロックで保護された値の一部を指しており、`PhantomData<&'a mut U>`によって、
+/// `&'a mut U`を保持しているのと同様にライフタイムと変性をコンパイラーに伝えている。
+pub struct MappedGuard<'a, U: ?Sized> {
+    locked: &'a AtomicBool,
+    poisoned: &'a AtomicBool,
+    #[cfg(debug_assertions)]
+    owner: &'a AtomicU64,
+    panicking: bool,
+    #[cfg(debug_assertions)]
+    acquired_at: Instant,
+    value: NonNull<U>,
+    _marker: PhantomData<&'a mut U>,
+}
+
+impl<U: ?Sized> Deref for MappedGuard<'_, U> {
+    type Target = U;
+
+    fn deref(&self) -> &Self::Target {
+        // 安全性: ロックを保持しているため、`value`に他のスレッドはアクセスできない。
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<U: fmt::Debug + ?Sized> fmt::Debug for MappedGuard<'_, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+unsafe impl<U: ?Sized> Send for MappedGuard<'_, U> where U: Send {}
+unsafe impl<U: ?Sized> Sync for MappedGuard<'_, U> where U: Sync {}
+
+impl<U: ?Sized> Drop for MappedGuard<'_, U> {
+    fn drop(&mut self) {
+        debug_assert!(
+            self.locked.load(Ordering::Relaxed),
+            "guard dropped while the lock is not held"
+        );
+        #[cfg(debug_assertions)]
+        let held = {
+            self.owner.store(0, Ordering::Relaxed);
+            self.acquired_at.elapsed()
+        };
+        unlock(self.locked, self.poisoned, self.panicking);
+        #[cfg(debug_assertions)]
+        check_hold_time(held);
+    }
+}
+
+/// `Arc`を保持することで、ロックよりも長生きできるガード
+///
+/// `SpinLock::lock_owned`または`SpinLock::try_lock_owned`からのみ作成でき、ドロップされたときにロックを解放する。
+/// 06章の`Arc`はライブラリとして共有されていないため、`std::sync::Arc`を使用している。
+pub struct OwnedGuard<T: ?Sized> {
+    lock: Arc<SpinLock<T>>,
+    panicking: bool,
+    #[cfg(debug_assertions)]
+    acquired_at: Instant,
+}
+
+impl<T: ?Sized> OwnedGuard<T> {
+    /// `guard`が獲得したロックの解放を、`OwnedGuard`に引き継ぐ。
+    fn new(lock: Arc<SpinLock<T>>, guard: Guard<'_, T>) -> Self {
+        let guard = ManuallyDrop::new(guard);
+        Self {
+            lock,
+            panicking: guard.panicking,
+            #[cfg(debug_assertions)]
+            acquired_at: guard.acquired_at,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for OwnedGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for OwnedGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for OwnedGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// `Arc<SpinLock<T>>`は`T: Send`であれば`Sync`になるが、共有されたガードは`&T`を提供するため、
+/// `Guard`と同様に`Sync`には`T: Sync`を要求する。
+unsafe impl<T: ?Sized> Send for OwnedGuard<T> where T: Send {}
+unsafe impl<T: ?Sized> Sync for OwnedGuard<T> where T: Sync {}
+
+impl<T: ?Sized> Drop for OwnedGuard<T> {
+    fn drop(&mut self) {
+        debug_assert!(
+            self.lock.is_locked(),
+            "guard dropped while the lock is not held"
+        );
+        #[cfg(debug_assertions)]
+        let held = {
+            self.lock.owner.store(0, Ordering::Relaxed);
+            self.acquired_at.elapsed()
+        };
+        unlock(&self.lock.locked, &self.lock.poisoned, self.panicking);
+        #[cfg(debug_assertions)]
+        check_hold_time(held);
     }
 }
 
 fn main() {
+    // デバッグビルドでは、ロックを100ミリ秒より長く保持すると警告を出力する。
+    set_hold_warn_threshold(Duration::from_millis(100));
+    set_violation_hook(|held| eprintln!("critical section took {held:?}"));
+
     let x = SpinLock::new(Vec::new());
     std::thread::scope(|s| {
         s.spawn(|| x.lock().push(1));
@@ -99,4 +836,792 @@ fn main() {
     assert!(guard.as_slice().contains(&1));
     assert!(guard.as_slice().contains(&2));
     assert!(guard.as_slice().contains(&3));
+    drop(guard);
+
+    // ガードを変換して、`Vec`の先頭要素だけを公開する。
+    let mut first = Guard::map(x.lock(), |v| &mut v[0]);
+    *first += 10;
+    drop(first);
+    let guard = x.lock();
+    assert!(guard.as_slice().iter().any(|&n| 10 < n));
+    drop(guard);
+
+    // クロージャーを使用して、ロックを保持する範囲を明示する。
+    let len = x.with_lock(|v| {
+        v.push(4);
+        v.len()
+    });
+    assert_eq!(x.try_with_lock(|v| v.len()), Some(len));
+
+    // スピンする予算を制限してロックの獲得を試みる。
+    let budget = SpinBudget::spins(1_000).with_timeout(Duration::from_millis(1));
+    assert!(x.try_lock_for(budget).is_some());
+
+    // 空の`Vec`の先頭要素は存在しないため、元のガードが返される。
+    let empty = SpinLock::new(Vec::<i32>::new());
+    assert!(Guard::try_map(empty.lock(), |v| v.first_mut()).is_err());
+
+    // ロックを保持したままスレッドがパニックすると、ロックは毒状態になる。
+    std::thread::scope(|s| {
+        let result = s
+            .spawn(|| {
+                let mut guard = x.lock();
+                guard.push(5);
+                panic!("panicked while holding the lock");
+            })
+            .join();
+        assert!(result.is_err());
+    });
+    match x.lock_checked() {
+        Ok(_) => unreachable!("the lock must be poisoned"),
+        Err(e) => println!("poisoned: {:?}", *e.into_inner()),
+    }
+    x.clear_poison();
+    assert!(x.lock_checked().is_ok());
+
+    // `cargo run --features stats`で実行した場合は、競合の統計情報を出力する。
+    #[cfg(feature = "stats")]
+    println!("{:?}", x.stats());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // スピンロックはロックを獲得したスレッドを記録しないため、ガードは`T: Send`であれば`Send`であり、
+    // `T: Sync`であれば`Sync`であるはず。
+    assert_impl_all!(Guard<'static, u32>: Send, Sync);
+    assert_impl_all!(Guard<'static, Cell<u32>>: Send);
+    assert_not_impl_any!(Guard<'static, Cell<u32>>: Sync);
+    assert_not_impl_any!(Guard<'static, Rc<u32>>: Send, Sync);
+    assert_impl_all!(MappedGuard<'static, u32>: Send, Sync);
+    assert_not_impl_any!(MappedGuard<'static, Rc<u32>>: Send, Sync);
+    assert_impl_all!(OwnedGuard<u32>: Send, Sync);
+    assert_not_impl_any!(OwnedGuard<Rc<u32>>: Send, Sync);
+
+    #[test]
+    fn backoff_spins_then_yields_then_completes() {
+        let mut backoff = Backoff::new();
+        let mut spins = Vec::new();
+        while let Some(n) = backoff.spins() {
+            spins.push(n);
+            backoff.snooze();
+        }
+        // 最初の4回は、スピン回数を倍増させるはず。
+        assert_eq!(spins, [1, 2, 4, 8]);
+
+        // 次の8回は`yield_now`し、その後に使い切るはず。
+        for _ in 0..8 {
+            assert!(!backoff.completed());
+            backoff.snooze();
+        }
+        assert!(backoff.completed());
+
+        // 使い切った後は、スレッドを一時停止するはず。
+        let start = Instant::now();
+        backoff.snooze();
+        assert!(start.elapsed() >= backoff::PARK_TIMEOUT / 2);
+        assert_eq!(backoff.spins(), None);
+        assert!(backoff.completed());
+
+        // スピンの上限を指定した場合は、合計がその回数になるように最後のスピン回数を減らすはず。
+        let mut backoff = Backoff::with_spin_limit(10);
+        let mut spins = Vec::new();
+        while let Some(n) = backoff.spins() {
+            spins.push(n);
+            backoff.snooze();
+        }
+        assert_eq!(spins, [1, 2, 4, 3]);
+        // `0`の場合はスピンせず、`yield_now`だけで使い切るはず。
+        let mut backoff = Backoff::with_spin_limit(0);
+        assert_eq!(backoff.spins(), None);
+        for _ in 0..backoff::YIELD_LIMIT {
+            assert!(!backoff.completed());
+            backoff.snooze();
+        }
+        assert!(backoff.completed());
+    }
+
+    #[test]
+    fn lock_with_backoff_keeps_count_exact() {
+        let lock = SpinLock::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *lock.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 80_000);
+    }
+
+    #[test]
+    fn static_lock_is_shared_between_threads() {
+        // `SpinLock::new`は`const`関数であるため、`lazy_static`などを使用せずに`static`変数にできる。
+        static LOCK: SpinLock<u32> = SpinLock::new(0);
+        let threads = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..1_000 {
+                        *LOCK.lock() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        // `'static`な参照であるため、スコープ付きスレッドでなくても共有でき、すべてのインクリメントが反映されるはず。
+        assert_eq!(*LOCK.lock(), 4_000);
+    }
+
+    #[test]
+    fn ttas_paths_keep_count_exact() {
+        let lock = SpinLock::new(0);
+        std::thread::scope(|s| {
+            for id in 0..8 {
+                let lock = &lock;
+                s.spawn(move || {
+                    for _ in 0..10_000 {
+                        // `lock`と`try_lock_for`の両方の待機経路を通す。
+                        if id % 2 == 0 {
+                            *lock.lock() += 1;
+                        } else {
+                            let budget = SpinBudget::timeout(Duration::from_secs(60));
+                            *lock.try_lock_for(budget).unwrap() += 1;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 80_000);
+    }
+
+    #[test]
+    fn try_lock_fails_while_locked() {
+        let lock = SpinLock::new(0);
+        let guard = lock.lock();
+
+        // 別スレッドでは、ロックが保持されているため獲得できないはず。
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(lock.try_lock().is_none()));
+        });
+
+        // ガードをドロップすると、ロックを獲得できるはず。
+        drop(guard);
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(lock.try_lock().is_some()));
+        });
+    }
+
+    #[test]
+    fn try_lock_counts_successful_acquisitions() {
+        let lock = SpinLock::new(0);
+        let successes = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        if let Some(mut guard) = lock.try_lock() {
+                            *guard += 1;
+                            successes.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        // ロックを獲得できた回数だけ、値が更新されているはず。
+        assert_eq!(*lock.lock(), successes.load(Ordering::Relaxed));
+    }
+
+    struct ServerState {
+        connections: u32,
+        name: String,
+    }
+
+    #[test]
+    fn map_exposes_only_a_field() {
+        let lock = SpinLock::new(ServerState {
+            connections: 0,
+            name: String::from("server"),
+        });
+
+        let mut connections = Guard::map(lock.lock(), |state| &mut state.connections);
+        *connections += 1;
+        // 変換後のガードがロックを保持しているはず。
+        assert!(lock.is_locked());
+        drop(connections);
+
+        // ロックは1回だけ解放され、変更が観測できるはず。
+        assert!(!lock.is_locked());
+        let state = lock.lock();
+        assert_eq!(state.connections, 1);
+        assert_eq!(state.name, "server");
+    }
+
+    #[test]
+    fn try_map_returns_original_guard_on_failure() {
+        let lock = SpinLock::new(ServerState {
+            connections: 0,
+            name: String::new(),
+        });
+
+        let guard = lock.lock();
+        let Err(mut guard) = Guard::try_map(guard, |state| {
+            (!state.name.is_empty()).then_some(&mut state.name)
+        }) else {
+            panic!("name is empty");
+        };
+        // 元のガードは引き続きロックを保持しているはず。
+        assert!(lock.is_locked());
+        guard.name.push_str("server");
+        drop(guard);
+        assert!(!lock.is_locked());
+
+        let mut name = Guard::try_map(lock.lock(), |state| {
+            (!state.name.is_empty()).then_some(&mut state.name)
+        })
+        .ok()
+        .unwrap();
+        name.push('!');
+        drop(name);
+        assert_eq!(lock.lock().name, "server!");
+    }
+
+    #[test]
+    fn map_releases_lock_when_closure_panics() {
+        let lock = SpinLock::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            Guard::map(lock.lock(), |_| -> &mut i32 { panic!("map failed") })
+        }));
+        assert!(result.is_err());
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn try_lock_for_gives_up_when_budget_is_exhausted() {
+        let lock = SpinLock::new(0);
+        let locked = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = lock.lock();
+                locked.wait();
+                std::thread::sleep(Duration::from_millis(200));
+            });
+            locked.wait();
+
+            // 期限に達した時点で、すぐに`None`を返すはず。
+            let start = Instant::now();
+            assert!(
+                lock.try_lock_for(SpinBudget::timeout(Duration::from_millis(20)))
+                    .is_none()
+            );
+            let elapsed = start.elapsed();
+            assert!(Duration::from_millis(20) <= elapsed);
+            assert!(elapsed < Duration::from_millis(150));
+
+            // スピン回数の上限に達した場合も`None`を返すはず。
+            assert!(lock.try_lock_for(SpinBudget::spins(100)).is_none());
+        });
+
+        // ロックが解放されていれば、獲得できるはず。
+        assert!(lock.try_lock_for(SpinBudget::spins(1)).is_some());
+    }
+
+    #[test]
+    fn unlock_releases_lock_immediately() {
+        let lock = SpinLock::new(0);
+        let mut guard = lock.lock();
+        *guard += 1;
+        Guard::unlock(guard);
+
+        // 解放した直後に、他のスレッドがロックを獲得できるはず。
+        assert!(!lock.is_locked());
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(*lock.try_lock().unwrap(), 1));
+        });
+        // ロックは1回だけ解放されるため、ロックの状態は正しいはず。
+        let guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        Guard::unlock(guard);
+        assert!(!lock.is_poisoned());
+    }
+
+    #[test]
+    fn leak_keeps_lock_held_forever() {
+        let lock = SpinLock::new(Vec::new());
+        let frozen = Guard::leak(lock.lock());
+        frozen.push(1);
+
+        // ロックは解放されないため、どのスレッドからも獲得できないはず。
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(lock.try_lock().is_none()));
+        });
+        assert_eq!(frozen, &[1]);
+    }
+
+    #[test]
+    fn with_lock_releases_lock_after_closure() {
+        let lock = SpinLock::new(0);
+        assert_eq!(lock.with_lock(|v| std::mem::replace(v, 1)), 0);
+        assert!(!lock.is_locked());
+
+        // ロックが保持されている場合は、クロージャーを実行しないはず。
+        let guard = lock.lock();
+        assert_eq!(lock.try_with_lock(|_| unreachable!()), None::<()>);
+        drop(guard);
+        assert_eq!(lock.try_with_lock(|v| *v), Some(1));
+    }
+
+    #[test]
+    fn with_lock_releases_lock_when_closure_panics() {
+        let lock = SpinLock::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lock.with_lock(|v| {
+                *v += 1;
+                panic!("closure panicked");
+            })
+        }));
+        assert!(result.is_err());
+
+        // 別スレッドからロックを獲得できるはず。
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(lock.with_lock(|v| *v), 1));
+        });
+        // ただし、クロージャーがパニックしたため毒状態になっているはず。
+        assert!(lock.is_poisoned());
+    }
+
+    #[test]
+    fn panic_while_locked_poisons_lock() {
+        struct Account {
+            balance: i32,
+            history: Vec<i32>,
+        }
+
+        let lock = SpinLock::new(Account {
+            balance: 0,
+            history: Vec::new(),
+        });
+        std::thread::scope(|s| {
+            let result = s
+                .spawn(|| {
+                    let mut account = lock.lock();
+                    account.balance += 100;
+                    // `history`を更新する前にパニックする。
+                    panic!("panicked in the middle of an update");
+                })
+                .join();
+            assert!(result.is_err());
+        });
+
+        // 次にロックを獲得したスレッドは、毒状態を観測できるはず。
+        assert!(lock.is_poisoned());
+        let Err(e) = lock.lock_checked() else {
+            panic!("the lock must be poisoned");
+        };
+        let mut account = e.into_inner();
+        assert_eq!(account.balance, 100);
+        assert!(account.history.is_empty());
+
+        // 値を修復してから、毒状態を解除する。
+        account.history.push(100);
+        drop(account);
+        lock.clear_poison();
+        assert!(lock.lock_checked().is_ok());
+    }
+
+    #[test]
+    fn replace_and_take_swap_values_under_lock() {
+        let lock = SpinLock::new(vec![1]);
+        assert_eq!(lock.replace(vec![2, 3]).unwrap(), [1]);
+        assert_eq!(lock.take().unwrap(), [2, 3]);
+        assert!(lock.lock().is_empty());
+
+        // 毒状態の場合は、値を置き換えずに、渡した値を返すはず。
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut guard = lock.lock();
+            guard.push(4);
+            panic!("poison the lock");
+        }));
+        let Err(e) = lock.replace(vec![5]) else {
+            panic!("the lock must be poisoned");
+        };
+        assert_eq!(e.into_inner(), [5]);
+        assert_eq!(*lock.lock(), [4]);
+    }
+
+    #[test]
+    fn lock_acquired_while_panicking_does_not_poison() {
+        struct LockOnDrop<'a>(&'a SpinLock<i32>);
+
+        impl Drop for LockOnDrop<'_> {
+            fn drop(&mut self) {
+                // 巻き戻し中にロックを獲得して、正常に解放する。
+                *self.0.lock() += 1;
+            }
+        }
+
+        let lock = SpinLock::new(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _lock_on_drop = LockOnDrop(&lock);
+            panic!("unwinding");
+        }));
+        assert!(result.is_err());
+        assert_eq!(*lock.lock(), 1);
+        assert!(!lock.is_poisoned());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "already holds the lock")]
+    fn reentrant_lock_panics_in_debug_mode() {
+        let lock = SpinLock::new(0);
+        let _guard = lock.lock();
+        // デッドロックせずにパニックするはず。
+        let _reentrant = lock.lock();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn long_hold_triggers_violation_hook() {
+        use std::cell::Cell;
+
+        static LOCK: SpinLock<i32> = SpinLock::new(0);
+
+        thread_local! {
+            /// 通知された時間と、フックを呼び出したときにロックが保持されていたかどうか
+            static VIOLATION: Cell<Option<(Duration, bool)>> = const { Cell::new(None) };
+            static PANIC_IN_HOOK: Cell<bool> = const { Cell::new(false) };
+        }
+
+        // フックはガードをドロップしたスレッドで呼び出されるため、他のテストの影響を受けないように、
+        // スレッドローカルに記録する。
+        set_violation_hook(|held| {
+            VIOLATION.with(|v| v.set(Some((held, LOCK.is_locked()))));
+            if PANIC_IN_HOOK.with(Cell::get) {
+                panic!("violation hook panicked");
+            }
+        });
+
+        *LOCK.lock() += 1;
+        // 短時間だけ保持した場合は、通知されないはず。
+        assert_eq!(VIOLATION.with(Cell::get), None);
+
+        let guard = Guard::map(LOCK.lock(), |v| v);
+        std::thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        let (held, locked) = VIOLATION.with(Cell::get).expect("hook must be called");
+        assert!(Duration::from_millis(20) <= held && held < Duration::from_secs(1));
+        // フックはロックを解放した後に呼び出されるはず。
+        assert!(!locked);
+
+        // フックがパニックしても、ロックは解放されており、毒状態にもならないはず。
+        PANIC_IN_HOOK.with(|p| p.set(true));
+        let result = std::panic::catch_unwind(|| {
+            let _guard = LOCK.lock();
+            std::thread::sleep(Duration::from_millis(20));
+        });
+        PANIC_IN_HOOK.with(|p| p.set(false));
+        assert!(result.is_err());
+        assert!(!LOCK.is_locked());
+        assert!(!LOCK.is_poisoned());
+        assert_eq!(*LOCK.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn lock_from_other_threads_is_not_reentrant() {
+        let lock = SpinLock::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        // 同じスレッドでも、解放した後に再びロックするのは再入ではない。
+                        *lock.lock() += 1;
+                        *Guard::map(lock.lock(), |v| v) += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 160_000);
+    }
+
+    #[test]
+    fn stats_are_compiled_out_without_feature() {
+        // `locked`と`poisoned`、デバッグビルドでは`owner`、`stats`フィーチャーでは3つの`AtomicU64`が加わる。
+        let base = if cfg!(debug_assertions) { 16 } else { 3 };
+        let size = std::mem::size_of::<SpinLock<u8>>();
+        if cfg!(feature = "stats") {
+            assert!(base + 3 * std::mem::size_of::<AtomicU64>() <= size);
+        } else {
+            assert_eq!(size, base);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn contention_count_under_eight_threads() {
+        // 統計情報は、`locked`とは別のキャッシュラインに配置されるはず。
+        assert_eq!(std::mem::align_of::<SpinLock<u8>>(), 64);
+
+        let lock = SpinLock::new(0_u32);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let mut guard = lock.lock();
+                        *guard += 1;
+                        // ロックを保持したままCPUを譲り、他のスレッドと競合させる。
+                        if guard.is_multiple_of(100) {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(), 80_000);
+        assert!(0 < lock.contention_count());
+
+        lock.reset_contention_count();
+        assert_eq!(lock.contention_count(), 0);
+        *lock.lock() += 1;
+        assert_eq!(lock.contention_count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn stats_count_contention() {
+        let lock = SpinLock::new(0);
+        *lock.lock() += 1;
+        assert!(lock.try_lock().is_some());
+        // 競合していない場合は、獲得した回数だけが増えるはず。
+        assert_eq!(
+            lock.stats(),
+            SpinLockStats {
+                acquisitions: 2,
+                contended_acquisitions: 0,
+                spin_iterations: 0,
+            }
+        );
+
+        // 他のスレッドがロックを保持している間にロックすると、競合した回数とスピンした回数が増えるはず。
+        let guard = lock.lock();
+        std::thread::scope(|s| {
+            s.spawn(|| *lock.lock() += 1);
+            std::thread::sleep(Duration::from_millis(10));
+            drop(guard);
+        });
+        let stats = lock.stats();
+        assert_eq!(stats.acquisitions, 4);
+        assert_eq!(stats.contended_acquisitions, 1);
+        assert!(0 < stats.spin_iterations);
+
+        // 予算付きのロックも、競合した場合は計測されるはず。
+        let guard = lock.lock();
+        assert!(lock.try_lock_for(SpinBudget::spins(10)).is_none());
+        drop(guard);
+        let after = lock.stats();
+        assert_eq!(after.contended_acquisitions, 2);
+        assert!(stats.spin_iterations < after.spin_iterations);
+
+        lock.reset_stats();
+        assert_eq!(lock.stats(), SpinLockStats::default());
+    }
+
+    #[test]
+    fn owned_guard_can_be_moved_to_another_thread() {
+        fn lock_and_push(lock: Arc<SpinLock<Vec<i32>>>) -> OwnedGuard<Vec<i32>> {
+            // ガードはロックを借用しないため、`Arc`を所有する関数から返せるはず。
+            let mut guard = lock.lock_owned();
+            guard.push(1);
+            guard
+        }
+
+        let lock = Arc::new(SpinLock::new(Vec::new()));
+        let guard = lock_and_push(Arc::clone(&lock));
+        assert!(lock.try_lock_owned().is_none());
+
+        let handle = std::thread::spawn(move || {
+            let mut guard = guard;
+            guard.push(2);
+        });
+        handle.join().unwrap();
+
+        // 他のスレッドでガードをドロップした時点で、ロックは解放されているはず。
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.lock(), [1, 2]);
+        assert!(!lock.is_poisoned());
+    }
+
+    #[test]
+    fn owned_guards_over_different_locks_can_be_stored() {
+        let locks: Vec<_> = (0..4).map(|i| Arc::new(SpinLock::new(i))).collect();
+        let mut guards: Vec<_> = locks.iter().map(SpinLock::lock_owned).collect();
+        for guard in &mut guards {
+            **guard *= 10;
+        }
+        assert!(locks.iter().all(|lock| lock.try_lock().is_none()));
+
+        // ロックの`Arc`を手放しても、ガードが`Arc`を保持しているため値にアクセスできるはず。
+        drop(locks);
+        assert_eq!(
+            guards.iter().map(|guard| **guard).collect::<Vec<_>>(),
+            [0, 10, 20, 30]
+        );
+        let lock = Arc::clone(&guards[0].lock);
+        drop(guards);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn lock_both_does_not_deadlock_in_either_order() {
+        let accounts = Arc::new([SpinLock::new(1000_i64), SpinLock::new(1000_i64)]);
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        {
+            let accounts = Arc::clone(&accounts);
+            std::thread::spawn(move || {
+                std::thread::scope(|s| {
+                    for id in 0..8 {
+                        let accounts = &accounts;
+                        s.spawn(move || {
+                            // 半分のスレッドは逆の順序で引数を渡す。
+                            let (from, to) = if id % 2 == 0 {
+                                (&accounts[0], &accounts[1])
+                            } else {
+                                (&accounts[1], &accounts[0])
+                            };
+                            for _ in 0..10_000 {
+                                let (mut from, mut to) = SpinLock::lock_both(from, to);
+                                *from -= 1;
+                                *to += 1;
+                            }
+                        });
+                    }
+                });
+                done_tx.send(()).unwrap();
+            });
+        }
+
+        // デッドロックした場合は、タイムアウトで失敗するはず。
+        done_rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("lock_both deadlocked");
+        assert_eq!(*accounts[0].lock() + *accounts[1].lock(), 2000);
+    }
+
+    #[test]
+    fn try_lock_both_releases_first_lock_on_failure() {
+        let a = SpinLock::new(1);
+        let b = SpinLock::new("b");
+        let guard = b.lock();
+        assert!(SpinLock::try_lock_both(&a, &b).is_none());
+        // 獲得できた方のロックも解放されているはず。
+        assert!(!a.is_locked());
+        drop(guard);
+
+        let (x, y) = SpinLock::try_lock_both(&a, &b).unwrap();
+        assert_eq!((*x, *y), (1, "b"));
+    }
+
+    #[test]
+    #[should_panic(expected = "same lock twice")]
+    fn lock_both_panics_on_same_lock() {
+        let lock = SpinLock::new(0);
+        let _ = SpinLock::lock_both(&lock, &lock);
+    }
+
+    #[test]
+    fn static_lock_is_shared_by_threads() {
+        // `new`は`const fn`であるため、`static`を初期化できるはず。
+        static NAMES: SpinLock<Vec<usize>> = SpinLock::new(Vec::new());
+        static COUNTER: SpinLock<u64> = SpinLock::new(0);
+
+        std::thread::scope(|s| {
+            for id in 0..4 {
+                s.spawn(move || {
+                    NAMES.lock().push(id);
+                    for _ in 0..10_000 {
+                        *COUNTER.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*COUNTER.lock(), 40_000);
+        let mut names = NAMES.lock().clone();
+        names.sort();
+        assert_eq!(names, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn unsized_lock_holds_trait_object_and_slice() {
+        let mut count = 0;
+        {
+            // `Box<SpinLock<クロージャー>>`から型強制できるはず。
+            let lock: Box<SpinLock<dyn FnMut() + Send>> = Box::new(SpinLock::new(|| count += 1));
+            std::thread::scope(|s| {
+                for _ in 0..4 {
+                    s.spawn(|| (lock.lock())());
+                }
+            });
+        }
+        assert_eq!(count, 4);
+
+        let mut lock: Box<SpinLock<[u8]>> = Box::new(SpinLock::new([1, 2, 3]));
+        lock.lock()[0] = 10;
+        lock.get_mut().reverse();
+        assert_eq!(&*lock.lock(), [3, 2, 10]);
+        assert_eq!(format!("{lock:?}"), "SpinLock { value: [3, 2, 10] }");
+        assert_eq!(SpinLock::new(vec![1]).into_inner(), [1]);
+    }
+
+    #[test]
+    fn is_locked_reflects_guard() {
+        let lock = SpinLock::new(0);
+        assert!(!lock.is_locked());
+        let guard = lock.lock();
+        assert!(lock.is_locked());
+        drop(guard);
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn debug_prints_value_or_locked() {
+        let lock = SpinLock::new(vec![1, 2]);
+        assert_eq!(format!("{lock:?}"), "SpinLock { value: [1, 2] }");
+
+        // ロックを保持している間は、値の代わりに`<locked>`を出力するはず。
+        let guard = lock.lock();
+        assert_eq!(format!("{lock:?}"), "SpinLock { value: <locked> }");
+        drop(guard);
+
+        // `Debug`の出力後もロックは解放されているはず。
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn default_creates_unlocked_lock_with_default_value() {
+        let lock = SpinLock::<Vec<i32>>::default();
+        assert!(!lock.is_locked());
+        assert!(!lock.is_poisoned());
+        assert!(lock.lock().is_empty());
+    }
+
+    #[test]
+    fn guards_debug_print_the_value() {
+        let lock = Arc::new(SpinLock::new((1, "a")));
+        let guard = lock.lock();
+        assert_eq!(format!("{guard:?}"), "(1, \"a\")");
+        let mapped = Guard::map(guard, |(_, s)| s);
+        assert_eq!(format!("{mapped:?}"), "\"a\"");
+        drop(mapped);
+        let owned = lock.lock_owned();
+        assert_eq!(format!("{owned:?}"), "(1, \"a\")");
+    }
 }
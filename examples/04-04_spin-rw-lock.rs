@@ -0,0 +1,314 @@
+//! # リーダ・ライタ・スピンロック
+//!
+//! 04-03のスピンロックは、値を読み込むだけのスレッド同士も排他するため、読み込みが大半を占める場合は
+//! 並列性が失われる。
+//! リーダ・ライタ・ロックは、複数のスレッドが同時に読み込むこと（共有ロック）を許可し、
+//! 書き込むスレッドだけが排他的にアクセスする（排他ロック）。
+//!
+//! 状態は1つの`AtomicU32`で表現する。
+//!
+//! - ビット0: 書き込むスレッドがロックを保持している
+//! - ビット1: 書き込むスレッドがロックの獲得を待機している
+//! - ビット2以降: 読み込み用のロックを保持しているスレッドの数
+//!
+//! 書き込むスレッドが待機している間は、新しい読み込み用のロックの獲得を拒否する。
+//! これにより、読み込むスレッドが途切れずにロックを獲得し続けても、書き込むスレッドが永遠に
+//! ロックを獲得できなくなること（ライタの飢餓）を防ぐ。
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+#[path = "shared/backoff.rs"]
+mod backoff;
+
+use backoff::Backoff;
+
+/// 書き込むスレッドがロックを保持している
+const WRITER: u32 = 1;
+/// 書き込むスレッドがロックの獲得を待機している
+const WRITER_WAITING: u32 = 2;
+/// 読み込み用のロックを保持しているスレッド1つ分
+const READER: u32 = 4;
+
+pub struct SpinRwLock<T> {
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+/// 複数のスレッドが同時に`&T`を保持するため、`SpinLock<T>`と異なり`T: Sync`も必要である。
+unsafe impl<T> Sync for SpinRwLock<T> where T: Send + Sync {}
+
+pub struct ReadGuard<'a, T> {
+    lock: &'a SpinRwLock<T>,
+}
+
+pub struct WriteGuard<'a, T> {
+    lock: &'a SpinRwLock<T>,
+}
+
+/// `ReadGuard`は`&T`のみを提供するため、他のスレッドに送る場合も`T: Sync`であれば十分である。
+unsafe impl<T> Send for ReadGuard<'_, T> where T: Sync {}
+unsafe impl<T> Sync for ReadGuard<'_, T> where T: Sync {}
+unsafe impl<T> Send for WriteGuard<'_, T> where T: Send {}
+unsafe impl<T> Sync for WriteGuard<'_, T> where T: Sync {}
+
+impl<T> SpinRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// 読み込み用のロックを獲得する。
+    ///
+    /// 書き込むスレッドがロックを保持している場合、またはロックの獲得を待機している場合は、
+    /// ロックを獲得できるまでスピンする。
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// 読み込み用のロックの獲得を試みる。
+    ///
+    /// 書き込むスレッドがロックを保持している場合、またはロックの獲得を待機している場合は`None`を返す。
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        // 読み込むスレッドの数が変化しただけの場合は、再試行する。
+        while state & (WRITER | WRITER_WAITING) == 0 {
+            assert!(state < u32::MAX - READER, "too many readers");
+            match self.state.compare_exchange_weak(
+                state,
+                state + READER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(ReadGuard { lock: self }),
+                Err(s) => state = s,
+            }
+        }
+        None
+    }
+
+    /// 書き込み用のロックを獲得する。
+    ///
+    /// ロックを獲得できない場合は、待機していることを示すビットを設定してから、
+    /// 読み込むスレッドがすべてロックを解放するまでスピンする。
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(guard) = self.try_write() {
+                return guard;
+            }
+            // 新しい読み込むスレッドがロックを獲得しないようにする。
+            // 他の書き込むスレッドがロックを獲得するときにビットを消去するため、毎回設定し直す。
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            backoff.snooze();
+        }
+    }
+
+    /// 書き込み用のロックの獲得を試みる。
+    ///
+    /// 他のスレッドがロックを保持している場合は`None`を返す。
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        // 待機していることを示すビット以外が設定されていなければ、ロックを獲得できる。
+        while state & !WRITER_WAITING == 0 {
+            // ロックを獲得した時点で、待機していることを示すビットは消去する。
+            match self.state.compare_exchange_weak(
+                state,
+                WRITER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(WriteGuard { lock: self }),
+                Err(s) => state = s,
+            }
+        }
+        None
+    }
+}
+
+impl<T: Default> Default for SpinRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// 04-03と同様に、ロックの獲得を待機せず、読み込み用のロックを獲得できない場合は`<locked>`を出力する。
+impl<T: fmt::Debug> fmt::Debug for SpinRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("SpinRwLock");
+        match self.try_read() {
+            Some(guard) => d.field("value", &&*guard),
+            None => d.field("value", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for WriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // 他の書き込むスレッドが設定した、待機していることを示すビットは維持する。
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+fn main() {
+    let config = SpinRwLock::new(String::from("v1"));
+    std::thread::scope(|s| {
+        for i in 0..3 {
+            let config = &config;
+            s.spawn(move || {
+                for _ in 0..3 {
+                    println!("reader {i}: {}", *config.read());
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            });
+        }
+        s.spawn(|| {
+            std::thread::sleep(Duration::from_millis(5));
+            *config.write() = String::from("v2");
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Instant;
+
+    #[test]
+    fn concurrent_readers_observe_consistent_data() {
+        let lock = SpinRwLock::new((0_u64, 0_u64));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let pair = lock.read();
+                        // 書き込み中の値を観測しないため、2つの値は常に一致するはず。
+                        assert_eq!(pair.0, pair.1);
+                    }
+                });
+            }
+            s.spawn(|| {
+                for _ in 0..10_000 {
+                    let mut pair = lock.write();
+                    pair.0 += 1;
+                    pair.1 += 1;
+                }
+            });
+        });
+        assert_eq!(*lock.read(), (10_000, 10_000));
+    }
+
+    #[test]
+    fn readers_share_and_writer_excludes() {
+        let lock = SpinRwLock::new(0);
+        let r1 = lock.read();
+        let r2 = lock.try_read().unwrap();
+        // 読み込み用のロックが保持されている間は、書き込み用のロックを獲得できないはず。
+        assert!(lock.try_write().is_none());
+        drop((r1, r2));
+
+        let mut w = lock.write();
+        *w += 1;
+        // 書き込み用のロックが保持されている間は、どちらのロックも獲得できないはず。
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(w);
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn writer_is_not_starved_by_readers() {
+        let lock = SpinRwLock::new(0);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            // 読み込み用のロックの保持期間が重なり、常にいずれかのスレッドが保持している状態を作る。
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !stop.load(Ordering::Relaxed) {
+                        let _guard = lock.read();
+                        std::thread::sleep(Duration::from_micros(100));
+                    }
+                });
+            }
+            std::thread::sleep(Duration::from_millis(10));
+
+            // 待機していることを示すビットにより新しい読み込みが拒否されるため、
+            // 書き込むスレッドはロックを獲得できるはず。
+            let start = Instant::now();
+            *lock.write() = 1;
+            assert!(start.elapsed() < Duration::from_secs(1));
+            stop.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn default_and_debug() {
+        let lock = SpinRwLock::<Vec<i32>>::default();
+        assert_eq!(format!("{lock:?}"), "SpinRwLock { value: [] }");
+
+        let mut guard = lock.write();
+        guard.push(1);
+        assert_eq!(format!("{guard:?}"), "[1]");
+        // 書き込み用のロックを保持している間は、`<locked>`を出力するはず。
+        assert_eq!(format!("{lock:?}"), "SpinRwLock { value: <locked> }");
+        drop(guard);
+
+        // 読み込み用のロックは共有できるため、保持している間も値を出力するはず。
+        let guard = lock.read();
+        assert_eq!(format!("{guard:?}"), "[1]");
+        assert_eq!(format!("{lock:?}"), "SpinRwLock { value: [1] }");
+    }
+}
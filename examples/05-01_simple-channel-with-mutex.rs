@@ -9,7 +9,11 @@
 //! また、条件変数の待機は「スプリアスウェイクアップ（spurious wakeup、偽の目覚め）」が発生する可能性
 //! があるため、待機は必ずループ内で行い、起床後に条件を再評価する必要がある。
 use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 /// 型パラメーター`T`に対して`Send`と`Sync`のトレイト境界を明示していない理由は、`T`が`Mutex`によって
 /// 保護されていることをRustコンパイラが認識しているためである。
@@ -17,19 +21,28 @@ use std::sync::{Arc, Condvar, Mutex};
 /// `Mutex<T>`は、`T: Send`である場合に`Sync`となる。
 /// このため、`T`が`Send`であれば、`Mutex<VecDeque<T>>`と`Condvar`のみをフィールドに持つ
 /// `Channel<T>`も`Send`かつ`Sync`となる。
-#[derive(Default)]
+/// `std::sync::Mutex`の`Debug`はロックの獲得を待機しないため、`Debug`は導出で十分である。
+#[derive(Default, Debug)]
 pub struct Channel<T> {
     queue: Mutex<VecDeque<T>>,
     item_ready: Condvar,
+    /// `channel`関数で作成した`Sender`の数
+    ///
+    /// 0になった時点で、メッセージを送信するスレッドが存在しなくなるため、チャネルは切断される。
+    senders: AtomicUsize,
 }
 
 impl<T> Channel<T> {
-    // pub fn new() -> Self {
-    //     Self {
-    //         queue: Mutex::new(VecDeque::new()),
-    //         item_ready: Condvar::new(),
-    //     }
-    // }
+    /// `const`関数であるため、`static`変数の初期化にも使用できる。
+    ///
+    /// `#[derive(Default)]`と異なり、`T: Default`を必要としない。
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            item_ready: Condvar::new(),
+            senders: AtomicUsize::new(0),
+        }
+    }
 
     pub fn send(&self, message: T) {
         self.queue.lock().unwrap().push_back(message);
@@ -60,9 +73,279 @@ impl<T> Channel<T> {
     }
 }
 
+/// `Receiver::recv_timeout`が返すエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// 指定された時間内にメッセージが届かなかった。
+    Timeout,
+    /// すべての`Sender`がドロップされ、キューも空である。
+    Disconnected,
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timed out waiting on channel"),
+            Self::Disconnected => write!(f, "channel is empty and sending half is closed"),
+        }
+    }
+}
+
+impl std::error::Error for RecvTimeoutError {}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        queue: Mutex::new(VecDeque::new()),
+        item_ready: Condvar::new(),
+        senders: AtomicUsize::new(1),
+    });
+    (
+        Sender {
+            channel: Arc::clone(&channel),
+        },
+        Receiver { channel },
+    )
+}
+
+/// 複数の`Receiver`が受信するメッセージを、1つの`Receiver`にまとめる（ファンイン）。
+///
+/// 入力の`Receiver`ごとにスレッドを起動し、受信したメッセージを出力のチャネルに転送する。
+/// 各スレッドは、担当する入力のすべての`Sender`がドロップされ、キューが空になった時点で終了する。
+/// 各スレッドは出力の`Sender`を1つずつ保持しているため、すべての入力が切断されると、出力も切断される。
+///
+/// 同じ入力から届いたメッセージの順序は保たれるが、異なる入力のメッセージの順序は保証されない。
+/// 出力の`Receiver`をドロップしても、スレッドは入力が切断されるまで転送を続ける。
+pub fn merge<T>(receivers: Vec<Receiver<T>>) -> Receiver<T>
+where
+    T: Send + 'static,
+{
+    let (sender, output) = channel();
+    for receiver in receivers {
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            for message in receiver {
+                sender.send(message);
+            }
+        });
+    }
+    // 転送するスレッドが保持する`Sender`だけを残す。入力が空の場合は、ここで出力が切断される。
+    drop(sender);
+    output
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, message: T) {
+        self.channel.send(message);
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            channel: Arc::clone(&self.channel),
+        }
+    }
+}
+
+/// メッセージを出力すると`Channel`のロックが必要になるため、`std::sync::mpsc`と同様に型名だけを出力する。
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::Release) == 1 {
+            // `Receiver`は`Mutex`をロックした状態で`senders`を確認してから待機する。
+            // 一度`Mutex`をロックしてから通知することで、`senders`を確認した後、待機に入る前の
+            // `Receiver`に対する通知が失われないようにする。
+            drop(self.channel.queue.lock().unwrap());
+            self.channel.item_ready.notify_all();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// メッセージを受信するまで待機する。
+    ///
+    /// すべての`Sender`がドロップされ、キューも空の場合は`RecvError`を返す。
+    pub fn receive(&self) -> Result<T, RecvError> {
+        let mut queue = self.channel.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return Ok(message);
+            }
+            if self.channel.senders.load(Ordering::Acquire) == 0 {
+                return Err(RecvError);
+            }
+            queue = self.channel.item_ready.wait(queue).unwrap();
+        }
+    }
+
+    /// 待機せずに、キューにメッセージがあれば受信する。
+    pub fn try_receive(&self) -> Option<T> {
+        self.channel.queue.lock().unwrap().pop_front()
+    }
+
+    /// 待機せずに、キューの先頭のメッセージを取り出さずに参照する。
+    ///
+    /// 返した`PeekGuard`が生存している間は`Mutex`をロックしたままであるため、他のスレッドの送信や受信は
+    /// 待機する。
+    /// 同じスレッドで`PeekGuard`を保持したまま`receive`などを呼び出すと、デッドロックする。
+    pub fn peek(&self) -> Option<PeekGuard<'_, T>> {
+        let queue = self.channel.queue.lock().unwrap();
+        if queue.is_empty() {
+            return None;
+        }
+        Some(PeekGuard { queue })
+    }
+
+    /// メッセージを受信するたびに返すイテレーターを返す。
+    ///
+    /// `next`はメッセージを受信するまで待機し、すべての`Sender`がドロップされた時点で`None`を返す。
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { receiver: self }
+    }
+
+    /// 現在キューにあるメッセージだけを返すイテレーターを返す。
+    ///
+    /// `next`は待機せず、キューが空になった時点で（切断されていなくても）`None`を返す。
+    pub fn try_iter(&self) -> TryIter<'_, T> {
+        TryIter { receiver: self }
+    }
+
+    /// 最大`timeout`だけメッセージの到着を待機する。
+    ///
+    /// `Condvar::wait_timeout`はスプリアスウェイクアップで指定時間より早く復帰することがあるため、
+    /// 起床するたびにキューと経過時間の両方を再評価して、残り時間だけ再び待機する。
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let start = Instant::now();
+        let mut queue = self.channel.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return Ok(message);
+            }
+            if self.channel.senders.load(Ordering::Acquire) == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let remaining = match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => return Err(RecvTimeoutError::Timeout),
+            };
+            queue = self
+                .channel
+                .item_ready
+                .wait_timeout(queue, remaining)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+/// `Receiver::receive`が返すエラー
+///
+/// すべての`Sender`がドロップされ、これ以上メッセージが届かないことを示す。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiving on a closed channel")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+/// `Receiver::peek`が返す、キューの先頭のメッセージへの参照
+///
+/// ドロップするときに`Mutex`のロックを解放する。
+pub struct PeekGuard<'a, T> {
+    /// 空でないことを確認したキュー
+    queue: std::sync::MutexGuard<'a, VecDeque<T>>,
+}
+
+impl<T> Deref for PeekGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // `peek`で空でないことを確認し、ロックを保持している間は他のスレッドが取り出すことはない。
+        &self.queue[0]
+    }
+}
+
+pub struct Iter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<T> Iterator for Iter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive().ok()
+    }
+}
+
+pub struct TryIter<'a, T> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<T> Iterator for TryIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.try_receive()
+    }
+}
+
+/// `for message in receiver`のように、`Receiver`を消費して反復するためのイテレーター
+pub struct IntoIter<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive().ok()
+    }
+}
+
+impl<T> IntoIterator for Receiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { receiver: self }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Receiver<T> {
+    type Item = T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
 fn main() {
-    // let channel = Arc::new(Channel::new());
-    let channel = Arc::new(Channel::default());
+    let channel = Arc::new(Channel::new());
     let cloned_channel = Arc::clone(&channel);
 
     let sender = std::thread::spawn(move || {
@@ -82,4 +365,223 @@ fn main() {
 
     receiver.join().unwrap();
     sender.join().unwrap();
+
+    receive_with_timeout();
+    receive_with_iterator();
+}
+
+/// イテレーターによる受信
+fn receive_with_iterator() {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        for i in 0..5 {
+            sender.send(i);
+        }
+    });
+    // `Sender`がドロップされると、ループが終了する。
+    for message in receiver {
+        println!("Received: {message}");
+    }
+}
+
+/// 時間制限付きの受信
+fn receive_with_timeout() {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        sender.send("hello");
+    });
+    let timeout = Duration::from_millis(100);
+    println!("recv_timeout: {:?}", receiver.recv_timeout(timeout));
+    println!("recv_timeout: {:?}", receiver.recv_timeout(timeout));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn iter_collects_until_disconnected() {
+        let (sender, receiver) = channel();
+        let t = std::thread::spawn(move || {
+            for i in 0..100 {
+                sender.send(i);
+            }
+        });
+        assert_eq!(
+            receiver.iter().collect::<Vec<_>>(),
+            (0..100).collect::<Vec<_>>()
+        );
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn into_iter_collects_from_multiple_senders() {
+        let (sender, receiver) = channel();
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    for j in 0..25 {
+                        sender.send(i * 25 + j);
+                    }
+                });
+            }
+        });
+        drop(sender);
+
+        let mut messages = receiver.into_iter().collect::<Vec<_>>();
+        messages.sort();
+        assert_eq!(messages, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn try_iter_does_not_block() {
+        let (sender, receiver) = channel();
+        sender.send(1);
+        sender.send(2);
+
+        // `Sender`は生存しているが、キューが空になった時点で終了するはず。
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(receiver.try_iter().next(), None);
+
+        sender.send(3);
+        drop(sender);
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), [3]);
+    }
+
+    #[test]
+    fn recv_timeout_receives_message_within_timeout() {
+        let (sender, receiver) = channel();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sender.send(42);
+            // `sender`がドロップされる前に受信できるように、しばらく待機する。
+            std::thread::sleep(Duration::from_millis(200));
+        });
+        assert_eq!(receiver.recv_timeout(TIMEOUT), Ok(42));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn recv_timeout_times_out() {
+        let (sender, receiver) = channel();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            sender.send(42);
+        });
+        let start = Instant::now();
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Err(RecvTimeoutError::Timeout)
+        );
+        assert!(TIMEOUT <= start.elapsed());
+        t.join().unwrap();
+
+        // 送信済みのメッセージは、`Sender`がドロップされた後でも受信できるはず。
+        assert_eq!(receiver.recv_timeout(TIMEOUT), Ok(42));
+    }
+
+    #[test]
+    fn recv_timeout_detects_disconnection() {
+        let (sender, receiver) = channel::<i32>();
+        let t = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(sender);
+        });
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Err(RecvTimeoutError::Disconnected)
+        );
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn peek_does_not_consume_message() {
+        let (sender, receiver) = channel();
+        assert!(receiver.peek().is_none());
+        sender.send(1);
+        sender.send(2);
+
+        // 先頭のメッセージが2でなければ受信しないと判断した場合、メッセージはキューに残るはず。
+        let next = receiver.peek().unwrap();
+        assert_eq!(*next, 1);
+        drop(next);
+        assert_eq!(*receiver.peek().unwrap(), 1);
+
+        assert_eq!(receiver.receive(), Ok(1));
+        assert_eq!(*receiver.peek().unwrap(), 2);
+        assert_eq!(receiver.receive(), Ok(2));
+    }
+
+    #[test]
+    fn peek_guard_blocks_sender_until_dropped() {
+        let (sender, receiver) = channel();
+        sender.send(1);
+        let peeked = receiver.peek().unwrap();
+        std::thread::scope(|s| {
+            let t = s.spawn(|| sender.send(2));
+            std::thread::sleep(Duration::from_millis(50));
+            // `PeekGuard`がロックを保持しているため、送信は完了しないはず。
+            assert!(!t.is_finished());
+            assert_eq!(*peeked, 1);
+            drop(peeked);
+        });
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn merge_receives_every_message_exactly_once() {
+        const INPUTS: usize = 4;
+        const MESSAGES: usize = 1000;
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..INPUTS).map(|_| channel()).unzip();
+        let output = merge(receivers);
+        for (input, sender) in senders.into_iter().enumerate() {
+            std::thread::spawn(move || {
+                for i in 0..MESSAGES {
+                    sender.send((input, i));
+                }
+            });
+        }
+
+        let mut next = [0; INPUTS];
+        for (input, i) in output {
+            // 同じ入力から届いたメッセージは、送信した順に受信するはず。
+            assert_eq!(i, next[input]);
+            next[input] += 1;
+        }
+        // すべての入力が切断された後に出力が切断され、それまでにすべてのメッセージを1回ずつ受信したはず。
+        assert_eq!(next, [MESSAGES; INPUTS]);
+    }
+
+    #[test]
+    fn merge_disconnects_after_all_inputs_disconnect() {
+        let (first, first_receiver) = channel();
+        let (second, second_receiver) = channel::<i32>();
+        let output = merge(vec![first_receiver, second_receiver]);
+        first.send(1);
+        drop(first);
+        assert_eq!(output.receive(), Ok(1));
+        // 切断されていない入力が残っている間は、出力は切断されないはず。
+        assert_eq!(
+            output.recv_timeout(Duration::from_millis(50)),
+            Err(RecvTimeoutError::Timeout)
+        );
+        drop(second);
+        assert_eq!(output.receive(), Err(RecvError));
+
+        // 入力がない場合は、すぐに切断されるはず。
+        assert_eq!(merge::<i32>(Vec::new()).receive(), Err(RecvError));
+    }
+
+    #[test]
+    fn debug_does_not_print_messages() {
+        let (sender, receiver) = channel();
+        sender.send(1);
+        assert_eq!(format!("{sender:?}"), "Sender { .. }");
+        assert_eq!(format!("{receiver:?}"), "Receiver { .. }");
+        assert!(format!("{:?}", Channel::<i32>::default()).starts_with("Channel { queue: Mutex"));
+    }
 }
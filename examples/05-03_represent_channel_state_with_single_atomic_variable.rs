@@ -1,4 +1,5 @@
 use std::cell::UnsafeCell;
+use std::fmt;
 use std::mem::MaybeUninit;
 use std::sync::atomic::{AtomicU8, Ordering};
 
@@ -16,52 +17,96 @@ unsafe impl<T: Send> Sync for Channel<T> {}
 
 impl<T> Default for Channel<T> {
     fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Channel<T> {
+    /// `const`関数であるため、`static`変数の初期化にも使用できる。
+    pub const fn new() -> Self {
         Self {
             message: UnsafeCell::new(MaybeUninit::uninit()),
             state: AtomicU8::new(EMPTY),
         }
     }
-}
 
-impl<T> Channel<T> {
-    //pub const fn new() -> Self {
-    //    Self {
-    //        message: UnsafeCell::new(MaybeUninit::uninit()),
-    //        state: AtomicU8::new(EMPTY),
-    //    }
-    //}
-
-    pub fn send(&self, message: T) {
+    /// メッセージを送信する。
+    ///
+    /// 2回目以降の呼び出しは、パニックする代わりに`AlreadySentError`でメッセージを返す。
+    pub fn send(&self, message: T) -> Result<(), AlreadySentError<T>> {
         if self
             .state
             .compare_exchange(EMPTY, WRITING, Ordering::Relaxed, Ordering::Relaxed)
             .is_err()
         {
-            panic!("can't send more than cone message!");
+            return Err(AlreadySentError(message));
         }
         unsafe {
             (*self.message.get()).write(message);
         }
         // 次のReleaseストアと、`receive()`メソッドのAcquireロードがbefore-after関係を形成
         self.state.store(READY, Ordering::Release);
+        Ok(())
     }
 
     pub fn is_ready(&self) -> bool {
         self.state.load(Ordering::Relaxed) == READY
     }
 
-    pub fn receive(&self) -> T {
+    /// メッセージを受信する。
+    ///
+    /// メッセージが準備できていない場合や、すでに受信した場合は`NotReadyError`を返す。
+    pub fn receive(&self) -> Result<T, NotReadyError> {
         if self
             .state
             .compare_exchange(READY, READING, Ordering::Acquire, Ordering::Relaxed)
             .is_err()
         {
-            panic!("no message available!");
+            return Err(NotReadyError);
         }
-        unsafe { (*self.message.get()).assume_init_read() }
+        Ok(unsafe { (*self.message.get()).assume_init_read() })
+    }
+}
+
+/// `send`が2回目以降に呼び出されたときに返すエラー
+///
+/// 送信できなかったメッセージを保持しているため、呼び出し側は取り出して再利用できる。
+#[derive(PartialEq, Eq)]
+pub struct AlreadySentError<T>(pub T);
+
+impl<T> AlreadySentError<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// `T`が`Debug`を実装していなくても使用できるように、メッセージは出力しない。
+impl<T> fmt::Debug for AlreadySentError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlreadySentError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for AlreadySentError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't send more than one message")
     }
 }
 
+impl<T> std::error::Error for AlreadySentError<T> {}
+
+/// メッセージが準備できていないときに`receive`が返すエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotReadyError;
+
+impl fmt::Display for NotReadyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no message available")
+    }
+}
+
+impl std::error::Error for NotReadyError {}
+
 impl<T> Drop for Channel<T> {
     fn drop(&mut self) {
         if *self.state.get_mut() == READY {
@@ -71,16 +116,56 @@ impl<T> Drop for Channel<T> {
 }
 
 fn main() {
-    let channel = Channel::default();
+    let channel = Channel::new();
     let t = std::thread::current();
     std::thread::scope(|s| {
         s.spawn(|| {
-            channel.send("hello world!");
+            channel.send("hello world!").unwrap();
             t.unpark();
         });
         while !channel.is_ready() {
             std::thread::park();
         }
-        assert_eq!(channel.receive(), "hello world!");
+        assert_eq!(channel.receive(), Ok("hello world!"));
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misuse_returns_errors_instead_of_panicking() {
+        let channel = Channel::default();
+        assert_eq!(channel.receive(), Err(NotReadyError));
+        assert_eq!(channel.send(1), Ok(()));
+        // 2回目の送信は、メッセージとともにエラーを返すはず。
+        assert_eq!(channel.send(2), Err(AlreadySentError(2)));
+        assert_eq!(channel.receive(), Ok(1));
+        // 受信済みのメッセージは、再度受信できないはず。
+        assert_eq!(channel.receive(), Err(NotReadyError));
+    }
+
+    #[test]
+    fn caller_can_retry_with_returned_message() {
+        let used = Channel::default();
+        used.send(vec![1]).unwrap();
+        let message = used.send(vec![2]).unwrap_err().into_inner();
+
+        // 送信できなかったメッセージを、別のチャネルで送信し直せるはず。
+        let fresh = Channel::default();
+        fresh.send(message).unwrap();
+        assert_eq!(fresh.receive(), Ok(vec![2]));
+        assert_eq!(used.receive(), Ok(vec![1]));
+    }
+
+    #[test]
+    fn static_channel_is_shared_between_threads() {
+        static CHANNEL: Channel<u32> = Channel::new();
+        std::thread::scope(|s| {
+            s.spawn(|| CHANNEL.send(42).unwrap());
+        });
+        // `static`変数のチャネルでも、他のスレッドが送信したメッセージを受信できるはず。
+        assert_eq!(CHANNEL.receive(), Ok(42));
+    }
+}
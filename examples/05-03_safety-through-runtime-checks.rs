@@ -1,4 +1,5 @@
 use std::cell::UnsafeCell;
+use std::fmt;
 use std::mem::MaybeUninit;
 use std::sync::{
     Arc,
@@ -22,19 +23,19 @@ impl<T> Channel<T> {
         }
     }
 
-    /// # Safety
+    /// メッセージを送信する。
     ///
-    /// 1回だけよびだすこと！
-    /// 同時に複数スレッドから呼び出してはダメ！
-    pub fn send(&self, message: T) {
+    /// 2回目以降の呼び出しは、パニックする代わりに`AlreadySentError`でメッセージを返す。
+    pub fn send(&self, message: T) -> Result<(), AlreadySentError<T>> {
         if self.in_use.swap(true, Ordering::Relaxed) {
-            panic!("can't send more than one message!");
+            return Err(AlreadySentError(message));
         }
         unsafe {
             (*self.message.get()).write(message);
         }
         // `message`への書き込みを公開するReleaseストア
         self.ready.store(true, Ordering::Release);
+        Ok(())
     }
 
     pub fn is_ready(&self) -> bool {
@@ -50,23 +51,62 @@ impl<T> Channel<T> {
         self.ready.load(Ordering::Relaxed)
     }
 
-    pub fn receive(&self) -> T {
+    pub fn receive(&self) -> Result<T, NotReadyError> {
         // `Atomic*::swap`メソッドは、アトミック変数の値を新しい値に置き換え、
         // 置き換え前の古い値を返す。
         // したがって、`ready`が`false`のときに、つまり`message`に値が与えられて
-        // いないときに、`receive()`メソッドを呼び出すとパニックする。
+        // いないときに、`receive()`メソッドを呼び出すと`NotReadyError`を返す。
         //
         // このAcquireロードが、`send()`メソッドのReleaseストアと同期して、
         // `message`への書き込みが観測可能になる。
         if !self.ready.swap(false, Ordering::Acquire) {
-            panic!("no message available!");
+            return Err(NotReadyError);
         }
         // `ready == true`をAcquireロードで観測しているため、`message`は
         // 初期化されていることが保証される。
-        unsafe { (*self.message.get()).assume_init_read() }
+        Ok(unsafe { (*self.message.get()).assume_init_read() })
     }
 }
 
+/// `send`が2回目以降に呼び出されたときに返すエラー
+///
+/// 送信できなかったメッセージを保持しているため、呼び出し側は取り出して再利用できる。
+#[derive(PartialEq, Eq)]
+pub struct AlreadySentError<T>(pub T);
+
+impl<T> AlreadySentError<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// `T`が`Debug`を実装していなくても使用できるように、メッセージは出力しない。
+impl<T> fmt::Debug for AlreadySentError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlreadySentError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for AlreadySentError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't send more than one message")
+    }
+}
+
+impl<T> std::error::Error for AlreadySentError<T> {}
+
+/// メッセージが準備できていないときに`receive`が返すエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotReadyError;
+
+impl fmt::Display for NotReadyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no message available")
+    }
+}
+
+impl std::error::Error for NotReadyError {}
+
 impl<T> Drop for Channel<T> {
     fn drop(&mut self) {
         if *self.ready.get_mut() {
@@ -82,7 +122,7 @@ fn main() {
     let t = std::thread::current();
     std::thread::scope(|s| {
         s.spawn(|| {
-            channel.send("hello world!");
+            channel.send("hello world!").unwrap();
             // `unpark()`は、対応するスレッドを起床させるための通知を送信する。
             // 正確には、対象スレッドに許可トークンを1つ与える操作である。
             //
@@ -99,6 +139,56 @@ fn main() {
             // ただし、すでに許可トークンが与えられている場合、`park()`は停止せずに即座に復帰する。
             std::thread::park();
         }
-        assert_eq!(channel.receive(), "hello world!");
+        assert_eq!(channel.receive(), Ok("hello world!"));
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misuse_returns_errors_instead_of_panicking() {
+        let channel = Channel::default();
+        assert_eq!(channel.receive(), Err(NotReadyError));
+        assert_eq!(channel.send(1), Ok(()));
+        // 2回目の送信は、メッセージとともにエラーを返すはず。
+        assert_eq!(channel.send(2), Err(AlreadySentError(2)));
+        assert_eq!(channel.receive(), Ok(1));
+        // 受信済みのメッセージは、再度受信できないはず。
+        assert_eq!(channel.receive(), Err(NotReadyError));
+    }
+
+    #[test]
+    fn caller_can_retry_with_returned_message() {
+        let used = Channel::default();
+        used.send(String::from("first")).unwrap();
+        let message = used.send(String::from("second")).unwrap_err().into_inner();
+
+        // 送信できなかったメッセージを、別のチャネルで送信し直せるはず。
+        let fresh = Channel::default();
+        fresh.send(message).unwrap();
+        assert_eq!(fresh.receive().as_deref(), Ok("second"));
+        assert_eq!(used.receive().as_deref(), Ok("first"));
+    }
+
+    #[test]
+    fn second_send_returns_non_clone_message_untouched() {
+        /// `Clone`を実装しないメッセージ
+        #[derive(Debug, PartialEq, Eq)]
+        struct Unique(Box<u32>);
+
+        let channel = Channel::default();
+        channel.send(Unique(Box::new(1))).unwrap();
+        let second = Unique(Box::new(2));
+        let address = std::ptr::from_ref(&*second.0);
+        let error = channel.send(second).unwrap_err();
+        assert_eq!(error.to_string(), "can't send more than one message");
+        // 複製されずに、同じ割り当てを持つ元の値がそのまま返されるはず。
+        let returned = error.into_inner();
+        assert_eq!(std::ptr::from_ref(&*returned.0), address);
+        assert_eq!(returned, Unique(Box::new(2)));
+        // 2回目の送信は、1回目のメッセージに影響しないはず。
+        assert_eq!(channel.receive(), Ok(Unique(Box::new(1))));
+    }
+}
@@ -1,13 +1,19 @@
 use std::{
     cell::UnsafeCell,
+    fmt,
     mem::MaybeUninit,
     sync::Arc,
     sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
 struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    /// `Sender`と`Receiver`の両方が生存しているか
+    ///
+    /// どちらか一方がドロップされた時点で`false`に設定する。
+    connected: AtomicBool,
 }
 
 pub struct Sender<T> {
@@ -20,10 +26,24 @@ pub struct Receiver<T> {
 
 unsafe impl<T: Send> Sync for Channel<T> {}
 
+/// メッセージは受信するまで読み込めないため、型名だけを出力する。
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish_non_exhaustive()
+    }
+}
+
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     let channel = Arc::new(Channel {
         message: UnsafeCell::new(MaybeUninit::uninit()),
         ready: AtomicBool::new(false),
+        connected: AtomicBool::new(true),
     });
     (
         Sender {
@@ -39,11 +59,39 @@ impl<T> Sender<T> {
     /// このメソッドはパニックしない。
     /// また、`send()`メソッドを呼び出すと、メソッド内にインスタンスがムーブするため、
     /// 1回だけ呼び出し可能であることを型システムによって保証する。
-    pub fn send(self, message: T) {
+    ///
+    /// `Receiver`がドロップされている場合は、メッセージを書き込まずに`SendError`で返却する。
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        if !self.is_connected() {
+            return Err(SendError(message));
+        }
         unsafe {
             (*self.channel.message.get()).write(message);
         }
         self.channel.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// `Receiver`が生存しているかを返す。
+    ///
+    /// `true`を返した直後に`Receiver`がドロップされる可能性があるため、戻り値はヒントに過ぎない。
+    pub fn is_connected(&self) -> bool {
+        self.channel.connected.load(Ordering::Relaxed)
+    }
+}
+
+/// `send()`は`self`を消費するため、メッセージを送信した後にも`drop`が呼び出される。
+/// `ready`へのReleaseストアの後に`connected`をReleaseストアするため、`connected`が`false`で
+/// あることをAcquireロードで観測した`Receiver`は、送信されたメッセージも観測できる。
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.connected.store(false, Ordering::Release);
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.connected.store(false, Ordering::Release);
     }
 }
 
@@ -52,14 +100,133 @@ impl<T> Receiver<T> {
         self.channel.ready.load(Ordering::Relaxed)
     }
 
-    pub fn receive(self) -> T {
-        if !self.channel.ready.swap(false, Ordering::Acquire) {
-            panic!("no message available!");
+    /// `Sender`がメッセージを送信せずにドロップされている場合は`RecvError`を返す。
+    pub fn receive(self) -> Result<T, RecvError> {
+        if let Some(message) = self.take_message() {
+            return Ok(message);
+        }
+        if !self.channel.connected.load(Ordering::Acquire) {
+            // `Sender`が送信後にドロップされた可能性があるため、もう一度確認する。
+            return self.take_message().ok_or(RecvError);
+        }
+        panic!("no message available!");
+    }
+
+    /// メッセージが届いている場合は受信し、届いていない場合は再試行できるように`Receiver`を返す。
+    ///
+    /// `receive()`と異なりパニックしないため、`is_ready`と`park`を組み合わせずに、ポーリングで受信できる。
+    /// `Sender`がメッセージを送信せずにドロップされた場合も`Err`で`Receiver`を返すため、区別する場合は
+    /// `is_connected`を確認するか、`receive()`を呼び出して`RecvError`を受け取る。
+    /// `take_message`は`ready`が`true`の場合だけメッセージを読み込むため、失敗してもメッセージは失われない。
+    pub fn try_receive(self) -> Result<T, Self> {
+        match self.take_message() {
+            Some(message) => Ok(message),
+            None => Err(self),
+        }
+    }
+
+    /// `Sender`が生存しているかを返す。
+    ///
+    /// `Sender`は`send()`で消費されるため、メッセージを送信した後は`false`を返す。
+    pub fn is_connected(&self) -> bool {
+        self.channel.connected.load(Ordering::Relaxed)
+    }
+
+    fn take_message(&self) -> Option<T> {
+        self.channel
+            .ready
+            .swap(false, Ordering::Acquire)
+            .then(|| unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+
+    /// 最大`timeout`だけメッセージの到着を待機する。
+    ///
+    /// `receive()`と同様に`self`を消費するが、メッセージを受信できなかった場合は、再試行できるように
+    /// エラーと一緒に`Receiver`を返す。
+    /// 待機には`park_timeout`を使用するため、`Sender`側のスレッドは送信後に受信側のスレッドを
+    /// `unpark`することが望ましい。`unpark`されない場合でも、期限に達した時点でもう一度確認する。
+    pub fn recv_timeout(self, timeout: Duration) -> Result<T, (Self, RecvTimeoutError)> {
+        let start = Instant::now();
+        loop {
+            if let Some(message) = self.take_message() {
+                return Ok(message);
+            }
+            // `Sender`がメッセージを送信してからドロップされた可能性があるため、もう一度確認する。
+            if !self.channel.connected.load(Ordering::Acquire) {
+                return match self.take_message() {
+                    Some(message) => Ok(message),
+                    None => Err((self, RecvTimeoutError::Disconnected)),
+                };
+            }
+            match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => std::thread::park_timeout(remaining),
+                _ => return Err((self, RecvTimeoutError::Timeout)),
+            }
+        }
+    }
+}
+
+/// `Sender::send`が返すエラー
+///
+/// `Receiver`がドロップされているため、送信できなかったメッセージを保持している。
+#[derive(PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> SendError<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// `T`が`Debug`を実装していなくても使用できるように、メッセージは出力しない。
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+/// `Receiver::receive`が返すエラー
+///
+/// `Sender`がメッセージを送信せずにドロップされたことを示す。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiving on a closed channel")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+/// `Receiver::recv_timeout`が返すエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// 指定された時間内にメッセージが届かなかった。
+    Timeout,
+    /// `Sender`がメッセージを送信せずにドロップされた。
+    Disconnected,
+}
+
+impl fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timed out waiting on channel"),
+            Self::Disconnected => write!(f, "channel is empty and sending half is closed"),
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
     }
 }
 
+impl std::error::Error for RecvTimeoutError {}
+
 impl<T> Drop for Channel<T> {
     fn drop(&mut self) {
         // `ready`が`true`の場合、読み込まれていないメッセージがチャネルに存在するため
@@ -77,7 +244,7 @@ fn main() {
         let (sender, receiver) = channel();
         let t = std::thread::current();
         s.spawn(move || {
-            sender.send("hello world!");
+            sender.send("hello world!").unwrap();
             // 次は`sender`がむーぶしているため、コンパイルエラーになる。
             // sender.send("second message");
             t.unpark();
@@ -85,6 +252,150 @@ fn main() {
         while !receiver.is_ready() {
             std::thread::park();
         }
-        assert_eq!(receiver.receive(), "hello world!");
-    })
+        assert_eq!(receiver.receive(), Ok("hello world!"));
+    });
+
+    // 時間制限付きの受信
+    std::thread::scope(|s| {
+        let (sender, receiver) = channel();
+        let t = std::thread::current();
+        s.spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sender.send("hello world!").unwrap();
+            t.unpark();
+        });
+        match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(message) => println!("received: {message}"),
+            Err((_, e)) => println!("error: {e}"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
+    use std::rc::Rc;
+
+    // 送信側と受信側は、メッセージを別のスレッドに移動できる場合（`T: Send`）にだけ、別のスレッドに送信できるはず。
+    assert_impl_all!(Sender<u32>: Send, Sync);
+    assert_impl_all!(Receiver<u32>: Send, Sync);
+    assert_not_impl_any!(Sender<Rc<u32>>: Send, Sync);
+    assert_not_impl_any!(Receiver<Rc<u32>>: Send, Sync);
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    fn send_after(sender: Sender<i32>, delay: Duration) -> std::thread::JoinHandle<()> {
+        let t = std::thread::current();
+        std::thread::spawn(move || {
+            std::thread::sleep(delay);
+            sender.send(42).unwrap();
+            t.unpark();
+        })
+    }
+
+    #[test]
+    fn recv_timeout_receives_message_within_timeout() {
+        let (sender, receiver) = channel();
+        let t = send_after(sender, Duration::from_millis(50));
+        assert_eq!(receiver.recv_timeout(TIMEOUT).ok(), Some(42));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn recv_timeout_returns_receiver_on_timeout() {
+        let (sender, receiver) = channel();
+        let t = send_after(sender, Duration::from_millis(200));
+        let Err((receiver, e)) = receiver.recv_timeout(TIMEOUT) else {
+            panic!("message must not arrive within the timeout");
+        };
+        assert_eq!(e, RecvTimeoutError::Timeout);
+        t.join().unwrap();
+
+        // 返却された`Receiver`で再試行できるはず。
+        assert_eq!(receiver.recv_timeout(TIMEOUT).ok(), Some(42));
+    }
+
+    #[test]
+    fn recv_timeout_detects_disconnection() {
+        let (sender, receiver) = channel::<i32>();
+        drop(sender);
+        let Err((_, e)) = receiver.recv_timeout(TIMEOUT) else {
+            panic!("sender was dropped without sending");
+        };
+        assert_eq!(e, RecvTimeoutError::Disconnected);
+    }
+
+    #[test]
+    fn sender_gets_error_after_receiver_dropped() {
+        let (sender, receiver) = channel();
+        let t = std::thread::spawn(move || {
+            // `Receiver`がドロップされるまで待機してから送信する。
+            while sender.is_connected() {
+                std::thread::yield_now();
+            }
+            sender.send(String::from("hello"))
+        });
+        drop(receiver);
+
+        // 送信できなかったメッセージが返却されるはず。
+        let error = t.join().unwrap().unwrap_err();
+        assert_eq!(error.into_inner(), "hello");
+    }
+
+    #[test]
+    fn receive_detects_disconnection() {
+        let (sender, receiver) = channel::<i32>();
+        assert!(receiver.is_connected());
+        drop(sender);
+        assert!(!receiver.is_connected());
+        assert_eq!(receiver.receive(), Err(RecvError));
+    }
+
+    #[test]
+    fn receive_after_sender_sent_and_dropped() {
+        let (sender, receiver) = channel();
+        sender.send(42).unwrap();
+        // `Sender`はドロップされているが、送信済みのメッセージは受信できるはず。
+        assert!(!receiver.is_connected());
+        assert_eq!(receiver.receive(), Ok(42));
+    }
+
+    #[test]
+    fn try_receive_returns_receiver_until_message_arrives() {
+        let (sender, mut receiver) = channel();
+        // 送信される前は、何度失敗しても同じ`Receiver`が返されるはず。
+        for _ in 0..3 {
+            receiver = receiver.try_receive().unwrap_err();
+            assert!(receiver.is_connected());
+        }
+        sender.send(42).unwrap();
+        assert_eq!(receiver.try_receive().ok(), Some(42));
+    }
+
+    #[test]
+    fn try_receive_polls_while_sender_delays() {
+        let (sender, mut receiver) = channel();
+        let t = send_after(sender, Duration::from_millis(50));
+        let mut attempts = 0;
+        let message = loop {
+            match receiver.try_receive() {
+                Ok(message) => break message,
+                Err(r) => receiver = r,
+            }
+            attempts += 1;
+            std::thread::yield_now();
+        };
+        // 送信されるまでの失敗でメッセージが失われず、送信されたメッセージを受信できるはず。
+        assert_eq!(message, 42);
+        assert!(attempts > 0);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn debug_prints_type_name_only() {
+        let (sender, receiver) = channel::<i32>();
+        assert_eq!(format!("{sender:?}"), "Sender { .. }");
+        assert_eq!(format!("{receiver:?}"), "Receiver { .. }");
+    }
 }
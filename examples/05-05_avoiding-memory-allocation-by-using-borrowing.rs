@@ -1,31 +1,68 @@
 use std::cell::UnsafeCell;
+use std::fmt;
 use std::mem::MaybeUninit;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    /// 05-04と同様に、送信側と受信側の両方が生存しているか
+    ///
+    /// どちらか一方がドロップされた時点で`false`に設定する。
+    connected: AtomicBool,
 }
 
 unsafe impl<T: Send> Sync for Channel<T> {}
 
 impl<T> Default for Channel<T> {
     fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Channel<T> {
+    /// `const`関数であるため、`static`変数や`const`ブロックの初期化にも使用できる。
+    pub const fn new() -> Self {
         Channel {
             message: UnsafeCell::new(MaybeUninit::uninit()),
             ready: AtomicBool::new(false),
+            connected: AtomicBool::new(true),
         }
     }
-}
 
-impl<T> Channel<T> {
     //pub fn split<'a>(&'a mut self) -> (Sender<'a, T>, Receiver<'a, T>) {
     pub fn split(&'_ mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
         // 自身の可変参照を受け取り、自身の可変参照を介して新しいインスタンスで初期化することで、
         // 上書き前の`*self`がドロップされるようにする。
-        *self = Self::default();
+        *self = Self::new();
         (Sender { channel: self }, Receiver { channel: self })
     }
+
+    /// チャネルをヒープに確保し、`Arc`で共有する送信側と受信側を返す。
+    ///
+    /// `split`の送信側と受信側はチャネルを借用するため、チャネルより長く生存できず、`std::thread::spawn`のように
+    /// `'static`を要求する関数には渡せない。
+    /// `ArcSender`と`ArcReceiver`はライフタイムパラメーターを持たず、両方がドロップされた時点でチャネルも解放される。
+    /// 05-04と同様にメモリの割り当てが必要になるため、借用で済む場合は`split`を使用する。
+    pub fn new_split() -> (ArcSender<T>, ArcReceiver<T>) {
+        let channel = Arc::new(Self::new());
+        (
+            ArcSender {
+                channel: Arc::clone(&channel),
+            },
+            ArcReceiver { channel },
+        )
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// 送信側または受信側がドロップされたことを記録する。
+    fn disconnect(&self) {
+        self.connected.store(false, Ordering::Release);
+    }
 }
 
 impl<T> Drop for Channel<T> {
@@ -53,6 +90,27 @@ impl<T> Sender<'_, T> {
         }
         self.channel.ready.store(true, Ordering::Release);
     }
+
+    /// `Receiver`が生存しているかを返す。
+    ///
+    /// `true`を返した直後に`Receiver`がドロップされる可能性があるため、戻り値はヒントに過ぎない。
+    pub fn is_connected(&self) -> bool {
+        self.channel.is_connected()
+    }
+}
+
+/// 05-04と同様に、`send()`は`self`を消費するため、メッセージを送信した後にも`drop`が呼び出される。
+/// `ready`へのReleaseストアの後に`connected`をReleaseストアする。
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+impl<T> Drop for Receiver<'_, T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
 }
 
 impl<T> Receiver<'_, T> {
@@ -60,14 +118,95 @@ impl<T> Receiver<'_, T> {
         self.channel.ready.load(Ordering::Relaxed)
     }
 
-    pub fn receive(self) -> T {
+    /// `Sender`が生存しているかを返す。
+    ///
+    /// `Sender`は`send()`で消費されるため、メッセージを送信した後は`false`を返す。
+    /// `receive`が`NotReadyError`を返した場合に、送信を待つべきか（`true`）、送信されずに
+    /// 送信側がドロップされたか（`false`）を区別するために使用する。
+    pub fn is_connected(&self) -> bool {
+        self.channel.is_connected()
+    }
+
+    /// メッセージを受信する。
+    ///
+    /// `Sender::send`は`self`を消費するため、2回送信されることはないが、メッセージが準備できる前に
+    /// 呼び出した場合は、パニックする代わりに`NotReadyError`を返す。
+    pub fn receive(self) -> Result<T, NotReadyError> {
+        if !self.channel.ready.swap(false, Ordering::Acquire) {
+            return Err(NotReadyError);
+        }
+        Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
+    }
+}
+
+/// `Channel::new_split`が返す、チャネルを`Arc`で共有する送信側
+pub struct ArcSender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+/// `Channel::new_split`が返す、チャネルを`Arc`で共有する受信側
+pub struct ArcReceiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> ArcSender<T> {
+    /// `Sender::send`と同じである。
+    pub fn send(self, message: T) {
+        unsafe {
+            (*self.channel.message.get()).write(message);
+        }
+        self.channel.ready.store(true, Ordering::Release);
+    }
+
+    /// `Sender::is_connected`と同じである。
+    pub fn is_connected(&self) -> bool {
+        self.channel.is_connected()
+    }
+}
+
+impl<T> Drop for ArcSender<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+impl<T> Drop for ArcReceiver<T> {
+    fn drop(&mut self) {
+        self.channel.disconnect();
+    }
+}
+
+impl<T> ArcReceiver<T> {
+    pub fn is_ready(&self) -> bool {
+        self.channel.ready.load(Ordering::Relaxed)
+    }
+
+    /// `Receiver::is_connected`と同じである。
+    pub fn is_connected(&self) -> bool {
+        self.channel.is_connected()
+    }
+
+    /// `Receiver::receive`と同じである。
+    pub fn receive(self) -> Result<T, NotReadyError> {
         if !self.channel.ready.swap(false, Ordering::Acquire) {
-            panic!("no message available!");
+            return Err(NotReadyError);
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
+        Ok(unsafe { (*self.channel.message.get()).assume_init_read() })
     }
 }
 
+/// メッセージが準備できていないときに`receive`が返すエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotReadyError;
+
+impl fmt::Display for NotReadyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no message available")
+    }
+}
+
+impl std::error::Error for NotReadyError {}
+
 fn main() {
     let mut channel = Channel::default();
     std::thread::scope(|s| {
@@ -80,6 +219,96 @@ fn main() {
         while !receiver.is_ready() {
             std::thread::park();
         }
-        assert_eq!(receiver.receive(), "hello world!");
+        assert_eq!(receiver.receive(), Ok("hello world!"));
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
+    use std::rc::Rc;
+
+    // チャネルを借用する送信側と受信側も、`T: Send`の場合にだけ別のスレッドに送信できるはず。
+    assert_impl_all!(Sender<'static, u32>: Send, Sync);
+    assert_impl_all!(Receiver<'static, u32>: Send, Sync);
+    assert_not_impl_any!(Sender<'static, Rc<u32>>: Send, Sync);
+    assert_not_impl_any!(Receiver<'static, Rc<u32>>: Send, Sync);
+    assert_impl_all!(ArcSender<u32>: Send, Sync);
+    assert_impl_all!(ArcReceiver<u32>: Send, Sync);
+    assert_not_impl_any!(ArcSender<Rc<u32>>: Send, Sync);
+    assert_not_impl_any!(ArcReceiver<Rc<u32>>: Send, Sync);
+
+    #[test]
+    fn receive_before_send_returns_error() {
+        let mut channel = Channel::default();
+        let (sender, receiver) = channel.split();
+        assert_eq!(receiver.receive(), Err(NotReadyError));
+        drop(sender);
+
+        let (sender, receiver) = channel.split();
+        sender.send(1);
+        assert_eq!(receiver.receive(), Ok(1));
+    }
+
+    #[test]
+    fn new_split_ends_are_static_and_deliver_across_threads() {
+        let (sender, receiver) = Channel::new_split();
+        // ライフタイムを持たないため、`std::thread::scope`を使わずに別々のスレッドへ移動できるはず。
+        let receiving = std::thread::spawn(move || {
+            while !receiver.is_ready() {
+                std::thread::yield_now();
+            }
+            receiver.receive()
+        });
+        let sending = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            sender.send(String::from("hello world!"));
+        });
+        sending.join().unwrap();
+        assert_eq!(receiving.join().unwrap().as_deref(), Ok("hello world!"));
+    }
+
+    #[test]
+    fn new_split_frees_unreceived_message_when_both_ends_drop() {
+        let message = Arc::new(());
+        let (sender, receiver) = Channel::new_split();
+        sender.send(Arc::clone(&message));
+        assert_eq!(Arc::strong_count(&message), 2);
+        // 受信されなかったメッセージは、最後の端がドロップされてチャネルが解放されるときにドロップされるはず。
+        drop(receiver);
+        assert_eq!(Arc::strong_count(&message), 1);
+    }
+
+    #[test]
+    fn dropped_sender_is_distinguished_from_pending_message() {
+        let mut channel = Channel::<i32>::default();
+        let (sender, receiver) = channel.split();
+        assert!(sender.is_connected());
+        assert!(receiver.is_connected());
+        drop(sender);
+        // 送信されずにドロップされたため、`NotReadyError`の後に待っても受信できないことがわかるはず。
+        assert!(!receiver.is_connected());
+        assert_eq!(receiver.receive(), Err(NotReadyError));
+
+        let (sender, receiver) = channel.split();
+        drop(receiver);
+        assert!(!sender.is_connected());
+
+        let (sender, receiver) = Channel::<i32>::new_split();
+        let waiting = std::thread::spawn(move || {
+            // 送信側がドロップされるまで待機し、送信されていないことを確認する。
+            while receiver.is_connected() {
+                std::thread::yield_now();
+            }
+            receiver.is_ready()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        drop(sender);
+        assert!(!waiting.join().unwrap());
+
+        let (sender, receiver) = Channel::<i32>::new_split();
+        drop(receiver);
+        assert!(!sender.is_connected());
+    }
+}
@@ -1,4 +1,5 @@
 use std::cell::UnsafeCell;
+use std::fmt;
 use std::mem::MaybeUninit;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::Thread;
@@ -6,6 +7,10 @@ use std::thread::Thread;
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    /// 05-04と同様に、`Sender`と`Receiver`の両方が生存しているか
+    ///
+    /// どちらか一方がドロップされた時点で`false`に設定する。
+    connected: AtomicBool,
 }
 
 pub struct Sender<'a, T> {
@@ -22,10 +27,7 @@ unsafe impl<T: Send> Sync for Channel<T> {}
 
 impl<T> Default for Channel<T> {
     fn default() -> Self {
-        Channel {
-            message: UnsafeCell::new(MaybeUninit::uninit()),
-            ready: AtomicBool::new(false),
-        }
+        Self::new()
     }
 }
 
@@ -40,8 +42,17 @@ impl<T> Drop for Channel<T> {
 }
 
 impl<T> Channel<T> {
+    /// `const`関数であるため、`static`変数や`const`ブロックの初期化にも使用できる。
+    pub const fn new() -> Self {
+        Channel {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            ready: AtomicBool::new(false),
+            connected: AtomicBool::new(true),
+        }
+    }
+
     pub fn split(&'_ mut self) -> (Sender<'_, T>, Receiver<'_, T>) {
-        *self = Self::default();
+        *self = Self::new();
         (
             Sender {
                 channel: self,
@@ -56,31 +67,176 @@ impl<T> Channel<T> {
 }
 
 impl<T> Sender<'_, T> {
-    pub fn send(self, message: T) {
+    /// `Receiver`がドロップされている場合は、05-04と同様に、メッセージを書き込まずに`SendError`で返却する。
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        if !self.is_connected() {
+            return Err(SendError(message));
+        }
         unsafe {
             (*self.channel.message.get()).write(message);
         }
         self.channel.ready.store(true, Ordering::Release);
         self.receiving_thread.unpark();
+        Ok(())
+    }
+
+    /// `Receiver`が生存しているかを返す。
+    ///
+    /// `true`を返した直後に`Receiver`がドロップされる可能性があるため、戻り値はヒントに過ぎない。
+    pub fn is_connected(&self) -> bool {
+        self.channel.connected.load(Ordering::Relaxed)
+    }
+}
+
+/// `send()`は`self`を消費するため、メッセージを送信した後にも`drop`が呼び出される。
+///
+/// メッセージを送信せずにドロップされた場合、`receive`で待機している`Receiver`を起床させなければ、
+/// `Receiver`は永久に待機する。そのため、`connected`を`false`にした後に`unpark`する。
+/// 05-04と同様に、`ready`へのReleaseストアの後に`connected`をReleaseストアするため、`connected`が`false`で
+/// あることをAcquireロードで観測した`Receiver`は、送信されたメッセージも観測できる。
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        self.channel.connected.store(false, Ordering::Release);
+        self.receiving_thread.unpark();
+    }
+}
+
+impl<T> Drop for Receiver<'_, T> {
+    fn drop(&mut self) {
+        self.channel.connected.store(false, Ordering::Release);
     }
 }
 
 impl<T> Receiver<'_, T> {
-    pub fn receive(self) -> T {
-        while !self.channel.ready.swap(false, Ordering::Acquire) {
+    /// メッセージが届くまで待機して受信する。
+    ///
+    /// `Sender`がメッセージを送信せずにドロップされた場合は、待機をやめて`RecvError`を返す。
+    pub fn receive(self) -> Result<T, RecvError> {
+        loop {
+            if let Some(message) = self.take_message() {
+                return Ok(message);
+            }
+            if !self.channel.connected.load(Ordering::Acquire) {
+                // `Sender`が送信後にドロップされた可能性があるため、もう一度確認する。
+                return self.take_message().ok_or(RecvError);
+            }
             std::thread::park();
         }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
+    }
+
+    /// `Sender`が生存しているかを返す。
+    ///
+    /// `Sender`は`send()`で消費されるため、メッセージを送信した後は`false`を返す。
+    pub fn is_connected(&self) -> bool {
+        self.channel.connected.load(Ordering::Relaxed)
+    }
+
+    fn take_message(&self) -> Option<T> {
+        self.channel
+            .ready
+            .swap(false, Ordering::Acquire)
+            .then(|| unsafe { (*self.channel.message.get()).assume_init_read() })
     }
 }
 
+/// 05-04と同じ、`Sender::send`が返すエラー
+///
+/// `Receiver`がドロップされていたため送信できなかったメッセージを保持する。
+pub struct SendError<T>(pub T);
+
+impl<T> SendError<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+/// `T`が`Debug`を実装していなくても使用できるように、メッセージは出力しない。
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sending on a closed channel")
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+/// 05-04と同じ、`Receiver::receive`が返すエラー
+///
+/// `Sender`がメッセージを送信せずにドロップされたことを示す。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "receiving on a closed channel")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
 fn main() {
     let mut channel = Channel::default();
     std::thread::scope(|s| {
         let (sender, receiver) = channel.split();
         s.spawn(move || {
-            sender.send("hello world!");
+            sender.send("hello world!").unwrap();
         });
-        assert_eq!(receiver.receive(), "hello world!");
+        assert_eq!(receiver.receive(), Ok("hello world!"));
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
+    use std::rc::Rc;
+
+    // 送信側は`split`を呼び出したスレッドをunparkするため、どのスレッドからでも送信できるはず。
+    assert_impl_all!(Sender<'static, u32>: Send, Sync);
+    assert_not_impl_any!(Sender<'static, Rc<u32>>: Send, Sync);
+    // 受信側は`split`を呼び出したスレッドでparkする必要があるため、`T`に関わらず`Send`ではないはず。
+    assert_not_impl_any!(Receiver<'static, u32>: Send, Sync);
+
+    #[test]
+    fn receive_wakes_up_when_sender_is_dropped() {
+        let mut channel = Channel::<i32>::new();
+        std::thread::scope(|s| {
+            let (sender, receiver) = channel.split();
+            assert!(receiver.is_connected());
+            s.spawn(move || {
+                // `Receiver`が`park`するまで待ってから、送信せずにドロップする。
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                drop(sender);
+            });
+            // 永久に待機せず、`RecvError`を返すはず。
+            assert_eq!(receiver.receive(), Err(RecvError));
+        });
+    }
+
+    #[test]
+    fn receive_after_sender_sent_and_dropped() {
+        let mut channel = Channel::new();
+        let (sender, receiver) = channel.split();
+        sender.send(42).unwrap();
+        // `Sender`はドロップされているが、送信済みのメッセージは受信できるはず。
+        assert!(!receiver.is_connected());
+        assert_eq!(receiver.receive(), Ok(42));
+    }
+
+    #[test]
+    fn send_fails_after_receiver_dropped() {
+        let mut channel = Channel::new();
+        let (sender, receiver) = channel.split();
+        assert!(sender.is_connected());
+        drop(receiver);
+        assert!(!sender.is_connected());
+        // 送信できなかったメッセージが返却されるはず。
+        let error = sender.send(String::from("hello")).unwrap_err();
+        assert_eq!(error.into_inner(), "hello");
+    }
+}
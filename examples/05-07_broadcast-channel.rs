@@ -0,0 +1,258 @@
+//! # ブロードキャストチャネル
+//!
+//! これまでのチャネルは、1つのメッセージを1つの受信者にだけ届けていた。
+//! ブロードキャストチャネルは、送信したメッセージを、その時点で存在するすべての受信者に届ける。
+//!
+//! 受信者ごとにキューを持ち、`send`はメッセージを複製して、それぞれのキューに追加する。
+//! したがって、受信が遅い受信者がいても、そのキューにメッセージが溜まるだけで、送信者や他の受信者は
+//! ブロックされない。
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Duration;
+
+/// 受信者ごとのキュー
+struct ReceiverQueue<T> {
+    queue: Mutex<VecDeque<T>>,
+    item_ready: Condvar,
+}
+
+/// 送信者と受信者で共有するデータ
+///
+/// 受信者は動的に追加、削除されるため、受信者のキューの一覧を`RwLock`で保護する。
+/// `send`は一覧を読み取るだけであるため、複数の送信者が同時にメッセージを送信できる。
+struct Shared<T> {
+    receivers: RwLock<Vec<Arc<ReceiverQueue<T>>>>,
+    /// 生存している`BroadcastSender`の数
+    senders: AtomicUsize,
+}
+
+pub struct BroadcastSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct BroadcastReceiver<T> {
+    shared: Arc<Shared<T>>,
+    queue: Arc<ReceiverQueue<T>>,
+}
+
+pub fn broadcast<T: Clone>() -> (BroadcastSender<T>, BroadcastReceiver<T>) {
+    let sender = BroadcastSender {
+        shared: Arc::new(Shared {
+            receivers: RwLock::new(Vec::new()),
+            senders: AtomicUsize::new(1),
+        }),
+    };
+    let receiver = sender.subscribe();
+    (sender, receiver)
+}
+
+impl<T: Clone> BroadcastSender<T> {
+    /// 現在存在するすべての受信者に、メッセージを送信する。
+    pub fn send(&self, message: T) {
+        let receivers = self.shared.receivers.read().unwrap();
+        for receiver in receivers.iter() {
+            receiver.queue.lock().unwrap().push_back(message.clone());
+            receiver.item_ready.notify_one();
+        }
+    }
+
+    /// 新しい受信者を作成する。
+    ///
+    /// 作成した受信者は、作成後に送信されたメッセージのみを受信する。
+    pub fn subscribe(&self) -> BroadcastReceiver<T> {
+        let queue = Arc::new(ReceiverQueue {
+            queue: Mutex::new(VecDeque::new()),
+            item_ready: Condvar::new(),
+        });
+        self.shared
+            .receivers
+            .write()
+            .unwrap()
+            .push(Arc::clone(&queue));
+        BroadcastReceiver {
+            shared: Arc::clone(&self.shared),
+            queue,
+        }
+    }
+
+    /// 現在の受信者の数を返す。
+    pub fn receiver_count(&self) -> usize {
+        self.shared.receivers.read().unwrap().len()
+    }
+}
+
+impl<T> Clone for BroadcastSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+/// 受信者ごとのキューはロックで保護されているため、ロックを獲得せずに型名だけを出力する。
+impl<T> fmt::Debug for BroadcastSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BroadcastSender").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for BroadcastReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BroadcastReceiver").finish_non_exhaustive()
+    }
+}
+
+impl<T> Drop for BroadcastSender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::Release) == 1 {
+            // 待機中の受信者に、切断されたことを通知する。
+            // 受信者は`Mutex`をロックした状態で`senders`を確認するため、一度ロックしてから通知することで、
+            // 通知が失われないようにする。
+            for receiver in self.shared.receivers.read().unwrap().iter() {
+                drop(receiver.queue.lock().unwrap());
+                receiver.item_ready.notify_all();
+            }
+        }
+    }
+}
+
+impl<T> BroadcastReceiver<T> {
+    /// メッセージを受信するまで待機する。
+    ///
+    /// すべての送信者がドロップされ、キューが空の場合は`None`を返す。
+    pub fn receive(&self) -> Option<T> {
+        let mut queue = self.queue.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return Some(message);
+            }
+            if self.shared.senders.load(Ordering::Acquire) == 0 {
+                return None;
+            }
+            queue = self.queue.item_ready.wait(queue).unwrap();
+        }
+    }
+}
+
+/// 受信者がドロップされたとき、送信者がメッセージを複製し続けないように、受信者の一覧から削除する。
+/// キューに残っていたメッセージは、キューと一緒にドロップされる。
+impl<T> Drop for BroadcastReceiver<T> {
+    fn drop(&mut self) {
+        self.shared
+            .receivers
+            .write()
+            .unwrap()
+            .retain(|queue| !Arc::ptr_eq(queue, &self.queue));
+    }
+}
+
+fn main() {
+    let (sender, receiver1) = broadcast();
+    let receiver2 = sender.subscribe();
+
+    std::thread::scope(|s| {
+        for (name, receiver) in [("receiver1", receiver1), ("receiver2", receiver2)] {
+            s.spawn(move || {
+                while let Some(message) = receiver.receive() {
+                    println!("{name} received: {message}");
+                }
+                println!("{name} disconnected");
+            });
+        }
+
+        for i in 0..3 {
+            sender.send(i);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        drop(sender);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_receiver_gets_every_message() {
+        let (sender, receiver) = broadcast();
+        let receivers = [receiver, sender.subscribe(), sender.subscribe()];
+
+        std::thread::scope(|s| {
+            let handles = receivers.map(|receiver| {
+                s.spawn(move || std::iter::from_fn(|| receiver.receive()).collect::<Vec<_>>())
+            });
+            for i in 0..100 {
+                sender.send(i);
+            }
+            drop(sender);
+
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), (0..100).collect::<Vec<_>>());
+            }
+        });
+    }
+
+    #[test]
+    fn slow_receiver_does_not_block_sender() {
+        let (sender, slow) = broadcast();
+        let fast = sender.subscribe();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    sender.send(i);
+                }
+            });
+            // `slow`が受信しなくても、`fast`はすべてのメッセージを受信できるはず。
+            for i in 0..1000 {
+                assert_eq!(fast.receive(), Some(i));
+            }
+        });
+
+        // `slow`のキューには、すべてのメッセージが溜まっているはず。
+        assert_eq!(slow.queue.queue.lock().unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn dropped_receiver_is_cleaned_up() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        #[derive(Clone)]
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let (sender, receiver) = broadcast();
+        let other = sender.subscribe();
+        assert_eq!(sender.receiver_count(), 2);
+
+        // 送信した元のメッセージは、複製後にドロップされる。
+        sender.send(DetectDrop);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+
+        // 受信者をドロップすると、一覧から削除され、キューに残っていたメッセージもドロップされるはず。
+        let queue = Arc::downgrade(&receiver.queue);
+        drop(receiver);
+        assert_eq!(sender.receiver_count(), 1);
+        assert!(queue.upgrade().is_none());
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 2);
+
+        // 残りの受信者は引き続き受信できるはず。
+        drop(other.receive());
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn debug_prints_type_name_only() {
+        let (sender, receiver) = broadcast::<i32>();
+        assert_eq!(format!("{sender:?}"), "BroadcastSender { .. }");
+        assert_eq!(format!("{receiver:?}"), "BroadcastReceiver { .. }");
+    }
+}
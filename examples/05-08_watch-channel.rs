@@ -0,0 +1,227 @@
+//! # ウォッチチャネル
+//!
+//! ウォッチチャネルは、状態を配信するためのチャネルである。
+//! 受信者は送信された履歴をすべて処理するのではなく、常に最新の値のみを参照する。
+//! したがって、受信が遅れた受信者は、途中の値を観測せずに最新の値だけを観測する。
+//!
+//! 値は`RwLock<T>`で保護し、複数の受信者が同時に値を参照できるようにしている。
+//! また、値が更新されるたびにバージョンを1つ増やすことで、受信者はロックを獲得せずに、
+//! 前回参照した後に値が更新されたかを確認できる。
+use std::cell::Cell;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+struct WatchInner<T> {
+    value: RwLock<T>,
+    /// 値が更新された回数
+    ///
+    /// `value`の書き込みロックを保持した状態で更新する。
+    version: AtomicUsize,
+}
+
+pub struct WatchSender<T> {
+    inner: Arc<WatchInner<T>>,
+}
+
+/// `seen_version`は`Cell`であるため、`WatchReceiver`は`Sync`ではない。
+/// 複数のスレッドで受信する場合は、`clone`してそれぞれのスレッドに渡すこと。
+pub struct WatchReceiver<T> {
+    inner: Arc<WatchInner<T>>,
+    /// 最後に`borrow`したときのバージョン
+    seen_version: Cell<usize>,
+}
+
+/// 現在の値への参照
+///
+/// 保持している間は読み込みロックを保持しているため、送信者は値を更新できない。
+/// 長時間保持しないこと。
+pub struct Ref<'a, T> {
+    guard: RwLockReadGuard<'a, T>,
+}
+
+pub fn watch<T>(initial: T) -> (WatchSender<T>, WatchReceiver<T>) {
+    let inner = Arc::new(WatchInner {
+        value: RwLock::new(initial),
+        version: AtomicUsize::new(0),
+    });
+    (
+        WatchSender {
+            inner: Arc::clone(&inner),
+        },
+        WatchReceiver {
+            inner,
+            seen_version: Cell::new(0),
+        },
+    )
+}
+
+impl<T> WatchSender<T> {
+    /// 値を置き換える。
+    ///
+    /// 置き換えられた値は、受信者に参照されていなくても破棄される。
+    pub fn send(&self, value: T) {
+        let mut guard = self.inner.value.write().unwrap();
+        *guard = value;
+        // Releaseストアにより、`changed`で新しいバージョンを観測した受信者は、
+        // その後の`borrow`で新しい値を観測できる。
+        self.inner.version.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T> WatchReceiver<T> {
+    /// 現在の値を参照し、その値を参照済みとして記録する。
+    pub fn borrow(&self) -> Ref<'_, T> {
+        let guard = self.inner.value.read().unwrap();
+        // 読み込みロックを保持している間は`version`が更新されないため、参照している値と
+        // バージョンは一致する。
+        self.seen_version
+            .set(self.inner.version.load(Ordering::Relaxed));
+        Ref { guard }
+    }
+
+    /// 最後に`borrow`した後に、値が更新されたかを返す。
+    ///
+    /// ロックを獲得せず、バージョンを比較するだけである。
+    pub fn changed(&self) -> bool {
+        self.inner.version.load(Ordering::Acquire) != self.seen_version.get()
+    }
+}
+
+impl<T> Clone for WatchReceiver<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            seen_version: Cell::new(self.seen_version.get()),
+        }
+    }
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+/// 値は`RwLock`で保護されているため、ロックを獲得せずに型名だけを出力する。
+impl<T> fmt::Debug for WatchSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchSender").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Debug for WatchReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchReceiver").finish_non_exhaustive()
+    }
+}
+
+/// `Ref`は読み込みロックを保持しているため、値をそのまま出力する。
+impl<T: fmt::Debug> fmt::Debug for Ref<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+fn main() {
+    let (sender, receiver) = watch(String::from("starting"));
+
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            for state in ["running", "stopping", "stopped"] {
+                std::thread::sleep(Duration::from_millis(10));
+                sender.send(String::from(state));
+            }
+        });
+
+        // 状態が`stopped`になるまで、変化を監視する。
+        loop {
+            if receiver.changed() {
+                let state = receiver.borrow();
+                println!("state: {}", *state);
+                if *state == "stopped" {
+                    break;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_is_cleared_by_borrow() {
+        let (sender, receiver) = watch(0);
+        assert!(!receiver.changed());
+        assert_eq!(*receiver.borrow(), 0);
+
+        sender.send(1);
+        assert!(receiver.changed());
+        assert_eq!(*receiver.borrow(), 1);
+        assert!(!receiver.changed());
+    }
+
+    #[test]
+    fn lagging_receiver_sees_only_latest_value() {
+        let (sender, receiver) = watch(0);
+        let lagging = receiver.clone();
+
+        // 受信者が参照しない間に、複数回値を更新する。
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 1..=100 {
+                    sender.send(i);
+                }
+            });
+        });
+
+        // 途中の値を観測せず、最新の値だけを観測するはず。
+        assert!(lagging.changed());
+        assert_eq!(*lagging.borrow(), 100);
+        assert!(!lagging.changed());
+        assert_eq!(*receiver.borrow(), 100);
+    }
+
+    #[test]
+    fn concurrent_receiver_observes_monotonic_values() {
+        let (sender, receiver) = watch(0);
+        let observed = std::thread::scope(|s| {
+            let t = s.spawn(move || {
+                let mut observed = Vec::new();
+                loop {
+                    if receiver.changed() {
+                        let value = *receiver.borrow();
+                        observed.push(value);
+                        if value == 10_000 {
+                            return observed;
+                        }
+                    }
+                    std::thread::yield_now();
+                }
+            });
+            for i in 1..=10_000 {
+                sender.send(i);
+            }
+            t.join().unwrap()
+        });
+
+        // 観測した値は単調増加し、最後は最新の値であるはず。
+        assert!(observed.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(observed.last(), Some(&10_000));
+    }
+
+    #[test]
+    fn debug_prints_value_only_through_ref() {
+        let (sender, receiver) = watch(vec![1]);
+        assert_eq!(format!("{sender:?}"), "WatchSender { .. }");
+        assert_eq!(format!("{receiver:?}"), "WatchReceiver { .. }");
+        assert_eq!(format!("{:?}", receiver.borrow()), "[1]");
+    }
+}
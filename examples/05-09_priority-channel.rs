@@ -0,0 +1,198 @@
+//! # 優先度付きチャネル
+//!
+//! 優先度付きチャネルは、メッセージとともに優先度を送信し、受信者は残っているメッセージのうち、
+//! 最も優先度の高いメッセージから受信するチャネルである。
+//!
+//! 05-01のチャネルの`VecDeque`を`BinaryHeap`に置き換えたものであり、ロックには09-01-02のミューテックスと
+//! 09-02の条件変数を使用する。
+//!
+//! `BinaryHeap`は、同じ優先度の要素を取り出す順序を保証しない。
+//! そこで、送信するたびにインクリメントする送信番号をメッセージに付与し、同じ優先度の場合は送信番号が小さい
+//! （先に送信された）メッセージを優先することで、同じ優先度のメッセージを送信した順に受信する。
+//!
+//! 09-02のファイルを`#[path]`でモジュールとして読み込み、09-01-02のミューテックスと09-02の条件変数をそのまま使用する。
+//! そのため、`cargo test --example 05-09_priority-channel`は、09-02と09-01-02のテストも実行する。
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// 09-01-02のミューテックスと09-02の条件変数
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+#[allow(dead_code)]
+#[path = "09-02_condvar.rs"]
+mod condvar;
+
+use condvar::{Condvar, Mutex};
+
+/// 優先度と送信番号を付与したメッセージ
+///
+/// 優先度が高いほど大きく、同じ優先度の場合は送信番号が小さいほど大きい。
+/// `BinaryHeap`は最大の要素から取り出すため、優先度が高く、先に送信されたメッセージから取り出される。
+struct PriorityItem<T, P> {
+    priority: P,
+    sequence: u64,
+    value: T,
+}
+
+impl<T, P: Ord> Ord for PriorityItem<T, P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl<T, P: Ord> PartialOrd for PriorityItem<T, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// 送信番号は一意であるため、値を比較する必要はない。
+impl<T, P: Ord> PartialEq for PriorityItem<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<T, P: Ord> Eq for PriorityItem<T, P> {}
+
+struct Queue<T, P> {
+    heap: BinaryHeap<PriorityItem<T, P>>,
+    /// 次に送信するメッセージの送信番号
+    next_sequence: u64,
+}
+
+pub struct PriorityChannel<T, P: Ord> {
+    queue: Mutex<Queue<T, P>>,
+    item_ready: Condvar,
+}
+
+impl<T, P: Ord> PriorityChannel<T, P> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(Queue {
+                heap: BinaryHeap::new(),
+                next_sequence: 0,
+            }),
+            item_ready: Condvar::new(),
+        }
+    }
+
+    /// 優先度`priority`でメッセージを送信する。
+    pub fn send(&self, value: T, priority: P) {
+        let mut queue = self.queue.lock();
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.heap.push(PriorityItem {
+            priority,
+            sequence,
+            value,
+        });
+        drop(queue);
+        self.item_ready.notify_one();
+    }
+
+    /// 最も優先度の高いメッセージを受信する。
+    ///
+    /// メッセージがない場合は、送信されるまで待機する。
+    pub fn recv(&self) -> T {
+        let mut queue = self.queue.lock();
+        loop {
+            if let Some(item) = queue.heap.pop() {
+                return item.value;
+            }
+            queue = self.item_ready.wait(queue);
+        }
+    }
+
+    /// 待機せずに、メッセージがあれば最も優先度の高いメッセージを受信する。
+    pub fn try_recv(&self) -> Option<T> {
+        self.queue.lock().heap.pop().map(|item| item.value)
+    }
+}
+
+impl<T, P: Ord> Default for PriorityChannel<T, P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn main() {
+    let channel = PriorityChannel::new();
+    channel.send("low", 1);
+    channel.send("high", 10);
+    channel.send("normal", 5);
+    channel.send("high (second)", 10);
+    while let Some(message) = channel.try_recv() {
+        println!("Received: {message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn receives_highest_priority_first() {
+        let channel = PriorityChannel::new();
+        for (value, priority) in [('a', 3), ('b', 1), ('c', 4), ('d', 1), ('e', 5), ('f', 9)] {
+            channel.send(value, priority);
+        }
+        let received = std::iter::from_fn(|| channel.try_recv()).collect::<String>();
+        // 同じ優先度の`b`と`d`は、送信した順に受信するはず。
+        assert_eq!(received, "fecabd");
+    }
+
+    #[test]
+    fn equal_priorities_are_fifo() {
+        let channel = PriorityChannel::new();
+        for i in 0..1000 {
+            channel.send(i, ());
+        }
+        for i in 0..1000 {
+            assert_eq!(channel.recv(), i);
+        }
+        assert_eq!(channel.try_recv(), None);
+    }
+
+    #[test]
+    fn recv_returns_highest_remaining_priority_from_multiple_senders() {
+        const SENDERS: u32 = 4;
+        const MESSAGES: u32 = 250;
+        let channel = PriorityChannel::new();
+        std::thread::scope(|s| {
+            for t in 0..SENDERS {
+                let channel = &channel;
+                s.spawn(move || {
+                    for i in 0..MESSAGES {
+                        // 優先度をスレッド間で交互に並べる。
+                        let priority = (i * 7919 + t * 104_729) % 1000;
+                        channel.send((priority, t), priority);
+                    }
+                });
+            }
+        });
+
+        // すべて送信した後は、残っているメッセージのうち最も高い優先度から受信するはず。
+        let mut last = u32::MAX;
+        for _ in 0..SENDERS * MESSAGES {
+            let (priority, _) = channel.recv();
+            assert!(priority <= last, "{priority} > {last}");
+            last = priority;
+        }
+        assert_eq!(channel.try_recv(), None);
+    }
+
+    #[test]
+    fn recv_blocks_until_sent() {
+        let channel = PriorityChannel::new();
+        std::thread::scope(|s| {
+            let receiver = s.spawn(|| channel.recv());
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!receiver.is_finished());
+            channel.send("hello", 0);
+            assert_eq!(receiver.join().unwrap(), "hello");
+        });
+    }
+}
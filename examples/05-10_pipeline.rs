@@ -0,0 +1,235 @@
+//! # メッセージを変換するパイプライン
+//!
+//! `Iterator::map`のように、チャネルを包んで、メッセージを変換するパイプラインである。
+//!
+//! - `MapSend`: 送信するときに変換し、内側のチャネルには変換後の値を格納する。
+//!   変換は送信するスレッドで実行される。
+//! - `MapReceive`: 内側のチャネルには変換前の値を格納し、受信するときに変換する。
+//!   受信されなかったメッセージは変換されないため、`MapSend`より遅延評価である。
+//!
+//! 内側のチャネルは、`ChannelSend`と`ChannelReceive`トレイトで抽象化しているため、05-01のチャネル以外や、
+//! パイプライン自身も内側のチャネルとして使用できる。
+//! 変換するクロージャーは、パイプラインが1つだけ保持し、メッセージごとに複製しない（`Clone`を要求しない）。
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// メッセージを送信するチャネル
+pub trait ChannelSend<T> {
+    fn send(&self, message: T);
+
+    /// 送信するときに`f`で変換するパイプラインを作成する。
+    fn map_send<S, F>(self, f: F) -> MapSend<S, Self, F>
+    where
+        Self: Sized,
+        F: Fn(S) -> T,
+    {
+        MapSend {
+            inner: self,
+            f,
+            _input: PhantomData,
+        }
+    }
+}
+
+/// メッセージを受信するチャネル
+pub trait ChannelReceive<T> {
+    /// メッセージを受信するまで待機する。
+    fn receive(&self) -> T;
+
+    /// 待機せずに、メッセージがあれば受信する。
+    fn try_receive(&self) -> Option<T>;
+
+    /// 受信するときに`f`で変換するパイプラインを作成する。
+    fn map_receive<U, F>(self, f: F) -> MapReceive<T, Self, F>
+    where
+        Self: Sized,
+        F: Fn(T) -> U,
+    {
+        MapReceive {
+            inner: self,
+            f,
+            _input: PhantomData,
+        }
+    }
+}
+
+/// 送信するときに変換するパイプライン
+///
+/// `T`は変換前のメッセージの型であり、`fn(T)`として保持することで、`T`が`Send`や`Sync`でなくても
+/// パイプライン自体の`Send`と`Sync`に影響しない。
+pub struct MapSend<T, S, F> {
+    inner: S,
+    f: F,
+    _input: PhantomData<fn(T)>,
+}
+
+impl<T, U, S, F> ChannelSend<T> for MapSend<T, S, F>
+where
+    S: ChannelSend<U>,
+    F: Fn(T) -> U,
+{
+    fn send(&self, message: T) {
+        self.inner.send((self.f)(message));
+    }
+}
+
+/// 受信するときに変換するパイプライン
+pub struct MapReceive<T, R, F> {
+    inner: R,
+    f: F,
+    _input: PhantomData<fn() -> T>,
+}
+
+impl<T, U, R, F> ChannelReceive<U> for MapReceive<T, R, F>
+where
+    R: ChannelReceive<T>,
+    F: Fn(T) -> U,
+{
+    fn receive(&self) -> U {
+        (self.f)(self.inner.receive())
+    }
+
+    fn try_receive(&self) -> Option<U> {
+        self.inner.try_receive().map(&self.f)
+    }
+}
+
+/// 05-01のチャネル
+struct Channel<T> {
+    queue: Mutex<VecDeque<T>>,
+    item_ready: Condvar,
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        queue: Mutex::new(VecDeque::new()),
+        item_ready: Condvar::new(),
+    });
+    (
+        Sender {
+            channel: Arc::clone(&channel),
+        },
+        Receiver { channel },
+    )
+}
+
+impl<T> ChannelSend<T> for Sender<T> {
+    fn send(&self, message: T) {
+        self.channel.queue.lock().unwrap().push_back(message);
+        self.channel.item_ready.notify_one();
+    }
+}
+
+impl<T> ChannelReceive<T> for Receiver<T> {
+    fn receive(&self) -> T {
+        let mut queue = self.channel.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                return message;
+            }
+            queue = self.channel.item_ready.wait(queue).unwrap();
+        }
+    }
+
+    fn try_receive(&self) -> Option<T> {
+        self.channel.queue.lock().unwrap().pop_front()
+    }
+}
+
+fn main() {
+    let (sender, receiver) = channel::<i32>();
+    let sender = sender.map_send(|x: i32| x * 2);
+    let receiver = receiver.map_receive(|x| format!("<{x}>"));
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..5 {
+                sender.send(i);
+            }
+        });
+        for _ in 0..5 {
+            println!("Received: {}", receiver.receive());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn map_send_converts_integers_to_strings() {
+        let (sender, receiver) = channel::<String>();
+        let sender = sender.map_send(|x: i32| x.to_string());
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..100 {
+                    sender.send(i);
+                }
+            });
+            for i in 0..100 {
+                assert_eq!(receiver.receive(), i.to_string());
+            }
+        });
+    }
+
+    #[test]
+    fn map_receive_converts_integers_to_strings() {
+        let (sender, receiver) = channel::<i32>();
+        let receiver = receiver.map_receive(|x| x.to_string());
+        sender.send(1);
+        sender.send(23);
+        assert_eq!(receiver.receive(), "1");
+        assert_eq!(receiver.try_receive().as_deref(), Some("23"));
+        assert_eq!(receiver.try_receive(), None);
+    }
+
+    #[test]
+    fn map_receive_is_lazy() {
+        // `Clone`を実装しない値をキャプチャしたクロージャーでも使用できるはず。
+        struct NotClone(Cell<usize>);
+        let send_calls = NotClone(Cell::new(0));
+        let receive_calls = NotClone(Cell::new(0));
+
+        let (sender, receiver) = channel::<i32>();
+        let sender = sender.map_send(|x: i32| {
+            send_calls.0.set(send_calls.0.get() + 1);
+            x
+        });
+        let receiver = receiver.map_receive(|x| {
+            receive_calls.0.set(receive_calls.0.get() + 1);
+            x.to_string()
+        });
+        for i in 0..10 {
+            sender.send(i);
+        }
+        // `MapSend`は送信するたびに変換するが、`MapReceive`は受信するまで変換しないはず。
+        assert_eq!(send_calls.0.get(), 10);
+        assert_eq!(receive_calls.0.get(), 0);
+
+        assert_eq!(receiver.receive(), "0");
+        assert_eq!(receiver.receive(), "1");
+        // 受信したメッセージだけが変換されるはず。
+        assert_eq!(receive_calls.0.get(), 2);
+    }
+
+    #[test]
+    fn pipelines_can_be_chained() {
+        let (sender, receiver) = channel::<String>();
+        let sender = sender
+            .map_send(|x: i32| x.to_string())
+            .map_send(|x: u8| i32::from(x) * 100);
+        let receiver = receiver.map_receive(|s| s.len());
+        sender.send(7);
+        assert_eq!(receiver.receive(), 3);
+    }
+}
@@ -0,0 +1,216 @@
+//! 06-03の`Weak::upgrade`と、再試行の回数を制限した`Weak::upgrade_checked`を比較する。
+//!
+//! 16スレッドが同じ`Weak`から`upgrade`して、得た`Arc`をすぐにドロップすることを繰り返し、
+//! 全体の所要時間、1回の呼び出しにかかった最大の時間、`upgrade_checked`が上限に達して`None`を返した回数を出力する。
+//!
+//! 複数のCPUコアがある環境では、`data_ref_count`が変化し続けるため`compare_exchange_weak`が失敗しやすく、
+//! `upgrade`では1回の呼び出しが長くなる場合がある。
+//! `upgrade_checked`は上限に達すると`None`を返すため、1回の呼び出しにかかる最大の時間が短くなることが期待される。
+//! その代わり、呼び出し側は`None`を受け取った場合にCPUを譲ってから再試行する必要がある。
+//!
+//! 例えば、CPUコアが1つのLinux環境では、スレッドが同時に実行されないため`compare_exchange_weak`が失敗することは
+//! ほとんどなく、`upgrade_checked`が上限に達することもなかった。
+//! 全体の所要時間（どちらも約150〜200ms）に差はなかった。
+//! 最大の時間は、どちらも数十ミリ秒から約100msであったが、これは`upgrade`の途中でタイムスライスが切り替わり、
+//! 他の15スレッドが実行されるのを待った時間であり、再試行の回数とは関係がない。
+//! 結果はCPUコアの数に大きく依存するため、実際に使用する環境で計測すること。
+//!
+//! 09-01-06と同様に、06-03の`Arc`と`Weak`の最小限の実装をここにコピーしている。
+//! 計測する場合は`cargo run --release --example 06-03-01_upgrade-benchmark`で実行すること。
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering, fence};
+use std::time::{Duration, Instant};
+
+/// スレッドの数
+const THREADS: usize = 16;
+/// 1スレッドあたりの`upgrade`の回数
+const ITERATIONS: usize = 100_000;
+/// 06-03の`UPGRADE_RETRY_LIMIT`
+const UPGRADE_RETRY_LIMIT: u32 = 64;
+
+/// 06-03の`ArcData`（値は`ManuallyDrop`にせず、弱参照だけで解放する）
+struct ArcData<T> {
+    data_ref_count: AtomicUsize,
+    alloc_ref_count: AtomicUsize,
+    data: T,
+}
+
+/// 06-03の`Arc`
+pub struct Arc<T> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+
+/// 06-03の`Weak`
+pub struct Weak<T> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Weak<T> {}
+unsafe impl<T: Send + Sync> Sync for Weak<T> {}
+
+impl<T> Arc<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            ptr: NonNull::from(Box::leak(Box::new(ArcData {
+                data_ref_count: AtomicUsize::new(1),
+                alloc_ref_count: AtomicUsize::new(1),
+                data,
+            }))),
+        }
+    }
+
+    pub fn downgrade(arc: &Self) -> Weak<T> {
+        let data = unsafe { arc.ptr.as_ref() };
+        data.alloc_ref_count.fetch_add(1, Ordering::Relaxed);
+        Weak { ptr: arc.ptr }
+    }
+}
+
+impl<T> std::ops::Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &self.ptr.as_ref().data }
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        // 値は弱参照とともに解放するため、強参照の数だけを減らす。
+        let data = unsafe { self.ptr.as_ref() };
+        if data.data_ref_count.fetch_sub(1, Ordering::Release) == 1 {
+            fence(Ordering::Acquire);
+            drop(Weak { ptr: self.ptr });
+        }
+    }
+}
+
+impl<T> Weak<T> {
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// 06-03の`Weak::upgrade`
+    pub fn upgrade(&self) -> Option<Arc<T>> {
+        let mut n = self.data().data_ref_count.load(Ordering::Relaxed);
+        loop {
+            if n == 0 {
+                return None;
+            }
+            match self.data().data_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Arc { ptr: self.ptr }),
+                Err(e) => n = e,
+            }
+        }
+    }
+
+    /// 06-03の`Weak::upgrade_checked`
+    pub fn upgrade_checked(&self) -> Option<Arc<T>> {
+        let mut n = self.data().data_ref_count.load(Ordering::Relaxed);
+        for _ in 0..UPGRADE_RETRY_LIMIT {
+            if n == 0 {
+                return None;
+            }
+            match self.data().data_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Arc { ptr: self.ptr }),
+                Err(e) => n = e,
+            }
+        }
+        None
+    }
+}
+
+impl<T> Drop for Weak<T> {
+    fn drop(&mut self) {
+        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Release) == 1 {
+            fence(Ordering::Acquire);
+            unsafe { drop(Box::from_raw(self.ptr.as_ptr())) };
+        }
+    }
+}
+
+/// 計測結果
+struct Report {
+    /// 全体の所要時間
+    total: Duration,
+    /// 1回の呼び出しにかかった最大の時間
+    slowest: Duration,
+    /// 上限に達して`None`を返した回数
+    gave_up: usize,
+}
+
+/// `THREADS`個のスレッドで、`upgrade`を`ITERATIONS`回ずつ呼び出す。
+fn bench(upgrade: impl Fn(&Weak<u64>) -> Option<Arc<u64>> + Sync) -> Report {
+    let arc = Arc::new(0_u64);
+    let weak = Arc::downgrade(&arc);
+    let start = Instant::now();
+    let results = std::thread::scope(|s| {
+        let threads = (0..THREADS)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut slowest = Duration::ZERO;
+                    let mut gave_up = 0;
+                    for _ in 0..ITERATIONS {
+                        let start = Instant::now();
+                        let upgraded = upgrade(&weak);
+                        slowest = slowest.max(start.elapsed());
+                        match upgraded {
+                            Some(arc) => {
+                                std::hint::black_box(*arc);
+                            }
+                            None => {
+                                gave_up += 1;
+                                std::thread::yield_now();
+                            }
+                        }
+                    }
+                    (slowest, gave_up)
+                })
+            })
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    let total = start.elapsed();
+    // すべての`Arc`がドロップされ、元の1つだけが残っているはず。
+    assert_eq!(weak.data().data_ref_count.load(Ordering::Relaxed), 1);
+    drop(arc);
+    Report {
+        total,
+        slowest: results.iter().map(|r| r.0).max().unwrap(),
+        gave_up: results.iter().map(|r| r.1).sum(),
+    }
+}
+
+fn main() {
+    println!(
+        "available parallelism: {}",
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+    println!("{THREADS} threads, {ITERATIONS} upgrades each");
+
+    for (label, report) in [
+        ("upgrade", bench(Weak::upgrade)),
+        ("upgrade_checked", bench(Weak::upgrade_checked)),
+    ] {
+        println!(
+            "  {label:<16}: total {:>12?} slowest {:>12?} gave up {}",
+            report.total, report.slowest, report.gave_up
+        );
+    }
+}
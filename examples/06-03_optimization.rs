@@ -1,7 +1,16 @@
 use std::cell::UnsafeCell;
+use std::cmp::Ordering as CmpOrdering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::mem::ManuallyDrop;
+use std::pin::Pin;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicUsize, Ordering, fence};
+
+#[path = "shared/sync.rs"]
+mod sync;
+
+// `--cfg loom`を指定した場合は、10-21がloomのアトミック型でこの`Arc`を検査する。
+use sync::{AtomicUsize, Ordering, fence, spin_loop};
 
 pub struct Arc<T> {
     ptr: NonNull<ArcData<T>>,
@@ -104,7 +113,7 @@ impl<T> Arc<T> {
         let mut n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
         loop {
             if n == usize::MAX {
-                std::hint::spin_loop();
+                spin_loop();
                 n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
                 continue;
             }
@@ -121,6 +130,144 @@ impl<T> Arc<T> {
             return Weak { ptr: arc.ptr };
         }
     }
+
+    /// `downgrade`と同様に弱参照を作成するが、他のスレッドが`get_mut`を実行中の場合は、スピンせずに`None`を返す。
+    ///
+    /// `get_mut`が`alloc_ref_count`を`usize::MAX`に設定している期間は短いが、そのスレッドがプリエンプトされると
+    /// `downgrade`はいつまでもスピンする。
+    /// 待機する時間に上限が必要な処理では、これを使用して、`None`の場合は後で再試行する。
+    /// `compare_exchange_weak`の偽の失敗や、他のスレッドによる弱参照の増減では、`usize::MAX`を観測するまで再試行する。
+    pub fn try_downgrade(arc: &Self) -> Option<Weak<T>> {
+        let mut n = arc.data().alloc_ref_count.load(Ordering::Relaxed);
+        loop {
+            if n == usize::MAX {
+                return None;
+            }
+            assert!(n < usize::MAX - 1);
+            if let Err(e) = arc.data().alloc_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                n = e;
+                continue;
+            }
+            return Some(Weak { ptr: arc.ptr });
+        }
+    }
+
+    /// 2つの`Arc`が同じ制御ブロック（同じ割り当て）を指しているかどうかを返す。
+    ///
+    /// `==`（`PartialEq`）は参照先の値を比較するため、別々に`Arc::new`した等しい値も等しいとみなす。
+    /// 一方、`ptr_eq`は`clone`で複製した`Arc`同士の場合だけ`true`を返す。
+    /// 値が等しいかどうかを知りたい場合は`==`を、同じオブジェクトを共有しているかどうか（一方を通じた変更が
+    /// 他方から見えるかどうかや、キャッシュから取り出した`Arc`が同じものかどうか）を知りたい場合は`ptr_eq`を使用する。
+    /// `T`のメソッドと衝突しないように、関連関数としている。
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+}
+
+impl<T> Arc<T> {
+    /// `Arc`が保持している値の一部（フィールドなど）だけを参照する`ProjectedArc`を作成する。
+    ///
+    /// `ProjectedArc`は元の`Arc`を保持するため、すべての`ProjectedArc`がドロップされるまで、値は解放されない。
+    ///
+    /// `ArcData<T>`はヒープに確保され、`Arc`が存在する間は移動しないため、`proj`が返す参照の
+    /// アドレスは変わらない。
+    /// したがって、`pin-project`のように`Unpin`を考慮する必要はなく、任意のフィールドに射影できる。
+    pub fn project<U: ?Sized, F>(arc: Self, proj: F) -> ProjectedArc<T, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let ptr = NonNull::from(proj(&arc));
+        ProjectedArc { arc, ptr }
+    }
+}
+
+impl<T> Arc<T> {
+    /// 値を`Arc`に格納し、ピン留めする。
+    ///
+    /// `T`が`Unpin`でない（自己参照を持つなど）場合でも、`Pin<Arc<T>>`の`as_ref`で`Pin<&T>`を得られる。
+    pub fn pin(data: T) -> Pin<Arc<T>> {
+        // 安全性: 他に`Arc`も`Weak`も存在しないため、`into_pin`の条件を満たす。
+        unsafe { Pin::new_unchecked(Self::new(data)) }
+    }
+
+    /// 他に`Arc`も`Weak`も存在しない場合、`Arc`をピン留めする。共有されている場合は`Err`で`arc`を返す。
+    ///
+    /// `T`は`ArcData<T>`の一部としてヒープに確保され、`clone`しても複製されるのは`ArcData<T>`へのポインタだけであるため、
+    /// `Arc`を移動しても値のアドレスは変わらない。また、`Arc`は`DerefMut`を実装していないため、
+    /// `Pin<Arc<T>>`から`&mut T`を得る方法は、`Pin<&mut Arc<T>>`を経由しても存在しない。
+    /// ただし、ピン留めされていない`Arc`や`Weak`が残っていると、`Pin<Arc<T>>`がすべてドロップされた後に
+    /// `get_mut`で`&mut T`を得て、値を移動できてしまう。そのため、共有されていないことを確認してからピン留めする。
+    /// `Arc<T>`自体は、`std::sync::Arc`と同様に`T`に関わらず`Unpin`であるが、`Arc`を移動しても値は移動しないため問題ない。
+    pub fn into_pin(arc: Self) -> Result<Pin<Arc<T>>, Self> {
+        // 自分が唯一の強参照であれば、他のスレッドが`clone`や`downgrade`で参照を増やすことはできず、
+        // `alloc_ref_count`が1であれば、`upgrade`で強参照を増やせる弱参照も存在しない。
+        let data = arc.data();
+        if data.data_ref_count.load(Ordering::Acquire) != 1
+            || data.alloc_ref_count.load(Ordering::Acquire) != 1
+        {
+            return Err(arc);
+        }
+        // 安全性: ピン留めされていない参照が存在しないため、値がドロップされるまで移動されることはない。
+        Ok(unsafe { Pin::new_unchecked(arc) })
+    }
+}
+
+/// `Arc<T>`が保持している値の一部を参照するポインタ
+///
+/// `Arc::project`からのみ作成できる。
+/// `ptr`は`arc`が保持している値の一部を指しており、`arc`が生存している間は有効である。
+pub struct ProjectedArc<T, U: ?Sized> {
+    arc: Arc<T>,
+    ptr: NonNull<U>,
+}
+
+/// `Arc<T>`と`&U`の両方を保持しているものとして、`Send`と`Sync`を実装する。
+unsafe impl<T: Send + Sync, U: Sync + ?Sized> Send for ProjectedArc<T, U> {}
+unsafe impl<T: Send + Sync, U: Sync + ?Sized> Sync for ProjectedArc<T, U> {}
+
+impl<T, U: ?Sized> ProjectedArc<T, U> {
+    /// 射影した値を、さらに射影する。
+    pub fn project<V: ?Sized, F>(this: Self, proj: F) -> ProjectedArc<T, V>
+    where
+        F: FnOnce(&U) -> &V,
+    {
+        let ptr = NonNull::from(proj(&this));
+        ProjectedArc { arc: this.arc, ptr }
+    }
+
+    /// 射影する前の`Arc`への参照を返す。
+    pub fn parent(this: &Self) -> &Arc<T> {
+        &this.arc
+    }
+}
+
+impl<T, U: ?Sized> std::ops::Deref for ProjectedArc<T, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        // 安全性: `ptr`は`arc`が保持している値の一部を指しており、`arc`が生存しているため有効である。
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, U: ?Sized> Clone for ProjectedArc<T, U> {
+    fn clone(&self) -> Self {
+        Self {
+            arc: self.arc.clone(),
+            ptr: self.ptr,
+        }
+    }
+}
+
+impl<T, U: fmt::Debug + ?Sized> fmt::Debug for ProjectedArc<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
 }
 
 impl<T> std::ops::Deref for Arc<T> {
@@ -136,20 +283,38 @@ impl<T> std::ops::Deref for Arc<T> {
 }
 
 impl<T> Weak<T> {
-    fn data(&self) -> &ArcData<T> {
-        unsafe { self.ptr.as_ref() }
+    /// どの`Arc`も指さない`Weak`を作成する。`upgrade`は常に`None`を返す。
+    ///
+    /// `std::sync::Weak::new`と同様に、`ArcData`を確保せずにダングリングポインタを保持する。
+    /// メモリを確保しないため`const`関数にでき、`static`変数の初期化にも使用できる。
+    pub const fn new() -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+        }
+    }
+
+    /// 制御ブロックへの参照を返す。`Weak::new`で作成した場合は`None`を返す。
+    ///
+    /// `NonNull::dangling`のアドレスは`ArcData<T>`のアラインメントと等しく、ヒープに確保した
+    /// `ArcData<T>`がそのアドレスに配置されることはないため、`Weak::new`で作成したものと区別できる。
+    fn data(&self) -> Option<&ArcData<T>> {
+        if self.ptr == NonNull::dangling() {
+            return None;
+        }
+        Some(unsafe { self.ptr.as_ref() })
     }
 
     pub fn upgrade(&self) -> Option<Arc<T>> {
+        let data = self.data()?;
         // 強参照が存在することを保証できれば良いため、Relaxedで十分である。
         // Acquireが必要になるのは、他のスレッドのReleaseより後に行われた書き込みを観測したいときである。
-        let mut n = self.data().data_ref_count.load(Ordering::Relaxed);
+        let mut n = data.data_ref_count.load(Ordering::Relaxed);
         loop {
             if n == 0 {
                 return None;
             }
             assert!(n < usize::MAX);
-            if let Err(e) = self.data().data_ref_count.compare_exchange_weak(
+            if let Err(e) = data.data_ref_count.compare_exchange_weak(
                 n,
                 n + 1,
                 Ordering::Relaxed,
@@ -161,20 +326,70 @@ impl<T> Weak<T> {
             return Some(Arc { ptr: self.ptr });
         }
     }
+
+    /// `upgrade`と同様であるが、`compare_exchange_weak`の再試行を最大`UPGRADE_RETRY_LIMIT`回に制限する。
+    ///
+    /// 多数のスレッドが同時に`upgrade`や`Arc`の`clone`と`drop`を行うと、`data_ref_count`が変化し続けるため、
+    /// `upgrade`の`compare_exchange_weak`が何度も失敗し、1回の呼び出しに長い時間がかかる場合がある。
+    /// `upgrade_checked`は、再試行の上限に達した場合も`None`を返すため、1回の呼び出しにかかる時間に上限がある。
+    ///
+    /// そのため、`None`はデータがすでにドロップされたことを意味するとは限らない。
+    /// 呼び出し側は、`None`を一時的なエラーとして扱い、`std::thread::yield_now`などでCPUを譲ってから
+    /// 再度呼び出すか、ドロップされたかどうかを確実に知る必要がある場合は`upgrade`を呼び出すこと。
+    ///
+    /// `ArcData<T>`を再利用するプールを使用する実装では、ドロップされた後に別の値で再利用された制御ブロックを
+    /// 誤って`upgrade`しないように、再利用するたびに増やす世代番号も確認する必要がある。
+    /// この`Arc`は`Arc::new`のたびに`ArcData<T>`を確保し、弱参照が残っている間は解放しないため、
+    /// 制御ブロックが再利用されることはなく、世代番号は必要ない。
+    pub fn upgrade_checked(&self) -> Option<Arc<T>> {
+        let data = self.data()?;
+        let mut n = data.data_ref_count.load(Ordering::Relaxed);
+        for _ in 0..UPGRADE_RETRY_LIMIT {
+            if n == 0 {
+                return None;
+            }
+            assert!(n < usize::MAX);
+            match data.data_ref_count.compare_exchange_weak(
+                n,
+                n + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(Arc { ptr: self.ptr }),
+                Err(e) => n = e,
+            }
+        }
+        None
+    }
 }
 
+/// `Weak::upgrade_checked`が`compare_exchange_weak`を試みる最大の回数
+const UPGRADE_RETRY_LIMIT: u32 = 64;
+
 impl<T> Clone for Weak<T> {
     fn clone(&self) -> Self {
-        if self.data().alloc_ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
+        if let Some(data) = self.data()
+            && data.alloc_ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2
+        {
             std::process::abort();
         }
         Self { ptr: self.ptr }
     }
 }
 
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Drop for Weak<T> {
     fn drop(&mut self) {
-        if self.data().alloc_ref_count.fetch_sub(1, Ordering::Release) == 1 {
+        // `Weak::new`で作成した場合は、解放する制御ブロックがない。
+        let Some(data) = self.data() else {
+            return;
+        };
+        if data.alloc_ref_count.fetch_sub(1, Ordering::Release) == 1 {
             fence(Ordering::Acquire);
             unsafe {
                 drop(Box::from_raw(self.ptr.as_ptr()));
@@ -206,11 +421,62 @@ impl<T> Drop for Arc<T> {
     }
 }
 
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// `std::sync::Arc`と同様に、参照先の値をそのまま出力する。
+impl<T: fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// `std::sync::Arc`と同様に、参照先の値を比較する（割り当てを比較する場合は`Arc::ptr_eq`を使用すること）。
+impl<T: PartialEq> PartialEq for Arc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for Arc<T> {}
+
+/// `PartialEq`と同様に、参照先の値で順序を決める。
+impl<T: PartialOrd> PartialOrd for Arc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: Ord> Ord for Arc<T> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (**self).cmp(&**other)
+    }
+}
+
+/// `Eq`と一致するように、参照先の値からハッシュ値を計算する。
+impl<T: Hash> Hash for Arc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state);
+    }
+}
+
+/// 値がドロップされている可能性があるため、`std::sync::Weak`と同様に`(Weak)`だけを出力する。
+impl<T> fmt::Debug for Weak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(Weak)")
+    }
+}
+
 fn main() {}
 
-#[cfg(test)]
+// テストはloomのモデルの外で実行するため、`--cfg loom`を指定した場合は除外する。
+#[cfg(all(test, not(loom)))]
 mod tests {
     use super::*;
+    use std::sync::atomic::AtomicBool;
 
     #[test]
     fn test() {
@@ -247,4 +513,234 @@ mod tests {
         assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
         assert!(z.upgrade().is_none());
     }
+
+    #[test]
+    fn projection_keeps_parent_alive() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Config {
+            name: String,
+            _detect_drop: DetectDrop,
+        }
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let config = Arc::new(Config {
+            name: String::from("server"),
+            _detect_drop: DetectDrop,
+        });
+        let name = Arc::project(config, |config| &config.name);
+        let text = ProjectedArc::project(name.clone(), |name| name.as_str());
+        assert_eq!(ProjectedArc::parent(&text).name, "server");
+
+        let threads = (0..4)
+            .map(|_| {
+                let name = name.clone();
+                std::thread::spawn(move || assert_eq!(*name, "server"))
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // 元の`Arc`はすでにムーブされているが、射影が残っている間は解放されないはず。
+        drop(name);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        assert_eq!(&*text, "server");
+
+        // 最後の射影をドロップした時点で、1回だけ解放されるはず。
+        drop(text);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn default_and_debug() {
+        let a = Arc::<Vec<i32>>::default();
+        assert!(a.is_empty());
+        assert_eq!(format!("{:?}", Arc::new((1, "a"))), "(1, \"a\")");
+        assert_eq!(format!("{:?}", Arc::downgrade(&a)), "(Weak)");
+    }
+
+    #[test]
+    fn value_equality_and_pointer_equality_differ() {
+        let a = Arc::new(42);
+        let b = Arc::new(42);
+        // 別々に作成した等しい値は、`==`では等しいが、同じ割り当てではないはず。
+        assert_eq!(a, b);
+        assert!(!Arc::ptr_eq(&a, &b));
+        // 複製は、値も割り当ても等しいはず。
+        let c = Arc::clone(&a);
+        assert_eq!(a, c);
+        assert!(Arc::ptr_eq(&a, &c));
+        // 順序も参照先の値で決まるはず。
+        assert!(Arc::new(1) < Arc::new(2));
+        assert_eq!(Arc::new(3).cmp(&Arc::new(3)), CmpOrdering::Equal);
+    }
+
+    #[test]
+    fn arc_string_is_a_hash_map_key_by_value() {
+        use std::collections::HashMap;
+
+        let mut map = HashMap::new();
+        map.insert(Arc::new(String::from("key")), 1);
+        // 別に作成した`Arc`でも、値が等しければ同じキーとして検索できるはず。
+        assert_eq!(map.get(&Arc::new(String::from("key"))), Some(&1));
+        *map.entry(Arc::new(String::from("key"))).or_insert(0) += 1;
+        assert_eq!(map.len(), 1);
+        assert_eq!(map[&Arc::new(String::from("key"))], 2);
+    }
+
+    #[test]
+    fn pinned_self_referential_value_does_not_move() {
+        use std::cell::Cell;
+        use std::marker::PhantomPinned;
+
+        /// `ptr`が自身の`value`を指す、自己参照を持つ値
+        struct SelfReferential {
+            value: u32,
+            ptr: Cell<*const u32>,
+            _pinned: PhantomPinned,
+        }
+
+        let pinned = Arc::pin(SelfReferential {
+            value: 42,
+            ptr: Cell::new(std::ptr::null()),
+            _pinned: PhantomPinned,
+        });
+        let this: Pin<&SelfReferential> = pinned.as_ref();
+        this.ptr.set(&this.value);
+        let address = this.ptr.get();
+
+        // `Pin<Arc<T>>`を複製したり移動したりしても、値のアドレスは変わらないはず。
+        let clones = [pinned.clone(), pinned.clone()];
+        let moved = pinned;
+        for p in clones.iter().chain([&moved]) {
+            assert_eq!(std::ptr::from_ref(&p.value), address);
+            assert_eq!(unsafe { *p.ptr.get() }, 42);
+        }
+
+        // 共有されている`Arc`や、`Weak`が存在する`Arc`はピン留めできないはず。
+        let arc = Arc::new(0);
+        let clone = Arc::clone(&arc);
+        let arc = Arc::into_pin(arc).unwrap_err();
+        drop(clone);
+        let weak = Arc::downgrade(&arc);
+        let arc = Arc::into_pin(arc).unwrap_err();
+        drop(weak);
+        assert_eq!(*Arc::into_pin(arc).unwrap(), 0);
+    }
+
+    #[test]
+    fn weak_new_is_const_and_never_upgrades() {
+        static EMPTY: Weak<String> = Weak::new();
+        // どの`Arc`も指さないため、`upgrade`は常に`None`を返すはず。
+        assert!(EMPTY.upgrade().is_none());
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(EMPTY.clone().upgrade().is_none()));
+        });
+        // 複製やドロップで、存在しない制御ブロックにアクセスしないはず。
+        let weak = Weak::<Vec<i32>>::default();
+        drop(weak.clone());
+        drop(weak);
+    }
+
+    #[test]
+    fn try_downgrade_fails_while_get_mut_is_in_progress() {
+        let arc = Arc::new(0);
+        // `get_mut`が`alloc_ref_count`を`usize::MAX`に設定している期間を、別のスレッドとの受け渡しで再現する。
+        // 一方のスレッドがパニックしても、チャネルが切断されるため、もう一方は待機し続けない。
+        let (request, requested) = std::sync::mpsc::channel();
+        let (respond, responses) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            let arc = &arc;
+            s.spawn(move || {
+                for () in requested {
+                    respond.send(Arc::try_downgrade(arc).is_some()).unwrap();
+                }
+            });
+            arc.data()
+                .alloc_ref_count
+                .store(usize::MAX, Ordering::Relaxed);
+            request.send(()).unwrap();
+            // スピンせずに、すぐに`None`を返すはず。
+            assert!(!responses.recv().unwrap());
+            // `get_mut`が1に戻した後は、弱参照を作成できるはず。
+            arc.data().alloc_ref_count.store(1, Ordering::Release);
+            request.send(()).unwrap();
+            assert!(responses.recv().unwrap());
+            drop(request);
+        });
+        // 別のスレッドで作成した弱参照はドロップされ、弱参照の数は元に戻っているはず。
+        assert_eq!(arc.data().alloc_ref_count.load(Ordering::Relaxed), 1);
+
+        // 実際に`get_mut`と並行して呼び出しても、弱参照の数が合っているはず。
+        let mut unique = arc;
+        let shared = Arc::clone(&unique);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let downgrader = s.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    drop(Arc::try_downgrade(&shared));
+                }
+            });
+            for _ in 0..10_000 {
+                // 強参照が2つあるため`None`を返すが、弱参照がなければ一時的に`usize::MAX`を設定する。
+                assert!(Arc::get_mut(&mut unique).is_none());
+            }
+            stop.store(true, Ordering::Relaxed);
+            downgrader.join().unwrap();
+        });
+        // `get_mut`のループを止めた後は、`usize::MAX`が残っていないため、必ず弱参照を作成できるはず。
+        let weak = Arc::try_downgrade(&shared).unwrap();
+        assert_eq!(*weak.upgrade().unwrap(), 0);
+        drop(weak);
+        drop(shared);
+        assert!(Arc::get_mut(&mut unique).is_some());
+    }
+
+    #[test]
+    fn upgrade_checked_is_bounded_and_keeps_counts() {
+        let arc = Arc::new(0);
+        let weak = Arc::downgrade(&arc);
+        let upgraded = AtomicUsize::new(0);
+        let contended = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        match weak.upgrade_checked() {
+                            Some(arc) => {
+                                upgraded.fetch_add(1, Ordering::Relaxed);
+                                drop(arc);
+                            }
+                            None => {
+                                contended.fetch_add(1, Ordering::Relaxed);
+                                std::thread::yield_now();
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        // 値が生存している間は、上限に達した場合を除いて成功するはず。
+        let upgraded = upgraded.into_inner();
+        assert!(upgraded > 0);
+        assert_eq!(upgraded + contended.into_inner(), 8 * 10_000);
+        // 成功した分だけ強参照が増減し、最後には元の1つだけが残るはず。
+        let mut arc = arc;
+        drop(weak);
+        assert!(Arc::get_mut(&mut arc).is_some());
+
+        // ドロップされた後は、`None`を返すはず。
+        let weak = Arc::downgrade(&arc);
+        drop(arc);
+        assert!(weak.upgrade_checked().is_none());
+        assert!(Weak::<i32>::new().upgrade_checked().is_none());
+    }
 }
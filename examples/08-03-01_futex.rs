@@ -23,6 +23,25 @@ pub fn wake_one(a: &AtomicU32) {
     }
 }
 
+/// aがexpectedと等しい場合、aを待機しているスレッドのうち1つを起こし、残りをtoで待機するように付け替える。
+///
+/// aがexpectedと等しくない場合は何もせずに`false`を返す。
+/// 09-02の条件変数が、`notify_all`で起床させるスレッドを1つに抑えるために使用する。
+pub fn wake_one_and_requeue(a: &AtomicU32, expected: u32, to: &AtomicU32) -> bool {
+    unsafe {
+        // Futexシステムコールを呼び出し、1つを起こして、残り（最大i32::MAX個）をtoに付け替える。
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_CMP_REQUEUE,
+            1,
+            i32::MAX,
+            to as *const AtomicU32,
+            expected,
+        ) >= 0
+    }
+}
+
 fn main() {
     // 待機条件となるアトミック変数
     // 初期値は0で、0の場合はメインスレッドを停止し、0以外になったら起こす。
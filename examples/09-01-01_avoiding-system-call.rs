@@ -24,6 +24,9 @@ pub struct MutexGuard<'a, T> {
 /// したがって、どのようなライフタイムであっても問題ないことを示すために`'_`を使う。
 unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
 
+/// 09-01と同様に、ロックを獲得したスレッドとは別のスレッドで解放できるため、`T: Send`であれば`Send`である。
+unsafe impl<T> Send for MutexGuard<'_, T> where T: Send {}
+
 impl<T> Deref for MutexGuard<'_, T> {
     type Target = T;
 
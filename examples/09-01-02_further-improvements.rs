@@ -1,27 +1,422 @@
 use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::time::Instant;
+use std::ptr::NonNull;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use atomic_wait::{wait, wake_one};
+#[path = "shared/backoff.rs"]
+mod backoff;
+#[path = "shared/cancellation.rs"]
+mod cancellation;
+#[path = "shared/sync.rs"]
+mod sync;
 
-pub struct Mutex<T> {
+use backoff::Backoff;
+use cancellation::CancellationToken;
+use sync::const_fn;
+// `--cfg loom`を指定した場合は、10-21がloomのアトミック型でこのミューテックスを検査する。
+#[cfg(feature = "stats")]
+use sync::AtomicU64;
+#[cfg(all(debug_assertions, not(loom)))]
+use sync::AtomicUsize;
+#[cfg(any(not(target_os = "linux"), loom))]
+use sync::yield_now;
+use sync::{AtomicU32, Ordering, wait, wake_one};
+
+/// 値を保護しない、futexによるロックの状態遷移だけを実装したミューテックス
+///
+/// 条件変数や一度だけの初期化など、値を保護せずにロックだけを必要とするプリミティブの部品として使用する。
+/// `Mutex<T>`も、これに値と統計情報などを加えたものである。
+pub struct RawFutexMutex {
     /// 0: ロックされていない状態
     /// 1: ロックされており、待機中のスレッドがない状態
     /// 2: ロックされており、待機中のスレッドがある状態
+    /// 3: `unlock_fair`により、待機中のスレッドにロックを引き渡している状態
     state: AtomicU32,
+    /// ロックを保持しているスレッドのID（ロックされていない場合は`NO_OWNER`、デバッグビルドのみ）
+    ///
+    /// 同じスレッドが2回ロックしようとしたこと（自己デッドロック）を検出するためだけに使用する。
+    /// リリースビルドではフィールドごと存在しないため、ロックの獲得と解放にアトミック操作は追加されない。
+    /// loomのスレッドはスレッドローカル変数を区別できないため、`--cfg loom`を指定した場合も存在しない。
+    #[cfg(all(debug_assertions, not(loom)))]
+    owner: AtomicUsize,
+}
+
+impl RawFutexMutex {
+    const_fn! {
+        pub fn new() -> Self {
+            Self {
+                state: AtomicU32::new(0), // ロックされていない状態で初期化
+                #[cfg(all(debug_assertions, not(loom)))]
+                owner: AtomicUsize::new(NO_OWNER),
+            }
+        }
+    }
+
+    /// ロックを獲得する。競合した場合は、最大`DEFAULT_SPIN`回スピンしてからfutexで待機する。
+    ///
+    /// ロックを保持しているスレッドが再びロックしようとすると、永久に待機する（デッドロックする）。
+    /// デバッグビルドでは、待機する代わりにパニックする。
+    /// 同じスレッドで再帰的にロックする必要がある場合は、10-25の`ReentrantMutex`を使用すること。
+    pub fn lock(&self) {
+        self.lock_with_spin(DEFAULT_SPIN);
+    }
+
+    /// ロックを獲得する。最初の試行で獲得できた場合は`None`を、競合した場合は待機したかどうかを返す。
+    fn lock_with_spin(&self, spin: u32) -> Option<bool> {
+        if self.try_lock() {
+            return None;
+        }
+        self.assert_not_owner();
+        let waited = lock_contented(&self.state, spin);
+        self.set_owner();
+        Some(waited)
+    }
+
+    /// ロックの現在の状態を返す。
+    ///
+    /// 他のスレッドがすぐに変更する可能性があるため、診断用の参考値としてだけ使用する。
+    /// 値を読み込むだけであり、他のメモリ操作との順序関係は必要ないため、`Relaxed`で読み込む。
+    /// `unlock_fair`でロックを引き渡している状態（state=3）は、引き渡されるスレッドが待機しているため、
+    /// `LockedWithWaiters`として扱う。
+    pub fn lock_state(&self) -> LockState {
+        match self.state.load(Ordering::Relaxed) {
+            0 => LockState::Unlocked,
+            1 => LockState::LockedNoWaiters,
+            _ => LockState::LockedWithWaiters,
+        }
+    }
+
+    /// ロックの獲得を1回だけ試みる。
+    pub fn try_lock(&self) -> bool {
+        let locked = self
+            .state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if locked {
+            self.set_owner();
+        }
+        locked
+    }
+
+    /// 現在のスレッドを、ロックを保持しているスレッドとして記録する（デバッグビルドのみ）。
+    ///
+    /// ロックを獲得した直後に呼び出す。
+    fn set_owner(&self) {
+        #[cfg(all(debug_assertions, not(loom)))]
+        self.owner.store(current_thread_id(), Ordering::Relaxed);
+    }
+
+    /// ロックを保持しているスレッドの記録を消去する（デバッグビルドのみ）。
+    ///
+    /// `ArcMutexGuard`は別のスレッドに送って解放できるため、ロックを獲得したスレッドではなく、解放するスレッドが
+    /// stateを変更する前に呼び出す。
+    fn clear_owner(&self) {
+        #[cfg(all(debug_assertions, not(loom)))]
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+
+    /// 現在のスレッドがロックを保持していないことを確認する（デバッグビルドのみ）。
+    ///
+    /// 最初の試行でロックを獲得できず、待機する前に呼び出す。
+    /// 最初の試行で獲得できた場合は確認する必要がないため、競合しない場合の処理は変わらない。
+    fn assert_not_owner(&self) {
+        #[cfg(all(debug_assertions, not(loom)))]
+        {
+            // `Acquire`で読み込んだstateは、最後にロックを解放したスレッドの`Release`の書き込み（または、それに続く
+            // 読み込み・変更・書き込み操作）であるため、解放する前に行った`clear_owner`が見える。
+            // そのため、`owner`が自分のIDであるのは、自分がロックを獲得し、まだ解放していない場合だけである。
+            self.state.load(Ordering::Acquire);
+            assert_ne!(
+                self.owner.load(Ordering::Relaxed),
+                current_thread_id(),
+                "attempted to lock a Mutex already held by this thread"
+            );
+        }
+    }
+
+    /// ロックを解放し、待機中のスレッドがある場合は1つ起床させる。
+    ///
+    /// デバッグビルドの自己デッドロックの検出は、ロックを獲得したスレッドを記録するため、`MutexGuard`と同様に
+    /// ロックを獲得したスレッドで呼び出すこと。
+    ///
+    /// # Safety
+    ///
+    /// 呼び出し側がロックを保持していなければならない。
+    /// デバッグビルドでは、ロックされていない状態で呼び出すとパニックする。
+    pub unsafe fn unlock(&self) {
+        unsafe { self.release() };
+    }
+
+    /// `unlock`と同じであるが、待機中のスレッドを起床させた場合は`true`を返す。
+    unsafe fn release(&self) -> bool {
+        self.clear_owner();
+        // stateを0（ロックされていない）にセット
+        let previous = self.state.swap(0, Ordering::Release);
+        debug_assert!(
+            matches!(previous, 1 | 2),
+            "unlocked a RawFutexMutex that was not locked"
+        );
+        if previous == 2 {
+            wake_one(&self.state);
+            return true;
+        }
+        false
+    }
+
+    /// ロックを解放し、待機中のスレッドがある場合はロックを引き渡す。
+    /// 待機中のスレッドがいた（stateが2だった）場合は`true`を返す。
+    ///
+    /// 安全性: `unlock`と同じである。
+    unsafe fn release_fair(&self) -> bool {
+        self.clear_owner();
+        let state = &self.state;
+        // ロックを保持している間、stateは1か2であり、2から変更できるのはロックを保持しているスレッドだけである。
+        match state.compare_exchange(1, 0, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return false,
+            Err(previous) => {
+                debug_assert_eq!(previous, 2, "unlocked a RawFutexMutex that was not locked")
+            }
+        }
+        // ロックを引き渡すスレッドは、`Acquire`でstateを3から2に変更して、このストアと同期する。
+        state.store(3, Ordering::Release);
+        if !wake_one_waiter(state) {
+            // 待機中のスレッドがいなかった。
+            // 失敗した場合は、futexで待機する直前だったスレッドが、すでにロックを獲得している。
+            let _ = state.compare_exchange(3, 0, Ordering::Release, Ordering::Relaxed);
+        }
+        true
+    }
+}
+
+/// ロックを保持しているスレッドがないことを表すスレッドID（10-25と同じ）
+#[cfg(all(debug_assertions, not(loom)))]
+const NO_OWNER: usize = 0;
+
+/// スレッドIDを割り当てるカウンター（0は`NO_OWNER`であるため、1から割り当てる）
+#[cfg(all(debug_assertions, not(loom)))]
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[cfg(all(debug_assertions, not(loom)))]
+thread_local! {
+    /// スレッドごとに1回だけ割り当てて、キャッシュしたスレッドID
+    static THREAD_ID: usize = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 現在のスレッドのIDを返す。
+#[cfg(all(debug_assertions, not(loom)))]
+fn current_thread_id() -> usize {
+    THREAD_ID.with(|id| *id)
+}
+
+impl Default for RawFutexMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `RawFutexMutex::lock_state`と`Mutex::lock_state`が返す、ロックの状態のスナップショット
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    /// ロックされていない状態（state=0）
+    Unlocked,
+    /// ロックされており、待機中のスレッドがない状態（state=1）
+    LockedNoWaiters,
+    /// ロックされており、待機中のスレッドがある状態（state=2または3）
+    LockedWithWaiters,
+}
+
+pub struct Mutex<T: ?Sized> {
+    raw: RawFutexMutex,
+    /// 競合の統計情報（`stats`フィーチャーを有効にした場合のみ）
+    ///
+    /// 更新するたびに`raw`と同じキャッシュラインが無効化されないように、別のキャッシュラインに配置する。
+    #[cfg(feature = "stats")]
+    stats: CachePadded<Stats>,
+    /// 競合したときに、futexで待機する前にスピンする最大の回数
+    spin: u32,
+    /// スピンの結果からスピンする回数を調整する場合の推定値（`Mutex::with_adaptive_spin`の場合のみ）
+    adaptive: Option<AdaptiveSpin>,
+    /// 何回の解放ごとに、待機中のスレッドにロックを引き渡すか（0の場合は`unlock_fair`を呼び出した場合だけ）
+    fair_every: u32,
+    /// 前回ロックを引き渡してから解放した回数
+    ///
+    /// ロックを保持しているスレッドだけが更新し、ロックの獲得と解放で同期されるため、`Relaxed`で十分である。
+    releases: AtomicU32,
+    /// 保護している値
+    ///
+    /// `Mutex<[u8]>`や`Mutex<dyn Write + Send>`のように、`T`が動的サイズ型である場合を許すため、最後のフィールドにする。
+    /// 動的サイズ型のミューテックスは直接作成できないが、`Box<Mutex<[u8; N]>>`や`Arc<Mutex<Vec<u8>>>`から
+    /// 型強制（unsizing coercion）で`Box<Mutex<[u8]>>`や`Arc<Mutex<dyn Write + Send>>`に変換できる。
     value: UnsafeCell<T>,
 }
 
-unsafe impl<T> Sync for Mutex<T> where T: Send {}
+/// `Mutex::new`で作成したミューテックスがスピンする最大の回数
+pub const DEFAULT_SPIN: u32 = 100;
 
-pub struct MutexGuard<'a, T> {
+/// `Mutex::with_adaptive_spin`で作成したミューテックスがスピンする最小の回数
+///
+/// 0にすると、スピンが成功しなくなった後に、再び成功するようになったことを検出できないため、少しだけスピンする。
+const MIN_ADAPTIVE_SPIN: u32 = 10;
+/// `Mutex::with_adaptive_spin`で作成したミューテックスがスピンする最大の回数
+const MAX_ADAPTIVE_SPIN: u32 = 1000;
+/// `AdaptiveSpin::success_rate`の固定小数点数で、1.0を表す値
+const SUCCESS_RATE_ONE: u32 = 1024;
+
+/// 競合したときに、待機せずにロックを獲得できた割合の推定値から、スピンする回数を決める。
+///
+/// 推定値は、競合するたびに`rate = rate * 7/8 + (成功した場合は1/8)`で更新する指数移動平均であり、
+/// 直近の8回程度の競合の結果を反映する。
+/// スピンする回数は、推定値に比例して`MIN_ADAPTIVE_SPIN`から`MAX_ADAPTIVE_SPIN`までの間で変化する。
+/// クリティカルセクションが短く、スピンしている間に解放されることが多い場合は長くスピンし、
+/// 長く保持されてスピンが無駄になることが多い場合は、すぐにfutexで待機するようになる。
+struct AdaptiveSpin {
+    /// 待機せずに獲得できた割合（`SUCCESS_RATE_ONE`が1.0）
+    ///
+    /// 推定値であるため、複数のスレッドが同時に更新して一方の更新が失われても問題ない。
+    /// そのため、`fetch_update`を使用せずに`Relaxed`で読み込んで書き込む。
+    success_rate: AtomicU32,
+}
+
+impl AdaptiveSpin {
+    const_fn! {
+        /// `DEFAULT_SPIN`回スピンする推定値から始める。
+        fn new() -> Self {
+            // `spin`で切り捨てても`DEFAULT_SPIN`になるように、切り上げる。
+            let rate = ((DEFAULT_SPIN - MIN_ADAPTIVE_SPIN) * SUCCESS_RATE_ONE)
+                .div_ceil(MAX_ADAPTIVE_SPIN - MIN_ADAPTIVE_SPIN);
+            Self {
+                success_rate: AtomicU32::new(rate),
+            }
+        }
+    }
+
+    /// 現在の推定値から、スピンする最大の回数を返す。
+    fn spin(&self) -> u32 {
+        let rate = self.success_rate.load(Ordering::Relaxed);
+        MIN_ADAPTIVE_SPIN + (MAX_ADAPTIVE_SPIN - MIN_ADAPTIVE_SPIN) * rate / SUCCESS_RATE_ONE
+    }
+
+    /// 競合したときに、futexで待機せずに獲得できたかどうかを推定値に反映する。
+    fn record(&self, waited: bool) {
+        let rate = self.success_rate.load(Ordering::Relaxed);
+        let success = if waited { 0 } else { SUCCESS_RATE_ONE / 8 };
+        self.success_rate
+            .store(rate - rate / 8 + success, Ordering::Relaxed);
+    }
+}
+
+/// 07-02-02と同様に、値を64バイトにアラインして、他のフィールドとキャッシュラインを共有しないようにするラッパー
+#[cfg(feature = "stats")]
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+#[cfg(feature = "stats")]
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// 04-03のスピンロックと同様に、ロックの競合を計測するカウンタ
+///
+/// 統計情報であり、他のメモリ操作との順序関係は必要ないため、すべて`Relaxed`で更新する。
+#[cfg(feature = "stats")]
+struct Stats {
+    fast_path_locks: AtomicU64,
+    contended_locks: AtomicU64,
+    spin_acquisitions: AtomicU64,
+    waiting_locks: AtomicU64,
+    wakes: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl Stats {
+    const_fn! {
+        fn new() -> Self {
+            Self {
+                fast_path_locks: AtomicU64::new(0),
+                contended_locks: AtomicU64::new(0),
+                spin_acquisitions: AtomicU64::new(0),
+                waiting_locks: AtomicU64::new(0),
+                wakes: AtomicU64::new(0),
+            }
+        }
+    }
+
+    /// `RawFutexMutex::lock_with_spin`の結果から、最初の試行で獲得したか、競合した後にスピンで獲得したか、
+    /// 待機してから獲得したかを分けて数える。
+    fn record_lock(&self, contended: Option<bool>) {
+        let Some(waited) = contended else {
+            self.fast_path_locks.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        self.contended_locks.fetch_add(1, Ordering::Relaxed);
+        if waited {
+            self.waiting_locks.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.spin_acquisitions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// ロックを解放したときに、待機中のスレッドを起床させた場合は数える。
+    fn record_release(&self, woke: bool) {
+        if woke {
+            self.wakes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// `Mutex::contention_stats`が返す、統計情報のスナップショット
+///
+/// `lock`を呼び出した回数は`fast_path_locks + contended_locks`であり、
+/// `contended_locks`は`spin_acquisitions + waiting_locks`（と`try_lock_for`で競合した回数）の合計である。
+#[cfg(feature = "stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContentionStats {
+    /// `lock`が最初の試行でロックを獲得した回数
+    pub fast_path_locks: u64,
+    /// `lock`と`try_lock_for`が、最初の試行でロックを獲得できなかった回数
+    pub contended_locks: u64,
+    /// `lock`が競合した後、futexで待機せずにスピンで獲得した回数
+    pub spin_acquisitions: u64,
+    /// `lock`が競合した後、futexで1回以上待機してから獲得した回数
+    pub waiting_locks: u64,
+    /// ロックを解放するときに、待機中のスレッドを起床させた（`wake_one`を呼び出した）回数
+    pub wakes: u64,
+}
+
+unsafe impl<T: ?Sized> Sync for Mutex<T> where T: Send {}
+
+/// ロックを獲得したスレッドで解放しなければならないため、`ReentrantMutexGuard`（10-25）と同様に
+/// `PhantomData<*const ()>`を保持して`Send`を実装しない。
+///
+/// デバッグビルドの自己デッドロックの検出は、ロックを獲得したスレッドを記録して行う。
+/// ガードを別のスレッドに送れると、送ったスレッドが、送った先で解放される前に再びロックしようとしたときに、
+/// 自己デッドロックではないにもかかわらずパニックしてしまう。
+/// 別のスレッドで解放する必要がある場合は、ロックを獲得したスレッドを記録しない`Mutex::lock_arc`を使用する。
+pub struct MutexGuard<'a, T: ?Sized> {
     mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
 }
 
-unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+unsafe impl<T: ?Sized> Sync for MutexGuard<'_, T> where T: Sync {}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// ロックを獲得した後に、ガードを作成する。
+    fn new(mutex: &'a Mutex<T>) -> Self {
+        Self {
+            mutex,
+            _not_send: PhantomData,
+        }
+    }
+}
 
-impl<T> Deref for MutexGuard<'_, T> {
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
@@ -29,61 +424,772 @@ impl<T> Deref for MutexGuard<'_, T> {
     }
 }
 
-impl<T> DerefMut for MutexGuard<'_, T> {
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         unsafe { &mut *self.mutex.value.get() }
     }
 }
 
 impl<T> Mutex<T> {
-    pub const fn new(value: T) -> Self {
-        Self {
-            state: AtomicU32::new(0), // ロックされていない状態で初期化
-            value: UnsafeCell::new(value),
+    const_fn! {
+        pub fn new(value: T) -> Self {
+            Self::with_spin(value, DEFAULT_SPIN)
+        }
+    }
+
+    const_fn! {
+        /// 競合したときに、futexで待機する前に最大`spin`回スピンするミューテックスを作成する。
+        ///
+        /// クリティカルセクションが短い場合は、スピンしている間にロックが解放される可能性が高いため、
+        /// 大きな値にするとシステムコールを避けられる。クリティカルセクションが長い場合や、スレッドの数が
+        /// CPUコアの数を超える場合は、スピンはCPUを浪費するだけであるため、小さな値にする。
+        /// `0`の場合はスピンせず、`Backoff`で`yield_now`してからfutexで待機する。
+        /// 09-01-06のベンチマークを参照すること。
+        pub fn with_spin(value: T, spin: u32) -> Self {
+            Self {
+                raw: RawFutexMutex::new(),
+                #[cfg(feature = "stats")]
+                stats: CachePadded(Stats::new()),
+                spin,
+                adaptive: None,
+                fair_every: 0,
+                releases: AtomicU32::new(0),
+                value: UnsafeCell::new(value),
+            }
+        }
+    }
+
+    const_fn! {
+        /// `every`回の解放ごとに1回、`MutexGuard::unlock_fair`と同様にロックを待機中のスレッドに引き渡す
+        /// ミューテックスを作成する。
+        ///
+        /// 通常の解放は、解放したスレッドがすぐにロックを獲得し直せるため（バージング）、ロックと解放を繰り返す
+        /// スレッドがあると、待機中のスレッドが飢餓状態になる可能性がある。
+        /// 定期的にロックを引き渡すことで、待機中のスレッドが待機する時間に上限を設ける。
+        /// 引き渡すたびに、起床したスレッドが実行されるまでロックを獲得できるスレッドがいなくなるため、
+        /// `every`を小さくするほどスループットは低下する（09-01-07のベンチマークを参照すること）。
+        /// `MutexGuard::map`で変換したガードの解放は、回数に数えない。
+        pub fn with_fairness(value: T, every: u32) -> Self {
+            let mut mutex = Self::with_spin(value, DEFAULT_SPIN);
+            mutex.fair_every = every;
+            mutex
+        }
+    }
+
+    const_fn! {
+        /// 直近の競合でスピンが成功したかどうかから、スピンする回数を調整するミューテックスを作成する。
+        ///
+        /// `with_spin`の最適な値は、クリティカルセクションの長さによって変わる。
+        /// 処理の段階によってクリティカルセクションの長さが変わる場合は、固定の値では、短い段階ではスピンが
+        /// 足りずに待機し、長い段階では無駄にスピンする。
+        /// このミューテックスは、競合するたびに待機せずに獲得できたかどうかを記録し、その割合に応じて
+        /// `MIN_ADAPTIVE_SPIN`（10）から`MAX_ADAPTIVE_SPIN`（1000）回の間でスピンする回数を変える。
+        /// 競合しない場合の処理は`new`と同じであり、推定値を読み書きするのは競合した場合だけである。
+        /// 09-01-09のベンチマークを参照すること。
+        pub fn with_adaptive_spin(value: T) -> Self {
+            let mut mutex = Self::with_spin(value, DEFAULT_SPIN);
+            mutex.adaptive = Some(AdaptiveSpin::new());
+            mutex
         }
     }
 
+    /// ミューテックスを消費して、保護している値を返す。
+    ///
+    /// 所有権を持っている場合は、他に参照が存在しないため、ロックを獲得する必要はない。
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    /// ロックを獲得して値を`value`に置き換え、ロックを解放してから元の値を返す。
+    pub fn replace(&self, value: T) -> T {
+        std::mem::replace(&mut *self.lock(), value)
+    }
+
+    /// ロックを獲得して値を`T::default()`に置き換え、ロックを解放してから元の値を返す。
+    pub fn take(&self) -> T
+    where
+        T: Default,
+    {
+        self.replace(T::default())
+    }
+}
+
+/// 値を移動しない操作は、`T`が動的サイズ型であっても使用できる。
+impl<T: ?Sized> Mutex<T> {
+    /// ロックを獲得する。
+    ///
+    /// `RawFutexMutex::lock`と同様に、デバッグビルドでは、ロックを保持しているスレッドが再びロックしようとすると
+    /// パニックする。
     pub fn lock(&self) -> MutexGuard<'_, T> {
-        if self
-            .state
-            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            lock_contented(&self.state);
+        #[cfg(feature = "stats")]
+        self.stats.record_lock(self.acquire());
+        #[cfg(not(feature = "stats"))]
+        self.acquire();
+        MutexGuard::new(self)
+    }
+
+    /// ロックを獲得する。戻り値は`RawFutexMutex::lock_with_spin`と同じである。
+    fn acquire(&self) -> Option<bool> {
+        let Some(adaptive) = &self.adaptive else {
+            return self.raw.lock_with_spin(self.spin);
+        };
+        if self.raw.try_lock() {
+            return None;
+        }
+        self.raw.assert_not_owner();
+        let waited = lock_contented(&self.raw.state, adaptive.spin());
+        self.raw.set_owner();
+        adaptive.record(waited);
+        Some(waited)
+    }
+
+    /// 競合したときにスピンする最大の回数を返す。
+    ///
+    /// `with_adaptive_spin`で作成した場合は、現在の推定値から決めた回数であり、競合するたびに変化する。
+    pub fn spin(&self) -> u32 {
+        match &self.adaptive {
+            Some(adaptive) => adaptive.spin(),
+            None => self.spin,
+        }
+    }
+
+    /// `Arc`で共有しているミューテックスのロックを獲得し、`Arc`を保持するガードを返す。
+    ///
+    /// 04-03のスピンロックの`lock_owned`と同様に、ガードはミューテックスを借用しないため、
+    /// コールバックに渡したり、別のスレッドに送ってそのスレッドで解放したりできる。
+    ///
+    /// 別のスレッドに送ることを前提としたガードであるため、ロックを保持しているスレッドを記録しない。
+    /// そのため、このガードを保持している間は、デバッグビルドの自己デッドロックの検出は行われない。
+    pub fn lock_arc(self: &Arc<Self>) -> ArcMutexGuard<T> {
+        // ロックの解放は`ArcMutexGuard`に引き継ぐため、借用するガードの`Drop`を実行しない。
+        std::mem::forget(self.lock());
+        // ガードを送ったスレッドが再びロックしようとしたときに、自己デッドロックと誤検出しないようにする。
+        self.raw.clear_owner();
+        ArcMutexGuard {
+            mutex: Arc::clone(self),
+        }
+    }
+
+    /// ロックの獲得を1回だけ試み、獲得できた場合は`Arc`を保持するガードを返す。
+    ///
+    /// `lock_arc`と同様に、ロックを保持しているスレッドを記録しない。
+    pub fn try_lock_arc(self: &Arc<Self>) -> Option<ArcMutexGuard<T>> {
+        let guard = self.try_lock()?;
+        std::mem::forget(guard);
+        self.raw.clear_owner();
+        Some(ArcMutexGuard {
+            mutex: Arc::clone(self),
+        })
+    }
+
+    /// ロックを解放し、待機中のスレッドがある場合は1つ起床させる。
+    ///
+    /// 安全性: 呼び出し側がロックを保持しており、以降は値にアクセスしてはならない。
+    unsafe fn unlock(&self) {
+        if self.fair_every != 0 {
+            let releases = self.releases.load(Ordering::Relaxed) + 1;
+            if releases >= self.fair_every {
+                self.releases.store(0, Ordering::Relaxed);
+                unsafe { self.unlock_fair() };
+                return;
+            }
+            self.releases.store(releases, Ordering::Relaxed);
+        }
+        #[cfg(feature = "stats")]
+        self.stats.record_release(unsafe { self.raw.release() });
+        #[cfg(not(feature = "stats"))]
+        unsafe {
+            self.raw.release()
+        };
+    }
+
+    /// ロックを獲得せずに、保護している値へのポインタを返す。
+    ///
+    /// ポインタを参照外しする場合は、ロックを保持しているスレッドの可変参照と共存しないことを、
+    /// 呼び出し側が保証しなければならない。
+    /// 09-01-04の`OnceLock`は、初期化した後は値を変更しないことで、ロックを獲得せずに値を参照する。
+    pub fn data_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    /// 可変参照から、ロックを獲得せずに値への可変参照を返す。
+    ///
+    /// `&mut self`により他に参照が存在しないことが保証されるため、`state`を操作せず、
+    /// futexで待機したり、他のスレッドを起床させたりしない。
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// ロックの獲得を1回だけ試みる。
+    ///
+    /// ロックが他のスレッドに保持されている場合は、待機せずに`None`を返す。
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        // `then_some`はガードを先に作成し、失敗した場合にドロップして解放してしまうため、分岐する。
+        if self.raw.try_lock() {
+            Some(MutexGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    /// `timeout`が経過するまで、ロックの獲得を試みる。
+    ///
+    /// 期限までにロックを獲得できなかった場合は`None`を返す。
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            // 期限を表現できないほど長い場合は、期限なしで待機する。
+            None => Some(self.lock()),
+        }
+    }
+
+    /// `deadline`まで、ロックの獲得を試みる。
+    ///
+    /// 期限までにロックを獲得できなかった場合は`None`を返す。
+    pub fn try_lock_until(&self, deadline: Instant) -> Option<MutexGuard<'_, T>> {
+        if !self.raw.try_lock() {
+            #[cfg(feature = "stats")]
+            self.stats.contended_locks.fetch_add(1, Ordering::Relaxed);
+            if !lock_contented_until(&self.raw.state, self.spin(), deadline) {
+                return None;
+            }
+            self.raw.set_owner();
+        }
+        Some(MutexGuard::new(self))
+    }
+
+    /// `token`がキャンセルされるまで、ロックの獲得を試みる。
+    ///
+    /// ロックを獲得する前に`token`がキャンセルされた場合は`Err(Cancelled)`を返す。
+    /// 呼び出した時点ですでにキャンセルされている場合は、ロックが解放されていても獲得しない。
+    ///
+    /// futexで待機する前に、stateのアドレスを`token`に登録する。
+    /// `CancellationToken::cancel`は、登録されたアドレスで待機しているスレッドを起床させ、起床したスレッドは
+    /// `token`がキャンセルされたかどうかで、ロックの解放による起床と区別する。
+    pub fn lock_cancellable(
+        &self,
+        token: &CancellationToken,
+    ) -> Result<MutexGuard<'_, T>, Cancelled> {
+        if token.is_cancelled() {
+            return Err(Cancelled);
+        }
+        if !self.raw.try_lock() {
+            self.raw.assert_not_owner();
+            #[cfg(feature = "stats")]
+            self.stats.contended_locks.fetch_add(1, Ordering::Relaxed);
+            if !spin_then_try_lock(&self.raw.state, self.spin()) {
+                token.register(&self.raw.state);
+                let locked = lock_contented_cancellable(&self.raw.state, token);
+                token.unregister(&self.raw.state);
+                if !locked {
+                    return Err(Cancelled);
+                }
+            }
+            self.raw.set_owner();
+        }
+        Ok(MutexGuard::new(self))
+    }
+
+    /// ロックの状態を表すfutexのワード（`RawFutexMutex`のstate）を返す。
+    ///
+    /// 09-02の条件変数が、`notify_all`で待機しているスレッドを付け替える（requeue）先として使用する。
+    /// 値を変更するとロックが正しく動作しなくなるため、futexの操作だけに使用すること。
+    pub fn futex(&self) -> &AtomicU32 {
+        &self.raw.state
+    }
+
+    /// 条件変数で待機した後に、ロックを獲得し直す。ミューテックスのfutexで待機した場合は`true`を返す。
+    ///
+    /// 09-02の`Condvar::notify_all`は、1つ以外のスレッドをこのミューテックスのstateで待機するように付け替える。
+    /// 付け替えられたスレッドは、ロックの解放（`unlock_fair`による引き渡しを含む）で1つずつ起床するため、
+    /// 条件変数から戻ったスレッドは、最初から待機したスレッドとして`try_lock_contented`でロックを獲得する。
+    ///
+    /// - stateを常に2にして獲得するため、解放するときに、付け替えられた次のスレッドを起床させる。
+    ///   stateを1にして獲得すると、付け替えられたスレッドは誰にも起床されない。
+    /// - stateが3（引き渡し中）でも獲得できるため、`unlock_fair`で起床した付け替えられたスレッドが、
+    ///   引き渡されたロックを獲得できずに待機し続けることはない。
+    ///
+    /// 付け替えられたかどうかは区別できないため、付け替えられずに起床したスレッドも引き渡されたロックを獲得できる。
+    /// その場合、引き渡されるはずだったスレッドは再び待機するだけであり、ロックの解放で起床する。
+    pub fn relock_after_wait(&self) -> (MutexGuard<'_, T>, bool) {
+        self.raw.assert_not_owner();
+        let mut waited = false;
+        while let Err(current) = try_lock_contented(&self.raw.state, true) {
+            wait(&self.raw.state, current);
+            waited = true;
+        }
+        self.raw.set_owner();
+        #[cfg(feature = "stats")]
+        self.stats.record_lock(waited.then_some(true));
+        (MutexGuard::new(self), waited)
+    }
+
+    /// ロックの現在の状態を返す。
+    ///
+    /// `RawFutexMutex::lock_state`と同じく、読み込んだ直後に変化している可能性がある参考値である。
+    pub fn lock_state(&self) -> LockState {
+        self.raw.lock_state()
+    }
+
+    /// いずれかのスレッドがロックを保持しているかどうかを返す（`lock_state`と同じく参考値である）。
+    pub fn is_locked(&self) -> bool {
+        self.lock_state() != LockState::Unlocked
+    }
+
+    /// ロックの獲得を待機しているスレッドがあるかどうかを返す（`lock_state`と同じく参考値である）。
+    ///
+    /// 待機しているスレッドが`lock_contented`でスピンしている間は、まだstateを2にしていないため`false`を返す。
+    pub fn has_waiters(&self) -> bool {
+        self.lock_state() == LockState::LockedWithWaiters
+    }
+
+    /// 最初の試行でロックを獲得できなかった回数を返す（`stats`フィーチャーを有効にした場合のみ）。
+    ///
+    /// 統計情報であり、他のメモリ操作との順序関係は必要ないため、`Relaxed`で読み込む。
+    /// `contention_stats().contended_locks`と同じ値である。
+    #[cfg(feature = "stats")]
+    pub fn contention_count(&self) -> u64 {
+        self.stats.contended_locks.load(Ordering::Relaxed)
+    }
+
+    /// 最初の試行でロックを獲得できなかった回数を0に戻す（`stats`フィーチャーを有効にした場合のみ）。
+    #[cfg(feature = "stats")]
+    pub fn reset_contention_count(&self) {
+        self.stats.contended_locks.store(0, Ordering::Relaxed);
+    }
+
+    /// 競合の統計情報のスナップショットを返す（`stats`フィーチャーを有効にした場合のみ）。
+    ///
+    /// それぞれのカウンタを別々に読み込むため、他のスレッドがロックしている間は、カウンタ同士の関係が
+    /// 一時的に成り立たない場合がある。
+    #[cfg(feature = "stats")]
+    pub fn contention_stats(&self) -> ContentionStats {
+        ContentionStats {
+            fast_path_locks: self.stats.fast_path_locks.load(Ordering::Relaxed),
+            contended_locks: self.stats.contended_locks.load(Ordering::Relaxed),
+            spin_acquisitions: self.stats.spin_acquisitions.load(Ordering::Relaxed),
+            waiting_locks: self.stats.waiting_locks.load(Ordering::Relaxed),
+            wakes: self.stats.wakes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 競合の統計情報を0に戻す（`stats`フィーチャーを有効にした場合のみ）。
+    #[cfg(feature = "stats")]
+    pub fn reset_contention_stats(&self) {
+        self.stats.fast_path_locks.store(0, Ordering::Relaxed);
+        self.stats.contended_locks.store(0, Ordering::Relaxed);
+        self.stats.spin_acquisitions.store(0, Ordering::Relaxed);
+        self.stats.waiting_locks.store(0, Ordering::Relaxed);
+        self.stats.wakes.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// 04-03のスピンロックと同様に、ロックの獲得を待機しない。
+/// ロックを獲得できた場合は値を出力し、他のスレッドがロックを保持している場合は`<locked>`と、
+/// 待機中のスレッドがあるかどうか（`waiters`）を出力する。
+///
+/// 値を読み込むために獲得したロックは、`unlock`ではなく`RawFutexMutex::release`で直接解放するため、
+/// 公平モードの解放回数や統計情報は変化しない。
+/// 獲得できなかった場合は、失敗した`compare_exchange`と`Relaxed`の読み込みだけを行うため、
+/// stateは変更されず、待機中のスレッドが起床されることもない。
+impl<T: fmt::Debug + ?Sized> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        if self.raw.try_lock() {
+            let value = unsafe { &*self.value.get() };
+            d.field("value", &value);
+            // 安全性: 直前に`try_lock`でロックを獲得しており、以降は値にアクセスしない。
+            unsafe { self.raw.release() };
+        } else {
+            d.field("value", &format_args!("<locked>"));
+            // 読み込むまでの間に解放された場合（state=0）は、待機中のスレッドはないものとして出力する。
+            d.field("waiters", &self.has_waiters());
+        }
+        d.finish()
+    }
+}
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// ガードがロックを保持しているミューテックスを返す。
+    ///
+    /// `ArcMutexGuard::mutex`と同様に、`T`のメソッドと衝突しないように関連関数にする。
+    pub fn mutex(guard: &Self) -> &'a Mutex<T> {
+        guard.mutex
+    }
+
+    /// ロックを解放する。
+    ///
+    /// `drop(guard)`と同じ動作であるが、ロックを解放する箇所を明示するために使用する。
+    /// ガードを消費して`Drop`に解放を委ねるため、ロックが2回解放されることはない。
+    pub fn unlock(guard: Self) {
+        drop(guard);
+    }
+
+    /// ロックを待機中のスレッドに直接引き渡して解放する。
+    ///
+    /// 通常の解放では、stateを0にしてから待機中のスレッドを起床させるため、起床したスレッドがロックを獲得する前に、
+    /// 解放したスレッド自身や新しく`lock`を呼び出したスレッドがロックを獲得できる。
+    /// ロックと解放を繰り返すスレッドがあると、待機中のスレッドはいつまでもロックを獲得できない可能性がある。
+    ///
+    /// `unlock_fair`は、待機中のスレッドがある場合、stateを0にせずに3（引き渡し中）にしてから1つのスレッドを起床させる。
+    /// stateが3の間は、待機したことがあるスレッドだけがロックを獲得でき、新しく`lock`を呼び出したスレッドは待機する。
+    /// 起床させるスレッドがいなかった場合は、通常どおりstateを0にする。
+    pub fn unlock_fair(guard: Self) {
+        let mutex = guard.mutex;
+        std::mem::forget(guard);
+        unsafe { mutex.unlock_fair() };
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// ロックを解放し、待機中のスレッドがある場合はロックを引き渡す。
+    ///
+    /// 安全性: `unlock`と同じである。
+    unsafe fn unlock_fair(&self) {
+        #[cfg(feature = "stats")]
+        self.stats
+            .record_release(unsafe { self.raw.release_fair() });
+        #[cfg(not(feature = "stats"))]
+        unsafe {
+            self.raw.release_fair()
+        };
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// ロックを保持したまま、保護している値の一部（フィールドなど）だけを公開するガードに変換する。
+    ///
+    /// 04-03のスピンロックの`Guard::map`と同様に、`T`のメソッドと衝突しないように関連関数としている。
+    pub fn map<U: ?Sized>(
+        mut guard: Self,
+        f: impl FnOnce(&mut T) -> &mut U,
+    ) -> MappedMutexGuard<'a, U> {
+        let value = NonNull::from(f(&mut *guard));
+        let mutex = guard.mutex;
+        // ロックの解放は`MappedMutexGuard`に引き継ぐため、元のガードの`Drop`を実行しない。
+        std::mem::forget(guard);
+        MappedMutexGuard {
+            raw: &mutex.raw,
+            #[cfg(feature = "stats")]
+            stats: &mutex.stats,
+            value,
+            _marker: PhantomData,
         }
-        MutexGuard { mutex: self }
+    }
+
+    /// `f`が`Some`を返した場合は`map`と同様に変換し、`None`を返した場合は元のガードを返す。
+    pub fn try_map<U: ?Sized>(
+        mut guard: Self,
+        f: impl FnOnce(&mut T) -> Option<&mut U>,
+    ) -> Result<MappedMutexGuard<'a, U>, Self> {
+        let Some(value) = f(&mut *guard).map(NonNull::from) else {
+            return Err(guard);
+        };
+        let mutex = guard.mutex;
+        std::mem::forget(guard);
+        Ok(MappedMutexGuard {
+            raw: &mutex.raw,
+            #[cfg(feature = "stats")]
+            stats: &mutex.stats,
+            value,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// 保護している値の一部のみを公開するガード
+///
+/// `MutexGuard::map`または`MutexGuard::try_map`からのみ作成でき、ドロップされたときに`MutexGuard`と同じ方法で
+/// ロックを解放する。値の型が変わるため、`Mutex<T>`の代わりに`RawFutexMutex`への参照を保持する。
+pub struct MappedMutexGuard<'a, U: ?Sized> {
+    raw: &'a RawFutexMutex,
+    #[cfg(feature = "stats")]
+    stats: &'a Stats,
+    value: NonNull<U>,
+    /// 元の`MutexGuard`と同様に、ロックを獲得したスレッドで解放しなければならないため、`Send`を実装しない。
+    _marker: PhantomData<(&'a mut U, *const ())>,
+}
+
+unsafe impl<U: ?Sized> Sync for MappedMutexGuard<'_, U> where U: Sync {}
+
+impl<U: ?Sized> Deref for MappedMutexGuard<'_, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        // 安全性: ロックを保持しているため、`value`に他のスレッドはアクセスできない。
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedMutexGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { self.value.as_mut() }
     }
 }
 
-fn lock_contented(state: &AtomicU32) {
-    // ロックが取得されており、待機しているスレッドがない場合（state=1）はスピンロック
-    let mut spin_count = 0;
-    while state.load(Ordering::Relaxed) == 1 && spin_count < 100 {
-        spin_count += 1;
-        std::hint::spin_loop();
+impl<U: fmt::Debug + ?Sized> fmt::Debug for MappedMutexGuard<'_, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
     }
+}
 
-    if state
+impl<U: ?Sized> Drop for MappedMutexGuard<'_, U> {
+    fn drop(&mut self) {
+        #[cfg(feature = "stats")]
+        self.stats.record_release(unsafe { self.raw.release() });
+        #[cfg(not(feature = "stats"))]
+        unsafe {
+            self.raw.release()
+        };
+    }
+}
+
+/// ロックが取得されており、待機しているスレッドがない場合（state=1）は、04-03のスピンロックと同じ`Backoff`で
+/// 待機してから、ロックの獲得を1回試みる。
+///
+/// `Backoff`は合計`spin`回までスピンした後、`yield_now`する。
+/// `Backoff::completed`が`true`を返すまでに獲得できなかった場合は、呼び出し側がfutexで待機する。
+fn spin_then_try_lock(state: &AtomicU32, spin: u32) -> bool {
+    let mut backoff = Backoff::with_spin_limit(spin);
+    while state.load(Ordering::Relaxed) == 1 && !backoff.completed() {
+        backoff.snooze();
+    }
+
+    state
         .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
         .is_ok()
-    {
+}
+
+/// 競合したときにロックを獲得する。futexで1回以上待機した場合は`true`を返す。
+fn lock_contented(state: &AtomicU32, spin: u32) -> bool {
+    if spin_then_try_lock(state, spin) {
         // ロックを獲得できた。
-        return;
+        return false;
     }
 
-    while state.swap(2, Ordering::Acquire) != 0 {
-        wait(state, 2);
+    let mut waited = false;
+    while let Err(current) = try_lock_contented(state, waited) {
+        wait(state, current);
+        waited = true;
     }
+    waited
 }
-impl<T> Drop for MutexGuard<'_, T> {
-    fn drop(&mut self) {
-        // stateを0（ロックされていない）にセット
-        if self.mutex.state.swap(0, Ordering::Release) == 2 {
-            wake_one(&self.mutex.state);
+
+/// `lock_contented`と同様であるが、`deadline`を過ぎた場合は待機をやめて`false`を返す。
+fn lock_contented_until(state: &AtomicU32, spin: u32, deadline: Instant) -> bool {
+    if spin_then_try_lock(state, spin) {
+        return true;
+    }
+
+    let mut waited = false;
+    while let Err(current) = try_lock_contented(state, waited) {
+        // 偽の起床（spurious wakeup）や、他のスレッドにロックを奪われた場合に備えて、
+        // 待機するたびに残り時間を計算し直す。
+        let now = Instant::now();
+        if now >= deadline {
+            // stateは2のままになるが、次に解放するスレッドが不要なwake_oneを呼び出すだけである。
+            return false;
+        }
+        wait_timeout(state, current, deadline - now);
+        waited = true;
+    }
+    true
+}
+
+/// `lock_contented`の待機する部分と同様であるが、`token`がキャンセルされた場合は待機をやめて`false`を返す。
+///
+/// 起床したときは、キャンセルを確認する前にロックの獲得を試みる。
+/// `unlock_fair`でロックを引き渡された（stateが3の）スレッドが獲得せずに戻ると、stateが3のまま残り、
+/// 新しく`lock`を呼び出したスレッドが獲得できなくなるためである。
+fn lock_contented_cancellable(state: &AtomicU32, token: &CancellationToken) -> bool {
+    let mut waited = false;
+    while let Err(current) = try_lock_contented(state, waited) {
+        if token.is_cancelled() {
+            // `lock_contented_until`と同様に、stateは2のままになるが、不要な`wake_one`が呼び出されるだけである。
+            return false;
         }
+        wait(state, current);
+        waited = true;
     }
+    true
 }
 
+/// 待機中のスレッドがあることを記録しながら、ロックの獲得を試みる。
+///
+/// ロックを獲得した場合は、他に待機中のスレッドがいるかもしれないため、stateを2にする。
+/// 獲得できなかった場合は、futexで待機するときに期待するstateの値を返す。
+///
+/// stateが3（引き渡し中）の場合は、一度でも待機した（`waited`が`true`の）スレッドだけがロックを獲得できる。
+/// `unlock_fair`が起床させたスレッドは必ず待機していたため、新しく`lock`を呼び出したスレッドに横取りされない。
+fn try_lock_contented(state: &AtomicU32, waited: bool) -> Result<(), u32> {
+    loop {
+        match state.load(Ordering::Relaxed) {
+            0 => {
+                if state
+                    .compare_exchange(0, 2, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Ok(());
+                }
+            }
+            1 => {
+                if state
+                    .compare_exchange(1, 2, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Err(2);
+                }
+            }
+            3 if waited => {
+                if state
+                    .compare_exchange(3, 2, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return Ok(());
+                }
+            }
+            current => return Err(current),
+        }
+    }
+}
+
+/// 1つのスレッドを起床させ、起床させたスレッドがいたかどうかを返す。
+///
+/// `FUTEX_WAKE`は、起床させたスレッドの数を返す。
+/// `atomic_wait::wait`で待機しているスレッドを起床させるため、同じく`FUTEX_PRIVATE_FLAG`を指定する。
+#[cfg(all(target_os = "linux", not(loom)))]
+fn wake_one_waiter(a: &AtomicU32) -> bool {
+    let woken = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+        )
+    };
+    woken > 0
+}
+
+/// futexがないOS（とloom）では、起床させたスレッドの数がわからないため、起床させたスレッドがいなかったものとして扱う。
+/// この場合、`unlock_fair`は通常の解放と同じ動作になる。
+#[cfg(any(not(target_os = "linux"), loom))]
+fn wake_one_waiter(a: &AtomicU32) -> bool {
+    wake_one(a);
+    false
+}
+
+/// 08-03-01のfutexのラッパーに、タイムアウトを追加した関数である。
+///
+/// `a`が`expected`と等しい場合、起床されるか`timeout`が経過するまで待機する。
+/// `FUTEX_WAIT`のタイムアウトは、絶対時刻ではなく相対時間である。
+/// ロックを解放するスレッドは`atomic_wait::wake_one`で起床させるため、`atomic_wait`と同じく
+/// `FUTEX_PRIVATE_FLAG`を指定する（指定しないと起床されず、タイムアウトするまで待機し続ける）。
+/// 09-02の条件変数も、通知カウンターで期限付きで待機するために使用する。
+#[cfg(all(target_os = "linux", not(loom)))]
+pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: timeout.subsec_nanos() as _,
+    };
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            expected,
+            &timeout as *const libc::timespec,
+        );
+    }
+}
+
+/// futexがないOS（とloom）では、CPUを譲ってすぐに戻る（偽の起床として扱われる）。
+#[cfg(any(not(target_os = "linux"), loom))]
+pub fn wait_timeout(a: &AtomicU32, expected: u32, _timeout: Duration) {
+    if a.load(Ordering::Relaxed) == expected {
+        yield_now();
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.mutex.unlock() };
+    }
+}
+
+/// `Arc`を保持することで、ミューテックスを借用しないガード
+///
+/// `Mutex::lock_arc`または`Mutex::try_lock_arc`からのみ作成でき、ドロップされたときに`MutexGuard`と同じ方法で
+/// ロックを解放する。
+/// `T: Send`であれば`Send`であり、ロックを獲得したスレッドとは別のスレッドで解放できる。
+/// futexによるロックは、スレッドの所有権を持たないためである。
+pub struct ArcMutexGuard<T: ?Sized> {
+    mutex: Arc<Mutex<T>>,
+}
+
+/// `Send`は`Arc<Mutex<T>>`から自動的に実装される（`T: Send`）が、`Sync`は`&T`を共有するため`T: Sync`を要求する。
+unsafe impl<T: ?Sized> Sync for ArcMutexGuard<T> where T: Sync {}
+
+impl<T: ?Sized> ArcMutexGuard<T> {
+    /// ガードが保持している`Arc`への参照を返す。
+    pub fn mutex(guard: &Self) -> &Arc<Mutex<T>> {
+        &guard.mutex
+    }
+}
+
+impl<T: ?Sized> Deref for ArcMutexGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for ArcMutexGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: fmt::Debug + ?Sized> fmt::Debug for ArcMutexGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> Drop for ArcMutexGuard<T> {
+    fn drop(&mut self) {
+        // ロックを解放した後に、フィールドの`Arc`がドロップされる。
+        // 最後の`Arc`であれば、ミューテックスと値もここで解放される。
+        unsafe { self.mutex.unlock() };
+    }
+}
+
+/// `Mutex::lock_cancellable`が返すエラー
+///
+/// ロックを獲得する前に`CancellationToken`がキャンセルされたことを示す。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "lock attempt was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
 /*
 /// シングルスレッド
 fn main() {
@@ -115,3 +1221,918 @@ fn main() {
     let duration = start.elapsed();
     println!("locked {} times in {:?}", *m.lock(), duration);
 }
+
+// テストはloomのモデルの外で実行するため、`--cfg loom`を指定した場合は除外する。
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn raw_futex_mutex_excludes_other_threads() {
+        let raw = RawFutexMutex::new();
+        let counter = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        raw.lock();
+                        // アトミックでない読み込みと書き込みでも、ロックで排他されていれば失われないはず。
+                        let value = counter.load(Ordering::Relaxed);
+                        counter.store(value + 1, Ordering::Relaxed);
+                        unsafe { raw.unlock() };
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.into_inner(), 40_000);
+        assert_eq!(raw.state.into_inner(), 0);
+    }
+
+    #[test]
+    fn raw_futex_mutex_try_lock_fails_while_locked() {
+        let raw = RawFutexMutex::new();
+        assert!(raw.try_lock());
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(!raw.try_lock()));
+        });
+        unsafe { raw.unlock() };
+        assert!(raw.try_lock());
+        unsafe { raw.unlock() };
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not locked")]
+    fn raw_futex_mutex_unlock_without_lock_panics_in_debug() {
+        let raw = RawFutexMutex::new();
+        // ロックを保持せずに解放すると、デバッグビルドではパニックするはず。
+        unsafe { raw.unlock() };
+    }
+
+    #[test]
+    fn unlock_releases_lock_immediately() {
+        let m = Mutex::new(0);
+        let mut guard = m.lock();
+        *guard += 1;
+        MutexGuard::unlock(guard);
+
+        // 解放した直後は、ロックされていない状態のはず。
+        assert_eq!(m.raw.state.load(Ordering::Relaxed), 0);
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(*m.lock(), 1));
+        });
+        assert_eq!(m.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn unlock_wakes_waiting_thread() {
+        let m = Mutex::new(0);
+        let guard = m.lock();
+        std::thread::scope(|s| {
+            let t = s.spawn(|| *m.lock() += 1);
+            // 待機中のスレッドがいる状態（state=2）になるまで待つ。
+            while m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::yield_now();
+            }
+            MutexGuard::unlock(guard);
+            t.join().unwrap();
+        });
+        assert_eq!(*m.lock(), 1);
+    }
+
+    /// ロックを保持したまま、もう一方のスレッドが待機するまで待ち、解放した直後に`try_lock`する。
+    /// 解放したスレッドと待機していたスレッドがロックを獲得した順序を返す。
+    fn release_and_try_lock_again(fair: bool) -> Vec<&'static str> {
+        let m = Mutex::new(Vec::new());
+        let guard = m.lock();
+        std::thread::scope(|s| {
+            s.spawn(|| m.lock().push("waiter"));
+            // 待機するスレッドも`Backoff`で`yield_now`するため、ここで`yield_now`を繰り返すと、スケジューラによっては
+            // このスレッドの実行が後回しにされ、解放したときに起床したスレッドに先を越される。
+            // そのため、`sleep`でstateを確認する。
+            while m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            // stateが2になってからfutexで待機するまでの間に解放すると、起床させるスレッドがいないため、
+            // 少し待ってから解放する。
+            std::thread::sleep(Duration::from_millis(10));
+            if fair {
+                MutexGuard::unlock_fair(guard);
+            } else {
+                MutexGuard::unlock(guard);
+            }
+            match m.try_lock() {
+                Some(mut guard) => guard.push("releaser"),
+                // 獲得できなかった場合は、待機していたスレッドがロックを獲得しているため、その後に獲得する。
+                None => m.lock().push("releaser"),
+            }
+        });
+        m.into_inner()
+    }
+
+    #[test]
+    fn unlock_fair_hands_lock_to_waiting_thread() {
+        // ロックは待機中のスレッドに引き渡されるため、解放したスレッドはすぐには獲得できないはず。
+        for _ in 0..10 {
+            assert_eq!(release_and_try_lock_again(true), ["waiter", "releaser"]);
+        }
+        // 通常の解放では、起床したスレッドより先に、解放したスレッドがロックを獲得できるはず。
+        // 起床したスレッドが先に獲得する場合もあるため、何回か試す。
+        assert!(
+            (0..10)
+                .map(|_| release_and_try_lock_again(false))
+                .any(|order| order == ["releaser", "waiter"])
+        );
+    }
+
+    #[test]
+    fn unlock_fair_lets_other_thread_acquire_during_tight_loop() {
+        const ACQUISITIONS: u32 = 100;
+        let m = Mutex::new(0_u32);
+        let looped = AtomicU32::new(0);
+        let done = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            // ロックと解放を繰り返すスレッド
+            s.spawn(|| {
+                while done.load(Ordering::Relaxed) == 0 {
+                    let guard = m.lock();
+                    looped.fetch_add(1, Ordering::Relaxed);
+                    MutexGuard::unlock_fair(guard);
+                }
+            });
+            while looped.load(Ordering::Relaxed) == 0 {
+                std::thread::yield_now();
+            }
+            for _ in 0..ACQUISITIONS {
+                *m.lock() += 1;
+            }
+            // 待機中のスレッドにロックを引き渡すため、ループしているスレッドがあっても、
+            // もう一方のスレッドは決められた回数だけロックを獲得できるはず。
+            done.store(1, Ordering::Relaxed);
+        });
+        assert_eq!(m.raw.state.load(Ordering::Relaxed), 0);
+        assert_eq!(m.into_inner(), ACQUISITIONS);
+    }
+
+    #[test]
+    fn fair_mode_bounds_wait_during_tight_loop() {
+        let m = Mutex::with_fairness(0_u32, 8);
+        let looped = AtomicU32::new(0);
+        let done = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            // 通常の`drop`でロックと解放を繰り返すスレッド
+            s.spawn(|| {
+                while done.load(Ordering::Relaxed) == 0 {
+                    let _guard = m.lock();
+                    looped.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+            while looped.load(Ordering::Relaxed) == 0 {
+                std::thread::yield_now();
+            }
+            for _ in 0..100 {
+                let start = Instant::now();
+                *m.lock() += 1;
+                // 8回の解放ごとにロックが引き渡されるため、待機する時間には上限があるはず。
+                assert!(start.elapsed() < Duration::from_secs(1));
+            }
+            done.store(1, Ordering::Relaxed);
+        });
+        assert_eq!(m.raw.state.load(Ordering::Relaxed), 0);
+        assert_eq!(m.into_inner(), 100);
+    }
+
+    /// 10個のスレッドがすべてロックを待機している状態から、各スレッドが`ROUNDS`回ずつロックを獲得し、
+    /// ロックを獲得したスレッドの番号を順番に記録する。
+    ///
+    /// いずれかのスレッドが`ROUNDS`回獲得し終えるまでの記録について、同じスレッドが連続して獲得した最大の回数を返す。
+    /// 獲得し終えたスレッドは待機しなくなり、その後は引き渡す相手がいない場合があるため、それ以降の記録は数えない。
+    fn longest_run_while_all_waiting(fair: bool) -> usize {
+        const THREADS: usize = 10;
+        const ROUNDS: usize = 20;
+        let m = Mutex::new(Vec::new());
+        let guard = m.lock();
+        std::thread::scope(|s| {
+            for id in 0..THREADS {
+                let m = &m;
+                s.spawn(move || {
+                    for _ in 0..ROUNDS {
+                        let mut guard = m.lock();
+                        guard.push(id);
+                        if fair {
+                            MutexGuard::unlock_fair(guard);
+                        } else {
+                            MutexGuard::unlock(guard);
+                        }
+                    }
+                });
+            }
+            // すべてのスレッドがfutexで待機するまで待つ。
+            std::thread::sleep(Duration::from_millis(100));
+            MutexGuard::unlock_fair(guard);
+        });
+        let order = m.into_inner();
+        let mut counts = [0; THREADS];
+        let mut longest = 0;
+        let mut run = 0;
+        for (i, &id) in order.iter().enumerate() {
+            run = if i > 0 && order[i - 1] == id {
+                run + 1
+            } else {
+                1
+            };
+            longest = longest.max(run);
+            counts[id] += 1;
+            if counts[id] == ROUNDS {
+                break;
+            }
+        }
+        longest
+    }
+
+    #[test]
+    fn unlock_fair_alternates_between_ten_threads() {
+        // 他のスレッドが待機している間は、解放したスレッドではなく待機中のスレッドがロックを獲得するため、
+        // 同じスレッドが連続して獲得することはないはず。
+        assert_eq!(longest_run_while_all_waiting(true), 1);
+        // 通常の解放では、起床したスレッドが実行される前に、解放したスレッドがロックを獲得し直すため、
+        // 1つのスレッドが連続して獲得し続けるはず。
+        assert!(longest_run_while_all_waiting(false) > 1);
+    }
+
+    struct Config {
+        server: Server,
+        name: String,
+    }
+
+    struct Server {
+        ports: Vec<u16>,
+    }
+
+    #[test]
+    fn map_exposes_nested_field() {
+        let m = Mutex::new(Config {
+            server: Server { ports: vec![80] },
+            name: String::from("config"),
+        });
+
+        let mut ports = MutexGuard::map(m.lock(), |config| &mut config.server.ports);
+        ports.push(443);
+        // 変換後のガードがロックを保持しているはず。
+        assert!(m.try_lock().is_none());
+        drop(ports);
+
+        // ロックは1回だけ解放され、変更が観測できるはず。
+        assert_eq!(m.raw.state.load(Ordering::Relaxed), 0);
+        let config = m.lock();
+        assert_eq!(config.server.ports, [80, 443]);
+        assert_eq!(config.name, "config");
+        drop(config);
+
+        // `None`を返した場合は、元のガードが返されるはず。
+        let Err(guard) = MutexGuard::try_map(m.lock(), |config| config.server.ports.get_mut(2))
+        else {
+            panic!("ports has only two elements");
+        };
+        let mut port = MutexGuard::try_map(guard, |config| config.server.ports.last_mut())
+            .ok()
+            .unwrap();
+        *port = 8443;
+        drop(port);
+        assert_eq!(m.lock().server.ports, [80, 8443]);
+    }
+
+    #[test]
+    fn mapped_guard_wakes_waiting_thread() {
+        let m = Mutex::new((0, 0));
+        let guard = MutexGuard::map(m.lock(), |(first, _)| first);
+        std::thread::scope(|s| {
+            let t = s.spawn(|| m.lock().1 += 1);
+            // 待機中のスレッドがいる状態（state=2）になるまで待つ。
+            while m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::yield_now();
+            }
+            // 変換後のガードをドロップしても、待機中のスレッドを起床させるはず。
+            drop(guard);
+            t.join().unwrap();
+
+            // 競合しているスレッドが変換後のガードだけを使用しても、更新は失われないはず。
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *MutexGuard::map(m.lock(), |(first, _)| first) += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(m.into_inner(), (40_000, 1));
+    }
+
+    #[test]
+    fn zero_spin_budget_is_still_a_correct_lock() {
+        let m = Mutex::with_spin(0_u32, 0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let mut guard = m.lock();
+                        *guard += 1;
+                        // ロックを保持したままCPUを譲り、他のスレッドをfutexで待機させる。
+                        if guard.is_multiple_of(100) {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+        });
+        // スピンせずにfutexだけで待機しても、更新は失われないはず。
+        assert_eq!(m.into_inner(), 40_000);
+
+        // スピンしない場合も、futexで待機しているスレッドは解放したときに起床されるはず。
+        let m = Mutex::with_spin((), 0);
+        let guard = m.lock();
+        std::thread::scope(|s| {
+            s.spawn(|| drop(m.lock()));
+            while m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::yield_now();
+            }
+            drop(guard);
+        });
+        assert_eq!(m.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn adaptive_spin_follows_recent_outcomes() {
+        let adaptive = AdaptiveSpin::new();
+        assert_eq!(adaptive.spin(), DEFAULT_SPIN);
+        // 待機せずに獲得できることが続くと、スピンする回数は最大値に近づくはず。
+        for _ in 0..64 {
+            adaptive.record(false);
+        }
+        assert!(
+            adaptive.spin() > MAX_ADAPTIVE_SPIN * 9 / 10,
+            "{}",
+            adaptive.spin()
+        );
+        // 待機することが続くと、スピンする回数は最小値に近づくはず。
+        for _ in 0..64 {
+            adaptive.record(true);
+        }
+        assert!(
+            adaptive.spin() < MIN_ADAPTIVE_SPIN + 10,
+            "{}",
+            adaptive.spin()
+        );
+        // 推定値は直近の結果を反映するため、数回成功すれば再び増えるはず。
+        for _ in 0..8 {
+            adaptive.record(false);
+        }
+        assert!(
+            adaptive.spin() > MAX_ADAPTIVE_SPIN / 2,
+            "{}",
+            adaptive.spin()
+        );
+    }
+
+    #[test]
+    fn adaptive_spin_mutex_is_a_correct_lock() {
+        let m = Mutex::with_adaptive_spin(0_u32);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let mut guard = m.lock();
+                        *guard += 1;
+                        // ロックを保持したままCPUを譲り、他のスレッドを待機させて推定値を変化させる。
+                        if guard.is_multiple_of(100) {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+        });
+        // スピンする回数が変化しても、更新は失われないはず。
+        let spin = m.spin();
+        assert!(
+            (MIN_ADAPTIVE_SPIN..=MAX_ADAPTIVE_SPIN).contains(&spin),
+            "{spin}"
+        );
+        assert_eq!(m.into_inner(), 40_000);
+    }
+
+    #[test]
+    fn try_lock_fails_while_locked() {
+        let m = Mutex::new(0);
+        let guard = m.try_lock().unwrap();
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn default_and_debug() {
+        let m = Mutex::<String>::default();
+        assert_eq!(format!("{m:?}"), "Mutex { value: \"\" }");
+
+        let mut guard = m.lock();
+        guard.push('a');
+        assert_eq!(format!("{guard:?}"), "\"a\"");
+        // ロックを保持している間は、待機せずに`<locked>`を出力するはず。
+        assert_eq!(
+            format!("{m:?}"),
+            "Mutex { value: <locked>, waiters: false }"
+        );
+        drop(guard);
+        assert_eq!(format!("{m:?}"), "Mutex { value: \"a\" }");
+    }
+
+    #[test]
+    fn into_inner_collects_results_after_scope() {
+        let m = Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            for i in 0..4 {
+                let m = &m;
+                s.spawn(move || m.lock().push(i));
+            }
+        });
+        let mut results = m.into_inner();
+        results.sort();
+        assert_eq!(results, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn get_mut_does_not_touch_state() {
+        let mut m = Mutex::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| *m.lock() += 1);
+        });
+        assert_eq!(*m.raw.state.get_mut(), 0);
+        *m.get_mut() += 1;
+        // ロックを獲得しないため、状態は0のままのはず。
+        assert_eq!(*m.raw.state.get_mut(), 0);
+        assert_eq!(*m.get_mut(), 2);
+        assert_eq!(m.into_inner(), 2);
+    }
+
+    #[test]
+    fn try_lock_for_times_out_while_locked() {
+        let m = Mutex::new(0);
+        let locked = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = m.lock();
+                locked.store(1, Ordering::Release);
+                std::thread::sleep(Duration::from_millis(200));
+            });
+            while locked.load(Ordering::Acquire) == 0 {
+                std::thread::yield_now();
+            }
+
+            // 200ミリ秒ロックを保持しているため、50ミリ秒では獲得できないはず。
+            let start = Instant::now();
+            assert!(m.try_lock_for(Duration::from_millis(50)).is_none());
+            let elapsed = start.elapsed();
+            assert!(elapsed >= Duration::from_millis(50), "{elapsed:?}");
+            assert!(elapsed < Duration::from_millis(190), "{elapsed:?}");
+
+            // 500ミリ秒待機すれば、ロックが解放されて獲得できるはず。
+            let mut guard = m.try_lock_for(Duration::from_millis(500)).unwrap();
+            *guard += 1;
+        });
+        assert_eq!(*m.lock(), 1);
+    }
+
+    #[test]
+    fn try_lock_until_past_deadline_behaves_like_try_lock() {
+        let m = Mutex::new(0);
+        let deadline = Instant::now();
+        assert!(m.try_lock_until(deadline).is_some());
+        let _guard = m.lock();
+        assert!(m.try_lock_until(deadline).is_none());
+        assert!(m.try_lock_for(Duration::ZERO).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn contention_count_under_eight_threads() {
+        assert_eq!(std::mem::align_of::<Mutex<u8>>(), 64);
+
+        let m = Mutex::new(0_u32);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let mut guard = m.lock();
+                        *guard += 1;
+                        // ロックを保持したままCPUを譲り、他のスレッドと競合させる。
+                        if guard.is_multiple_of(100) {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 80_000);
+        // 競合した場合は、カウンタが増えているはず。
+        assert!(0 < m.contention_count());
+
+        m.reset_contention_count();
+        assert_eq!(m.contention_count(), 0);
+        *m.lock() += 1;
+        assert_eq!(m.contention_count(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn contention_stats_distinguish_fast_path_and_waits() {
+        let m = Mutex::new(0_u32);
+        for _ in 0..1000 {
+            *m.lock() += 1;
+        }
+        // 競合していない場合は、すべて最初の試行で獲得し、待機も起床もしないはず。
+        assert_eq!(
+            m.contention_stats(),
+            ContentionStats {
+                fast_path_locks: 1000,
+                ..ContentionStats::default()
+            }
+        );
+
+        m.reset_contention_stats();
+        const THREADS: u64 = 4;
+        const LOCKS: u64 = 10_000;
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..LOCKS {
+                        let mut guard = m.lock();
+                        *guard += 1;
+                        // ロックを保持したままCPUを譲り、他のスレッドをfutexで待機させる。
+                        if guard.is_multiple_of(100) {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+        });
+        let stats = m.contention_stats();
+        assert_eq!(
+            stats.fast_path_locks + stats.contended_locks,
+            THREADS * LOCKS
+        );
+        assert_eq!(
+            stats.spin_acquisitions + stats.waiting_locks,
+            stats.contended_locks
+        );
+        assert!(stats.waiting_locks <= stats.contended_locks);
+        // 競合した場合は、待機したスレッドと、それを起床させる解放があるはず。
+        assert!(0 < stats.waiting_locks, "{stats:?}");
+        assert!(0 < stats.wakes, "{stats:?}");
+        assert_eq!(m.contention_count(), stats.contended_locks);
+
+        m.reset_contention_stats();
+        assert_eq!(m.contention_stats(), ContentionStats::default());
+    }
+
+    // 借用するガードは、ロックを獲得したスレッドで解放するため`Send`を実装せず（ガードを別のスレッドに送ってから
+    // 元のスレッドで再びロックすると、自己デッドロックと誤検出するため）、保護する値が`Sync`であれば
+    // `Sync`を実装するはず。
+    assert_impl_all!(MutexGuard<'static, u32>: Sync);
+    assert_not_impl_any!(MutexGuard<'static, u32>: Send);
+    assert_not_impl_any!(MutexGuard<'static, Cell<u32>>: Send, Sync);
+    assert_not_impl_any!(MutexGuard<'static, Rc<u32>>: Send, Sync);
+    assert_impl_all!(MappedMutexGuard<'static, u32>: Sync);
+    assert_not_impl_any!(MappedMutexGuard<'static, u32>: Send);
+    assert_not_impl_any!(MappedMutexGuard<'static, Rc<u32>>: Send, Sync);
+    // `Arc`を保持するガードは、別のスレッドに送って解放できるはず。
+    assert_impl_all!(ArcMutexGuard<u32>: Send, Sync);
+    assert_not_impl_any!(ArcMutexGuard<Rc<u32>>: Send, Sync);
+
+    #[test]
+    fn arc_guard_is_released_on_another_thread() {
+        let m = Arc::new(Mutex::new(Vec::new()));
+        let mut guard = m.lock_arc();
+        guard.push("main");
+        assert!(m.try_lock_arc().is_none());
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| m.lock().push("waiter"));
+            // 待機中のスレッドがいる状態（state=2）になるまで待つ。
+            while m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::yield_now();
+            }
+            // ガードを別のスレッドに送り、そのスレッドで解放しても、待機中のスレッドは起床されるはず。
+            s.spawn(move || {
+                guard.push("sender");
+                drop(guard);
+            });
+            waiter.join().unwrap();
+        });
+        assert_eq!(*m.try_lock_arc().unwrap(), ["main", "sender", "waiter"]);
+        assert_eq!(m.raw.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn arc_guard_can_drop_last_arc() {
+        static NUM_DROPS: AtomicU32 = AtomicU32::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let m = Arc::new(Mutex::new(DetectDrop));
+        let guard = m.lock_arc();
+        drop(m);
+        // ガードが`Arc`を保持しているため、まだ解放されていないはず。
+        assert_eq!(Arc::strong_count(ArcMutexGuard::mutex(&guard)), 1);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        // ガードが最後の`Arc`をドロップするときに、ロックを解放してから値が解放されるはず。
+        drop(guard);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn replace_is_atomic() {
+        const THREADS: u32 = 4;
+        const REPLACES: u32 = 10_000;
+        // 値は常に、いずれかのスレッドが置き換えた値のどれか1つであり、古い値は1回だけ返されるはず。
+        let m = Mutex::new(u32::MAX);
+        let replaced = std::thread::scope(|s| {
+            let threads = (0..THREADS)
+                .map(|t| {
+                    let m = &m;
+                    s.spawn(move || {
+                        (0..REPLACES)
+                            .map(|i| m.replace(t * REPLACES + i))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        let mut values = replaced;
+        values.push(m.take());
+        values.sort();
+        let mut expected = (0..THREADS * REPLACES).collect::<Vec<_>>();
+        expected.push(u32::MAX);
+        assert_eq!(values, expected);
+        // `take`した後は、既定値に置き換えられているはず。
+        assert_eq!(m.into_inner(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "attempted to lock a Mutex already held by this thread")]
+    fn relocking_on_the_same_thread_panics_in_debug() {
+        let m = Mutex::new(0);
+        let _guard = m.lock();
+        // リリースビルドでは永久に待機するが、デバッグビルドではパニックするはず。
+        let _ = m.lock();
+    }
+
+    #[test]
+    fn ownership_bouncing_between_threads_is_not_a_self_deadlock() {
+        let m = Mutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *m.lock() += 1;
+                        std::thread::yield_now();
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 20_000);
+
+        // ロックを解放したスレッドが、他のスレッドがロックを保持している間に再びロックしようとしても、
+        // 自己デッドロックと誤検出しないはず。
+        *m.lock() += 1;
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        std::thread::scope(|s| {
+            let m = &m;
+            s.spawn(move || {
+                let mut guard = m.lock();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                *guard += 1;
+            });
+            locked_rx.recv().unwrap();
+            // 他のスレッドがロックを保持しているため、待機してから獲得するはず。
+            s.spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                release_tx.send(()).unwrap();
+            });
+            *m.lock() += 1;
+        });
+        assert_eq!(m.into_inner(), 20_003);
+    }
+
+    #[test]
+    fn relocking_after_handing_off_arc_guard_is_not_a_self_deadlock() {
+        let m = Arc::new(Mutex::new(0));
+        let guard = m.lock_arc();
+        let (received_tx, received_rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                let mut guard = guard;
+                received_tx.send(()).unwrap();
+                // ガードを受け取ったスレッドがロックを保持している間に、元のスレッドがロックしようとする。
+                std::thread::sleep(Duration::from_millis(50));
+                *guard += 1;
+            });
+            received_rx.recv().unwrap();
+            // ロックしたスレッドはガードを手放しているため、パニックせずに解放を待ってから獲得するはず。
+            *m.lock() += 1;
+        });
+        assert_eq!(*m.lock(), 2);
+
+        // `try_lock_arc`で獲得したガードを手放した場合も同様のはず。
+        let guard = m.try_lock_arc().unwrap();
+        std::thread::scope(|s| {
+            let (received_tx, received_rx) = std::sync::mpsc::channel();
+            s.spawn(move || {
+                let mut guard = guard;
+                received_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+                *guard += 1;
+            });
+            received_rx.recv().unwrap();
+            *m.lock() += 1;
+        });
+        assert_eq!(*m.lock(), 4);
+    }
+
+    #[test]
+    fn debug_does_not_block_or_wake_waiters() {
+        let m = Mutex::with_fairness(String::from("a"), 8);
+        let guard = m.lock();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| m.lock().push('b'));
+            // 待機中のスレッドがいる状態（state=2）になるまで待つ。
+            while m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::yield_now();
+            }
+            let start = Instant::now();
+            // 他のスレッドがロックを保持していても、待機せずに待機中のスレッドがあることを出力するはず。
+            assert_eq!(format!("{m:?}"), "Mutex { value: <locked>, waiters: true }");
+            assert!(start.elapsed() < Duration::from_millis(100));
+            // 出力してもstateは変化せず、待機中のスレッドは起床されないはず。
+            assert_eq!(m.raw.state.load(Ordering::Relaxed), 2);
+            assert!(!waiter.is_finished());
+            drop(guard);
+            waiter.join().unwrap();
+        });
+        // ロックを獲得できた場合の出力は、公平モードの解放回数を変化させないはず。
+        let releases = m.releases.load(Ordering::Relaxed);
+        assert_eq!(format!("{m:?}"), "Mutex { value: \"ab\" }");
+        assert_eq!(m.releases.load(Ordering::Relaxed), releases);
+    }
+
+    #[test]
+    fn trait_object_mutex_is_written_from_two_threads() {
+        use std::io::Write;
+
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        // `Arc<Mutex<Vec<u8>>>`から、差し替え可能な出力先として`Arc<Mutex<dyn Write + Send>>`に型強制する。
+        let sink: Arc<Mutex<dyn Write + Send>> = buffer.clone();
+        std::thread::scope(|s| {
+            for name in ["a", "b"] {
+                let sink = &sink;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        writeln!(sink.lock(), "{name}{i}").unwrap();
+                    }
+                });
+            }
+        });
+        sink.lock_arc().flush().unwrap();
+        // 1行ずつロックを獲得して書き込んでいるため、行が混ざらずに200行が書き込まれているはず。
+        let text = String::from_utf8(buffer.lock().clone()).unwrap();
+        let mut lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 200);
+        lines.sort_unstable();
+        lines.dedup();
+        assert_eq!(lines.len(), 200);
+        assert!(
+            lines
+                .iter()
+                .all(|line| line[1..].parse::<u32>().unwrap() < 100)
+        );
+    }
+
+    #[test]
+    fn slice_mutex_from_boxed_array() {
+        let mut m: Box<Mutex<[u8]>> = Box::new(Mutex::new([0; 4]));
+        m.lock()[1] = 1;
+        m.try_lock().unwrap()[2] = 2;
+        m.get_mut()[3] = 3;
+        // 動的サイズ型の値も、`lock`、`try_lock`、`get_mut`で読み書きできるはず。
+        assert_eq!(m.lock().len(), 4);
+        assert_eq!(format!("{m:?}"), "Mutex { value: [0, 1, 2, 3] }");
+    }
+
+    #[test]
+    fn lock_state_reports_all_three_states() {
+        let m = Mutex::new(0);
+        assert_eq!(m.lock_state(), LockState::Unlocked);
+        assert!(!m.is_locked());
+        assert!(!m.has_waiters());
+
+        let guard = m.lock();
+        assert_eq!(m.lock_state(), LockState::LockedNoWaiters);
+        assert!(m.is_locked());
+        assert!(!m.has_waiters());
+
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| *m.lock() += 1);
+            // 待機中のスレッドがfutexで待機する（state=2）まで待つ。
+            while !m.has_waiters() {
+                std::thread::yield_now();
+            }
+            assert_eq!(m.lock_state(), LockState::LockedWithWaiters);
+            assert!(m.is_locked());
+            drop(guard);
+            waiter.join().unwrap();
+        });
+        // 待機中のスレッドがロックを獲得して解放した後は、ロックされていない状態に戻るはず。
+        assert_eq!(m.lock_state(), LockState::Unlocked);
+        assert_eq!(m.into_inner(), 1);
+    }
+
+    #[test]
+    fn cancel_wakes_blocked_locker() {
+        let m = Mutex::new(0);
+        let token = CancellationToken::new();
+        let guard = m.lock();
+        std::thread::scope(|s| {
+            let locker = s.spawn(|| m.lock_cancellable(&token).map(|_| ()));
+            // futexで待機している状態（state=2）になるまで待つ。
+            while m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            let start = Instant::now();
+            token.cancel();
+            // ロックは保持されたままであるが、キャンセルにより待機をやめるはず。
+            assert_eq!(locker.join().unwrap(), Err(Cancelled));
+            assert!(start.elapsed() < Duration::from_millis(100));
+        });
+        drop(guard);
+        // キャンセルした後は、ロックが解放されていても獲得しないはず。
+        assert_eq!(m.lock_cancellable(&token).map(|_| ()), Err(Cancelled));
+        // 待機をやめた後も、ロックは通常どおり獲得できるはず。
+        assert_eq!(*m.try_lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn cancelling_parent_wakes_locker_waiting_with_child_token() {
+        let m = Mutex::new(0);
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let guard = m.lock();
+        std::thread::scope(|s| {
+            let locker = s.spawn(|| m.lock_cancellable(&child).map(|_| ()));
+            // 子トークンにstateのアドレスを登録して、futexで待機している状態になるまで待つ。
+            while child.registration_count() == 0 || m.raw.state.load(Ordering::Relaxed) != 2 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            // 親の`cancel`は子をキャンセルし、子に登録されたアドレスで待機しているスレッドも起床させるはず。
+            parent.cancel();
+            assert_eq!(locker.join().unwrap(), Err(Cancelled));
+        });
+        drop(guard);
+        assert_eq!(child.registration_count(), 0);
+    }
+
+    #[test]
+    fn uncancelled_locker_acquires_after_release() {
+        let m = Mutex::new(0);
+        let token = CancellationToken::new();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1_000 {
+                        *m.lock_cancellable(&token).unwrap() += 1;
+                        std::thread::yield_now();
+                    }
+                });
+            }
+        });
+        // キャンセルされない場合は、`lock`と同じく、すべての獲得が成功するはず。
+        assert_eq!(m.into_inner(), 4_000);
+        assert_eq!(token.registration_count(), 0);
+        assert!(!token.is_cancelled());
+    }
+}
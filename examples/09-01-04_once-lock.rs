@@ -0,0 +1,228 @@
+//! # `OnceLock<T>`
+//!
+//! 02-03-03の`OnceCell<T>`は、初期化中に競合したスレッドが`READY`になるまでスピンするため、
+//! ファイルの読み込みなど、初期化に時間がかかる場合はCPUを浪費する。
+//! `OnceLock<T>`は、09-01-02のミューテックスで`Option<T>`を保護することで、
+//! 競合したスレッドをスピンさせずに、futexで待機させる。
+//!
+//! 初期化された後は値が変更されないため、`done`フラグを確認するだけで、ロックを獲得せずに
+//! 値を参照できる（ファストパス）。
+//!
+//! `Lazy<T, F>`は、初期化する関数を`OnceLock<T>`と一緒に保持し、最初に参照されたときに値を初期化する。
+//! `new`は`const fn`であるため、`static`の初期化に使用できる。
+//!
+//! 09-01-02のファイルを`#[path]`でモジュールとして読み込み、そのミューテックスをそのまま使用する。
+//! そのため、`cargo test --example 09-01-04_once-lock`は、09-01-02のテストも実行する。
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// 09-01-02のミューテックス
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+#[allow(dead_code)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+use futex_mutex::Mutex;
+
+pub struct OnceLock<T> {
+    /// 値が初期化されたか
+    done: AtomicBool,
+    value: Mutex<Option<T>>,
+    /// `Mutex<Option<T>>`は`T: Send`であれば`Sync`であるが、`OnceLock<T>`は複数のスレッドに
+    /// `&T`を共有するため、`T: Sync`も必要である。
+    /// `PhantomData<T>`により、`T: Send + Sync`の場合にのみ`Sync`になる。
+    _marker: PhantomData<T>,
+}
+
+impl<T> OnceLock<T> {
+    pub const fn new() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            value: Mutex::new(None),
+            _marker: PhantomData,
+        }
+    }
+
+    /// 値が初期化されていれば、その値への参照を返す。
+    pub fn get(&self) -> Option<&T> {
+        // Acquireロードにより、`true`を観測した場合は、初期化された値を観測できる。
+        if self.done.load(Ordering::Acquire) {
+            // 安全性: `done`が`true`の場合、値は初期化されており、以降変更されないため、
+            // ロックを獲得せずに参照しても、可変参照と共存することはない。
+            unsafe { (*self.value.data_ptr()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// 値が初期化されていなければ`f`で初期化し、値への参照を返す。
+    ///
+    /// 他のスレッドが初期化している場合は、スピンせずに初期化が完了するまで待機する。
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.get_or_try_init(|| Ok::<T, Infallible>(f())) {
+            Ok(value) => value,
+        }
+    }
+
+    /// `get_or_init`と同様であるが、`f`がエラーを返した場合は値を初期化せずに、そのエラーを返す。
+    pub fn get_or_try_init<E>(&self, f: impl FnOnce() -> Result<T, E>) -> Result<&T, E> {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        let mut guard = self.value.lock();
+        if guard.is_none() {
+            *guard = Some(f()?);
+            // ロックを保持している間に設定するため、`done`が`true`になった後に値が変更されることはない。
+            self.done.store(true, Ordering::Release);
+        }
+        drop(guard);
+        Ok(self.get().unwrap())
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 最初に参照されたときに`init`で初期化される値
+///
+/// `OnceLock<T>`と同様に、`T: Send + Sync`かつ`F: Sync`の場合にのみ`Sync`になるため、
+/// `static`として複数のスレッドから参照できる。
+pub struct Lazy<T, F = fn() -> T> {
+    value: OnceLock<T>,
+    init: F,
+}
+
+/// 初期化する関数を関数ポインタで保持する`Lazy<T>`
+///
+/// キャプチャーしないクロージャーは関数ポインタに型強制されるため、`static`の型に使用できる。
+pub type SyncLazy<T> = Lazy<T, fn() -> T>;
+
+impl<T, F> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            value: OnceLock::new(),
+            init,
+        }
+    }
+}
+
+impl<T, F: Fn() -> T> Lazy<T, F> {
+    /// 値を初期化して、値への参照を返す。
+    ///
+    /// `*lazy`と同じであるが、初期化する箇所を明示するために使用する。
+    pub fn force(lazy: &Self) -> &T {
+        lazy.value.get_or_init(&lazy.init)
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
+
+fn load_config() -> String {
+    // ファイルの読み込みなど、時間のかかる初期化を想定する。
+    std::thread::sleep(Duration::from_millis(100));
+    String::from("config loaded")
+}
+
+fn main() {
+    static CONFIG: OnceLock<String> = OnceLock::new();
+
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for i in 0..4 {
+            s.spawn(move || {
+                println!("thread {i}: {}", CONFIG.get_or_init(load_config));
+            });
+        }
+    });
+    println!("initialized in {:?}", start.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn racing_threads_wait_for_slow_initializer() {
+        let cell = OnceLock::new();
+        let calls = AtomicUsize::new(0);
+
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            for i in 0..10 {
+                let (cell, calls) = (&cell, &calls);
+                s.spawn(move || {
+                    let value = cell.get_or_init(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        std::thread::sleep(Duration::from_millis(100));
+                        i
+                    });
+                    assert_eq!(Some(value), cell.get());
+                });
+            }
+        });
+        let elapsed = start.elapsed();
+
+        // `f`は1回だけ呼び出され、他のスレッドは初期化の完了を待つだけであるため、
+        // 全体の所要時間はおよそ100ミリ秒（10回分の1000ミリ秒ではない）のはず。
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(Duration::from_millis(100) <= elapsed);
+        assert!(elapsed < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn failed_initialization_can_be_retried() {
+        let cell = OnceLock::new();
+        assert_eq!(cell.get_or_try_init(|| Err("failed")), Err("failed"));
+        assert_eq!(cell.get(), None);
+
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(1)), Ok(&1));
+        // 初期化された後は、`f`を実行しないはず。
+        assert_eq!(cell.get_or_try_init(|| Err(())), Ok(&1));
+    }
+
+    #[test]
+    fn static_lazy_is_initialized_once_by_racing_threads() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static NAMES: SyncLazy<Vec<String>> = Lazy::new(|| {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+            std::thread::sleep(Duration::from_millis(50));
+            vec![String::from("a"), String::from("b")]
+        });
+
+        let addresses: Vec<usize> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| std::ptr::from_ref::<Vec<String>>(&NAMES).addr()))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        // 初期化は1回だけ行われ、すべてのスレッドが同じ値を参照するはず。
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert!(addresses.iter().all(|&a| a == addresses[0]));
+        // スレッドが終了した後も、値は`static`として生存し続けるはず。
+        assert_eq!(*NAMES, ["a", "b"]);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn lazy_with_capturing_closure() {
+        let base = 10;
+        let lazy = Lazy::new(|| base * 2);
+        assert_eq!(lazy.value.get(), None);
+        assert_eq!(*Lazy::force(&lazy), 20);
+        assert_eq!(lazy.value.get(), Some(&20));
+    }
+}
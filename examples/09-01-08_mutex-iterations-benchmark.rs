@@ -0,0 +1,169 @@
+//! 9章のミューテックスの3つの段階（09-01、09-01-01、09-01-02）を、04-03のスピンロックと
+//! `std::sync::Mutex`と比較する。
+//!
+//! 09-01は解放するたびに`wake_one`（システムコール）を呼び出し、09-01-01は待機中のスレッドがある場合だけ
+//! 呼び出し、09-01-02はさらに待機する前に短くスピンする。
+//! カウンタのインクリメントを、競合しない1スレッド、軽く競合する2スレッド、激しく競合する16スレッドで計測し、
+//! システムコールを避けることの効果を確認する。
+//!
+//! 例えば、CPUコアが1つのLinux環境では、09-01は`wake_one`のために約400msかかり、09-01-01と09-01-02
+//! （いずれも約35ms）の10倍以上であった。
+//! 09-01-01と09-01-02は、競合しない場合は同じ処理であるため差はなく、`std::sync::Mutex`とほぼ同じであった。
+//! スピンロックは、解放が単純なストアであるため、約20msと最も速かった。
+//! 1つのコアではスレッドが同時に実行されないため、2スレッドや16スレッドでも競合はタイムスライスの
+//! 切り替わりでしか発生せず、結果は1スレッドの場合とほぼ同じであった。
+//! 結果はCPUコアの数とスケジューラーに大きく依存するため、実際に使用する環境で計測すること。
+//!
+//! 09-01-05と同様に、比較するロックは、それぞれの例のファイルを`#[path]`でモジュールとして読み込み、
+//! 実装をそのまま計測する。
+//! そのため、`cargo test --example 09-01-08_mutex-iterations-benchmark`は、読み込んだ例のテストも実行する。
+//! 計測する場合は`cargo run --release --example 09-01-08_mutex-iterations-benchmark`で実行すること。
+use std::time::{Duration, Instant};
+
+/// 09-01のミューテックス
+///
+/// 解放するたびに、待機中のスレッドがあるかどうかに関わらず`wake_one`を呼び出す。
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+#[allow(dead_code)]
+#[path = "09-01_mutex.rs"]
+mod syscall_mutex;
+
+/// 09-01-01のミューテックス
+///
+/// 待機中のスレッドがある場合（state=2）だけ`wake_one`を呼び出す。
+#[allow(dead_code)]
+#[path = "09-01-01_avoiding-system-call.rs"]
+mod three_state_mutex;
+
+/// 09-01-02のミューテックス
+///
+/// 09-01-01に加えて、待機する前に`Backoff`でスピンする。
+// 04-03と09-01-02はどちらも`shared/backoff.rs`を読み込むため`duplicate_mod`も許可する。
+#[allow(dead_code, clippy::duplicate_mod)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+/// 04-03のスピンロック（指数バックオフ付き）
+#[allow(dead_code, clippy::duplicate_mod)]
+#[path = "04-03_safe-interface-with-lock-guard.rs"]
+mod spin_lock;
+
+use spin_lock::SpinLock;
+
+/// ロックを獲得する回数の合計（スレッドの数で等分する）
+const TOTAL_ITERATIONS: usize = 1_600_000;
+
+/// 比較するロックに共通する操作
+trait Lock<T>: Sync {
+    fn new(value: T) -> Self;
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R;
+}
+
+impl<T: Send> Lock<T> for syscall_mutex::Mutex<T> {
+    fn new(value: T) -> Self {
+        syscall_mutex::Mutex::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+}
+
+impl<T: Send> Lock<T> for three_state_mutex::Mutex<T> {
+    fn new(value: T) -> Self {
+        three_state_mutex::Mutex::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+}
+
+impl<T: Send> Lock<T> for futex_mutex::Mutex<T> {
+    fn new(value: T) -> Self {
+        futex_mutex::Mutex::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock())
+    }
+}
+
+impl<T: Send> Lock<T> for SpinLock<T> {
+    fn new(value: T) -> Self {
+        SpinLock::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.with_lock(f)
+    }
+}
+
+impl<T: Send> Lock<T> for std::sync::Mutex<T> {
+    fn new(value: T) -> Self {
+        std::sync::Mutex::new(value)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock().unwrap())
+    }
+}
+
+/// `threads`個のスレッドで、合計`TOTAL_ITERATIONS`回カウンタをインクリメントする時間を計測する。
+///
+/// スレッドが1つの場合は、スレッドを起動せずに現在のスレッドで計測する（競合しない場合）。
+fn bench_counter<L: Lock<usize>>(threads: usize) -> Duration {
+    let lock = L::new(0);
+    std::hint::black_box(&lock);
+    let iterations = TOTAL_ITERATIONS / threads;
+    let start = Instant::now();
+    if threads == 1 {
+        for _ in 0..iterations {
+            lock.with(|v| *v += 1);
+        }
+    } else {
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    for _ in 0..iterations {
+                        lock.with(|v| *v += 1);
+                    }
+                });
+            }
+        });
+    }
+    let duration = start.elapsed();
+    assert_eq!(lock.with(|v| *v), threads * iterations);
+    duration
+}
+
+fn main() {
+    println!(
+        "available parallelism: {}",
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+
+    for (label, threads) in [
+        ("uncontended", 1),
+        ("lightly contended", 2),
+        ("heavily contended", 16),
+    ] {
+        println!("{label} ({threads} threads, {TOTAL_ITERATIONS} increments)");
+        let results = [
+            ("09-01", bench_counter::<syscall_mutex::Mutex<_>>(threads)),
+            (
+                "09-01-01",
+                bench_counter::<three_state_mutex::Mutex<_>>(threads),
+            ),
+            ("09-01-02", bench_counter::<futex_mutex::Mutex<_>>(threads)),
+            ("04-03 SpinLock", bench_counter::<SpinLock<_>>(threads)),
+            (
+                "std::sync::Mutex",
+                bench_counter::<std::sync::Mutex<_>>(threads),
+            ),
+        ];
+        for (name, duration) in results {
+            println!("  {name:<16} {duration:>12?}");
+        }
+    }
+}
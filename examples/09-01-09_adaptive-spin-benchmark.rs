@@ -0,0 +1,110 @@
+//! 09-01-02のミューテックスの適応的なスピン（`Mutex::with_adaptive_spin`）と、固定のスピン回数
+//! （`Mutex::with_spin`）の性能を比較する。
+//!
+//! 4つのスレッドが、短いクリティカルセクション（カウンタのインクリメント）の段階と、長いクリティカル
+//! セクション（約`LONG_HOLD`回の`spin_loop`）の段階を交互に繰り返す。
+//! 段階ごとにかかった時間を合計し、短い段階と長い段階のそれぞれで、どのスピン回数が有利かを出力する。
+//!
+//! 複数のCPUコアがある環境では、短い段階ではスピンしている間にロックが解放されるため、スピン回数が大きいほど
+//! システムコールを避けられ、長い段階ではスピンが無駄になるため、スピン回数が小さいほど有利になる。
+//! 適応的なスピンは、段階が切り替わってから数回の競合でスピン回数を調整するため、両方の段階で
+//! 有利な固定のスピン回数に近い性能になることが期待される。
+//!
+//! 例えば、CPUコアが1つのLinux環境では、スレッドが同時に実行されないため、スピンしている間にロックが
+//! 解放されることはなく、どの設定でも差は計測誤差の範囲内であった（短い段階で合計約20〜30ms、
+//! 長い段階で約0.6〜0.75s）。
+//! 適応的なスピンは、スピンの後の`Backoff`の`yield_now`でロックを保持しているスレッドが実行され、待機せずに
+//! 獲得できることがあるため、スピン回数を既定値の近く（約140回）に保ったが、1つのコアではスピンの回数が
+//! 実行時間にほとんど影響しないため、その効果も現れなかった。
+//! 結果はCPUコアの数に大きく依存するため、実際に使用する環境で計測すること。
+//!
+//! 09-01-06と同様に、09-01-02のファイルを`#[path]`でモジュールとして読み込み、実装をそのまま計測する。
+//! そのため、`cargo test --example 09-01-09_adaptive-spin-benchmark`は、09-01-02のテストも実行する。
+//! 計測する場合は`cargo run --release --example 09-01-09_adaptive-spin-benchmark`で実行すること。
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+/// 09-01-02のミューテックス（`Mutex::with_spin`または`Mutex::with_adaptive_spin`で作成する）
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため。
+#[allow(dead_code)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+use futex_mutex::Mutex;
+
+/// スレッドの数
+const THREADS: usize = 4;
+/// 短い段階と長い段階を繰り返す回数
+const ROUNDS: usize = 4;
+/// 短い段階で、1スレッドあたりのロックを獲得する回数
+const SHORT_ITERATIONS: usize = 100_000;
+/// 長い段階で、1スレッドあたりのロックを獲得する回数
+const LONG_ITERATIONS: usize = 1_000;
+/// 長い段階で、ロックを保持している間に`spin_loop`を呼び出す回数
+const LONG_HOLD: u32 = 2_000;
+
+/// 短い段階と長い段階を`ROUNDS`回繰り返し、それぞれの段階にかかった時間の合計と、最後のスピン回数を返す。
+fn bench(lock: Mutex<u64>) -> (Duration, Duration, u32) {
+    // 各段階の開始と終了を、計測するスレッドとそろえる。
+    let barrier = Barrier::new(THREADS + 1);
+    let mut short = Duration::ZERO;
+    let mut long = Duration::ZERO;
+    std::thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    barrier.wait();
+                    for _ in 0..SHORT_ITERATIONS {
+                        *lock.lock() += 1;
+                    }
+                    barrier.wait();
+                    barrier.wait();
+                    for _ in 0..LONG_ITERATIONS {
+                        let mut guard = lock.lock();
+                        for _ in 0..LONG_HOLD {
+                            std::hint::spin_loop();
+                        }
+                        *guard += 1;
+                    }
+                    barrier.wait();
+                }
+            });
+        }
+        for _ in 0..ROUNDS {
+            barrier.wait();
+            let start = Instant::now();
+            barrier.wait();
+            short += start.elapsed();
+            barrier.wait();
+            let start = Instant::now();
+            barrier.wait();
+            long += start.elapsed();
+        }
+    });
+    let spin = lock.spin();
+    let expected = (THREADS * ROUNDS * (SHORT_ITERATIONS + LONG_ITERATIONS)) as u64;
+    assert_eq!(*lock.lock(), expected);
+    (short, long, spin)
+}
+
+fn main() {
+    println!(
+        "available parallelism: {}",
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+    println!("{THREADS} threads, {ROUNDS} rounds of short and long critical sections");
+
+    let configs = [
+        ("spin 0", Mutex::with_spin(0, 0)),
+        ("spin 100", Mutex::with_spin(0, 100)),
+        ("spin 1000", Mutex::with_spin(0, 1000)),
+        ("adaptive", Mutex::with_adaptive_spin(0)),
+    ];
+    for (label, lock) in configs {
+        let (short, long, spin) = bench(lock);
+        println!(
+            "  {label:<10}: short {short:>12?} long {long:>12?} total {:>12?} (final spin {spin})",
+            short + long
+        );
+    }
+}
@@ -65,6 +65,17 @@ pub struct MutexGuard<'a, T> {
 /// したがって、どのようなライフタイムであっても問題ないことを示すために`'_`を使う。
 unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
 
+/// `MutexGuard`は`&Mutex<T>`だけを保持しているため、`T: Send`であれば自動的に`Send`を実装する。
+/// ここでは、偶然ではなく方針として`Send`を実装することを明示する。
+///
+/// futexによるロックは、どのスレッドがロックを獲得したかを記録せず、解放は`state`を0にして待機中のスレッドを
+/// 起床させるだけであるため、ロックを獲得したスレッドとは別のスレッドで解放しても正しく動作する。
+/// ガードを別のスレッドに送信すると、そのスレッドから`&mut T`にアクセスできるため、`T: Send`を要求する。
+///
+/// 一方、`std::sync::MutexGuard`は`Send`を実装しない。
+/// pthreadのミューテックスのように、ロックしたスレッドで解放しなければならないプラットフォームがあるためである。
+unsafe impl<T> Send for MutexGuard<'_, T> where T: Send {}
+
 impl<T> Deref for MutexGuard<'_, T> {
     type Target = T;
 
@@ -0,0 +1,612 @@
+//! # 条件変数
+//!
+//! 09-01-02のミューテックスと組み合わせて使用する条件変数である。
+//! 09-01-02のファイルを`#[path]`でモジュールとして読み込み、`wait`などは09-01-02の`MutexGuard`を受け取って返す。
+//! そのため、`cargo test --example 09-02_condvar`は、09-01-02のテストも実行する。
+//!
+//! `counter`は、通知されるたびにインクリメントされる通知カウンターである。
+//! `wait`は、ミューテックスのロックを解放する前に`counter`の値を読み込み、ロックを解放した後に
+//! `counter`がその値のままである場合だけ待機する。
+//! ロックを解放してから待機するまでの間に通知された場合は、`counter`が変化しているため待機せずに戻る。
+//! これにより、通知が失われる（lost wakeup）ことはない。
+//!
+//! `wait_while`と`wait_timeout_while`は、条件を満たすまで`wait`を繰り返すループを条件変数側で実装したものであり、
+//! 呼び出し側はスプリアスウェイクアップを考慮したループを書く必要がない。
+//!
+//! `notify_all`は、待機しているすべてのスレッドを起床させると、それらが一斉にミューテックスを奪い合い、
+//! 1つ以外は再びミューテックスで待機することになる（thundering herd）。
+//! Linuxでは、`FUTEX_CMP_REQUEUE`で1つだけを起床させ、残りはミューテックスの`state`で待機するように
+//! 付け替える（requeue）。付け替えられたスレッドは、ミューテックスが解放されるたびに1つずつ起床する。
+//! そのため、条件変数で待機していたスレッドは、09-01-02の`Mutex::relock_after_wait`でロックを獲得し直す。
+//! `relock_after_wait`は、常に`state`を2にして獲得するため、解放するときに次のスレッドを起床させる。
+//! また、待機したスレッドとして獲得するため、`unlock_fair`や`Mutex::with_fairness`でロックが引き渡された
+//! （`state`が3の）場合も、起床した付け替えられたスレッドが獲得できる。
+//! 付け替える先のミューテックスは`wait`で記録するため、1つの条件変数は常に同じミューテックスと
+//! 組み合わせて使用しなければならない。
+//!
+//! 05-01のチャネルの`send`と`receive`だけを、このミューテックスと条件変数で実装した例を`Channel`に示す。
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use atomic_wait::{wait, wake_all, wake_one};
+
+/// 09-01-02のミューテックス
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+#[allow(dead_code)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+// 09-02を読み込む例が、この条件変数と組み合わせるミューテックスを使用できるように公開する。
+use futex_mutex::wait_timeout;
+pub use futex_mutex::{Mutex, MutexGuard};
+
+pub struct Condvar {
+    /// 通知カウンター
+    counter: AtomicU32,
+    /// `wait`で記録した、組み合わせて使用するミューテックスの`state`（記録する前はnull）
+    mutex: AtomicPtr<AtomicU32>,
+    /// 起床した後、ロックを獲得し直すためにミューテックスで待機した回数
+    #[cfg(feature = "stats")]
+    mutex_waits: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            counter: AtomicU32::new(0),
+            mutex: AtomicPtr::new(ptr::null_mut()),
+            #[cfg(feature = "stats")]
+            mutex_waits: AtomicU32::new(0),
+        }
+    }
+
+    /// 起床した後、ロックを獲得し直すためにミューテックスで待機した回数を返す。
+    #[cfg(feature = "stats")]
+    pub fn mutex_waits(&self) -> u32 {
+        self.mutex_waits.load(Ordering::Relaxed)
+    }
+
+    /// 待機しているスレッドのうち、1つを起床させる。
+    pub fn notify_one(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        wake_one(&self.counter);
+    }
+
+    /// 待機しているすべてのスレッドを起床させる。
+    ///
+    /// Linuxでは、1つだけを起床させ、残りはミューテックスで待機するように付け替える。
+    /// まだ`wait`が呼び出されていない場合や、付け替える前に別の通知で`counter`が変化した場合は、
+    /// すべてのスレッドを起床させる。
+    pub fn notify_all(&self) {
+        let counter_value = self.counter.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let mutex = self.mutex.load(Ordering::Relaxed);
+        // `mutex`は、ミューテックスがドロップされた後も残っていることがあるため、参照に変換せずに渡す。
+        // 待機しているスレッドは戻るまでミューテックスを借用しているため、付け替えるスレッドがある間は有効である。
+        if mutex.is_null() || !wake_one_and_requeue(&self.counter, counter_value, mutex) {
+            wake_all(&self.counter);
+        }
+    }
+
+    /// ロックを解放する前に、付け替える先として`mutex`を記録する。
+    fn record_mutex<T: ?Sized>(&self, mutex: &Mutex<T>) {
+        let state = ptr::from_ref(mutex.futex()).cast_mut();
+        let previous = self.mutex.swap(state, Ordering::Relaxed);
+        debug_assert!(
+            previous.is_null() || previous == state,
+            "attempted to use a condition variable with two mutexes"
+        );
+    }
+
+    /// ロックを獲得し直し、統計を記録する。
+    fn relock<'a, T: ?Sized>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        let (guard, waited) = mutex.relock_after_wait();
+        #[cfg(feature = "stats")]
+        if waited {
+            self.mutex_waits.fetch_add(1, Ordering::Relaxed);
+        }
+        #[cfg(not(feature = "stats"))]
+        let _ = waited;
+        guard
+    }
+
+    /// ミューテックスのロックを解放して通知を待機し、起床した後に再びロックを獲得してガードを返す。
+    ///
+    /// 通知されていなくても戻ることがある（スプリアスウェイクアップ）ため、呼び出し側はループ内で
+    /// 条件を再評価しなければならない。
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // ロックを解放する前に、通知カウンターの値を読み込む。
+        // 通知するスレッドは、ロックを獲得して条件を変更した後に`counter`をインクリメントするため、
+        // ロックを解放した後の通知は、必ずこの値を変化させる。
+        let counter_value = self.counter.load(Ordering::Relaxed);
+
+        let mutex = MutexGuard::mutex(&guard);
+        self.record_mutex(mutex);
+        drop(guard);
+
+        // ロックを解放した後に通知されていなければ、待機する。
+        wait(&self.counter, counter_value);
+
+        self.relock(mutex)
+    }
+
+    /// `condition`が`true`を返す間、`wait`を繰り返す。
+    ///
+    /// 戻ったときは、ロックを獲得しており、`condition`は`false`を返している。
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// `wait_while`と同様であるが、`timeout`が経過した場合は待機をやめる。
+    ///
+    /// `condition`が`true`を返したまま`timeout`が経過した場合は、`WaitTimeoutResult::timed_out`が`true`を返す。
+    /// 戻ったときは、どちらの場合もロックを獲得している。
+    pub fn wait_timeout_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        mut condition: F,
+    ) -> (MutexGuard<'a, T>, WaitTimeoutResult)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            // 期限を表現できないほど長い場合は、期限なしで待機する。
+            return (self.wait_while(guard, condition), WaitTimeoutResult(false));
+        };
+        while condition(&mut *guard) {
+            // スプリアスウェイクアップで戻った場合に備えて、待機するたびに残り時間を計算し直す。
+            let now = Instant::now();
+            if now >= deadline {
+                return (guard, WaitTimeoutResult(true));
+            }
+            let counter_value = self.counter.load(Ordering::Relaxed);
+            let mutex = MutexGuard::mutex(&guard);
+            self.record_mutex(mutex);
+            drop(guard);
+            wait_timeout(&self.counter, counter_value, deadline - now);
+            guard = self.relock(mutex);
+        }
+        (guard, WaitTimeoutResult(false))
+    }
+}
+
+/// `Condvar::wait_timeout_while`が、期限が経過したために戻ったかどうか
+///
+/// `std::sync::WaitTimeoutResult`と同様に、`bool`と取り違えないように型で区別する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// 条件を満たさないまま期限が経過した場合は`true`を返す。
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// 08-03-01の`wake_one_and_requeue`
+///
+/// `wait_timeout`と同じく、`FUTEX_PRIVATE_FLAG`を指定する。
+/// `to`は、付け替えるスレッドがない場合はカーネルが参照しないため、ポインタで受け取る。
+#[cfg(target_os = "linux")]
+fn wake_one_and_requeue(a: &AtomicU32, expected: u32, to: *const AtomicU32) -> bool {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_CMP_REQUEUE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+            i32::MAX,
+            to,
+            expected,
+        ) >= 0
+    }
+}
+
+/// futexがないOSでは付け替えられないため、常に`false`を返す（`wake_all`で起床させる）。
+#[cfg(not(target_os = "linux"))]
+fn wake_one_and_requeue(_a: &AtomicU32, _expected: u32, _to: *const AtomicU32) -> bool {
+    false
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 05-01のチャネルの`send`と`receive`を、09-01-02のミューテックスとこの条件変数で実装したもの
+///
+/// 05-01と異なり、送信側と受信側を分けず、切断も検出しない。
+pub struct Channel<T> {
+    queue: Mutex<VecDeque<T>>,
+    item_ready: Condvar,
+}
+
+impl<T> Channel<T> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            item_ready: Condvar::new(),
+        }
+    }
+
+    pub fn send(&self, message: T) {
+        self.queue.lock().push_back(message);
+        self.item_ready.notify_one();
+    }
+
+    pub fn receive(&self) -> T {
+        let mut queue = self
+            .item_ready
+            .wait_while(self.queue.lock(), |queue| queue.is_empty());
+        queue.pop_front().unwrap()
+    }
+}
+
+impl<T> Default for Channel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn main() {
+    let channel = Channel::new();
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 0..5 {
+                println!("Sending: {i}");
+                channel.send(i);
+            }
+        });
+        for _ in 0..5 {
+            println!("Received: {}", channel.receive());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    #[test]
+    fn wakes_single_waiter() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        let mut wakeups = 0;
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                *mutex.lock() = 123;
+                condvar.notify_one();
+            });
+
+            let mut m = mutex.lock();
+            while *m < 100 {
+                m = condvar.wait(m);
+                wakeups += 1;
+            }
+            assert_eq!(*m, 123);
+        });
+
+        // 待機中にビジーループしていなければ、起床した回数は少ないはず。
+        assert!(wakeups < 10, "{wakeups}");
+    }
+
+    #[test]
+    fn notify_all_wakes_multiple_waiters() {
+        let mutex = Mutex::new(false);
+        let condvar = Condvar::new();
+        let woken = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut ready = mutex.lock();
+                    while !*ready {
+                        ready = condvar.wait(ready);
+                    }
+                    woken.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(woken.load(Ordering::Relaxed), 0);
+            *mutex.lock() = true;
+            condvar.notify_all();
+        });
+        // 1回の`notify_all`で、すべてのスレッドが戻るはず。
+        assert_eq!(woken.into_inner(), 4);
+    }
+
+    #[test]
+    fn notify_all_under_lock_wakes_many_waiters() {
+        const WAITERS: u32 = 16;
+        let mutex = Mutex::new(false);
+        let condvar = Condvar::new();
+        let woken = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..WAITERS {
+                s.spawn(|| {
+                    let mut ready = mutex.lock();
+                    while !*ready {
+                        ready = condvar.wait(ready);
+                    }
+                    woken.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            // ロックを保持したまま通知し、付け替えられたスレッドがロックの解放で起床することを確認する。
+            let mut ready = mutex.lock();
+            *ready = true;
+            condvar.notify_all();
+            std::thread::sleep(Duration::from_millis(50));
+            drop(ready);
+        });
+        // 付け替えられたスレッドも含めて、すべてのスレッドが条件を満たして戻るはず。
+        assert_eq!(woken.into_inner(), WAITERS);
+    }
+
+    #[test]
+    fn single_notify_all_unblocks_eight_waiters_promptly() {
+        let mutex = Mutex::new(false);
+        let condvar = Condvar::new();
+        let elapsed = std::thread::scope(|s| {
+            let waiters = (0..8)
+                .map(|_| s.spawn(|| drop(condvar.wait_while(mutex.lock(), |ready| !*ready))))
+                .collect::<Vec<_>>();
+            std::thread::sleep(Duration::from_millis(100));
+            let start = Instant::now();
+            *mutex.lock() = true;
+            condvar.notify_all();
+            for waiter in waiters {
+                waiter.join().unwrap();
+            }
+            start.elapsed()
+        });
+        // 1回の`notify_all`で、8つのスレッドのすべてが100ms以内に戻るはず。
+        assert!(elapsed < Duration::from_millis(100), "{elapsed:?}");
+    }
+
+    #[cfg(all(feature = "stats", target_os = "linux"))]
+    #[test]
+    fn notify_all_requeues_instead_of_waking_every_waiter() {
+        const WAITERS: u32 = 16;
+        let mutex = Mutex::new(false);
+        let condvar = Condvar::new();
+        std::thread::scope(|s| {
+            for _ in 0..WAITERS {
+                s.spawn(|| {
+                    let _ready = condvar.wait_while(mutex.lock(), |ready| !*ready);
+                });
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            let mut ready = mutex.lock();
+            *ready = true;
+            condvar.notify_all();
+            // すべてのスレッドを起床させた場合は、この間にすべてのスレッドがミューテックスで待機する。
+            std::thread::sleep(Duration::from_millis(50));
+            drop(ready);
+        });
+        // 通知で起床するのは1つだけで、残りはロックの解放で1つずつ起床するため、起床した直後に
+        // ミューテックスで待機する回数は、スレッドの数よりはるかに少ないはず。
+        let mutex_waits = condvar.mutex_waits();
+        assert!(mutex_waits < WAITERS / 2, "{mutex_waits}");
+    }
+
+    /// ロックを保持したまま`notify_all`を呼び出し、`unlock`で解放したときに、すべての待機しているスレッドが
+    /// 戻ることを確認する。待機していたスレッドも、条件を満たした後に`unlock`で解放する。
+    ///
+    /// 付け替えられたスレッドが起床しない場合に、スコープ付きスレッドの`join`が戻らずテストが終了しなくなるため、
+    /// `Arc`で共有してスレッドを生成し、期限を過ぎたらパニックする。
+    fn notify_all_under_lock_returns_every_waiter(
+        mutex: Mutex<bool>,
+        unlock: fn(MutexGuard<'_, bool>),
+    ) {
+        const WAITERS: u32 = 16;
+        let shared = Arc::new((mutex, Condvar::new(), AtomicU32::new(0)));
+        for _ in 0..WAITERS {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || {
+                let (mutex, condvar, woken) = &*shared;
+                let ready = condvar.wait_while(mutex.lock(), |ready| !*ready);
+                woken.fetch_add(1, Ordering::Relaxed);
+                unlock(ready);
+            });
+        }
+        let (mutex, condvar, woken) = &*shared;
+        std::thread::sleep(Duration::from_millis(100));
+        let mut ready = mutex.lock();
+        *ready = true;
+        condvar.notify_all();
+        // 付け替えられたスレッドが、ミューテックスのstateで待機している状態で解放する。
+        std::thread::sleep(Duration::from_millis(50));
+        unlock(ready);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let woken = woken.load(Ordering::Relaxed);
+            if woken == WAITERS {
+                break;
+            }
+            // ロックが引き渡されても、付け替えられたスレッドは獲得できるため、すべて戻るはず。
+            assert!(
+                Instant::now() < deadline,
+                "{woken} of {WAITERS} waiters returned"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn notify_all_with_unlock_fair_returns_every_waiter() {
+        notify_all_under_lock_returns_every_waiter(Mutex::new(false), |guard| {
+            MutexGuard::unlock_fair(guard)
+        });
+    }
+
+    #[test]
+    fn notify_all_with_fair_mutex_returns_every_waiter() {
+        // 解放するたびにロックを引き渡す。
+        notify_all_under_lock_returns_every_waiter(Mutex::with_fairness(false, 1), |guard| {
+            drop(guard)
+        });
+    }
+
+    #[test]
+    fn loop_tolerates_spurious_wakeups() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // 条件を満たさない通知（スプリアスウェイクアップと同じ状況）を繰り返してから、条件を満たす。
+                for i in 1..=10 {
+                    *mutex.lock() = i;
+                    condvar.notify_one();
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            });
+
+            let mut m = mutex.lock();
+            while *m < 10 {
+                m = condvar.wait(m);
+            }
+            // 条件を再評価しているため、条件を満たす前に戻ることはないはず。
+            assert_eq!(*m, 10);
+        });
+    }
+
+    #[test]
+    fn wait_while_reproduces_01_08_02_example() {
+        // 01-08-02と同じく、キューが空の間は待機し、要素が追加されたら取り出す。
+        let queue = Mutex::new(VecDeque::new());
+        let not_empty = Condvar::new();
+        let received = std::thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                let mut received = Vec::new();
+                while received.len() < 10 {
+                    let mut q = not_empty.wait_while(queue.lock(), |q| q.is_empty());
+                    received.push(q.pop_front().unwrap());
+                }
+                received
+            });
+            for i in 0..10 {
+                queue.lock().push_back(i);
+                not_empty.notify_one();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            consumer.join().unwrap()
+        });
+        // 01-08-02の手書きのループと同じく、追加した順にすべての要素を取り出すはず。
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        assert!(queue.lock().is_empty());
+    }
+
+    #[test]
+    fn wait_while_absorbs_notifications_until_condition_holds() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+        let checks = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // 条件を満たさない通知を4回行ってから、5回目で条件を満たす。
+                for i in 1..=5 {
+                    *mutex.lock() = i;
+                    condvar.notify_one();
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            });
+            let guard = condvar.wait_while(mutex.lock(), |m| {
+                checks.fetch_add(1, Ordering::Relaxed);
+                *m < 5
+            });
+            // 条件を満たさない通知では戻らず、条件を満たしてから戻るはず。
+            assert_eq!(*guard, 5);
+        });
+        // 最初の評価に加えて、通知されるたびに条件を評価し直しているはず。
+        assert!(checks.into_inner() >= 2);
+    }
+
+    #[test]
+    fn wait_timeout_while_times_out_despite_notifications() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // 条件を満たさない通知を繰り返す。
+                while !stop.load(Ordering::Relaxed) {
+                    *mutex.lock() += 1;
+                    condvar.notify_all();
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            });
+            let start = Instant::now();
+            let (guard, result) =
+                condvar.wait_timeout_while(mutex.lock(), Duration::from_millis(100), |_| true);
+            stop.store(true, Ordering::Relaxed);
+            // 通知されても条件を満たさないため、期限まで待機してから戻るはず。
+            assert!(result.timed_out());
+            assert!(start.elapsed() >= Duration::from_millis(100));
+            assert!(*guard > 0);
+        });
+    }
+
+    #[test]
+    fn wait_timeout_while_reports_timeout() {
+        let mutex = Mutex::new(0);
+        let condvar = Condvar::new();
+
+        // 条件を満たさないまま期限が過ぎた場合は、`true`を返すはず。
+        let start = Instant::now();
+        let (guard, result) =
+            condvar.wait_timeout_while(mutex.lock(), Duration::from_millis(50), |m| *m == 0);
+        assert!(result.timed_out());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(*guard, 0);
+        drop(guard);
+
+        // 期限までに条件を満たした場合は、`false`を返すはず。
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(50));
+                *mutex.lock() = 123;
+                condvar.notify_one();
+            });
+            let start = Instant::now();
+            let (guard, result) =
+                condvar.wait_timeout_while(mutex.lock(), Duration::from_secs(10), |m| *m == 0);
+            assert!(!result.timed_out());
+            assert_eq!(*guard, 123);
+            // 通知されたら、期限を待たずに戻るはず。
+            assert!(start.elapsed() < Duration::from_secs(5));
+        });
+    }
+
+    #[test]
+    fn channel_delivers_messages_in_order() {
+        let channel = Channel::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..1000 {
+                    channel.send(i);
+                }
+            });
+            for i in 0..1000 {
+                assert_eq!(channel.receive(), i);
+            }
+        });
+    }
+}
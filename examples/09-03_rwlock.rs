@@ -0,0 +1,377 @@
+//! # リーダー・ライターロック
+//!
+//! 09-01のミューテックスと同様に、futexで待機するリーダー・ライターロックである。
+//!
+//! `state`は、読み込みロックを保持しているリーダーの数の2倍に、待機しているライターがいる場合は1を加えた値であり、
+//! 書き込みロックされている場合は`u32::MAX`とする。
+//! リーダーは`state`が奇数（書き込みロックされているか、待機しているライターがいる）の間、`state`で待機する。
+//!
+//! リーダーの数だけを記録する場合、リーダーが途切れずに読み込みロックを獲得し続けると、
+//! リーダーの数が0にならないため、ライターはいつまでもロックを獲得できない（ライターの飢餓）。
+//! 待機しているライターがいる場合は、新しいリーダーを待機させることで、獲得済みの読み込みロックが
+//! すべて解放された時点で、ライターがロックを獲得できるようにする。
+//!
+//! ライターは、`state`が0または1以外の間待機するが、`state`の値はリーダーの増減で頻繁に変化するため、
+//! `state`で待機すると、値が変化するたびに待機に失敗する。
+//! そこで、ライターは、ロックが解放されるたびにインクリメントする`writer_wake_counter`で待機する。
+//!
+//! 書き込みロックを解放するときは、待機しているライターを1つと、待機しているすべてのリーダーを起床させる。
+//! 最後の読み込みロックを解放するときは、ライターが待機している場合だけ、ライターを1つ起床させる。
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use atomic_wait::{wait, wake_all, wake_one};
+
+pub struct RwLock<T> {
+    /// リーダーの数の2倍（ライターが待機している場合は+1、書き込みロックされている場合は`u32::MAX`）
+    state: AtomicU32,
+    /// ライターを起床させるときにインクリメントするカウンター
+    writer_wake_counter: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+/// 複数のリーダーが同時に`&T`にアクセスするため、`T: Sync`も必要である。
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// 読み込みロックを獲得する。
+    ///
+    /// 他のリーダーとは同時にロックを保持できるが、ライターがロックを保持している間や、
+    /// ライターが待機している間は待機する。
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            if s.is_multiple_of(2) {
+                // 偶数の場合は、書き込みロックされておらず、待機しているライターもいない。
+                assert!(s != u32::MAX - 2, "too many readers");
+                match self.state.compare_exchange_weak(
+                    s,
+                    s + 2,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        return RwLockReadGuard {
+                            rwlock: self,
+                            wake_writer: false,
+                        };
+                    }
+                    Err(e) => s = e,
+                }
+            }
+            if !s.is_multiple_of(2) {
+                // 書き込みロックが解放されるか、待機しているライターがロックを獲得して解放するまで待機する。
+                wait(&self.state, s);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 書き込みロックを獲得する。
+    ///
+    /// リーダーやライターがロックを保持している間は待機する。
+    /// 待機している間は、新しいリーダーが読み込みロックを獲得できないようにする。
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            // ロックされていない場合は、待機しているライターのビットの有無にかかわらず、ロックを獲得する。
+            if s <= 1 {
+                match self
+                    .state
+                    .compare_exchange(s, u32::MAX, Ordering::Acquire, Ordering::Relaxed)
+                {
+                    Ok(_) => return RwLockWriteGuard { rwlock: self },
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // 新しいリーダーを待機させるため、待機しているライターのビットを立てる。
+            if s.is_multiple_of(2) {
+                match self
+                    .state
+                    .compare_exchange(s, s + 1, Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            // `state`を確認する前にカウンターを読み込むことで、確認した後にロックが解放された場合は、
+            // カウンターが変化しているため待機しない。
+            let w = self.writer_wake_counter.load(Ordering::Acquire);
+            s = self.state.load(Ordering::Relaxed);
+            if s >= 2 {
+                wait(&self.writer_wake_counter, w);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+    /// 解放するときに、待機しているライターを起床させるか
+    ///
+    /// `RwLockWriteGuard::downgrade`で変換した読み込みロックの場合だけ`true`である。
+    wake_writer: bool,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let s = self.rwlock.state.fetch_sub(2, Ordering::Release);
+        // 最後のリーダーであり、ライターが待機している場合は、ライターを1つ起床させる。
+        // 変換した読み込みロックの場合は、待機しているライターのビットを立てていないライターがいる可能性があるため、
+        // 常にライターを1つ起床させる。
+        if s == 3 || self.wake_writer {
+            self.rwlock
+                .writer_wake_counter
+                .fetch_add(1, Ordering::Release);
+            wake_one(&self.rwlock.writer_wake_counter);
+        }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rwlock.state.store(0, Ordering::Release);
+        // 待機しているライターを1つと、待機しているすべてのリーダーを起床させる。
+        self.rwlock
+            .writer_wake_counter
+            .fetch_add(1, Ordering::Release);
+        wake_one(&self.rwlock.writer_wake_counter);
+        wake_all(&self.rwlock.state);
+    }
+}
+
+impl<'a, T> RwLockWriteGuard<'a, T> {
+    /// 書き込みロックを、解放せずに読み込みロックに変換する。
+    ///
+    /// 書き込みロックを解放してから読み込みロックを獲得し直すと、その間に他のライターが
+    /// ロックを獲得して値を更新する可能性がある。
+    /// `state`を`u32::MAX`（書き込みロック）から2（リーダー1つ）に直接変更するため、
+    /// 書き込んだ値を、他のライターに更新される前に読み込める。
+    pub fn downgrade(guard: Self) -> RwLockReadGuard<'a, T> {
+        let rwlock = guard.rwlock;
+        // 書き込みロックの`Drop`を実行しない。
+        std::mem::forget(guard);
+        // 書き込みロックされている間は、他のスレッドは`state`を変更しないため、`store`で十分である。
+        rwlock.state.store(2, Ordering::Release);
+        // 待機しているリーダーは、すぐに読み込みロックを獲得できる。
+        // ライターは、起床させると待機しているライターのビットを立て、新しいリーダーを待機させてしまうため、
+        // ここでは起床させない。
+        wake_all(&rwlock.state);
+        // 書き込みロックされている間に待機したライターは、待機しているライターのビットを立てていないため、
+        // 最後のリーダーが`state`の値からライターの待機を判断できない。
+        // そこで、変換した読み込みロックを解放するときに、ライターを起床させる。
+        RwLockReadGuard {
+            rwlock,
+            wake_writer: true,
+        }
+    }
+}
+
+fn main() {
+    let config = RwLock::new(String::from("v1"));
+    std::thread::scope(|s| {
+        for i in 0..3 {
+            let config = &config;
+            s.spawn(move || println!("reader {i}: {}", *config.read()));
+        }
+        s.spawn(|| config.write().replace_range(.., "v2"));
+    });
+    println!("final: {}", *config.read());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn readers_hold_lock_concurrently() {
+        const READERS: usize = 4;
+        let rwlock = RwLock::new(vec![1, 2, 3]);
+        let barrier = Barrier::new(READERS);
+        std::thread::scope(|s| {
+            for _ in 0..READERS {
+                s.spawn(|| {
+                    let guard = rwlock.read();
+                    // すべてのリーダーが同時に読み込みロックを保持しなければ、バリアを通過できないはず。
+                    barrier.wait();
+                    assert_eq!(*guard, [1, 2, 3]);
+                });
+            }
+        });
+        assert_eq!(rwlock.state.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn writer_excludes_readers() {
+        let rwlock = RwLock::new(0);
+        let guard = rwlock.write();
+        std::thread::scope(|s| {
+            let readers = (0..4)
+                .map(|_| s.spawn(|| *rwlock.read()))
+                .collect::<Vec<_>>();
+            let writer = s.spawn(|| *rwlock.write() += 1);
+            std::thread::sleep(Duration::from_millis(50));
+            // 書き込みロックを保持している間は、リーダーもライターも待機しているはず。
+            assert!(readers.iter().all(|t| !t.is_finished()));
+            assert!(!writer.is_finished());
+
+            let mut guard = guard;
+            *guard = 10;
+            drop(guard);
+            // リーダーは、解放前の値を読み込むことはないはず。
+            for t in readers {
+                assert!([10, 11].contains(&t.join().unwrap()));
+            }
+        });
+        assert_eq!(*rwlock.read(), 11);
+    }
+
+    #[test]
+    fn mixed_stress_keeps_pair_sum_invariant() {
+        // 2つの値の合計を常に0に保つ。リーダーが書き込み途中の値を観測すると、合計が0にならない。
+        let rwlock = RwLock::new((0_i64, 0_i64));
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for i in 0..10_000 {
+                        let mut guard = rwlock.write();
+                        guard.0 += i;
+                        std::hint::black_box(&mut *guard);
+                        guard.1 -= i;
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        let guard = rwlock.read();
+                        assert_eq!(guard.0 + guard.1, 0);
+                    }
+                });
+            }
+        });
+        let guard = rwlock.read();
+        assert_eq!(
+            *guard,
+            (2 * (0..10_000).sum::<i64>(), -2 * (0..10_000).sum::<i64>())
+        );
+    }
+
+    #[test]
+    fn writer_is_not_starved_by_readers() {
+        const READERS: usize = 8;
+        let rwlock = RwLock::new(0);
+        let writer_done = AtomicBool::new(false);
+        let give_up = Instant::now() + Duration::from_secs(5);
+        std::thread::scope(|s| {
+            for _ in 0..READERS {
+                s.spawn(|| {
+                    // 書き込みが完了するまで、読み込みロックを獲得し続ける。
+                    while !writer_done.load(Ordering::Relaxed) && Instant::now() < give_up {
+                        let guard = rwlock.read();
+                        std::thread::sleep(Duration::from_millis(1));
+                        drop(guard);
+                    }
+                });
+            }
+            // すべてのリーダーが読み込みロックを獲得し始めるまで待つ。
+            std::thread::sleep(Duration::from_millis(50));
+
+            let start = Instant::now();
+            *rwlock.write() += 1;
+            let elapsed = start.elapsed();
+            writer_done.store(true, Ordering::Relaxed);
+            // 待機しているライターがいる場合は、新しいリーダーが待機するため、
+            // 獲得済みの読み込みロックが解放された時点でライターが獲得できるはず。
+            assert!(
+                elapsed < Duration::from_secs(1),
+                "writer starved for {elapsed:?}"
+            );
+        });
+        assert_eq!(*rwlock.read(), 1);
+    }
+
+    #[test]
+    fn downgrade_keeps_competing_writer_out() {
+        let rwlock = RwLock::new(0);
+        let events = std::sync::Mutex::new(Vec::new());
+        let mut guard = rwlock.write();
+        std::thread::scope(|s| {
+            // 書き込みロックを保持している間に、競合するライターを待機させる。
+            let writer = s.spawn(|| {
+                let mut guard = rwlock.write();
+                events.lock().unwrap().push(format!("write {}", *guard));
+                *guard = 2;
+            });
+            std::thread::sleep(Duration::from_millis(50));
+
+            *guard = 1;
+            let guard = RwLockWriteGuard::downgrade(guard);
+            events.lock().unwrap().push(String::from("downgrade"));
+
+            // 読み込みロックに変換した後は、他のリーダーがすぐに書き込んだ値を読み込めるはず。
+            let readers = (0..4)
+                .map(|_| s.spawn(|| *rwlock.read()))
+                .collect::<Vec<_>>();
+            for t in readers {
+                assert_eq!(t.join().unwrap(), 1);
+            }
+            assert_eq!(*guard, 1);
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!writer.is_finished());
+            events.lock().unwrap().push(String::from("release"));
+            drop(guard);
+        });
+        // 競合するライターは、変換した読み込みロックを解放した後に、書き込んだ値を観測するはず。
+        assert_eq!(
+            events.into_inner().unwrap(),
+            ["downgrade", "release", "write 1"]
+        );
+        assert_eq!(*rwlock.read(), 2);
+    }
+}
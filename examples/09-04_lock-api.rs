@@ -0,0 +1,292 @@
+//! # futexのミューテックスとリーダー・ライターロックを`lock_api`に適合させる
+//!
+//! 04-01-01と同様に、09-01-02の3状態のミューテックスと09-03のリーダー・ライターロックから、
+//! 値を保護しない状態遷移だけを`RawFutexMutex`と`RawFutexRwLock`として抽出し、
+//! `lock_api::RawMutex`と`lock_api::RawRwLock`を実装する。
+//! ガードの変換（`map`）、`const`な初期化、`try_lock`などは`lock_api`が提供するため、
+//! このクレートではロックの状態遷移だけを保守すればよい。
+//!
+//! futexの待機と起床は、どのスレッドがロックを解放したかに依存しない。
+//! `unlock`は`state`を変更して待機中のスレッドを起床させるだけであり、ロックを獲得したスレッドを記録しないため、
+//! ロックを獲得したスレッドとは別のスレッドで解放しても正しく動作する。
+//! そのため、ガードのマーカーには`GuardSend`を選択し、`std::sync::MutexGuard`と異なり、ガードを
+//! 別のスレッドに送信できるようにする。
+//!
+//! `lock_api`フィーチャーを有効にして、`cargo run --features lock_api --example 09-04_lock-api`
+//! で実行すること。
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use atomic_wait::{wait, wake_all, wake_one};
+use lock_api::{GuardSend, RawMutex, RawRwLock};
+
+/// 09-01-02のミューテックスから抽出した、値を保護しないミューテックス
+pub struct RawFutexMutex {
+    /// 0: ロックされていない状態
+    /// 1: ロックされており、待機中のスレッドがない状態
+    /// 2: ロックされており、待機中のスレッドがある状態
+    state: AtomicU32,
+}
+
+pub type Mutex<T> = lock_api::Mutex<RawFutexMutex, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawFutexMutex, T>;
+
+/// 安全性: `lock`と`try_lock`は`state`を0から変更したスレッドだけがロックを獲得し、Acquireで獲得して
+/// `unlock`のReleaseで解放するため、同時に1つのスレッドだけがロックを保持する。
+unsafe impl RawMutex for RawFutexMutex {
+    const INIT: Self = Self {
+        state: AtomicU32::new(0),
+    };
+
+    /// futexは、ロックを獲得したスレッドとは別のスレッドで解放しても問題ないため、ガードは`Send`である。
+    type GuardMarker = GuardSend;
+
+    fn lock(&self) {
+        if self
+            .state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            lock_contented(&self.state);
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        if self.state.swap(0, Ordering::Release) == 2 {
+            wake_one(&self.state);
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+}
+
+/// 09-01-02の競合したときにロックを獲得する処理
+fn lock_contented(state: &AtomicU32) {
+    let mut spin_count = 0;
+    while state.load(Ordering::Relaxed) == 1 && spin_count < 100 {
+        spin_count += 1;
+        std::hint::spin_loop();
+    }
+
+    if state
+        .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+    {
+        return;
+    }
+
+    while state.swap(2, Ordering::Acquire) != 0 {
+        wait(state, 2);
+    }
+}
+
+/// 09-03のリーダー・ライターロックから抽出した、値を保護しないリーダー・ライターロック
+pub struct RawFutexRwLock {
+    /// リーダーの数の2倍（ライターが待機している場合は+1、書き込みロックされている場合は`u32::MAX`）
+    state: AtomicU32,
+    /// ライターを起床させるときにインクリメントするカウンター
+    writer_wake_counter: AtomicU32,
+}
+
+pub type RwLock<T> = lock_api::RwLock<RawFutexRwLock, T>;
+pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawFutexRwLock, T>;
+pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawFutexRwLock, T>;
+
+/// 安全性: 書き込みロックは`state`を0または1から`u32::MAX`に変更したスレッドだけが獲得し、
+/// 読み込みロックは`state`が偶数（書き込みロックされていない）の場合だけ獲得する。
+/// いずれもAcquireで獲得してReleaseで解放する。
+unsafe impl RawRwLock for RawFutexRwLock {
+    const INIT: Self = Self {
+        state: AtomicU32::new(0),
+        writer_wake_counter: AtomicU32::new(0),
+    };
+
+    /// `RawFutexMutex`と同様に、別のスレッドで解放しても問題ないため、ガードは`Send`である。
+    type GuardMarker = GuardSend;
+
+    fn lock_shared(&self) {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            if s.is_multiple_of(2) {
+                assert!(s != u32::MAX - 2, "too many readers");
+                match self.state.compare_exchange_weak(
+                    s,
+                    s + 2,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => s = e,
+                }
+            }
+            if !s.is_multiple_of(2) {
+                wait(&self.state, s);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        let mut s = self.state.load(Ordering::Relaxed);
+        // 書き込みロックされているか、ライターが待機している場合（奇数の場合）は失敗する。
+        // リーダーの数が上限に達している場合も、`lock_shared`のようにパニックせずに失敗する。
+        while s.is_multiple_of(2) && s != u32::MAX - 2 {
+            match self
+                .state
+                .compare_exchange_weak(s, s + 2, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(e) => s = e,
+            }
+        }
+        false
+    }
+
+    unsafe fn unlock_shared(&self) {
+        // 最後のリーダーであり、ライターが待機している場合は、ライターを1つ起床させる。
+        if self.state.fetch_sub(2, Ordering::Release) == 3 {
+            self.writer_wake_counter.fetch_add(1, Ordering::Release);
+            wake_one(&self.writer_wake_counter);
+        }
+    }
+
+    fn lock_exclusive(&self) {
+        let mut s = self.state.load(Ordering::Relaxed);
+        loop {
+            if s <= 1 {
+                match self
+                    .state
+                    .compare_exchange(s, u32::MAX, Ordering::Acquire, Ordering::Relaxed)
+                {
+                    Ok(_) => return,
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            if s.is_multiple_of(2) {
+                match self
+                    .state
+                    .compare_exchange(s, s + 1, Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => {}
+                    Err(e) => {
+                        s = e;
+                        continue;
+                    }
+                }
+            }
+            let w = self.writer_wake_counter.load(Ordering::Acquire);
+            s = self.state.load(Ordering::Relaxed);
+            if s >= 2 {
+                wait(&self.writer_wake_counter, w);
+                s = self.state.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        let s = self.state.load(Ordering::Relaxed);
+        s <= 1
+            && self
+                .state
+                .compare_exchange(s, u32::MAX, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.state.store(0, Ordering::Release);
+        self.writer_wake_counter.fetch_add(1, Ordering::Release);
+        wake_one(&self.writer_wake_counter);
+        wake_all(&self.state);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) >= 2
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == u32::MAX
+    }
+}
+
+/// `lock_api::Mutex::new`は`const fn`であるため、静的変数を初期化できる。
+static COUNTER: Mutex<u64> = Mutex::new(0);
+
+fn main() {
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..100_000 {
+                    *COUNTER.lock() += 1;
+                }
+            });
+        }
+    });
+    println!("counter: {}", *COUNTER.lock());
+
+    let config = RwLock::new((String::from("v1"), 1));
+    *RwLockWriteGuard::map(config.write(), |(name, _)| name) = String::from("v2");
+    println!("config: {:?}", *config.read());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn contended_counter_is_exact() {
+        let counter = Mutex::<u64>::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *counter.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.into_inner(), 80_000);
+    }
+
+    #[test]
+    fn guard_can_be_released_on_another_thread() {
+        let mutex = Mutex::new(0);
+        let mut guard = mutex.lock();
+        *guard += 1;
+        std::thread::scope(|s| {
+            // `GuardSend`であるため、ガードを別のスレッドに移動して解放できるはず。
+            s.spawn(move || drop(guard));
+        });
+        assert!(!mutex.is_locked());
+        assert_eq!(*mutex.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn rwlock_writer_excludes_readers() {
+        let rwlock = RwLock::new(0);
+        let reader = rwlock.read();
+        // 読み込みロックを保持している間は、他の読み込みロックだけを獲得できるはず。
+        assert!(rwlock.try_read().is_some());
+        assert!(rwlock.try_write().is_none());
+        std::thread::scope(|s| {
+            let writer = s.spawn(|| *rwlock.write() += 1);
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!writer.is_finished());
+            // ライターが待機している間は、新しいリーダーは読み込みロックを獲得できないはず。
+            assert!(rwlock.try_read().is_none());
+            drop(reader);
+        });
+        assert!(!rwlock.is_locked());
+        assert_eq!(*rwlock.read(), 1);
+    }
+}
@@ -0,0 +1,230 @@
+//! # セマフォ
+//!
+//! セマフォは、許可（パーミット）の数を数えるカウンタである。
+//! `acquire`は許可が1つ以上あればカウンタを1つ減らし、なければ許可が返却されるまで待機する。
+//! `release`はカウンタを1つ増やし、待機しているスレッドを起こす。
+//!
+//! 許可の数を1にすると、ミューテックスと同様に排他制御に使用できる。
+//! 許可の数をN個にすると、同時にアクセスできるスレッドをN個に制限できるため、接続数などの
+//! リソースの数を制限する目的に使用する。
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use atomic_wait::{wait, wake_all, wake_one};
+
+pub struct Semaphore {
+    /// 残りの許可の数
+    count: AtomicU32,
+    /// `acquire_many`で2つ以上の許可を待機しているスレッドの数
+    ///
+    /// `release`で1つのスレッドだけを起こすと、必要な許可が足りないスレッドを起こしてしまい、
+    /// 許可を1つだけ待機しているスレッドが起きない可能性がある。
+    /// そのようなスレッドが待機している場合は、すべてのスレッドを起こす。
+    many_waiters: AtomicU32,
+}
+
+/// `Semaphore`から獲得した許可
+///
+/// ドロップされたときに、獲得した数だけ許可を返却する。
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: u32,
+}
+
+impl Semaphore {
+    pub const fn new(permits: u32) -> Self {
+        Self {
+            count: AtomicU32::new(permits),
+            many_waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// 許可を1つ獲得する。許可がない場合は、許可が返却されるまで待機する。
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        self.acquire_many(1)
+    }
+
+    /// 許可を`n`個まとめて獲得する。
+    ///
+    /// 1つずつ獲得すると、複数のスレッドが許可を少しずつ獲得したまま、お互いに残りの許可を
+    /// 待機してデッドロックする可能性があるため、`n`個をまとめて獲得する。
+    pub fn acquire_many(&self, n: u32) -> SemaphorePermit<'_> {
+        if let Some(permit) = self.try_acquire_many(n) {
+            return permit;
+        }
+        if 1 < n {
+            // `release`側の`many_waiters`のロードとの順序を保証するため`SeqCst`を使用する。
+            self.many_waiters.fetch_add(1, Ordering::SeqCst);
+        }
+        let mut count = self.count.load(Ordering::SeqCst);
+        loop {
+            if n <= count {
+                match self.count.compare_exchange_weak(
+                    count,
+                    count - n,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(c) => {
+                        count = c;
+                        continue;
+                    }
+                }
+            }
+            // 許可が足りない場合は、`count`が変化するまで待機する。
+            // `wait`の呼び出し時点で`count`が変化していれば、待機せずに復帰する。
+            wait(&self.count, count);
+            count = self.count.load(Ordering::Relaxed);
+        }
+        if 1 < n {
+            self.many_waiters.fetch_sub(1, Ordering::Relaxed);
+        }
+        SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        }
+    }
+
+    /// 待機せずに許可を1つ獲得する。許可がない場合は`None`を返す。
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    /// 待機せずに許可を`n`個獲得する。許可が足りない場合は`None`を返す。
+    pub fn try_acquire_many(&self, n: u32) -> Option<SemaphorePermit<'_>> {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(n)
+            })
+            .ok()
+            .map(|_| SemaphorePermit {
+                semaphore: self,
+                permits: n,
+            })
+    }
+
+    /// 許可を1つ返却する。
+    ///
+    /// 通常は`SemaphorePermit`のドロップで返却されるため、直接呼び出す必要はない。
+    /// 直接呼び出した場合は、許可の総数が1つ増える。
+    pub fn release(&self) {
+        self.release_many(1);
+    }
+
+    /// 許可を`n`個返却する。
+    pub fn release_many(&self, n: u32) {
+        // `SeqCst`はReleaseを含むため、許可を獲得したスレッドは、許可を返却する前の操作を観測できる。
+        // また、`many_waiters`のロードとの順序を保証するため`SeqCst`を使用する。
+        self.count.fetch_add(n, Ordering::SeqCst);
+        if n == 1 && self.many_waiters.load(Ordering::SeqCst) == 0 {
+            wake_one(&self.count);
+        } else {
+            wake_all(&self.count);
+        }
+    }
+
+    /// 残りの許可の数を返す。
+    pub fn available_permits(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// 獲得した許可の数を返す。
+    pub fn permits(&self) -> u32 {
+        self.permits
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release_many(self.permits);
+    }
+}
+
+fn main() {
+    // 同時に3つのスレッドまでしか処理できないリソースを表現する。
+    let semaphore = Semaphore::new(3);
+    let inside = AtomicUsize::new(0);
+
+    std::thread::scope(|s| {
+        for i in 0..6 {
+            let semaphore = &semaphore;
+            let inside = &inside;
+            s.spawn(move || {
+                let _permit = semaphore.acquire();
+                let n = inside.fetch_add(1, Ordering::Relaxed) + 1;
+                println!("thread {i} entered ({n} inside)");
+                std::thread::sleep(Duration::from_millis(100));
+                inside.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn at_most_three_threads_inside() {
+        let semaphore = Semaphore::new(3);
+        let inside = AtomicUsize::new(0);
+        let max_inside = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let _permit = semaphore.acquire();
+                        let n = inside.fetch_add(1, Ordering::Relaxed) + 1;
+                        max_inside.fetch_max(n, Ordering::Relaxed);
+                        std::thread::sleep(Duration::from_micros(100));
+                        inside.fetch_sub(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+
+        assert!(max_inside.load(Ordering::Relaxed) <= 3);
+        assert_eq!(semaphore.available_permits(), 3);
+    }
+
+    #[test]
+    fn try_acquire_fails_when_exhausted() {
+        let semaphore = Semaphore::new(2);
+        let permit = semaphore.try_acquire_many(2).unwrap();
+        assert_eq!(permit.permits(), 2);
+        assert!(semaphore.try_acquire().is_none());
+
+        // 許可をドロップすると、返却されるはず。
+        drop(permit);
+        assert!(semaphore.try_acquire().is_some());
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn acquire_many_waits_for_enough_permits() {
+        let semaphore = Semaphore::new(3);
+        let permits = [
+            semaphore.acquire(),
+            semaphore.acquire(),
+            semaphore.acquire(),
+        ];
+
+        std::thread::scope(|s| {
+            let many = s.spawn(|| semaphore.acquire_many(2).permits());
+            let one = s.spawn(|| semaphore.acquire().permits());
+
+            // 許可を1つずつ返却しても、待機しているスレッドはすべて許可を獲得できるはず。
+            for permit in permits {
+                std::thread::sleep(Duration::from_millis(10));
+                drop(permit);
+            }
+            assert_eq!(many.join().unwrap(), 2);
+            assert_eq!(one.join().unwrap(), 1);
+        });
+        assert_eq!(semaphore.available_permits(), 3);
+    }
+}
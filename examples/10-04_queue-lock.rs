@@ -0,0 +1,259 @@
+//! # キューロック（MCSロック）
+//!
+//! 04-03のスピンロックは、待機しているすべてのスレッドが同じ`locked`フラグをスピンするため、
+//! 競合が激しくなると、ロックが解放されるたびにキャッシュラインの奪い合いが発生する。
+//! MCSロックは、待機しているスレッドを連結リストのキューに並べ、各スレッドは自分のノードのフラグだけを
+//! スピンする。
+//! ロックを解放するスレッドは、キューの次のスレッドのフラグだけを更新して、ロックを直接引き渡す。
+//! したがって、キャッシュラインの奪い合いが発生せず、ロックは到着した順（FIFO）に獲得される。
+//!
+//! ノードは待機するスレッドのスタックに置くことができるように、`lock`の引数で受け取る。
+//! ガードはノードを借用するため、ガードが存在する間はノードを移動したり再利用したりできない。
+//!
+//! `main`は、テスト・アンド・セット（TAS）方式のスピンロックと比較するベンチマークである。
+//! 計測する場合は`cargo run --release --example 10-04_queue-lock`で実行すること。
+//! ただし、ロックを引き渡す相手のスレッドがCPUを割り当てられていないと、そのスレッドが実行されるまで
+//! 誰もロックを獲得できないため、スレッドの数がCPUコアの数を超えると、キューロックの性能は大きく低下する。
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::time::{Duration, Instant};
+
+#[path = "shared/backoff.rs"]
+mod backoff;
+
+use backoff::Backoff;
+
+/// キューに並ぶ、待機しているスレッドごとのノード
+pub struct QueueNode {
+    /// キューで次に並んでいるスレッドのノード
+    next: AtomicPtr<QueueNode>,
+    /// 前のスレッドがロックを保持している間は`true`
+    locked: AtomicBool,
+}
+
+pub struct QueueLock<T> {
+    /// キューの末尾のノード（ロックされていない場合はヌル）
+    tail: AtomicPtr<QueueNode>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for QueueLock<T> where T: Send {}
+
+pub struct Guard<'a, T> {
+    lock: &'a QueueLock<T>,
+    node: &'a QueueNode,
+}
+
+unsafe impl<T> Send for Guard<'_, T> where T: Send {}
+unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
+
+impl QueueNode {
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicPtr::new(ptr::null_mut()),
+            locked: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for QueueNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> QueueLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// `node`をキューの末尾に追加し、ロックを獲得するまで待機する。
+    pub fn lock<'a>(&'a self, node: &'a mut QueueNode) -> Guard<'a, T> {
+        // 可変参照を受け取っているため、ノードは他のスレッドから参照されていない。
+        *node.next.get_mut() = ptr::null_mut();
+        *node.locked.get_mut() = true;
+        let node = &*node;
+
+        // Releaseにより、後続のスレッドは初期化したノードを観測できる。
+        // Acquireにより、キューが空だった場合は、前にロックを解放したスレッドの操作を観測できる。
+        let prev = self
+            .tail
+            .swap(ptr::from_ref(node).cast_mut(), Ordering::AcqRel);
+        if !prev.is_null() {
+            // 安全性: 前のスレッドは、後続のスレッドが`next`に書き込むまで`unlock`を完了しないため、
+            // 前のノードは有効である。
+            unsafe {
+                (*prev)
+                    .next
+                    .store(ptr::from_ref(node).cast_mut(), Ordering::Release)
+            };
+            // 自分のノードのフラグだけをスピンする。
+            // ロックを引き渡されるスレッドがCPUを割り当てられていないと、他のスレッドはロックを獲得できないため、
+            // CPUが少ない環境で全体が停止しないように、`Backoff`で一定回数スピンした後はCPUを譲る。
+            let mut backoff = Backoff::new();
+            while node.locked.load(Ordering::Acquire) {
+                backoff.snooze();
+            }
+        }
+        Guard { lock: self, node }
+    }
+}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        let node = ptr::from_ref(self.node).cast_mut();
+        let mut next = self.node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // 後続のスレッドがいなければ、キューを空にする。
+            if self
+                .lock
+                .tail
+                .compare_exchange(node, ptr::null_mut(), Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                return;
+            }
+            // 後続のスレッドが`tail`を更新したが、まだ`next`に書き込んでいないため、書き込むまで待機する。
+            let mut backoff = Backoff::new();
+            loop {
+                next = self.node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                backoff.snooze();
+            }
+        }
+        // 後続のスレッドに、ロックを直接引き渡す。
+        // 安全性: 後続のスレッドは`locked`が`false`になるまで待機しているため、そのノードは有効である。
+        unsafe { (*next).locked.store(false, Ordering::Release) };
+    }
+}
+
+/// 比較対象のテスト・アンド・セット方式のスピンロック
+pub struct TasLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for TasLock<T> where T: Send {}
+
+impl<T> TasLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// ロックを保持している間に`f`を実行する。
+    pub fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut backoff = Backoff::new();
+        while self.locked.swap(true, Ordering::Acquire) {
+            backoff.snooze();
+        }
+        let result = f(unsafe { &mut *self.value.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+const ITERATIONS: usize = 20_000;
+
+fn bench(threads: usize, increment: impl Fn() + Sync) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..ITERATIONS {
+                    increment();
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn main() {
+    for threads in [2, 8, 32] {
+        let tas = TasLock::new(0);
+        std::hint::black_box(&tas);
+        let duration = bench(threads, || tas.with_lock(|v| *v += 1));
+        assert_eq!(tas.with_lock(|v| *v), threads * ITERATIONS);
+        println!("TAS   {threads:>2} threads: locked {threads}x{ITERATIONS} times in {duration:?}");
+
+        let queue = QueueLock::new(0);
+        std::hint::black_box(&queue);
+        let duration = bench(threads, || *queue.lock(&mut QueueNode::new()) += 1);
+        assert_eq!(*queue.lock(&mut QueueNode::new()), threads * ITERATIONS);
+        println!("Queue {threads:>2} threads: locked {threads}x{ITERATIONS} times in {duration:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_keeps_count_exact() {
+        let lock = QueueLock::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let mut node = QueueNode::new();
+                    for _ in 0..10_000 {
+                        // 解放した後は、同じノードを再利用できる。
+                        *lock.lock(&mut node) += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock(&mut QueueNode::new()), 80_000);
+    }
+
+    #[test]
+    fn lock_is_acquired_in_fifo_order() {
+        const N: usize = 8;
+        let lock = QueueLock::new(Vec::new());
+        let mut node = QueueNode::new();
+        let guard = lock.lock(&mut node);
+
+        std::thread::scope(|s| {
+            for i in 0..N {
+                let tail = lock.tail.load(Ordering::Relaxed);
+                let lock = &lock;
+                s.spawn(move || {
+                    lock.lock(&mut QueueNode::new()).push(i);
+                });
+                // スレッド`i`がキューに並ぶまで待ってから、次のスレッドを起動する。
+                while lock.tail.load(Ordering::Relaxed) == tail {
+                    std::thread::yield_now();
+                }
+            }
+            drop(guard);
+        });
+
+        // キューに並んだ順にロックを獲得したはず。
+        assert_eq!(*lock.lock(&mut node), (0..N).collect::<Vec<_>>());
+        // すべてのスレッドがロックを解放した後は、キューは空のはず。
+        assert!(lock.tail.load(Ordering::Relaxed).is_null());
+    }
+}
@@ -0,0 +1,172 @@
+//! # シーケンスロック
+//!
+//! シーケンスロックは、読み込むスレッドがロックを獲得せずに値をコピーし、コピーしている間に
+//! 書き込みがあった場合は、読み込みをやり直す同期機構である。
+//! 読み込むスレッドは共有するメモリに何も書き込まないため、多数のスレッドが同時に読み込んでも
+//! キャッシュラインの奪い合いが発生せず、書き込むスレッドが読み込むスレッドに妨げられることもない。
+//!
+//! 書き込むスレッドは、書き込む前にシーケンス番号を奇数にし、書き込んだ後に偶数にする。
+//! 読み込むスレッドは、シーケンス番号が偶数であることを確認してから値をコピーし、コピーした後に
+//! シーケンス番号が変化していないことを確認する。
+//!
+//! 読み込むスレッドは、書き込み中の値をコピーする可能性がある（その場合は破棄してやり直す）。
+//! これは厳密にはRustのメモリモデルにおけるデータ競合であるため、`read_volatile`で読み込み、
+//! 値を`T: Copy`に制限して、コピーした値を検証するまで使用しないようにしている。
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering, fence};
+use std::time::Duration;
+
+pub struct SeqLock<T> {
+    /// 書き込み中は奇数、それ以外は偶数
+    sequence: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+/// 読み込むスレッドは値をコピーして受け取るため、`T: Send`であれば`Sync`である。
+unsafe impl<T> Sync for SeqLock<T> where T: Copy + Send {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            sequence: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// 値をコピーして返す。
+    ///
+    /// コピーしている間に書き込みがあった場合は、書き込みが完了してからやり直す。
+    pub fn read(&self) -> T {
+        loop {
+            // Acquireロードにより、偶数を観測した場合は、その番号にした書き込みを観測できる。
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                // 書き込み中のため、完了するまでスピンする。
+                std::hint::spin_loop();
+                continue;
+            }
+            let value = unsafe { self.data.get().read_volatile() };
+            // Acquireフェンスにより、値の読み込みが、次のシーケンス番号の読み込みより後に
+            // 並び替えられないようにする。
+            // 値の読み込みで書き込み中の値を観測した場合は、次の読み込みで奇数または
+            // 増えた番号を観測する。
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return value;
+            }
+        }
+    }
+
+    /// `f`で値を更新する。
+    ///
+    /// 書き込むスレッド同士は、シーケンス番号を偶数から奇数にする`compare_exchange`で排他制御する。
+    pub fn write<F: FnOnce(&mut T)>(&self, f: F) {
+        let mut sequence = self.sequence.load(Ordering::Relaxed);
+        loop {
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                sequence = self.sequence.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.sequence.compare_exchange_weak(
+                sequence,
+                sequence + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(s) => sequence = s,
+            }
+        }
+        // Releaseフェンスにより、奇数のシーケンス番号が、値の書き込みより前に観測されるようにする。
+        fence(Ordering::Release);
+        f(unsafe { &mut *self.data.get() });
+        // Releaseストアにより、偶数のシーケンス番号を観測したスレッドは、書き込んだ値を観測できる。
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+}
+
+fn main() {
+    // 位置と速度などのように、まとめて一貫した値を読み込む必要がある小さな値を想定する。
+    let position = SeqLock::new((0.0_f64, 0.0_f64));
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 1..=5 {
+                position.write(|p| *p = (i as f64, i as f64 * 2.0));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+        for _ in 0..5 {
+            let (x, y) = position.read();
+            println!("x: {x}, y: {y}");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn readers_never_observe_partial_writes() {
+        let lock = SeqLock::new([0_u64; 4]);
+        let stop = AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut last = 0;
+                        let mut reads = 0;
+                        while !stop.load(Ordering::Relaxed) {
+                            let values = lock.read();
+                            // 4つの値は、すべて同じ書き込み（バージョン）のものであるはず。
+                            assert!(
+                                values.iter().all(|&v| v == values[0]),
+                                "partially updated: {values:?}"
+                            );
+                            // バージョンは単調増加するはず。
+                            assert!(last <= values[0]);
+                            last = values[0];
+                            reads += 1;
+                        }
+                        reads
+                    })
+                })
+                .collect();
+
+            for version in 1..=100_000 {
+                lock.write(|values| {
+                    for v in values.iter_mut() {
+                        *v = version;
+                    }
+                });
+            }
+            stop.store(true, Ordering::Relaxed);
+            for reader in readers {
+                assert!(0 < reader.join().unwrap());
+            }
+        });
+        assert_eq!(lock.read(), [100_000; 4]);
+    }
+
+    #[test]
+    fn concurrent_writers_are_exclusive() {
+        let lock = SeqLock::new((0_u64, 0_u64));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        lock.write(|(a, b)| {
+                            *a += 1;
+                            *b += 1;
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(lock.read(), (40_000, 40_000));
+    }
+}
@@ -0,0 +1,137 @@
+//! # バリア
+//!
+//! バリアは、N個のスレッドがすべて到着するまで、到着したスレッドを待機させる同期機構である。
+//! 並列アルゴリズムをいくつかのフェーズに分けて、すべてのスレッドが前のフェーズを終えてから
+//! 次のフェーズに進む場合に使用する。
+//!
+//! 最後に到着したスレッドは、到着したスレッドの数を0に戻してから世代（generation）を1つ進め、
+//! 待機しているすべてのスレッドを起こす。
+//! 待機しているスレッドは、到着した時点の世代から世代が進むまで待機する。
+//! 到着したスレッドの数ではなく世代で待機することで、バリアを再利用した場合に、次の世代に
+//! 早く到着したスレッドが、前の世代の通知で誤って起こされることを防ぐ。
+//!
+//! `atomic_wait`は`AtomicU32`のみを待機できるため、スレッドの数と世代は`u32`で表現する。
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use atomic_wait::{wait, wake_all};
+
+pub struct Barrier {
+    /// 待ち合わせるスレッドの数
+    n: u32,
+    /// 現在の世代に到着したスレッドの数
+    count: AtomicU32,
+    /// すべてのスレッドが到着するたびに1つ増える世代
+    generation: AtomicU32,
+}
+
+/// `Barrier::wait`の結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+
+impl Barrier {
+    pub const fn new(n: u32) -> Self {
+        assert!(0 < n, "the number of threads must be greater than 0");
+        Self {
+            n,
+            count: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+        }
+    }
+
+    /// すべてのスレッドが到着するまで待機する。
+    pub fn wait(&self) -> BarrierWaitResult {
+        // 自分が到着するまで世代は進まないため、到着する前に世代を読み込んでおく。
+        let generation = self.generation.load(Ordering::Acquire);
+        // AcqRelにより、最後に到着したスレッドは、他のスレッドが到着する前の操作を観測できる。
+        if self.count.fetch_add(1, Ordering::AcqRel) + 1 < self.n {
+            // 世代が進むまで待機する。
+            // Acquireロードにより、最後に到着したスレッドを経由して、すべてのスレッドが
+            // 到着する前の操作を観測できる。
+            while self.generation.load(Ordering::Acquire) == generation {
+                wait(&self.generation, generation);
+            }
+            return BarrierWaitResult { is_leader: false };
+        }
+
+        // 最後に到着したスレッドは、次の世代のために到着したスレッドの数を0に戻す。
+        // 待機しているスレッドは世代が進んだことを観測するまで`wait`から戻らないため、
+        // 次の世代の`fetch_add`は、このストアの後に行われる。
+        self.count.store(0, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Release);
+        wake_all(&self.generation);
+        BarrierWaitResult { is_leader: true }
+    }
+}
+
+impl BarrierWaitResult {
+    /// 最後に到着したスレッドであるかを返す。
+    ///
+    /// 各世代で、1つのスレッドだけが`true`を受け取る。
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+fn main() {
+    let barrier = Barrier::new(3);
+    std::thread::scope(|s| {
+        for i in 0..3 {
+            let barrier = &barrier;
+            s.spawn(move || {
+                std::thread::sleep(Duration::from_millis(10 * i));
+                println!("thread {i} finished phase 1");
+                if barrier.wait().is_leader() {
+                    println!("thread {i} was the last to arrive");
+                }
+                println!("thread {i} started phase 2");
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn all_threads_pass_phase_before_any_finishes_next() {
+        const N: usize = 8;
+        const PHASES: usize = 100;
+        let barrier = Barrier::new(N as u32);
+        let sequence = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for _ in 0..N {
+                s.spawn(|| {
+                    for phase in 0..PHASES {
+                        // フェーズを終えたスレッドの数を数える。
+                        let seq = sequence.fetch_add(1, Ordering::Relaxed);
+                        // 前のフェーズをすべてのスレッドが終えてから、このフェーズに入ったはず。
+                        assert!(phase * N <= seq && seq < (phase + 1) * N);
+                        if barrier.wait().is_leader() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // バリアを通過した時点で、すべてのスレッドがこのフェーズを終えているはず。
+                        assert!((phase + 1) * N <= sequence.load(Ordering::Relaxed));
+                    }
+                });
+            }
+        });
+
+        // 各世代で、リーダーは1つだけのはず。
+        assert_eq!(leaders.load(Ordering::Relaxed), PHASES);
+        assert_eq!(sequence.load(Ordering::Relaxed), N * PHASES);
+    }
+
+    #[test]
+    fn single_thread_barrier_does_not_block() {
+        let barrier = Barrier::new(1);
+        assert!(barrier.wait().is_leader());
+        assert!(barrier.wait().is_leader());
+    }
+}
@@ -0,0 +1,130 @@
+//! # カウントダウンラッチ
+//!
+//! カウントダウンラッチは、カウンタが0になるまで待機するスレッドをブロックする同期機構である。
+//! `count_down`でカウンタを1つ減らし、カウンタが0になった時点で待機しているすべてのスレッドを起こす。
+//!
+//! バリアと異なり、カウンタは増えるのではなく減り、`count_down`はどのスレッドからでも呼び出せる。
+//! また、`count_down`を呼び出したスレッドは待機しない。
+//! カウンタが一度0になると元に戻らないため、バリアのように再利用することはできない。
+//!
+//! `atomic_wait`は`AtomicU32`のみを待機できるため、カウンタは`u32`で表現する。
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use atomic_wait::{wait, wake_all};
+
+pub struct CountDownLatch {
+    count: AtomicU32,
+}
+
+impl CountDownLatch {
+    pub const fn new(n: u32) -> Self {
+        Self {
+            count: AtomicU32::new(n),
+        }
+    }
+
+    /// カウンタを1つ減らし、0になった場合は待機しているすべてのスレッドを起こす。
+    ///
+    /// カウンタがすでに0の場合は何もしない。
+    pub fn count_down(&self) {
+        // Releaseにより、待機していたスレッドは、`count_down`を呼び出す前の操作を観測できる。
+        // 各スレッドの`fetch_update`はリリースシーケンスを形成するため、0を観測したスレッドは、
+        // 最後のスレッドだけでなく、すべてのスレッドの操作を観測できる。
+        let result = self
+            .count
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            });
+        if result == Ok(1) {
+            wake_all(&self.count);
+        }
+    }
+
+    /// カウンタが0になるまで待機する。
+    pub fn wait(&self) {
+        loop {
+            let count = self.count.load(Ordering::Acquire);
+            if count == 0 {
+                return;
+            }
+            // `wait`の呼び出し時点で`count`が変化していれば、待機せずに復帰する。
+            wait(&self.count, count);
+        }
+    }
+
+    /// 待機せずに、カウンタが0になったかを返す。
+    pub fn try_wait(&self) -> bool {
+        self.count.load(Ordering::Acquire) == 0
+    }
+
+    /// 現在のカウンタの値を返す。
+    pub fn count(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+fn main() {
+    let latch = CountDownLatch::new(3);
+    std::thread::scope(|s| {
+        for i in 0..3 {
+            let latch = &latch;
+            s.spawn(move || {
+                std::thread::sleep(Duration::from_millis(10 * (i + 1)));
+                println!("worker {i} finished");
+                latch.count_down();
+            });
+        }
+        latch.wait();
+        println!("all workers finished");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn master_waits_for_all_workers() {
+        const WORKERS: usize = 8;
+        let latch = CountDownLatch::new(WORKERS as u32);
+        let finished = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for i in 0..WORKERS {
+                let (latch, finished) = (&latch, &finished);
+                s.spawn(move || {
+                    std::thread::sleep(Duration::from_millis(5 * i as u64));
+                    finished.fetch_add(1, Ordering::Relaxed);
+                    latch.count_down();
+                });
+            }
+
+            // すべてのワーカーが`count_down`を呼び出した後に、待機が解除されるはず。
+            latch.wait();
+            assert_eq!(finished.load(Ordering::Relaxed), WORKERS);
+            assert!(latch.try_wait());
+        });
+    }
+
+    #[test]
+    fn count_down_after_zero_is_noop() {
+        let latch = CountDownLatch::new(1);
+        assert!(!latch.try_wait());
+        latch.count_down();
+        assert!(latch.try_wait());
+
+        // カウンタが0の場合は、アンダーフローせずに0のままのはず。
+        latch.count_down();
+        assert_eq!(latch.count(), 0);
+        latch.wait();
+    }
+
+    #[test]
+    fn zero_latch_does_not_block() {
+        let latch = CountDownLatch::new(0);
+        assert!(latch.try_wait());
+        latch.wait();
+    }
+}
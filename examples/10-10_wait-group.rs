@@ -0,0 +1,162 @@
+//! # ウェイトグループ
+//!
+//! Go言語の`sync.WaitGroup`と同様に、実行中の処理の数を数え、すべての処理が完了するまで待機する。
+//! 処理を開始する前に`add`で数を増やし、処理が完了したら`done`で数を減らす。
+//! `wait`は数が0になるまで待機する。
+//!
+//! カウントダウンラッチと異なり、処理の数を事前に決める必要はなく、`add`で後から増やせる。
+//! また、数が0になった後に再び`add`することで、再利用できる。
+//!
+//! `atomic_wait`は`AtomicU32`のみを待機できるため、符号付きのカウンタとは別に、カウンタが0になるたびに
+//! 1つ増やす`epoch`を用意し、待機するスレッドは`epoch`が変化するまで待機する。
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::time::Duration;
+
+use atomic_wait::{wait, wake_all};
+
+pub struct WaitGroup {
+    /// 完了していない処理の数
+    count: AtomicI32,
+    /// カウンタが0になった回数
+    epoch: AtomicU32,
+}
+
+impl WaitGroup {
+    pub const fn new() -> Self {
+        Self {
+            count: AtomicI32::new(0),
+            epoch: AtomicU32::new(0),
+        }
+    }
+
+    /// カウンタに`delta`を加える。カウンタが0になった場合は、待機しているすべてのスレッドを起こす。
+    ///
+    /// # パニック
+    ///
+    /// カウンタが負になる場合は、Go言語と同様にパニックする。
+    /// この場合、カウンタは変更されない。
+    pub fn add(&self, delta: i32) {
+        // Releaseにより、カウンタが0になったことを観測したスレッドは、`done`を呼び出す前の操作を観測できる。
+        let result = self
+            .count
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |count| {
+                count.checked_add(delta).filter(|&count| 0 <= count)
+            });
+        match result {
+            Ok(count) if count + delta == 0 => {
+                // カウンタを更新した後に`epoch`を更新するため、新しい`epoch`を観測したスレッドは、
+                // 0になったカウンタを観測できる。
+                self.epoch.fetch_add(1, Ordering::Release);
+                wake_all(&self.epoch);
+            }
+            Ok(_) => {}
+            Err(_) => panic!("negative WaitGroup counter"),
+        }
+    }
+
+    /// カウンタを1つ減らす（`add(-1)`と同じ）。
+    pub fn done(&self) {
+        self.add(-1);
+    }
+
+    /// カウンタが0になるまで待機する。
+    ///
+    /// 複数のスレッドから同時に呼び出すことができる。
+    pub fn wait(&self) {
+        loop {
+            // カウンタを確認する前に`epoch`を読み込んでおくことで、カウンタを確認した後に0になった場合でも、
+            // `epoch`が変化しているため、`wait`は待機せずに復帰する。
+            let epoch = self.epoch.load(Ordering::Acquire);
+            if self.count.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            wait(&self.epoch, epoch);
+        }
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn main() {
+    let wg = WaitGroup::new();
+    std::thread::scope(|s| {
+        for i in 0..3 {
+            wg.add(1);
+            let wg = &wg;
+            s.spawn(move || {
+                std::thread::sleep(Duration::from_millis(10 * (i + 1)));
+                println!("worker {i} finished");
+                wg.done();
+            });
+        }
+        wg.wait();
+        println!("all workers finished");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn orchestrator_waits_for_all_workers() {
+        const WORKERS: usize = 8;
+        let wg = WaitGroup::new();
+        let finished = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            wg.add(WORKERS as i32);
+            for i in 0..WORKERS {
+                let (wg, finished) = (&wg, &finished);
+                s.spawn(move || {
+                    std::thread::sleep(Duration::from_millis(5 * i as u64));
+                    finished.fetch_add(1, Ordering::Relaxed);
+                    wg.done();
+                });
+            }
+
+            // 複数のスレッドから同時に待機しても、すべてのワーカーが完了した後に復帰するはず。
+            let waiters: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        wg.wait();
+                        finished.load(Ordering::Relaxed)
+                    })
+                })
+                .collect();
+            wg.wait();
+            assert_eq!(finished.load(Ordering::Relaxed), WORKERS);
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap(), WORKERS);
+            }
+        });
+    }
+
+    #[test]
+    fn wait_group_can_be_reused() {
+        let wg = WaitGroup::new();
+        wg.wait();
+        for _ in 0..3 {
+            wg.add(2);
+            std::thread::scope(|s| {
+                s.spawn(|| wg.done());
+                s.spawn(|| wg.done());
+                wg.wait();
+            });
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "negative WaitGroup counter")]
+    fn negative_counter_panics() {
+        let wg = WaitGroup::new();
+        wg.add(1);
+        wg.done();
+        wg.done();
+    }
+}
@@ -0,0 +1,280 @@
+//! # Michael-Scottキュー
+//!
+//! Michael-Scottキューは、ロックを使用しない（ロックフリーな）複数生産者・複数消費者（MPMC）のキューである。
+//! キューは単方向の連結リストであり、先頭には値を持たない番兵（sentinel）ノードを置く。
+//!
+//! - `push`は、末尾のノードの`next`を`compare_exchange`で新しいノードに連結した後、`tail`を新しいノードに
+//!   移動する。
+//! - `pop`は、番兵ノードの次のノードから値を取り出し、`head`をそのノードに移動する。
+//!   取り出したノードが、新しい番兵ノードになる。
+//!
+//! `push`は連結と`tail`の移動の2段階で行われるため、他のスレッドが`tail`の`next`がヌルでないこと
+//! （移動が完了していないこと）を観測する場合がある。
+//! その場合は、自分の操作の前に`tail`の移動を手伝う（helping）ことで、`push`したスレッドが停止しても、
+//! 他のスレッドは処理を進められる。
+//!
+//! `pop`したスレッドが古い番兵ノードをすぐに解放すると、同時にそのノードを読み込んでいる他のスレッドが
+//! 解放済みのメモリにアクセスしてしまう。
+//! そこで、`push`と`pop`はノードを読み込む前に`EpochGuard::pin`でスレッドを固定し、古い番兵ノードは
+//! `defer_drop`で、固定しているスレッドがいなくなってから解放する（10-13のエポックベースのメモリ回収）。
+//! 固定しているスレッドがいる間はノードが解放されず、同じアドレスに再利用されないため、ABA問題も発生しない。
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+#[path = "shared/backoff.rs"]
+mod backoff;
+#[path = "shared/epoch.rs"]
+mod epoch;
+
+use backoff::Backoff;
+use epoch::EpochGuard;
+
+struct Node<T> {
+    /// 番兵ノードの値は初期化されていないか、すでに取り出されている。
+    value: UnsafeCell<MaybeUninit<T>>,
+    next: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new(value: MaybeUninit<T>) -> *mut Self {
+        Box::into_raw(Box::new(Self {
+            value: UnsafeCell::new(value),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+}
+
+pub struct MsQueue<T> {
+    /// 番兵ノード
+    head: AtomicPtr<Node<T>>,
+    /// 末尾のノード（`push`の途中では、末尾の1つ前のノードを指す場合がある）
+    tail: AtomicPtr<Node<T>>,
+}
+
+unsafe impl<T> Send for MsQueue<T> where T: Send {}
+unsafe impl<T> Sync for MsQueue<T> where T: Send {}
+
+impl<T> MsQueue<T> {
+    pub fn new() -> Self {
+        let sentinel = Node::new(MaybeUninit::uninit());
+        Self {
+            head: AtomicPtr::new(sentinel),
+            tail: AtomicPtr::new(sentinel),
+        }
+    }
+
+    /// 値をキューの末尾に追加する。
+    pub fn push(&self, value: T) {
+        let node = Node::new(MaybeUninit::new(value));
+        let _guard = EpochGuard::pin();
+        let mut backoff = Backoff::new();
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            // 安全性: 固定している間は、`pop`で番兵ではなくなったノードも解放されない。
+            let next = unsafe { (*tail).next.load(Ordering::Acquire) };
+            if !next.is_null() {
+                // 他のスレッドの`push`が`tail`を移動していないため、移動を手伝ってから再試行する。
+                let _ =
+                    self.tail
+                        .compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+                continue;
+            }
+            // Releaseにより、新しいノードを観測したスレッドは、ノードの値を観測できる。
+            let linked = unsafe {
+                (*tail).next.compare_exchange(
+                    ptr::null_mut(),
+                    node,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                )
+            };
+            if linked.is_ok() {
+                // 失敗した場合は、他のスレッドがすでに移動を手伝っている。
+                let _ =
+                    self.tail
+                        .compare_exchange(tail, node, Ordering::Release, Ordering::Relaxed);
+                return;
+            }
+            // 他のスレッドが先に連結したため、競合が続かないように少し待ってから再試行する。
+            backoff.snooze();
+        }
+    }
+
+    /// キューの先頭から値を取り出す。キューが空の場合は`None`を返す。
+    pub fn pop(&self) -> Option<T> {
+        let guard = EpochGuard::pin();
+        let mut backoff = Backoff::new();
+        loop {
+            let head = self.head.load(Ordering::Acquire);
+            let tail = self.tail.load(Ordering::Acquire);
+            // 安全性: 固定している間は、`head`は解放されない。
+            let next = unsafe { (*head).next.load(Ordering::Acquire) };
+            if next.is_null() {
+                return None;
+            }
+            if head == tail {
+                // `tail`が移動していないため、`head`が`tail`を追い越さないように、先に移動を手伝う。
+                let _ =
+                    self.tail
+                        .compare_exchange(tail, next, Ordering::Release, Ordering::Relaxed);
+                continue;
+            }
+            if self
+                .head
+                .compare_exchange(head, next, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                // 安全性: `head`を移動できたスレッドだけが、`next`の値を取り出す。
+                // `next`は新しい番兵ノードになるため、値は以降読み込まれない。
+                let value = unsafe { (*(*next).value.get()).assume_init_read() };
+                // 古い番兵ノードは、他のスレッドが固定を解除した後に解放する。
+                // 値は`MaybeUninit`であるため、ノードを解放しても値はドロップされない。
+                unsafe { guard.defer_drop(head) };
+                return Some(value);
+            }
+            // 他のスレッドが先に取り出したため、少し待ってから再試行する。
+            backoff.snooze();
+        }
+    }
+
+    /// キューが空であるかを返す。
+    pub fn is_empty(&self) -> bool {
+        let _guard = EpochGuard::pin();
+        let head = self.head.load(Ordering::Acquire);
+        unsafe { (*head).next.load(Ordering::Acquire).is_null() }
+    }
+}
+
+impl<T> Default for MsQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for MsQueue<T> {
+    fn drop(&mut self) {
+        // 古い番兵ノードは`pop`で解放を延期している。
+        // 現在の番兵ノードの値はドロップせず、それ以降のノードの値をドロップする。
+        let mut sentinel = unsafe { Box::from_raw(*self.head.get_mut()) };
+        let mut node = *sentinel.next.get_mut();
+        while !node.is_null() {
+            let mut boxed = unsafe { Box::from_raw(node) };
+            unsafe { boxed.value.get_mut().assume_init_drop() };
+            node = *boxed.next.get_mut();
+        }
+    }
+}
+
+fn main() {
+    let queue = MsQueue::new();
+    std::thread::scope(|s| {
+        for id in 0..2 {
+            let queue = &queue;
+            s.spawn(move || {
+                for i in 0..3 {
+                    queue.push(format!("producer {id}: message {i}"));
+                }
+            });
+        }
+    });
+    while let Some(message) = queue.pop() {
+        println!("{message}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn single_thread_is_fifo() {
+        let queue = MsQueue::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+        for i in 0..10 {
+            queue.push(i);
+        }
+        assert!(!queue.is_empty());
+        assert_eq!(
+            std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn mpmc_preserves_per_producer_order() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const MESSAGES: usize = 1_000_000;
+        const PER_PRODUCER: usize = MESSAGES / PRODUCERS;
+        let queue = MsQueue::new();
+        let consumed = AtomicUsize::new(0);
+
+        let received = std::thread::scope(|s| {
+            for producer in 0..PRODUCERS {
+                let queue = &queue;
+                s.spawn(move || {
+                    for seq in 0..PER_PRODUCER {
+                        queue.push((producer, seq));
+                    }
+                });
+            }
+            let consumers: Vec<_> = (0..CONSUMERS)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut last = [None; PRODUCERS];
+                        let mut count = 0;
+                        while consumed.load(Ordering::Relaxed) < MESSAGES {
+                            let Some((producer, seq)) = queue.pop() else {
+                                std::thread::yield_now();
+                                continue;
+                            };
+                            // 同じ生産者のメッセージは、送信した順に受信するはず。
+                            assert!(last[producer] < Some(seq));
+                            last[producer] = Some(seq);
+                            count += 1;
+                            consumed.fetch_add(1, Ordering::Relaxed);
+                        }
+                        count
+                    })
+                })
+                .collect();
+            consumers
+                .into_iter()
+                .map(|c| c.join().unwrap())
+                .sum::<usize>()
+        });
+
+        // すべてのメッセージを、1回ずつ受信したはず。
+        assert_eq!(received, MESSAGES);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_releases_remaining_values() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let queue = MsQueue::new();
+        for _ in 0..5 {
+            queue.push(DetectDrop);
+        }
+        drop(queue.pop());
+        drop(queue.pop());
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 2);
+
+        // 取り出されていない値だけが、1回ずつドロップされるはず。
+        drop(queue);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 5);
+    }
+}
@@ -0,0 +1,226 @@
+//! # `AtomicCell<T>`
+//!
+//! `std::cell::Cell<T>`は値をコピーして読み書きする型であるが、シングルスレッドでしか使用できない。
+//! `AtomicCell<T>`は、`Copy`な値をスレッド間で共有して読み書きする。
+//!
+//! 8バイト以下の型は、値のビット表現を`AtomicU64`に格納することで、ロックを使用せずに読み書きする。
+//! それより大きい型は、ミューテックスで保護する。
+//! どちらを使用するかは`T`のサイズによりコンパイル時に決まり、`AtomicCell::is_lock_free`で確認できる。
+//!
+//! `compare_exchange`は、`PartialEq`ではなくビット表現で値を比較する。
+//! したがって、`f64`の`0.0`と`-0.0`は異なる値として扱い、同じビット表現の`NaN`は等しい値として扱う。
+//! また、`T`はパディングを含まない型であること（パディングのバイトは初期化されていないため、
+//! ビット表現として読み込むことができない）。
+use std::marker::PhantomData;
+use std::mem::{align_of, size_of};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct AtomicCell<T> {
+    repr: Repr<T>,
+}
+
+enum Repr<T> {
+    /// 値のビット表現を格納する。
+    LockFree(AtomicU64, PhantomData<T>),
+    /// 8バイトを超える値は、ミューテックスで保護する。
+    Locked(Mutex<T>),
+}
+
+impl<T: Copy> AtomicCell<T> {
+    /// `AtomicU64`に格納できるか
+    const IS_LOCK_FREE: bool = size_of::<T>() <= size_of::<u64>() && align_of::<T>() <= 8;
+
+    pub fn new(value: T) -> Self {
+        let repr = if Self::IS_LOCK_FREE {
+            Repr::LockFree(AtomicU64::new(to_bits(value)), PhantomData)
+        } else {
+            Repr::Locked(Mutex::new(value))
+        };
+        Self { repr }
+    }
+
+    /// ロックを使用せずに読み書きするかを返す。
+    pub const fn is_lock_free() -> bool {
+        Self::IS_LOCK_FREE
+    }
+
+    pub fn load(&self) -> T {
+        match &self.repr {
+            // 安全性: `LockFree`は`T`のビット表現だけを格納している。
+            Repr::LockFree(bits, _) => unsafe { from_bits(bits.load(Ordering::Acquire)) },
+            Repr::Locked(value) => *value.lock().unwrap(),
+        }
+    }
+
+    pub fn store(&self, value: T) {
+        match &self.repr {
+            Repr::LockFree(bits, _) => bits.store(to_bits(value), Ordering::Release),
+            Repr::Locked(current) => *current.lock().unwrap() = value,
+        }
+    }
+
+    /// 値を置き換え、置き換える前の値を返す。
+    pub fn swap(&self, value: T) -> T {
+        match &self.repr {
+            Repr::LockFree(bits, _) => unsafe {
+                from_bits(bits.swap(to_bits(value), Ordering::AcqRel))
+            },
+            Repr::Locked(current) => std::mem::replace(&mut *current.lock().unwrap(), value),
+        }
+    }
+
+    /// 現在の値が`current`とビット表現で等しい場合に`new`に置き換える。
+    ///
+    /// 置き換えた場合は`Ok`で、置き換えなかった場合は`Err`で、現在の値を返す。
+    pub fn compare_exchange(&self, current: T, new: T) -> Result<T, T> {
+        match &self.repr {
+            Repr::LockFree(bits, _) => bits
+                .compare_exchange(
+                    to_bits(current),
+                    to_bits(new),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .map(|b| unsafe { from_bits(b) })
+                .map_err(|b| unsafe { from_bits(b) }),
+            Repr::Locked(value) => {
+                let mut value = value.lock().unwrap();
+                let old = *value;
+                if as_bytes(&old) == as_bytes(&current) {
+                    *value = new;
+                    Ok(old)
+                } else {
+                    Err(old)
+                }
+            }
+        }
+    }
+}
+
+impl<T: Copy + Default> Default for AtomicCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// 値のビット表現を、下位のバイトから`u64`にコピーする。
+fn to_bits<T: Copy>(value: T) -> u64 {
+    debug_assert!(size_of::<T>() <= size_of::<u64>());
+    let mut bits = 0_u64;
+    // 安全性: `T`は8バイト以下であるため、`bits`の範囲に収まる。
+    unsafe {
+        ptr::copy_nonoverlapping(
+            ptr::from_ref(&value).cast::<u8>(),
+            ptr::from_mut(&mut bits).cast::<u8>(),
+            size_of::<T>(),
+        );
+    }
+    bits
+}
+
+/// # Safety
+///
+/// `bits`は`to_bits`で`T`から変換した値でなければならない。
+unsafe fn from_bits<T: Copy>(bits: u64) -> T {
+    // `u64`のアラインメントは`T`のアラインメント以上であるため、そのまま読み込める。
+    unsafe { ptr::from_ref(&bits).cast::<T>().read() }
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(ptr::from_ref(value).cast::<u8>(), size_of::<T>()) }
+}
+
+fn main() {
+    let position = AtomicCell::new((0_u32, 0_u32));
+    std::thread::scope(|s| {
+        s.spawn(|| position.store((1, 2)));
+    });
+    println!(
+        "position: {:?} (lock free: {})",
+        position.load(),
+        AtomicCell::<(u32, u32)>::is_lock_free()
+    );
+
+    let large = AtomicCell::new([0_u64; 4]);
+    large.store([1, 2, 3, 4]);
+    println!(
+        "large: {:?} (lock free: {})",
+        large.load(),
+        AtomicCell::<[u64; 4]>::is_lock_free()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u32_cell() {
+        let cell = AtomicCell::new(1_u32);
+        assert!(AtomicCell::<u32>::is_lock_free());
+        assert_eq!(cell.load(), 1);
+        cell.store(2);
+        assert_eq!(cell.swap(3), 2);
+        assert_eq!(cell.compare_exchange(3, 4), Ok(3));
+        assert_eq!(cell.compare_exchange(3, 5), Err(4));
+        assert_eq!(cell.load(), 4);
+    }
+
+    #[test]
+    fn f64_cell_compares_bits() {
+        let cell = AtomicCell::new(1.5_f64);
+        assert!(AtomicCell::<f64>::is_lock_free());
+        assert_eq!(cell.swap(0.0), 1.5);
+        // ビット表現で比較するため、`-0.0`は`0.0`と一致しないはず。
+        assert_eq!(cell.compare_exchange(-0.0, 2.0), Err(0.0));
+        assert_eq!(cell.compare_exchange(0.0, f64::NAN), Ok(0.0));
+        // 同じビット表現の`NaN`は一致するはず。
+        assert!(cell.compare_exchange(f64::NAN, 3.0).is_ok());
+        assert_eq!(cell.load(), 3.0);
+    }
+
+    #[test]
+    fn tuple_cell() {
+        let cell = AtomicCell::new((1_u32, 2_u32));
+        assert!(AtomicCell::<(u32, u32)>::is_lock_free());
+        assert_eq!(cell.compare_exchange((1, 2), (3, 4)), Ok((1, 2)));
+        assert_eq!(cell.load(), (3, 4));
+    }
+
+    #[test]
+    fn large_type_falls_back_to_mutex() {
+        let cell = AtomicCell::new([0_u64; 4]);
+        assert!(!AtomicCell::<[u64; 4]>::is_lock_free());
+        assert_eq!(cell.swap([1; 4]), [0; 4]);
+        assert_eq!(cell.compare_exchange([0; 4], [2; 4]), Err([1; 4]));
+        assert_eq!(cell.compare_exchange([1; 4], [2; 4]), Ok([1; 4]));
+        assert_eq!(cell.load(), [2; 4]);
+    }
+
+    #[test]
+    fn concurrent_counter_is_exact() {
+        fn increment<T: Copy>(cell: &AtomicCell<T>, f: impl Fn(T) -> T) {
+            let mut current = cell.load();
+            while let Err(actual) = cell.compare_exchange(current, f(current)) {
+                current = actual;
+            }
+        }
+
+        let small = AtomicCell::new(0_u64);
+        let large = AtomicCell::new([0_u64; 2]);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        increment(&small, |v| v + 1);
+                        increment(&large, |[a, b]| [a + 1, b + 2]);
+                    }
+                });
+            }
+        });
+        assert_eq!(small.load(), 80_000);
+        assert_eq!(large.load(), [80_000, 160_000]);
+    }
+}
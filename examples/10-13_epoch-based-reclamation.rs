@@ -0,0 +1,446 @@
+//! # エポックベースのメモリ回収
+//!
+//! ロックフリーなデータ構造からノードを取り除いたスレッドは、すぐにそのノードを解放できない。
+//! 他のスレッドが、取り除かれる前に読み込んだポインタを使用して、そのノードにアクセスしている
+//! 可能性があるためである（安全なメモリ回収問題）。
+//! キューがドロップされるまでノードを解放しなければこの問題を回避できるが、データ構造を使用し続ける限り
+//! メモリが増え続ける。
+//!
+//! エポックベースの回収では、グローバルなエポック（世代番号）と、スレッドごとのローカルなエポックを管理する。
+//!
+//! - ノードにアクセスするスレッドは、アクセスする前に`EpochGuard::pin`でスレッドを固定（pin）し、
+//!   その時点のグローバルなエポックをローカルなエポックとして記録する。
+//! - ノードを取り除いたスレッドは、その時点のグローバルなエポックとともに、ノードを回収待ちのリストに追加する。
+//! - 固定されているすべてのスレッドのローカルなエポックが、グローバルなエポックに追い付いた場合にのみ、
+//!   グローバルなエポックを1つ進める。
+//! - グローバルなエポックが、ノードを取り除いたときから2つ進めば、そのノードにアクセスしている可能性のある
+//!   スレッドは存在しないため、ノードを解放できる。
+//!
+//! ノードは固定されたスレッドがいる間は解放されず、同じアドレスに再利用されないため、ABA問題も防げる。
+//! 回収の仕組み（`EpochGuard`）は`shared/epoch.rs`に実装し、10-11のMichael-Scottキューと共有している。
+//! ここでは、このメモリ回収を使用するTreiberスタックを実装する。
+//!
+//! また、設定やルーティングテーブルのように、ほとんど書き込まれずに頻繁に読み込まれる値のための
+//! `ReadMostly<T>`を実装する。
+//! 読み込みはスレッドを固定してポインタを読み込むだけであり、ロックを獲得せず、共有するカウンタも更新しない。
+//! 書き込みは新しい値を確保してポインタを置き換え、古い値の解放を、固定しているスレッドがいなくなるまで延期する。
+//!
+//! 実装を簡単にするため、参加しているスレッドの一覧は`Mutex`で保護している（スレッドの登録や、
+//! エポックを進めるときにだけロックする）。
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+#[path = "shared/epoch.rs"]
+mod epoch;
+
+use epoch::EpochGuard;
+
+/// Treiberスタック
+///
+/// `head`を`compare_exchange`で置き換えることで、ロックを使用せずにプッシュ、ポップする。
+pub struct TreiberStack<T> {
+    head: AtomicPtr<StackNode<T>>,
+}
+
+struct StackNode<T> {
+    /// ポップしたスレッドが値を取り出すため、ノードを解放するときに値をドロップしない。
+    value: ManuallyDrop<T>,
+    next: *mut StackNode<T>,
+}
+
+unsafe impl<T> Send for TreiberStack<T> where T: Send {}
+unsafe impl<T> Sync for TreiberStack<T> where T: Send {}
+
+impl<T> TreiberStack<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(StackNode {
+            value: ManuallyDrop::new(value),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // 安全性: ノードはまだ公開されていない。
+            unsafe { (*node).next = head };
+            // Releaseにより、ノードを観測したスレッドは、ノードの値を観測できる。
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let guard = EpochGuard::pin();
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head.is_null() {
+                return None;
+            }
+            // 安全性: 固定している間は、他のスレッドがポップしたノードも解放されない。
+            let next = unsafe { (*head).next };
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    // `head`を取り除いたスレッドだけが、値を取り出す。
+                    let value = unsafe { ptr::read(&*(*head).value) };
+                    unsafe { guard.defer_drop(head) };
+                    return Some(value);
+                }
+                Err(h) => head = h,
+            }
+        }
+    }
+}
+
+impl<T> Default for TreiberStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for TreiberStack<T> {
+    fn drop(&mut self) {
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            let mut boxed = unsafe { Box::from_raw(node) };
+            unsafe { ManuallyDrop::drop(&mut boxed.value) };
+            node = boxed.next;
+        }
+    }
+}
+
+/// ほとんど書き込まれずに、頻繁に読み込まれる値
+///
+/// 読み込んだ値への参照は`ReadGuard`が保持しており、ガードが存在する間は、値が書き換えられても
+/// 古い値は解放されない。
+pub struct ReadMostly<T> {
+    ptr: AtomicPtr<T>,
+    /// `ReadMostly<T>`は`T`を所有しているため、`T`のドロップを検査させる。
+    _marker: PhantomData<T>,
+}
+
+/// 複数のスレッドが`&T`を共有するため`T: Sync`を、古い値は最後に固定を解除したスレッドで解放されるため
+/// `T: Send`を要求する。
+unsafe impl<T> Send for ReadMostly<T> where T: Send {}
+unsafe impl<T> Sync for ReadMostly<T> where T: Send + Sync {}
+
+/// `ReadMostly::read`が返す、読み込んだ値への参照
+///
+/// `EpochGuard`を保持してスレッドを固定しているため、`EpochGuard`と同様に他のスレッドに送ることはできない。
+pub struct ReadGuard<'a, T> {
+    _guard: EpochGuard,
+    value: &'a T,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> ReadMostly<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            _marker: PhantomData,
+        }
+    }
+
+    /// スレッドを固定して、現在の値を読み込む。
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let guard = EpochGuard::pin();
+        // Acquireにより、値を書き込んだスレッドが初期化した内容を観測できる。
+        let ptr = self.ptr.load(Ordering::Acquire);
+        ReadGuard {
+            _guard: guard,
+            // 安全性: 固定している間は、置き換えられた値も解放されない。
+            value: unsafe { &*ptr },
+        }
+    }
+
+    /// 値を`value`に置き換える。古い値は、読み込んでいるスレッドがいなくなってから解放される。
+    pub fn write(&self, value: T) {
+        let new = Box::into_raw(Box::new(value));
+        let guard = EpochGuard::pin();
+        // Releaseにより、新しい値を読み込んだスレッドは値の内容を観測でき、
+        // Acquireにより、古い値をドロップする前に、古い値を書き込んだスレッドの初期化を観測できる。
+        let old = self.ptr.swap(new, Ordering::AcqRel);
+        // 安全性: 古い値は取り除かれたため、これから固定するスレッドは読み込めない。
+        unsafe { guard.defer_drop(old) };
+    }
+
+    /// 現在の値を複製して`f`で変更し、その間に他のスレッドが書き込んでいなければ置き換える。
+    ///
+    /// 他のスレッドが先に書き込んだ場合は、新しい値を複製してやり直すため、更新が失われることはない。
+    /// `f`は複数回呼び出される可能性がある。
+    pub fn update(&self, mut f: impl FnMut(&mut T))
+    where
+        T: Clone,
+    {
+        let guard = EpochGuard::pin();
+        let mut current = self.ptr.load(Ordering::Acquire);
+        loop {
+            // 安全性: 固定している間は、`current`は解放されない。
+            let mut value = unsafe { (*current).clone() };
+            f(&mut value);
+            let new = Box::into_raw(Box::new(value));
+            match self
+                .ptr
+                .compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    unsafe { guard.defer_drop(current) };
+                    return;
+                }
+                Err(actual) => {
+                    // 安全性: `new`は公開されていない。
+                    drop(unsafe { Box::from_raw(new) });
+                    current = actual;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for ReadMostly<T> {
+    fn drop(&mut self) {
+        // 安全性: `&mut self`により、読み込んでいるスレッドはいない。
+        drop(unsafe { Box::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+fn main() {
+    let stack = TreiberStack::new();
+    std::thread::scope(|s| {
+        for id in 0..4 {
+            let stack = &stack;
+            s.spawn(move || {
+                for i in 0..1000 {
+                    stack.push(id * 1000 + i);
+                    stack.pop();
+                }
+            });
+        }
+    });
+    println!("global epoch advanced to {}", epoch::global_epoch());
+
+    // 8つのスレッドが読み込み続けている間に、設定を書き換える。
+    // 読み込みの回数は`cargo run --release`で実行する場合の値である。
+    const READERS: usize = 8;
+    const READS_PER_THREAD: usize = 10_000_000;
+    let config = ReadMostly::new(vec![0_usize; 16]);
+    let start = std::time::Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..READERS {
+            s.spawn(|| {
+                let mut sum = 0;
+                for _ in 0..READS_PER_THREAD {
+                    sum += config.read()[0];
+                }
+                std::hint::black_box(sum);
+            });
+        }
+        for version in 1..=100 {
+            config.write(vec![version; 16]);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    });
+    let duration = start.elapsed();
+    println!(
+        "{} reads in {duration:?} ({:.1} M reads/s)",
+        READERS * READS_PER_THREAD,
+        (READERS * READS_PER_THREAD) as f64 / duration.as_secs_f64() / 1e6
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Miriで実行する場合は、繰り返し回数を減らす。
+    const ITERATIONS: usize = if cfg!(miri) { 100 } else { 10_000 };
+
+    /// エポックを進めて、回収待ちのノードを解放できるまで繰り返す。
+    fn flush_until(done: impl Fn() -> bool) {
+        for _ in 0..10_000 {
+            EpochGuard::pin().flush();
+            if done() {
+                return;
+            }
+            std::thread::yield_now();
+        }
+        panic!("deferred nodes were not reclaimed");
+    }
+
+    #[test]
+    fn deferred_drop_waits_for_pinned_threads() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let pinned = std::sync::Barrier::new(2);
+        let retired = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = EpochGuard::pin();
+                pinned.wait();
+                retired.wait();
+                // 固定している間は、何度エポックを進めようとしても解放されないはず。
+                for _ in 0..100 {
+                    EpochGuard::pin().flush();
+                }
+                assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+            });
+
+            pinned.wait();
+            let guard = EpochGuard::pin();
+            for _ in 0..10 {
+                unsafe { guard.defer_drop(Box::into_raw(Box::new(DetectDrop))) };
+            }
+            drop(guard);
+            for _ in 0..100 {
+                EpochGuard::pin().flush();
+            }
+            assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+            retired.wait();
+        });
+
+        // 固定しているスレッドがいなくなれば、すべて解放されるはず。
+        flush_until(|| NUM_DROPS.load(Ordering::Relaxed) == 10);
+    }
+
+    #[test]
+    fn treiber_stack_is_lifo_and_concurrent() {
+        let stack = TreiberStack::new();
+        for i in 0..10 {
+            stack.push(i);
+        }
+        assert_eq!(
+            std::iter::from_fn(|| stack.pop()).collect::<Vec<_>>(),
+            (0..10).rev().collect::<Vec<_>>()
+        );
+
+        let popped = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..ITERATIONS {
+                        stack.push(i);
+                        if let Some(v) = stack.pop() {
+                            popped.fetch_add(v, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        let rest: usize = std::iter::from_fn(|| stack.pop()).sum();
+        // プッシュしたすべての値を、1回ずつポップしたはず。
+        assert_eq!(
+            popped.load(Ordering::Relaxed) + rest,
+            4 * (0..ITERATIONS).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn read_mostly_readers_see_consistent_values_until_last_write() {
+        const READERS: usize = 8;
+        const WRITES: usize = if cfg!(miri) { 10 } else { 1000 };
+        let config = ReadMostly::new(vec![0; 16]);
+        let reads = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..READERS {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while last < WRITES {
+                        let value = config.read();
+                        // 書き込み中の値を読み込むことはなく、すべての要素が同じ版であるはず。
+                        assert!(value.iter().all(|&v| v == value[0]));
+                        // 置き換えた後の値を読み込んだスレッドは、それより古い値を読み込まないはず。
+                        assert!(value[0] >= last);
+                        last = value[0];
+                        reads.fetch_add(1, Ordering::Relaxed);
+                        drop(value);
+                        // CPUコアが少ない環境でも、書き込むスレッドが実行されるようにする。
+                        std::thread::yield_now();
+                    }
+                });
+            }
+            for version in 1..=WRITES {
+                config.write(vec![version; 16]);
+                std::thread::yield_now();
+            }
+        });
+        // すべての読み込みスレッドは、最後の書き込みを観測して終了したはず。
+        assert_eq!(config.read()[0], WRITES);
+        assert!(reads.into_inner() >= READERS);
+    }
+
+    #[test]
+    fn read_mostly_defers_dropping_replaced_values() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(usize);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let value = ReadMostly::new(DetectDrop(0));
+        let first = value.read();
+        for i in 1..=3 {
+            value.write(DetectDrop(i));
+        }
+        for _ in 0..100 {
+            EpochGuard::pin().flush();
+        }
+        // 読み込んだ値への参照がある間は、置き換えられた値も解放されないはず。
+        assert_eq!(first.0, 0);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        drop(first);
+        flush_until(|| NUM_DROPS.load(Ordering::Relaxed) == 3);
+        assert_eq!(value.read().0, 3);
+        drop(value);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn read_mostly_update_does_not_lose_writes() {
+        let counter = ReadMostly::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..ITERATIONS / 10 {
+                        counter.update(|v| *v += 1);
+                    }
+                });
+            }
+        });
+        // `compare_exchange`で置き換えるため、同時に更新しても失われないはず。
+        assert_eq!(*counter.read(), 4 * (ITERATIONS / 10));
+    }
+}
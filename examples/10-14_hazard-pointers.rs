@@ -0,0 +1,632 @@
+//! # ハザードポインタ
+//!
+//! 10-13のエポックベースの回収は、固定されているスレッドが1つでも停止すると、どのノードも解放できなくなる。
+//! ハザードポインタ（hazard pointer）では、スレッドが現在アクセスしているポインタそのものを、
+//! すべてのスレッドから見えるスロット（`HazardSlot`）に公開する。
+//!
+//! - ノードにアクセスするスレッドは、ポインタを読み込んだ後、そのポインタをスロットに公開する。
+//!   公開した後にポインタの読み込み元を再度読み込み、ポインタが変わっていないことを確認する。
+//!   変わっていた場合は、公開する前にノードが取り除かれた可能性があるため、やり直す。
+//! - ノードを取り除いたスレッドは、ノードを`retire`で回収待ちのリストに追加する。
+//!   回収待ちのノードが一定の数を超えたら、すべてのスロットを走査し、どのスロットにも公開されていない
+//!   ノードだけを解放する。
+//!
+//! 解放を待つのは、公開されているノードだけであるため、停止したスレッドがあっても他のノードは解放できる。
+//! 一方で、ノードにアクセスするたびに、ポインタを公開してSeqCstフェンスを実行する必要がある。
+//!
+//! `SafeAtomicPtr<T>`は、`Box<T>`を所有する`AtomicPtr<T>`であり、読み込んだ値をハザードポインタで保護した
+//! `HazardGuard`として返す。置き換えた値は`retire`で回収するため、`unsafe`を使わずに読み込みと更新ができる。
+//!
+//! テストで解放済みのメモリにアクセスしていないことを確認するには、アドレスサニタイザーを有効にして実行する。
+//!
+//! ```text
+//! RUSTFLAGS=-Zsanitizer=address cargo +nightly test --target x86_64-unknown-linux-gnu --example 10-14_hazard-pointers
+//! ```
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::ptr::{self, NonNull};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering, fence};
+
+/// 同時に使用できるハザードポインタの数
+const MAX_THREADS: usize = 64;
+
+/// スレッドごとの回収待ちのノードがこの数を超えたら、スロットを走査して解放する。
+const SCAN_THRESHOLD: usize = 2 * MAX_THREADS;
+
+/// ポインタを公開するスロット
+struct HazardSlot {
+    /// 公開しているポインタ（公開していない場合はヌル）
+    pointer: AtomicPtr<()>,
+    /// スロットを使用している`HazardPointer`が存在するか
+    acquired: AtomicBool,
+}
+
+/// すべてのスレッドで共有する、ハザードポインタのスロットの一覧
+pub struct HazardRegistry {
+    slots: [HazardSlot; MAX_THREADS],
+    /// 終了したスレッドが解放できなかった、回収待ちのノード
+    orphans: Mutex<Vec<Retired>>,
+}
+
+static REGISTRY: HazardRegistry = HazardRegistry {
+    slots: [const {
+        HazardSlot {
+            pointer: AtomicPtr::new(ptr::null_mut()),
+            acquired: AtomicBool::new(false),
+        }
+    }; MAX_THREADS],
+    orphans: Mutex::new(Vec::new()),
+};
+
+impl HazardRegistry {
+    /// 使用されていないスロットを獲得する。
+    fn acquire(&self) -> &HazardSlot {
+        self.slots
+            .iter()
+            .find(|slot| {
+                !slot.acquired.load(Ordering::Relaxed)
+                    && slot
+                        .acquired
+                        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+            })
+            .expect("too many hazard pointers in use")
+    }
+
+    /// 現在公開されているポインタの一覧を返す。
+    fn protected(&self) -> Vec<*mut ()> {
+        // `protect`側のSeqCstと組み合わせることで、ノードを取り除いた後に走査したときに、
+        // 公開されたポインタを見落とさないことを保証する。
+        fence(Ordering::SeqCst);
+        self.slots
+            .iter()
+            .map(|slot| slot.pointer.load(Ordering::Acquire))
+            .filter(|p| !p.is_null())
+            .collect()
+    }
+}
+
+/// 回収待ちのノード
+struct Retired {
+    ptr: *mut (),
+    /// `retire`に渡された`unsafe fn(*mut T)`
+    deleter: *const (),
+    /// `deleter`を元の型に戻して呼び出す関数
+    call: unsafe fn(*mut (), *const ()),
+}
+
+/// `retire`の安全性の契約により、どのスレッドで解放しても問題ない。
+unsafe impl Send for Retired {}
+
+impl Retired {
+    fn new<T>(ptr: *mut T, deleter: unsafe fn(*mut T)) -> Self {
+        unsafe fn call<T>(ptr: *mut (), deleter: *const ()) {
+            let deleter = unsafe { std::mem::transmute::<*const (), unsafe fn(*mut T)>(deleter) };
+            unsafe { deleter(ptr.cast()) }
+        }
+        Self {
+            ptr: ptr.cast(),
+            deleter: deleter as *const (),
+            call: call::<T>,
+        }
+    }
+
+    fn delete(self) {
+        unsafe { (self.call)(self.ptr, self.deleter) }
+    }
+}
+
+/// スレッドごとの回収待ちのリスト
+struct RetiredList(RefCell<Vec<Retired>>);
+
+impl Drop for RetiredList {
+    fn drop(&mut self) {
+        // スレッドが終了する場合は、解放できなかったノードを他のスレッドに引き継ぐ。
+        REGISTRY.orphans.lock().unwrap().append(self.0.get_mut());
+    }
+}
+
+thread_local! {
+    static RETIRED: RetiredList = const { RetiredList(RefCell::new(Vec::new())) };
+}
+
+/// 1つのポインタを保護するハザードポインタ
+///
+/// 作成したときにスロットを1つ獲得し、ドロップしたときにスロットを解放する。
+pub struct HazardPointer<T> {
+    slot: &'static HazardSlot,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> HazardPointer<T> {
+    pub fn new() -> Self {
+        Self {
+            slot: REGISTRY.acquire(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// `source`から読み込んだポインタを公開し、そのポインタを返す。
+    ///
+    /// `reset`するか、別のポインタを保護するか、ハザードポインタをドロップするまで、
+    /// 返したポインタが指すノードは解放されない。
+    pub fn protect(&self, source: &AtomicPtr<T>) -> *mut T {
+        let mut p = source.load(Ordering::Relaxed);
+        loop {
+            self.slot.pointer.store(p.cast(), Ordering::SeqCst);
+            // 公開する前にノードが取り除かれていた場合は、そのノードは解放されている可能性があるため、
+            // 新しいポインタでやり直す。
+            let current = source.load(Ordering::SeqCst);
+            if current == p {
+                return p;
+            }
+            p = current;
+        }
+    }
+
+    /// ポインタの公開を取り消す。
+    pub fn reset(&self) {
+        self.slot.pointer.store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+impl<T> Default for HazardPointer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for HazardPointer<T> {
+    fn drop(&mut self) {
+        self.reset();
+        self.slot.acquired.store(false, Ordering::Release);
+    }
+}
+
+/// `ptr`を回収待ちのリストに追加し、どのハザードポインタにも公開されなくなった後に`deleter`で解放する。
+///
+/// # Safety
+///
+/// - `ptr`はデータ構造から取り除かれており、これから`protect`するスレッドが読み込めてはならない。
+/// - `deleter(ptr)`は、後で任意のスレッドで1回だけ実行しても問題があってはならない。
+pub unsafe fn retire<T>(ptr: *mut T, deleter: unsafe fn(*mut T)) {
+    let len = RETIRED.with(|retired| {
+        let mut retired = retired.0.borrow_mut();
+        retired.push(Retired::new(ptr, deleter));
+        retired.len()
+    });
+    if len >= SCAN_THRESHOLD {
+        reclaim();
+    }
+}
+
+/// 回収待ちのノードのうち、どのハザードポインタにも公開されていないノードを解放する。
+pub fn reclaim() {
+    let mut candidates = RETIRED.with(|retired| std::mem::take(&mut *retired.0.borrow_mut()));
+    candidates.append(&mut REGISTRY.orphans.lock().unwrap());
+    let protected = REGISTRY.protected();
+    let (pending, ready): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|retired| protected.contains(&retired.ptr));
+    RETIRED.with(|retired| retired.0.borrow_mut().extend(pending));
+    // ノードのドロップ中に`retire`を呼び出しても問題ないように、借用を解除してから解放する。
+    for retired in ready {
+        retired.delete();
+    }
+}
+
+unsafe fn drop_box<T>(ptr: *mut T) {
+    drop(unsafe { Box::from_raw(ptr) });
+}
+
+/// `Box<T>`を所有し、ハザードポインタで保護して読み込むアトミックなポインタ
+///
+/// 置き換えた値は、どの`HazardGuard`にも保護されなくなった後に、任意のスレッドでドロップされるため、
+/// `T: Send`が必要である。また、複数のスレッドが同時に`&T`にアクセスするため、`T: Sync`も必要である。
+pub struct SafeAtomicPtr<T> {
+    ptr: AtomicPtr<T>,
+    /// `Box<T>`を所有していることを表す。
+    _marker: PhantomData<Box<T>>,
+}
+
+unsafe impl<T> Send for SafeAtomicPtr<T> where T: Send + Sync {}
+unsafe impl<T> Sync for SafeAtomicPtr<T> where T: Send + Sync {}
+
+/// `SafeAtomicPtr::load`が返す、ハザードポインタで保護した値への参照
+///
+/// ガードが生存している間は、他のスレッドが値を置き換えても、値は解放されない。
+pub struct HazardGuard<'a, T> {
+    hazard: HazardPointer<T>,
+    ptr: NonNull<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<T> Deref for HazardGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // 安全性: `hazard`で公開しているため、値は解放されない。
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> HazardGuard<'_, T> {
+    /// 保護を取り消して、ガードが使用していたスロットを解放する。
+    pub fn release(guard: Self) {
+        guard.hazard.reset();
+    }
+}
+
+impl<T> SafeAtomicPtr<T> {
+    pub fn new(value: Option<Box<T>>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(into_raw(value)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// 現在の値を読み込み、ハザードポインタで保護して返す。値がない場合は`None`を返す。
+    pub fn load(&self) -> Option<HazardGuard<'_, T>> {
+        let hazard = HazardPointer::new();
+        // `protect`はSeqCstで読み込むため、値を格納したスレッドのReleaseと同期する。
+        let ptr = NonNull::new(hazard.protect(&self.ptr))?;
+        Some(HazardGuard {
+            hazard,
+            ptr,
+            _marker: PhantomData,
+        })
+    }
+
+    /// 値を`new`に置き換える。置き換えた値は、保護されなくなった後に解放される。
+    pub fn store(&self, new: Option<Box<T>>) {
+        let old = self.ptr.swap(into_raw(new), Ordering::AcqRel);
+        // 安全性: `old`は取り除いたため、これから`load`するスレッドは読み込めない。
+        if !old.is_null() {
+            unsafe { retire(old, drop_box) };
+        }
+    }
+
+    /// 現在の値が`current`（`None`の場合は値がないこと）と等しい場合は、`new`に置き換える。
+    ///
+    /// 置き換えた値は、保護されなくなった後に解放される。
+    /// 等しくなかった場合は、`new`をそのまま`Err`で返す。
+    /// `current`は保護されているため、比較するまでの間に解放されて同じアドレスが再利用されることはなく、
+    /// ABA問題は発生しない。
+    pub fn compare_exchange(
+        &self,
+        current: Option<&HazardGuard<'_, T>>,
+        new: Option<Box<T>>,
+    ) -> Result<(), Option<Box<T>>> {
+        let current = current.map_or(ptr::null_mut(), |guard| guard.ptr.as_ptr());
+        let new = into_raw(new);
+        match self
+            .ptr
+            .compare_exchange(current, new, Ordering::AcqRel, Ordering::Relaxed)
+        {
+            Ok(old) => {
+                if !old.is_null() {
+                    unsafe { retire(old, drop_box) };
+                }
+                Ok(())
+            }
+            // 安全性: `new`は公開されなかったため、まだ所有している。
+            Err(_) => Err(NonNull::new(new).map(|new| unsafe { Box::from_raw(new.as_ptr()) })),
+        }
+    }
+}
+
+impl<T> Drop for SafeAtomicPtr<T> {
+    fn drop(&mut self) {
+        // `&mut self`であるため、`HazardGuard`は存在せず、すぐに解放できる。
+        let ptr = *self.ptr.get_mut();
+        if !ptr.is_null() {
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+    }
+}
+
+fn into_raw<T>(value: Option<Box<T>>) -> *mut T {
+    value.map_or(ptr::null_mut(), Box::into_raw)
+}
+
+/// `SafeAtomicPtr`だけを使用する、コピーオンライトのロックフリーなスタック
+///
+/// `SafeAtomicPtr`は値を所有するため、ノードの`next`を別の`SafeAtomicPtr`へ移動するような、所有権を
+/// 分割する操作はできない。
+/// そこで、スタック全体を1つの値とし、複製して更新したものを`compare_exchange`で置き換える。
+/// 操作ごとにスタック全体を複製するため実用的ではないが、`unsafe`を使わずにロックフリーに更新できる。
+pub struct CopyOnWriteStack<T> {
+    items: SafeAtomicPtr<Vec<T>>,
+}
+
+impl<T: Clone> CopyOnWriteStack<T> {
+    pub fn new() -> Self {
+        Self {
+            items: SafeAtomicPtr::new(None),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        loop {
+            let current = self.items.load();
+            let mut items = current.as_deref().cloned().unwrap_or_default();
+            items.push(value.clone());
+            if self
+                .items
+                .compare_exchange(current.as_ref(), Some(Box::new(items)))
+                .is_ok()
+            {
+                return;
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        loop {
+            let current = self.items.load()?;
+            let mut items = (*current).clone();
+            let value = items.pop()?;
+            let new = (!items.is_empty()).then(|| Box::new(items));
+            if self.items.compare_exchange(Some(&current), new).is_ok() {
+                return Some(value);
+            }
+        }
+    }
+}
+
+impl<T: Clone> Default for CopyOnWriteStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ハザードポインタを使用するTreiberスタック
+pub struct TreiberStack<T> {
+    head: AtomicPtr<Node<T>>,
+}
+
+struct Node<T> {
+    /// ポップしたスレッドが値を取り出すため、ノードを解放するときに値をドロップしない。
+    value: ManuallyDrop<T>,
+    next: *mut Node<T>,
+}
+
+unsafe fn drop_node<T>(node: *mut Node<T>) {
+    drop(unsafe { Box::from_raw(node) });
+}
+
+unsafe impl<T> Send for TreiberStack<T> where T: Send {}
+unsafe impl<T> Sync for TreiberStack<T> where T: Send {}
+
+impl<T> TreiberStack<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // 安全性: ノードはまだ公開されていない。
+            unsafe { (*node).next = head };
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let hazard = HazardPointer::new();
+        loop {
+            let head = hazard.protect(&self.head);
+            if head.is_null() {
+                return None;
+            }
+            // 安全性: `head`を公開しているため、他のスレッドがポップしても解放されない。
+            // また、解放されずに再利用もされないため、`compare_exchange`でABA問題は発生しない。
+            let next = unsafe { (*head).next };
+            if self
+                .head
+                .compare_exchange(head, next, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                let value = unsafe { ptr::read(&*(*head).value) };
+                hazard.reset();
+                unsafe { retire(head, drop_node) };
+                return Some(value);
+            }
+        }
+    }
+}
+
+impl<T> Default for TreiberStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for TreiberStack<T> {
+    fn drop(&mut self) {
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            let mut boxed = unsafe { Box::from_raw(node) };
+            unsafe { ManuallyDrop::drop(&mut boxed.value) };
+            node = boxed.next;
+        }
+    }
+}
+
+fn main() {
+    let stack = TreiberStack::new();
+    std::thread::scope(|s| {
+        for id in 0..4 {
+            let stack = &stack;
+            s.spawn(move || {
+                for i in 0..1000 {
+                    stack.push(id * 1000 + i);
+                    stack.pop();
+                }
+            });
+        }
+    });
+    reclaim();
+    println!(
+        "remaining: {:?}",
+        std::iter::from_fn(|| stack.pop()).count()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn protected_node_is_not_reclaimed() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe fn count_drop(ptr: *mut u32) {
+            drop(unsafe { Box::from_raw(ptr) });
+            NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let source = AtomicPtr::new(Box::into_raw(Box::new(1_u32)));
+        let hazard = HazardPointer::new();
+        let p = hazard.protect(&source);
+        source.store(ptr::null_mut(), Ordering::SeqCst);
+        unsafe { retire(p, count_drop) };
+
+        // 公開している間は、解放されないはず。
+        reclaim();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        assert_eq!(unsafe { *p }, 1);
+
+        // 公開を取り消せば、解放されるはず。
+        hazard.reset();
+        reclaim();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn safe_atomic_ptr_keeps_loaded_value_alive() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(u32);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let ptr = SafeAtomicPtr::new(Some(Box::new(DetectDrop(1))));
+        let guard = ptr.load().unwrap();
+        ptr.store(Some(Box::new(DetectDrop(2))));
+        // 置き換えられても、保護している間は解放されないはず。
+        reclaim();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        assert_eq!(guard.0, 1);
+
+        // 古い値との比較は失敗し、`new`が返されるはず。
+        let new = Some(Box::new(DetectDrop(3)));
+        let rejected = ptr.compare_exchange(Some(&guard), new).unwrap_err();
+        assert_eq!(rejected.as_ref().unwrap().0, 3);
+        drop(rejected);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+
+        HazardGuard::release(guard);
+        reclaim();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 2);
+        assert_eq!(ptr.load().unwrap().0, 2);
+        drop(ptr);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn copy_on_write_stack_under_contention() {
+        const THREADS: usize = 4;
+        const ITERATIONS: usize = 500;
+        let stack = CopyOnWriteStack::new();
+        let popped = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for i in 0..ITERATIONS {
+                        stack.push(i);
+                        if i % 3 != 0
+                            && let Some(v) = stack.pop()
+                        {
+                            popped.fetch_add(v, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        let rest: usize = std::iter::from_fn(|| stack.pop()).sum();
+        // すべての値を1回ずつポップしたはず。
+        assert_eq!(
+            popped.load(Ordering::Relaxed) + rest,
+            THREADS * (0..ITERATIONS).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn slots_are_released_on_drop() {
+        // スロットの数より多くのハザードポインタを、順に作成してドロップできるはず。
+        for _ in 0..MAX_THREADS * 2 {
+            let hazard = HazardPointer::<u32>::new();
+            drop(hazard);
+        }
+    }
+
+    #[test]
+    fn stack_push_pop_mix_under_contention() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop(usize);
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        const THREADS: usize = 8;
+        const ITERATIONS: usize = 20_000;
+        let stack = TreiberStack::new();
+        let popped = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for i in 0..ITERATIONS {
+                        stack.push(DetectDrop(i));
+                        if i % 3 != 0
+                            && let Some(v) = stack.pop()
+                        {
+                            popped.fetch_add(v.0, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        let rest: usize = std::iter::from_fn(|| stack.pop()).map(|v| v.0).sum();
+        // すべての値を1回ずつポップし、1回ずつドロップしたはず。
+        assert_eq!(
+            popped.load(Ordering::Relaxed) + rest,
+            THREADS * (0..ITERATIONS).sum::<usize>()
+        );
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), THREADS * ITERATIONS);
+    }
+}
@@ -0,0 +1,255 @@
+//! # 単一生産者・単一消費者（SPSC）のリングバッファ
+//!
+//! 05章のチャネルは、メッセージごとにヒープを割り当てる（または1つのメッセージしか送れない）。
+//! 音声やネットワークのように、1つの生産者と1つの消費者が高い頻度でメッセージをやり取りする場合は、
+//! 固定長のリングバッファを使用することで、メッセージごとの割り当てを避けられる。
+//!
+//! `head`は次に取り出す位置、`tail`は次に書き込む位置であり、どちらも単調に増加させ、
+//! バッファのインデックスは容量で割った余りとする。
+//! 容量を2のべき乗に制限することで、余りをビットマスクで計算できる。
+//!
+//! - 生産者だけが`tail`を更新し、消費者だけが`head`を更新する。
+//! - 生産者は値を書き込んだ後に、`tail`をReleaseでストアする。消費者は`tail`をAcquireでロードすることで、
+//!   書き込まれた値を観測できる。
+//! - 消費者は値を読み込んだ後に、`head`をReleaseでストアする。生産者は`head`をAcquireでロードすることで、
+//!   消費者が読み込みを終えたスロットにだけ書き込む。
+//!
+//! 生産者と消費者がそれぞれ1つであることを型で保証するため、05-05と同様に`split`で
+//! `Producer`と`Consumer`に分割してから使用する。
+//!
+//! データ競合がないことを確認するには、スレッドサニタイザーを有効にして実行する。
+//!
+//! ```text
+//! RUSTFLAGS=-Zsanitizer=thread cargo +nightly test -Zbuild-std --target x86_64-unknown-linux-gnu --example 10-15_spsc-ring-buffer
+//! ```
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct SpscQueue<T, const N: usize> {
+    /// 次に取り出す位置（消費者だけが更新する）
+    head: AtomicUsize,
+    /// 次に書き込む位置（生産者だけが更新する）
+    tail: AtomicUsize,
+    buffer: [UnsafeCell<MaybeUninit<T>>; N],
+}
+
+unsafe impl<T: Send, const N: usize> Sync for SpscQueue<T, N> {}
+
+impl<T, const N: usize> SpscQueue<T, N> {
+    /// インデックスを計算するマスク
+    const MASK: usize = {
+        assert!(N.is_power_of_two(), "capacity must be a power of two");
+        N - 1
+    };
+
+    pub const fn new() -> Self {
+        let _ = Self::MASK;
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+        }
+    }
+
+    /// キューを生産者と消費者に分割する。
+    ///
+    /// 残っている値はドロップし、キューを空の状態に戻す。
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        *self = Self::new();
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for SpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscQueue<T, N> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        for i in head..tail {
+            unsafe { self.buffer[i & Self::MASK].get_mut().assume_init_drop() };
+        }
+    }
+}
+
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a SpscQueue<T, N>,
+}
+
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a SpscQueue<T, N>,
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// 値をキューの末尾に書き込む。
+    ///
+    /// キューが満杯の場合は、値を失わないように`Err`で値を返す。
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let queue = self.queue;
+        // `tail`は自分だけが更新するため、`Relaxed`で読み込める。
+        let tail = queue.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(queue.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        // 安全性: 消費者は`tail`より前のスロットだけを読み込み、このスロットの読み込みは完了している。
+        unsafe { (*queue.buffer[tail & SpscQueue::<T, N>::MASK].get()).write(value) };
+        queue.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// キューの先頭から値を取り出す。キューが空の場合は`None`を返す。
+    pub fn pop(&mut self) -> Option<T> {
+        let queue = self.queue;
+        let head = queue.head.load(Ordering::Relaxed);
+        if head == queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // 安全性: `tail`をAcquireで読み込んだため、生産者が書き込んだ値を観測できる。
+        let value =
+            unsafe { (*queue.buffer[head & SpscQueue::<T, N>::MASK].get()).assume_init_read() };
+        queue.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+fn main() {
+    let mut queue = SpscQueue::<u64, 1024>::new();
+    let (mut producer, mut consumer) = queue.split();
+    let sum = std::thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..1_000_000 {
+                let mut value = i;
+                while let Err(v) = producer.push(value) {
+                    value = v;
+                    std::hint::spin_loop();
+                }
+            }
+        });
+        let mut sum = 0;
+        let mut received = 0;
+        while received < 1_000_000 {
+            match consumer.pop() {
+                Some(v) => {
+                    sum += v;
+                    received += 1;
+                }
+                None => std::hint::spin_loop(),
+            }
+        }
+        sum
+    });
+    println!("sum: {sum}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// 満杯の場合は、CPUを消費者に譲ってから再試行する。
+    fn push_blocking<T, const N: usize>(producer: &mut Producer<'_, T, N>, mut value: T) {
+        while let Err(v) = producer.push(value) {
+            value = v;
+            std::thread::yield_now();
+        }
+    }
+
+    /// 空の場合は、CPUを生産者に譲ってから再試行する。
+    fn pop_blocking<T, const N: usize>(consumer: &mut Consumer<'_, T, N>) -> T {
+        loop {
+            match consumer.pop() {
+                Some(v) => return v,
+                None => std::thread::yield_now(),
+            }
+        }
+    }
+
+    #[test]
+    fn full_and_empty() {
+        let mut queue = SpscQueue::<i32, 4>::new();
+        assert_eq!(queue.capacity(), 4);
+        let (mut producer, mut consumer) = queue.split();
+        assert_eq!(consumer.pop(), None);
+        for i in 0..4 {
+            assert_eq!(producer.push(i), Ok(()));
+        }
+        // 満杯の場合は、値を返すはず。
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.pop(), Some(0));
+        assert_eq!(producer.push(4), Ok(()));
+        assert_eq!(
+            std::iter::from_fn(|| consumer.pop()).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn no_elements_lost_over_10m_transfers() {
+        const TRANSFERS: u64 = 10_000_000;
+        let mut queue = SpscQueue::<u64, 1024>::new();
+        let (mut producer, mut consumer) = queue.split();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..TRANSFERS {
+                    push_blocking(&mut producer, i);
+                }
+            });
+            // 送信した順に、すべての値を受信するはず。
+            for i in 0..TRANSFERS {
+                assert_eq!(pop_blocking(&mut consumer), i);
+            }
+            assert_eq!(consumer.pop(), None);
+        });
+    }
+
+    #[test]
+    fn paced_transfers_at_one_million_per_second() {
+        const TRANSFERS: u32 = 100_000;
+        const INTERVAL: Duration = Duration::from_micros(1);
+        let mut queue = SpscQueue::<Box<u32>, 64>::new();
+        let (mut producer, mut consumer) = queue.split();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                let start = Instant::now();
+                for i in 0..TRANSFERS {
+                    // 1マイクロ秒に1つの割合で送信する。
+                    while start.elapsed() < INTERVAL * i {
+                        std::hint::spin_loop();
+                    }
+                    push_blocking(&mut producer, Box::new(i));
+                }
+            });
+            for i in 0..TRANSFERS {
+                assert_eq!(*pop_blocking(&mut consumer), i);
+            }
+        });
+    }
+
+    #[test]
+    fn drop_releases_remaining_values() {
+        let value = std::rc::Rc::new(());
+        let mut queue = SpscQueue::<_, 8>::new();
+        {
+            let (mut producer, mut consumer) = queue.split();
+            for _ in 0..5 {
+                producer.push(value.clone()).unwrap();
+            }
+            consumer.pop();
+        }
+        assert_eq!(std::rc::Rc::strong_count(&value), 5);
+        // 取り出されていない値だけが、ドロップされるはず。
+        drop(queue);
+        assert_eq!(std::rc::Rc::strong_count(&value), 1);
+    }
+}
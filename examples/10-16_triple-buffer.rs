@@ -0,0 +1,194 @@
+//! # トリプルバッファ
+//!
+//! トリプルバッファは、生産者が書き込んだ最新の値を、消費者に渡すための3つのスロットを持つバッファである。
+//! スロットは、生産者が書き込み中のスロット、消費者が読み込み中のスロット、その間で受け渡しを待つスロット
+//! （pending）に分かれる。
+//!
+//! - 生産者は自分のスロットに書き込んだ後、自分のスロットとpendingのスロットを交換する。
+//! - 消費者は、新しい値がpendingにある場合、自分のスロットとpendingのスロットを交換してから読み込む。
+//!
+//! 交換は、pendingのスロットの番号と新しい値があるかのフラグを詰めた`AtomicU8`を`swap`するだけであり、
+//! 生産者も消費者も待機しない。
+//! 消費者は、書き込みが完了した最新の値だけを読み込み、書き込み途中の値を読み込むことはない。
+//! ただし、消費者が読み込む前に生産者が複数回書き込んだ場合、途中の値は読み飛ばされる。
+//!
+//! 生産者と消費者は、05-05と同様に`split`で分割したハンドルが、それぞれ自分のスロットの番号を保持する。
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// `state`のうち、pendingのスロットの番号を表すビット
+const INDEX_MASK: u8 = 0b011;
+/// `state`のうち、pendingのスロットに消費者がまだ読み込んでいない値があることを表すビット
+const NEW: u8 = 0b100;
+
+pub struct TripleBuffer<T> {
+    slots: [UnsafeCell<T>; 3],
+    /// pendingのスロットの番号と`NEW`
+    state: AtomicU8,
+}
+
+unsafe impl<T: Send> Sync for TripleBuffer<T> {}
+
+impl<T: Clone> TripleBuffer<T> {
+    /// すべてのスロットを`value`で初期化する。
+    pub fn new(value: T) -> Self {
+        Self {
+            slots: [
+                UnsafeCell::new(value.clone()),
+                UnsafeCell::new(value.clone()),
+                UnsafeCell::new(value),
+            ],
+            state: AtomicU8::new(1),
+        }
+    }
+}
+
+impl<T> TripleBuffer<T> {
+    /// バッファを生産者と消費者に分割する。
+    ///
+    /// 生産者はスロット0、消費者はスロット2から開始し、スロット1をpendingとする。
+    pub fn split(&mut self) -> (Producer<'_, T>, Consumer<'_, T>) {
+        *self.state.get_mut() = 1;
+        (
+            Producer {
+                buffer: self,
+                index: 0,
+            },
+            Consumer {
+                buffer: self,
+                index: 2,
+            },
+        )
+    }
+}
+
+pub struct Producer<'a, T> {
+    buffer: &'a TripleBuffer<T>,
+    /// 生産者が所有するスロットの番号
+    index: u8,
+}
+
+pub struct Consumer<'a, T> {
+    buffer: &'a TripleBuffer<T>,
+    /// 消費者が所有するスロットの番号
+    index: u8,
+}
+
+impl<T> Producer<'_, T> {
+    /// 生産者が所有するスロットへの可変参照を返す。
+    ///
+    /// 書き込んだ値は、`publish`するまで消費者から見えない。
+    pub fn input(&mut self) -> &mut T {
+        // 安全性: このスロットは生産者だけが所有しており、`&mut self`により1つの参照しか作成されない。
+        unsafe { &mut *self.buffer.slots[self.index as usize].get() }
+    }
+
+    /// 生産者のスロットとpendingのスロットを交換し、書き込んだ値を消費者に公開する。
+    pub fn publish(&mut self) {
+        // Releaseにより、スロットへの書き込みは、このスロットを受け取った消費者から観測できる。
+        // Acquireにより、受け取ったスロットに対する消費者の読み込みは、これからの書き込みより前に完了する。
+        let old = self.buffer.state.swap(self.index | NEW, Ordering::AcqRel);
+        self.index = old & INDEX_MASK;
+    }
+
+    /// `value`を書き込んで公開する。
+    pub fn write(&mut self, value: T) {
+        *self.input() = value;
+        self.publish();
+    }
+}
+
+impl<T> Consumer<'_, T> {
+    /// 消費者がまだ読み込んでいない値が公開されているかを返す。
+    pub fn has_update(&self) -> bool {
+        self.buffer.state.load(Ordering::Relaxed) & NEW != 0
+    }
+
+    /// 公開された最新の値を返す。
+    ///
+    /// 新しい値が公開されていない場合は、前回読み込んだ値を返す。
+    pub fn read(&mut self) -> &T {
+        if self.has_update() {
+            // `NEW`を消去しながら、消費者のスロットとpendingのスロットを交換する。
+            let old = self.buffer.state.swap(self.index, Ordering::AcqRel);
+            self.index = old & INDEX_MASK;
+        }
+        // 安全性: このスロットは消費者だけが所有している。
+        unsafe { &*self.buffer.slots[self.index as usize].get() }
+    }
+}
+
+fn main() {
+    let mut buffer = TripleBuffer::new((0_u64, 0_u64));
+    let (mut producer, mut consumer) = buffer.split();
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            for i in 1..=1_000_000 {
+                producer.write((i, i * 2));
+            }
+        });
+        let mut reads = 0;
+        while *consumer.read() != (1_000_000, 2_000_000) {
+            reads += 1;
+        }
+        println!("read {reads} times before the last write");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_latest_published_value() {
+        let mut buffer = TripleBuffer::new(0);
+        let (mut producer, mut consumer) = buffer.split();
+        assert!(!consumer.has_update());
+        assert_eq!(*consumer.read(), 0);
+
+        // 公開するまでは、消費者から見えないはず。
+        *producer.input() = 1;
+        assert!(!consumer.has_update());
+        assert_eq!(*consumer.read(), 0);
+        producer.publish();
+        assert!(consumer.has_update());
+        assert_eq!(*consumer.read(), 1);
+        assert!(!consumer.has_update());
+
+        // 複数回書き込んだ場合は、最後の値だけを読み込むはず。
+        producer.write(2);
+        producer.write(3);
+        producer.write(4);
+        assert_eq!(*consumer.read(), 4);
+        assert_eq!(*consumer.read(), 4);
+    }
+
+    #[test]
+    fn consumer_sees_complete_and_monotonic_writes() {
+        const WRITES: u64 = 1_000_000;
+        // すべての要素に同じ値を書き込むことで、書き込み途中の値を読み込んでいないことを確認する。
+        let mut buffer = TripleBuffer::new([0_u64; 16]);
+        let (mut producer, mut consumer) = buffer.split();
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 1..=WRITES {
+                    let input = producer.input();
+                    for v in input.iter_mut() {
+                        *v = i;
+                    }
+                    producer.publish();
+                }
+            });
+            // 最後に書き込んだ値を読み込むまで繰り返す。
+            let mut last = 0;
+            while last != WRITES {
+                let value = consumer.read();
+                assert!(value.iter().all(|&v| v == value[0]), "torn read: {value:?}");
+                // 読み込む値は、前回より古くならないはず。
+                assert!(value[0] >= last);
+                last = value[0];
+                std::thread::yield_now();
+            }
+        });
+    }
+}
@@ -0,0 +1,205 @@
+//! # イベント
+//!
+//! Windowsの`CreateEvent`のように、シグナル状態（SET）と非シグナル状態（UNSET）を持つ同期プリミティブである。
+//! `wait`は、イベントがSETになるまで待機する。
+//!
+//! - 自動リセット（auto-reset）: `wait`から戻るスレッドが、イベントをUNSETに戻す。
+//!   したがって、1回の`set`で起床するスレッドは1つだけである。
+//! - 手動リセット（manual-reset）: `set`で待機しているすべてのスレッドを起床させ、`reset`を呼び出すまで
+//!   SETのままにする。
+//!
+//! 2つのモードは`wait`の意味が異なるため、誤って混同しないように、モードを型パラメーターで区別する。
+//! `Event::auto_reset`は`AutoResetEvent`を、`Event::manual_reset`は`ManualResetEvent`を返す。
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use atomic_wait::{wait, wake_all, wake_one};
+
+const UNSET: u32 = 0;
+const SET: u32 = 1;
+
+/// 自動リセットのモード
+pub struct AutoReset;
+
+/// 手動リセットのモード
+pub struct ManualReset;
+
+pub struct Event<Mode> {
+    state: AtomicU32,
+    _mode: PhantomData<Mode>,
+}
+
+pub type AutoResetEvent = Event<AutoReset>;
+pub type ManualResetEvent = Event<ManualReset>;
+
+impl<Mode> Event<Mode> {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(UNSET),
+            _mode: PhantomData,
+        }
+    }
+
+    /// イベントがSETであるかを返す。
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Relaxed) == SET
+    }
+
+    /// イベントをUNSETに戻す。
+    pub fn reset(&self) {
+        self.state.store(UNSET, Ordering::Relaxed);
+    }
+}
+
+impl Event<AutoReset> {
+    pub const fn auto_reset() -> Self {
+        Self::new()
+    }
+
+    /// イベントをSETにし、待機しているスレッドを1つ起床させる。
+    ///
+    /// すでにSETの場合は何もしない（`set`は累積しない）。
+    pub fn set(&self) {
+        // Releaseにより、`wait`から戻ったスレッドは、`set`より前の書き込みを観測できる。
+        if self.state.swap(SET, Ordering::Release) == UNSET {
+            wake_one(&self.state);
+        }
+    }
+
+    /// イベントがSETになるまで待機し、UNSETに戻してから戻る。
+    pub fn wait(&self) {
+        // SETからUNSETに戻せたスレッドだけが戻るため、1回の`set`で戻るスレッドは1つだけである。
+        while self
+            .state
+            .compare_exchange(SET, UNSET, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            wait(&self.state, UNSET);
+        }
+    }
+
+    /// イベントがSETであれば、UNSETに戻して`true`を返す。待機はしない。
+    pub fn try_wait(&self) -> bool {
+        self.state
+            .compare_exchange(SET, UNSET, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl Event<ManualReset> {
+    pub const fn manual_reset() -> Self {
+        Self::new()
+    }
+
+    /// イベントをSETにし、待機しているすべてのスレッドを起床させる。
+    pub fn set(&self) {
+        if self.state.swap(SET, Ordering::Release) == UNSET {
+            wake_all(&self.state);
+        }
+    }
+
+    /// イベントがSETになるまで待機する。イベントはSETのままである。
+    pub fn wait(&self) {
+        while self.state.load(Ordering::Acquire) == UNSET {
+            wait(&self.state, UNSET);
+        }
+    }
+}
+
+fn main() {
+    let start = Event::manual_reset();
+    let job = Event::auto_reset();
+    std::thread::scope(|s| {
+        for i in 0..3 {
+            let (start, job) = (&start, &job);
+            s.spawn(move || {
+                start.wait();
+                job.wait();
+                println!("thread {i} took a job");
+            });
+        }
+        start.set();
+        for _ in 0..3 {
+            job.set();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[test]
+    fn auto_reset_wakes_one_thread_per_set() {
+        let event = Event::auto_reset();
+        let woken = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    event.wait();
+                    woken.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            event.set();
+            std::thread::sleep(Duration::from_millis(50));
+            // 1回の`set`では、1つのスレッドだけが戻り、イベントはUNSETに戻るはず。
+            assert_eq!(woken.load(Ordering::Relaxed), 1);
+            assert!(!event.is_set());
+
+            // 残りのスレッドも、`set`するたびに1つずつ戻るはず。
+            while woken.load(Ordering::Relaxed) < 4 {
+                event.set();
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+        assert!(!event.try_wait());
+    }
+
+    #[test]
+    fn manual_reset_wakes_all_threads() {
+        let event = Event::manual_reset();
+        let woken = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    event.wait();
+                    woken.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(woken.load(Ordering::Relaxed), 0);
+            event.set();
+        });
+        // 1回の`set`で、すべてのスレッドが戻り、イベントはSETのままのはず。
+        assert_eq!(woken.load(Ordering::Relaxed), 8);
+        assert!(event.is_set());
+        event.wait();
+    }
+
+    #[test]
+    fn events_can_be_reused_after_reset() {
+        let manual = Event::manual_reset();
+        manual.set();
+        manual.reset();
+        assert!(!manual.is_set());
+        std::thread::scope(|s| {
+            let t = s.spawn(|| manual.wait());
+            std::thread::sleep(Duration::from_millis(50));
+            // リセットした後は、再度`set`するまで待機するはず。
+            assert!(!t.is_finished());
+            manual.set();
+        });
+
+        let auto = Event::auto_reset();
+        auto.set();
+        auto.reset();
+        assert!(!auto.try_wait());
+        auto.set();
+        assert!(auto.try_wait());
+        assert!(!auto.try_wait());
+    }
+}
@@ -0,0 +1,186 @@
+//! # トークンバケットによるレート制限
+//!
+//! トークンバケットは、1秒あたり`rate`個の割合でトークンを補充し、最大で`capacity`個のトークンを蓄えるバケットである。
+//! 処理を実行する前にトークンを取得し、トークンが足りない場合は処理を実行しないことで、
+//! 処理の頻度を平均`rate`回/秒に制限しつつ、`capacity`回までのバースト（まとまった実行）を許可する。
+//!
+//! リクエストごとにミューテックスを獲得しないように、トークンの数と最後に補充した時刻をアトミック変数で管理する。
+//!
+//! - 補充: 最後に補充した時刻から経過した時間で獲得したトークンの数を計算し、`last_refill`をその数のトークンに
+//!   相当する時間だけ進める。`last_refill`の`compare_exchange`に成功したスレッドだけがトークンを追加するため、
+//!   同じ時間に対するトークンが2回追加されることはない。`last_refill`は、補充の世代を表すカウンターの役割も兼ねる。
+//! - 取得: `tokens`から`n`を引いても負にならない場合だけ、CASループで`n`を引く。
+//!
+//! 補充に成功したスレッドが`last_refill`を進めてから`tokens`に追加するまでの間は、他のスレッドからは
+//! トークンが少なく見える。しかし、トークンを多く取得することはないため、レートを超えることはない。
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+pub struct TokenBucket {
+    /// 現在のトークンの数
+    tokens: AtomicU64,
+    /// 最後にトークンを補充した時刻（`epoch`からの経過時間、ナノ秒）
+    last_refill: AtomicU64,
+    /// 1秒あたりに補充するトークンの数
+    rate: u64,
+    /// 蓄えられるトークンの最大数
+    capacity: u64,
+    /// 時刻の基準
+    epoch: Instant,
+}
+
+impl TokenBucket {
+    /// トークンが満杯の状態のバケットを作成する。
+    pub fn new(rate: u64, capacity: u64) -> Self {
+        assert!(rate > 0, "rate must be positive");
+        Self {
+            tokens: AtomicU64::new(capacity),
+            last_refill: AtomicU64::new(0),
+            rate,
+            capacity,
+            epoch: Instant::now(),
+        }
+    }
+
+    /// `n`個のトークンの取得を1回試みる。
+    ///
+    /// トークンが足りない場合は、トークンを取得せずに`false`を返す。
+    pub fn try_acquire(&self, n: u64) -> bool {
+        self.refill();
+        self.tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                tokens.checked_sub(n)
+            })
+            .is_ok()
+    }
+
+    /// `n`個のトークンを取得できるまで待機する。
+    ///
+    /// # パニック
+    ///
+    /// `n`が`capacity`より大きい場合は、トークンを取得できないためパニックする。
+    pub fn blocking_acquire(&self, n: u64) {
+        assert!(
+            n <= self.capacity,
+            "cannot acquire more tokens than capacity"
+        );
+        while !self.try_acquire(n) {
+            // 足りないトークンが補充されるまでの時間だけスリープする。
+            // 他のスレッドに先に取得された場合は、再びスリープする。
+            let missing = n.saturating_sub(self.tokens.load(Ordering::Relaxed)).max(1);
+            let nanos = missing as u128 * NANOS_PER_SEC / self.rate as u128;
+            std::thread::sleep(Duration::from_nanos(nanos as u64));
+        }
+    }
+
+    /// 経過した時間に応じてトークンを補充する。
+    fn refill(&self) {
+        let now = self.epoch.elapsed().as_nanos() as u64;
+        let last = self.last_refill.load(Ordering::Relaxed);
+        let earned = (now.saturating_sub(last) as u128 * self.rate as u128 / NANOS_PER_SEC) as u64;
+        if earned == 0 {
+            return;
+        }
+        // 獲得したトークンに相当する時間だけ進めることで、端数の時間を次の補充に持ち越す。
+        let next = last + (earned as u128 * NANOS_PER_SEC / self.rate as u128) as u64;
+        if self
+            .last_refill
+            .compare_exchange(last, next, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            // 他のスレッドが、この時間に対するトークンを補充した。
+            return;
+        }
+        // 満杯を超えたトークンは捨てる。
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                Some(tokens.saturating_add(earned).min(self.capacity))
+            });
+    }
+}
+
+fn main() {
+    // 1秒あたり20回、最大5回のバーストを許可する。
+    let bucket = TokenBucket::new(20, 5);
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for i in 0..4 {
+            let bucket = &bucket;
+            s.spawn(move || {
+                for j in 0..5 {
+                    bucket.blocking_acquire(1);
+                    println!("{:>4?} thread {i}: request {j}", start.elapsed());
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn allows_bursts_up_to_capacity() {
+        let bucket = TokenBucket::new(1, 100);
+        // 満杯の状態から、`capacity`個までは続けて取得できるはず。
+        for _ in 0..100 {
+            assert!(bucket.try_acquire(1));
+        }
+        assert!(!bucket.try_acquire(1));
+
+        let bucket = TokenBucket::new(1, 10);
+        assert!(!bucket.try_acquire(11));
+        assert!(bucket.try_acquire(10));
+    }
+
+    #[test]
+    fn limits_rate_under_parallel_pressure() {
+        const RATE: u64 = 1_000;
+        const CAPACITY: u64 = 10;
+        let bucket = TokenBucket::new(RATE, CAPACITY);
+        let acquired = AtomicU64::new(0);
+        let stop = AtomicBool::new(false);
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !stop.load(Ordering::Relaxed) {
+                        if bucket.try_acquire(1) {
+                            acquired.fetch_add(1, Ordering::Relaxed);
+                        } else {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+            std::thread::sleep(Duration::from_millis(500));
+            stop.store(true, Ordering::Relaxed);
+        });
+        let elapsed = start.elapsed();
+        let acquired = acquired.into_inner();
+
+        // 最初のバーストと経過時間に補充されたトークンより多くは、取得できないはず。
+        let limit = CAPACITY + (elapsed.as_secs_f64() * RATE as f64) as u64 + 1;
+        assert!(acquired <= limit, "{acquired} > {limit}");
+        // 取得し続けているため、レートを大きく下回らないはず。
+        assert!(acquired >= 250, "{acquired}");
+    }
+
+    #[test]
+    fn blocking_acquire_waits_for_refill() {
+        let bucket = TokenBucket::new(100, 5);
+        let start = Instant::now();
+        for _ in 0..15 {
+            bucket.blocking_acquire(1);
+        }
+        // バーストの5個の後、残りの10個は100個/秒で補充されるため、約100ミリ秒かかるはず。
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(90), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+    }
+}
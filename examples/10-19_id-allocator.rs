@@ -0,0 +1,260 @@
+//! # 解放したIDを再利用するIDアロケーター
+//!
+//! 02-02-03と02-03-01は、単調に増加するIDを発行するため、長時間動作するサービスでは`u32`を使い果たしてしまう。
+//! `IdAllocator`は、解放されたIDをフリーリストに保持し、新しいIDを発行する前にフリーリストのIDを再利用する。
+//!
+//! フリーリストは`Mutex<Vec<u32>>`でも実装できるが、ここではロックを使用しないTreiberスタックで実装する。
+//!
+//! ## ABA問題とタグ付きポインター
+//!
+//! Treiberスタックの`pop`は、先頭のノードの`next`を読み込んでから、先頭を`compare_exchange`で`next`に置き換える。
+//! その間に、他のスレッドが先頭のノードを取り出し、別のノードを取り出し、最初のノードを再び積んだ場合、
+//! 先頭のポインターは同じであるため`compare_exchange`が成功し、すでに取り出されたノードを先頭にしてしまう（ABA問題）。
+//!
+//! 64ビットのプラットフォームでは、ユーザー空間のアドレスは下位48ビットに収まる。
+//! そこで、先頭のポインターの上位16ビットに、スタックを更新するたびにインクリメントする世代（タグ）を詰める。
+//! ポインターが同じでも世代が異なれば、`compare_exchange`は失敗する。
+//! ただし、`pop`の間に世代が65536回更新されて一周した場合は検出できない。
+//!
+//! ## ノードの再利用
+//!
+//! `pop`は、他のスレッドがすでに取り出したノードの`next`を読み込むことがあるため、取り出したノードを
+//! すぐに解放できない（10-13や10-14を参照）。
+//! そこで、取り出したノードは解放せずに予備のノードのスタックに積み、次の`free`で再利用する。
+//! ノードは`IdAllocator`がドロップされるまで解放されないため、`next`の読み込みは常に有効なメモリに対して行われる。
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("tagged pointers require a 64-bit platform");
+
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+/// アドレスを表すビット
+const ADDR_MASK: usize = (1 << 48) - 1;
+/// 世代を格納する位置
+const TAG_SHIFT: u32 = 48;
+
+struct FreeNode {
+    id: AtomicU32,
+    /// 次のノード（タグなし）
+    next: AtomicPtr<FreeNode>,
+}
+
+fn untagged(p: *mut FreeNode) -> *mut FreeNode {
+    p.map_addr(|addr| addr & ADDR_MASK)
+}
+
+fn tag_of(p: *mut FreeNode) -> usize {
+    p.addr() >> TAG_SHIFT
+}
+
+fn with_tag(p: *mut FreeNode, tag: usize) -> *mut FreeNode {
+    debug_assert_eq!(p.addr() & !ADDR_MASK, 0, "address does not fit in 48 bits");
+    p.map_addr(|addr| addr | (tag << TAG_SHIFT))
+}
+
+/// 上位16ビットに世代を詰めたポインターを先頭とするTreiberスタック
+struct TaggedStack {
+    head: AtomicPtr<FreeNode>,
+}
+
+impl TaggedStack {
+    const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// 安全性: `node`は、どのスタックにも積まれていない有効なノードでなければならない。
+    unsafe fn push(&self, node: *mut FreeNode) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next.store(untagged(head), Ordering::Relaxed) };
+            // `wrapping_add`の結果は、`with_tag`のシフトで上位16ビットに切り詰められる。
+            let new_head = with_tag(node, tag_of(head).wrapping_add(1));
+            // Releaseにより、`pop`したスレッドは`id`と`next`への書き込みを観測できる。
+            match self.head.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<*mut FreeNode> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let node = untagged(head);
+            if node.is_null() {
+                return None;
+            }
+            // 他のスレッドがすでにこのノードを取り出していても、ノードは解放されないため読み込める。
+            // その場合は世代が変化しているため、次の`compare_exchange`は失敗する。
+            let next = unsafe { (*node).next.load(Ordering::Relaxed) };
+            let new_head = with_tag(next, tag_of(head).wrapping_add(1));
+            match self.head.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(node),
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+impl Drop for TaggedStack {
+    fn drop(&mut self) {
+        let mut node = untagged(*self.head.get_mut());
+        while !node.is_null() {
+            let boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next.into_inner();
+        }
+    }
+}
+
+pub struct IdAllocator {
+    /// 次に発行する新しいID
+    next: AtomicU32,
+    /// 解放されたIDを保持するノードのスタック
+    free_list: TaggedStack,
+    /// IDを保持していない予備のノードのスタック
+    spare_nodes: TaggedStack,
+}
+
+impl IdAllocator {
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicU32::new(0),
+            free_list: TaggedStack::new(),
+            spare_nodes: TaggedStack::new(),
+        }
+    }
+
+    /// IDを発行する。
+    ///
+    /// 解放されたIDがある場合は、そのIDを再利用する。
+    ///
+    /// # パニック
+    ///
+    /// 再利用できるIDがなく、すべての`u32`を発行済みの場合はパニックする。
+    pub fn alloc(&self) -> u32 {
+        if let Some(node) = self.free_list.pop() {
+            let id = unsafe { (*node).id.load(Ordering::Relaxed) };
+            unsafe { self.spare_nodes.push(node) };
+            return id;
+        }
+        // 02-03-01と同様に、オーバーフローする場合は`next`を更新しない。
+        self.next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1))
+            .expect("too many IDs!")
+    }
+
+    /// IDを解放し、次の`alloc`で再利用できるようにする。
+    ///
+    /// 同じIDを2回解放すると、同じIDが2回発行されるため、呼び出し側は発行されたIDを1回だけ解放しなければならない。
+    pub fn free(&self, id: u32) {
+        let node = self.spare_nodes.pop().unwrap_or_else(|| {
+            Box::into_raw(Box::new(FreeNode {
+                id: AtomicU32::new(0),
+                next: AtomicPtr::new(ptr::null_mut()),
+            }))
+        });
+        unsafe {
+            (*node).id.store(id, Ordering::Relaxed);
+            self.free_list.push(node);
+        }
+    }
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn main() {
+    let allocator = IdAllocator::new();
+    let ids = (0..5).map(|_| allocator.alloc()).collect::<Vec<_>>();
+    println!("allocated: {ids:?}");
+    allocator.free(1);
+    allocator.free(3);
+    let reused = (0..3).map(|_| allocator.alloc()).collect::<Vec<_>>();
+    println!("allocated after freeing 1 and 3: {reused:?}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn reuses_freed_ids() {
+        let allocator = IdAllocator::new();
+        let ids = (0..1000).map(|_| allocator.alloc()).collect::<Vec<_>>();
+        assert_eq!(ids, (0..1000).collect::<Vec<_>>());
+
+        for &id in ids.iter().filter(|&&id| id % 2 == 1) {
+            allocator.free(id);
+        }
+        // 新しいIDを発行せずに、解放した奇数のIDを再利用するはず。
+        let reused = (0..500).map(|_| allocator.alloc()).collect::<HashSet<_>>();
+        assert_eq!(reused, (0..1000).filter(|id| id % 2 == 1).collect());
+        assert_eq!(allocator.alloc(), 1000);
+    }
+
+    #[test]
+    fn concurrent_alloc_and_free_never_hand_out_live_ids_twice() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 1000;
+        let allocator = IdAllocator::new();
+        let live = std::sync::Mutex::new(HashSet::new());
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        let ids = (0..8).map(|_| allocator.alloc()).collect::<Vec<_>>();
+                        {
+                            let mut live = live.lock().unwrap();
+                            // 発行されたIDは、他のスレッドが使用中のIDと重複しないはず。
+                            for &id in &ids {
+                                assert!(live.insert(id), "id {id} handed out twice");
+                            }
+                        }
+                        std::thread::yield_now();
+                        {
+                            let mut live = live.lock().unwrap();
+                            for &id in &ids {
+                                live.remove(&id);
+                            }
+                        }
+                        for id in ids {
+                            allocator.free(id);
+                        }
+                    }
+                });
+            }
+        });
+        // 同時に使用されるIDは最大で32個であるため、それ以上の新しいIDは発行されないはず。
+        assert!(allocator.alloc() < (THREADS * 8) as u32);
+    }
+
+    #[test]
+    fn tag_is_stored_in_upper_bits() {
+        let node = Box::into_raw(Box::new(FreeNode {
+            id: AtomicU32::new(0),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        let tagged = with_tag(node, 0xffff);
+        assert_eq!(tag_of(tagged), 0xffff);
+        assert_eq!(untagged(tagged), node);
+        // 世代が一周すると、タグは0に戻るはず。
+        assert_eq!(tag_of(with_tag(node, 0x1_0000)), 0);
+        drop(unsafe { Box::from_raw(node) });
+    }
+}
@@ -0,0 +1,331 @@
+//! # `no_std`環境のスピンロック、ミューテックスと`Arc`
+//!
+//! 組み込み機器やOSカーネルのように標準ライブラリ（`std`）を使用できない環境では、`core`と
+//! （ヒープを使用できる場合は）`alloc`だけでプリミティブを実装する必要がある。
+//!
+//! このファイルは`#![no_std]`であり、`SpinLock`、`Mutex`と`Arc`は`core`と`alloc`だけを使用する。
+//! `main`とテストは標準出力とスレッドを使用するため、それぞれのモジュールの中だけで`extern crate std`する。
+//! `extern crate std`はそのモジュールの中だけで有効であるため、プリミティブが誤って`std`を使用すると
+//! コンパイルエラーになる。
+//!
+//! `std`に依存していた部分は、次のように置き換える。
+//!
+//! - `std::process::abort`: `core`には存在しないため、パニック中にもう一度パニックすることで
+//!   プロセスを中断する`abort`関数を用意する。
+//! - `std::thread::yield_now`: OSのスケジューラーを使用できないため、`core::hint::spin_loop`だけでスピンする。
+//! - `atomic_wait`: Linuxでは`libc`（`no_std`で使用できる）でfutexシステムコールを直接呼び出し（08-03-01を参照）、
+//!   それ以外のOSでは待機の代わりにスピンする。
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering, fence};
+
+/// `std::process::abort`の代わりに、プロセスを中断する。
+///
+/// パニックの巻き戻し中にデストラクタがパニックすると、ランタイムはプロセスを中断する。
+/// `panic = "abort"`の場合は、最初のパニックで中断する。
+#[cold]
+fn abort() -> ! {
+    struct Abort;
+
+    impl Drop for Abort {
+        fn drop(&mut self) {
+            panic!("abort");
+        }
+    }
+
+    let _abort = Abort;
+    panic!("abort");
+}
+
+/// 04-03のスピンロックから、`std`に依存する機能（毒状態、所有者の記録、`yield_now`）を除いたもの
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> Guard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| Guard { lock: self })
+    }
+}
+
+pub struct Guard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+unsafe impl<T> Sync for Guard<'_, T> where T: Sync {}
+
+impl<T> Deref for Guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for Guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for Guard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// 08-03-01と同様に、futexシステムコールで待機する。
+#[cfg(target_os = "linux")]
+fn wait(a: &AtomicU32, expected: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            a as *const AtomicU32,
+            libc::FUTEX_WAIT,
+            expected,
+            core::ptr::null::<libc::timespec>(),
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn wake_one(a: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, a as *const AtomicU32, libc::FUTEX_WAKE, 1);
+    }
+}
+
+/// futexがない環境では、待機する代わりにスピンする（呼び出し側は値を再確認するため、すぐに戻ってよい）。
+#[cfg(not(target_os = "linux"))]
+fn wait(a: &AtomicU32, expected: u32) {
+    while a.load(Ordering::Relaxed) == expected {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wake_one(_a: &AtomicU32) {}
+
+/// 09-01-02のミューテックス
+pub struct Mutex<T> {
+    /// 0: ロックされていない状態
+    /// 1: ロックされており、待機中のスレッドがない状態
+    /// 2: ロックされており、待機中のスレッドがある状態
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.state.swap(2, Ordering::Acquire) != 0 {
+                wait(&self.state, 2);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.mutex.state.swap(0, Ordering::Release) == 2 {
+            wake_one(&self.mutex.state);
+        }
+    }
+}
+
+struct ArcData<T> {
+    ref_count: AtomicUsize,
+    data: T,
+}
+
+/// 06-01の`Arc`
+///
+/// `Box`は`alloc`にあるため、`std`なしで使用できる。
+pub struct Arc<T> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+
+impl<T> Arc<T> {
+    pub fn new(data: T) -> Self {
+        Arc {
+            ptr: NonNull::from(Box::leak(Box::new(ArcData {
+                ref_count: AtomicUsize::new(1),
+                data,
+            }))),
+        }
+    }
+
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data().data
+    }
+}
+
+impl<T> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        if self.data().ref_count.fetch_add(1, Ordering::Relaxed) > usize::MAX / 2 {
+            abort();
+        }
+        Arc { ptr: self.ptr }
+    }
+}
+
+impl<T> Drop for Arc<T> {
+    fn drop(&mut self) {
+        if self.data().ref_count.fetch_sub(1, Ordering::Release) == 1 {
+            fence(Ordering::Acquire);
+            unsafe {
+                drop(Box::from_raw(self.ptr.as_ptr()));
+            }
+        }
+    }
+}
+
+mod host {
+    extern crate std;
+
+    use super::*;
+
+    pub fn main() {
+        static COUNTER: SpinLock<u32> = SpinLock::new(0);
+        static TOTAL: Mutex<u32> = Mutex::new(0);
+        let shared = Arc::new(10);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                let shared = shared.clone();
+                s.spawn(move || {
+                    *COUNTER.lock() += 1;
+                    *TOTAL.lock() += *shared;
+                });
+            }
+        });
+        std::println!("counter: {}, total: {}", *COUNTER.lock(), *TOTAL.lock());
+    }
+}
+
+fn main() {
+    host::main();
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn spin_lock_and_mutex_keep_counts_exact() {
+        let spin_lock = SpinLock::new(0);
+        let mutex = Mutex::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *spin_lock.lock() += 1;
+                        *mutex.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*spin_lock.lock(), 40_000);
+        assert_eq!(*mutex.lock(), 40_000);
+        assert!(spin_lock.try_lock().is_some());
+    }
+
+    #[test]
+    fn arc_drops_data_once() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let x = Arc::new(("hello", DetectDrop));
+        let y = x.clone();
+        let t = std::thread::spawn(move || assert_eq!(x.0, "hello"));
+        assert_eq!(y.0, "hello");
+        t.join().unwrap();
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 0);
+        drop(y);
+        // すべての`Arc`がドロップされた時点で、1回だけドロップされるはず。
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,171 @@
+//! # loomによる並行処理のテスト
+//!
+//! 並行処理のテストは、スレッドの実行順序（インターリーブ）がたまたま問題を起こさない順序になると成功してしまう。
+//! [loom](https://docs.rs/loom)は、アトミック操作のたびにスレッドを切り替えて、すべての実行順序を探索する。
+//! また、C++メモリモデルに従って、`Relaxed`のロードが古い値を読み込む場合も探索するため、
+//! 通常のテストでは再現しにくいメモリオーダリングの誤りも検出できる。
+//!
+//! 06-03の`Arc`と09-01-02のミューテックスのファイルを`#[path]`でモジュールとして読み込み、それぞれの例の
+//! 実装をそのまま検査する。
+//! 2つのファイルは`shared/sync.rs`経由でアトミック型を使用しており、`--cfg loom`を指定した場合だけ、
+//! loomのアトミック型に置き換わる（futexで待機する代わりに、CPUを譲ってから値を再確認する）。
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --example 10-21_loom
+//! ```
+//!
+//! `--cfg loom`を指定しない場合、`cargo test --example 10-21_loom`は、読み込んだ2つの例のテストも実行する。
+//!
+//! 探索する実行順序はスレッド数と操作数に対して指数的に増えるため、loomのテストは2〜3スレッド、
+//! 2〜3操作に留める。
+//!
+//! ミューテックスが保護する値は標準ライブラリの`UnsafeCell`に格納されており、loomは値へのアクセスを検査しない。
+//! そのため、loomのテストではloomの`UnsafeCell`を値として保護し、ロックを保持している間に`with_mut`でアクセスする。
+//! loomの`UnsafeCell`は、アクセスするたびに、他のスレッドのアクセスと先行発生関係があるかを検査するため、
+//! ミューテックスのオーダリングが誤っていれば、値への同時アクセスとして検出される。
+
+/// 06-03の`Arc`
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+// また、06-03と09-01-02はどちらも`shared/sync.rs`を読み込むため`duplicate_mod`も許可する。
+#[allow(dead_code, clippy::duplicate_mod)]
+#[path = "06-03_optimization.rs"]
+mod arc;
+
+/// 09-01-02のミューテックス
+#[allow(dead_code, clippy::duplicate_mod)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+#[cfg(not(loom))]
+use arc::Arc;
+#[cfg(not(loom))]
+use futex_mutex::Mutex;
+
+#[cfg(not(loom))]
+fn main() {
+    let counter = Arc::new(Mutex::new(0));
+    let threads = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    *counter.lock() += 1;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    println!("counter: {}", *counter.lock());
+}
+
+#[cfg(loom)]
+fn main() {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutex_in_arc_counts_exactly() {
+        let counter = Arc::new(Mutex::new(0));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                let counter = counter.clone();
+                s.spawn(move || {
+                    for _ in 0..10_000 {
+                        *counter.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*counter.lock(), 40_000);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::arc::Arc;
+    use super::futex_mutex::Mutex;
+    use loom::cell::UnsafeCell;
+    use loom::sync::atomic::{AtomicUsize, Ordering};
+    use loom::thread;
+
+    struct DetectDrop(std::sync::Arc<AtomicUsize>);
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn arc_drops_data_exactly_once() {
+        loom::model(|| {
+            let drops = std::sync::Arc::new(AtomicUsize::new(0));
+            let x = Arc::new(DetectDrop(drops.clone()));
+            let y = x.clone();
+            let t = thread::spawn(move || drop(x));
+            drop(y);
+            t.join().unwrap();
+            // どの実行順序でも、データは1回だけドロップされるはず。
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn weak_upgrade_races_with_last_drop() {
+        loom::model(|| {
+            let drops = std::sync::Arc::new(AtomicUsize::new(0));
+            let x = Arc::new(DetectDrop(drops.clone()));
+            let weak = Arc::downgrade(&x);
+            let t = thread::spawn(move || drop(x));
+            // 最後の`Arc`がドロップされる前に`upgrade`できた場合は、その`Arc`がドロップされるまで
+            // データはドロップされないはず。
+            if let Some(upgraded) = weak.upgrade() {
+                assert_eq!(drops.load(Ordering::Relaxed), 0);
+                drop(upgraded);
+            }
+            t.join().unwrap();
+            assert_eq!(drops.load(Ordering::Relaxed), 1);
+            assert!(weak.upgrade().is_none());
+        });
+    }
+
+    #[test]
+    fn mutex_lock_unlock_round_trip() {
+        loom::model(|| {
+            let mutex = Arc::new(Mutex::new(UnsafeCell::new(0)));
+            let threads = (0..2)
+                .map(|_| {
+                    let mutex = mutex.clone();
+                    thread::spawn(move || mutex.lock().with_mut(|v| unsafe { *v += 1 }))
+                })
+                .collect::<Vec<_>>();
+            for t in threads {
+                t.join().unwrap();
+            }
+            // どの実行順序でも、値へのアクセスは排他的であり、更新は失われないはず。
+            assert_eq!(mutex.lock().with(|v| unsafe { *v }), 2);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "Causality violation")]
+    fn access_after_unlock_is_detected() {
+        // ロックを解放した後に値にアクセスすると、他のスレッドがロックを保持している間のアクセスと
+        // 先行発生関係がないため、loomは値への同時アクセスとして検出するはず。
+        loom::model(|| {
+            let mutex = Arc::new(Mutex::new(UnsafeCell::new(0)));
+            let other = mutex.clone();
+            let t = thread::spawn(move || other.lock().with_mut(|v| unsafe { *v += 1 }));
+            let cell: *const UnsafeCell<i32> = {
+                let guard = mutex.lock();
+                &*guard
+            };
+            unsafe { (*cell).with_mut(|v| *v += 1) };
+            t.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,200 @@
+//! # シャード化したミューテックス
+//!
+//! 複数のスレッドが、それぞれ独立した値を頻繁に更新する場合でも、`Mutex<Vec<T>>`のように1つのミューテックスで
+//! 保護すると、すべてのアクセスが直列化される。
+//! `ShardedMutex<T, N>`は、`N`個の独立したミューテックス（シャード）を保持し、キーによってシャードを選択する。
+//! 異なるシャードにアクセスするスレッドは、互いにロックを待機しない。
+//!
+//! 各シャードは、07-02-02と同様に64バイトにアラインして、別のキャッシュラインに配置する。
+//! 同じキャッシュラインに配置すると、異なるシャードのロックを獲得するたびにキャッシュラインが無効化され合う
+//! （フォールスシェアリング）。
+//!
+//! すべてのシャードのロックを獲得する`lock_all`は、常にインデックスの小さいシャードから順に獲得する。
+//! すべてのスレッドが同じ順序でロックを獲得するため、デッドロックは発生しない。
+//!
+//! カウンター、ハッシュマップ、コネクションプールなどに使用できる。
+//!
+//! 各シャードは、09-01-02のファイルを`#[path]`でモジュールとして読み込んだミューテックスであり、
+//! 統計情報（`stats`フィーチャー）や、デバッグビルドの自己デッドロックの検出をシャードごとに使用できる。
+//! そのため、`cargo test --example 10-22_sharded-mutex`は、09-01-02のテストも実行する。
+use std::ops::Deref;
+
+/// 09-01-02のミューテックス
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+#[allow(dead_code)]
+#[path = "09-01-02_further-improvements.rs"]
+mod futex_mutex;
+
+use futex_mutex::{Mutex, MutexGuard};
+
+/// 値を64バイトにアラインして、他の値とキャッシュラインを共有しないようにするラッパー
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+pub struct ShardedMutex<T, const N: usize> {
+    shards: [CachePadded<Mutex<T>>; N],
+}
+
+impl<T, const N: usize> ShardedMutex<T, N> {
+    /// `values[i]`を`i`番目のシャードの値とする。
+    pub fn new(values: [T; N]) -> Self {
+        const { assert!(N > 0, "ShardedMutex requires at least one shard") };
+        Self {
+            shards: values.map(|value| CachePadded(Mutex::new(value))),
+        }
+    }
+
+    /// `key`に対応するシャード（`key % N`番目）を返す。
+    pub fn shard(&self, key: usize) -> &Mutex<T> {
+        &self.shards[key % N]
+    }
+
+    /// すべてのシャードのロックを、インデックスの小さい順に獲得する。
+    ///
+    /// `lock_all`を呼び出すスレッドは常に同じ順序でロックを獲得するため、複数のスレッドが同時に`lock_all`を
+    /// 呼び出しても、デッドロックは発生しない。
+    /// ただし、1つのシャードのロックを保持したまま`lock_all`を呼び出すと、同じシャードを2回ロックするため、
+    /// デッドロックする（デバッグビルドでは、09-01-02のミューテックスがパニックする）。
+    pub fn lock_all(&self) -> [MutexGuard<'_, T>; N] {
+        std::array::from_fn(|i| self.shards[i].lock())
+    }
+
+    /// シャードを消費して、すべてのシャードの値を返す。
+    pub fn into_inner(self) -> [T; N] {
+        self.shards.map(|shard| shard.0.into_inner())
+    }
+}
+
+impl<T: Default, const N: usize> Default for ShardedMutex<T, N> {
+    fn default() -> Self {
+        Self::new(std::array::from_fn(|_| T::default()))
+    }
+}
+
+fn main() {
+    // スレッドごとに異なるシャードのカウンターをインクリメントし、最後に合計する。
+    let counters = ShardedMutex::<u64, 8>::default();
+    std::thread::scope(|s| {
+        for t in 0..8 {
+            let counters = &counters;
+            s.spawn(move || {
+                for _ in 0..1_000_000 {
+                    *counters.shard(t).lock() += 1;
+                }
+            });
+        }
+    });
+    let total = counters.lock_all().iter().map(|guard| **guard).sum::<u64>();
+    println!("total: {total}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn shards_are_on_separate_cache_lines() {
+        let sharded = ShardedMutex::<u8, 4>::default();
+        let addresses = (0..4)
+            .map(|i| sharded.shard(i) as *const Mutex<u8> as usize)
+            .collect::<Vec<_>>();
+        for pair in addresses.windows(2) {
+            assert!(pair[1] - pair[0] >= 64);
+        }
+        // キーは`key % N`番目のシャードに対応するはず。
+        assert!(std::ptr::eq(sharded.shard(1), sharded.shard(5)));
+    }
+
+    #[test]
+    fn lock_all_sees_every_shard() {
+        let counters = ShardedMutex::<u32, 4>::default();
+        std::thread::scope(|s| {
+            for t in 0..8 {
+                let counters = &counters;
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        *counters.shard(t).lock() += 1;
+                    }
+                });
+            }
+            // 他のスレッドが更新している間も、`lock_all`同士はデッドロックしないはず。
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let guards = counters.lock_all();
+                        assert!(guards.iter().map(|guard| **guard).sum::<u32>() <= 8000);
+                    }
+                });
+            }
+        });
+        // 2つのスレッドが同じシャードを更新するため、各シャードは2000になるはず。
+        assert_eq!(counters.into_inner(), [2000; 4]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "already held by this thread")]
+    fn lock_all_while_holding_a_shard_panics_in_debug() {
+        let sharded = ShardedMutex::<u32, 4>::default();
+        let _guard = sharded.shard(2).lock();
+        // 09-01-02のミューテックスは、同じスレッドが2回ロックしようとしたことを検出するため、
+        // デッドロックせずにパニックするはず。
+        let _guards = sharded.lock_all();
+    }
+
+    #[test]
+    fn independent_shards_do_not_serialize() {
+        const THREADS: usize = 4;
+        const ITERATIONS: usize = 10;
+        // ロックを保持したまま1ミリ秒スリープするクリティカルセクションを、それぞれのスレッドで繰り返す。
+        fn critical_section(value: &mut usize) {
+            std::thread::sleep(Duration::from_millis(1));
+            *value += 1;
+        }
+
+        let single = Mutex::new(0);
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ITERATIONS {
+                        critical_section(&mut single.lock());
+                    }
+                });
+            }
+        });
+        let single_elapsed = start.elapsed();
+
+        let sharded = ShardedMutex::<usize, THREADS>::default();
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            for t in 0..THREADS {
+                let sharded = &sharded;
+                s.spawn(move || {
+                    for _ in 0..ITERATIONS {
+                        critical_section(&mut sharded.shard(t).lock());
+                    }
+                });
+            }
+        });
+        let sharded_elapsed = start.elapsed();
+
+        assert_eq!(single.into_inner(), THREADS * ITERATIONS);
+        assert_eq!(sharded.into_inner(), [ITERATIONS; THREADS]);
+        // 1つのミューテックスではすべてのクリティカルセクションが直列化されるが、異なるシャードは並行して
+        // 実行されるため、大幅に速いはず。
+        assert!(
+            sharded_elapsed * 2 < single_elapsed,
+            "sharded: {sharded_elapsed:?}, single: {single_elapsed:?}"
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! # ワークスティーリング両端キュー（Chase-Lev deque）
+//!
+//! スレッドプールのスケジューラーでは、各ワーカースレッドが自分のタスクを両端キューに積み、
+//! 自分のキューが空になったスレッドは、他のスレッドのキューからタスクを盗む（ワークスティーリング）。
+//!
+//! - 所有者（`Worker`）は、下端（`bottom`）に`push`し、下端から`pop`する（LIFO）。
+//!   直前に積んだタスクはキャッシュに残っている可能性が高い。
+//! - 他のスレッド（`Stealer`）は、上端（`top`）から`steal`する（FIFO）。
+//!   古いタスクほど大きな処理に分割される前のタスクであることが多く、盗む回数を減らせる。
+//!
+//! `push`と`pop`は所有者だけが呼び出すため、`bottom`を変更するのは所有者だけである。
+//! `top`は`steal`と、最後の1つを取り出す`pop`が`compare_exchange`でインクリメントする。
+//! 実装は、Lêらによる、C11のメモリモデルに対応したChase-Lev dequeに従う。
+//!
+//! ## インデックス
+//!
+//! `top`と`bottom`は、ラップアラウンドする`usize`のインデックスである。
+//! `pop`は空のキューでも一時的に`bottom`をデクリメントするため、`bottom - top`は負になることがある。
+//! そこで、要素の数は`bottom.wrapping_sub(top)`を`isize`として解釈して求める。
+//!
+//! ## バッファの拡張
+//!
+//! バッファは容量が2の累乗の循環バッファであり、インデックスの下位ビットで要素の位置を決める。
+//! 満杯の状態で`push`すると、所有者は次の2段階でバッファを拡張する。
+//!
+//! 1. 容量が2倍の新しいバッファを確保し、`top`から`bottom`までの要素をコピーする。
+//! 2. 新しいバッファへのポインタを`Release`でストアして公開する。
+//!    `Acquire`でポインタを読み込んだ`steal`は、コピーした要素を観測できる。
+//!
+//! 古いバッファを読み込んでいる`steal`が残っている可能性があるため、古いバッファはすぐに解放せず、
+//! 両端キューがドロップされるまで保持する。
+//! 古いバッファの要素は新しいバッファの要素のビット単位のコピーであり、`top`の`compare_exchange`に
+//! 成功した1つのスレッドだけが取り出すため、2回取り出されることはない。
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering, fence};
+
+/// 最初に確保するバッファの容量
+const INITIAL_CAPACITY: usize = 16;
+
+/// 容量が2の累乗の循環バッファ
+struct Buffer<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+impl<T> Buffer<T> {
+    fn alloc(capacity: usize) -> *mut Self {
+        debug_assert!(capacity.is_power_of_two());
+        let slots = (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect();
+        Box::into_raw(Box::new(Self { slots }))
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index & (self.capacity() - 1)].get()
+    }
+
+    /// 安全性: 他のスレッドが同時に`index`の要素を読み書きしていてはならない。
+    unsafe fn write(&self, index: usize, value: MaybeUninit<T>) {
+        unsafe { self.slot(index).write(value) };
+    }
+
+    /// `index`の要素のビット単位のコピーを返す。
+    ///
+    /// `steal`は、読み込んだ後に`top`の`compare_exchange`に失敗した場合、コピーを破棄する。
+    /// その場合は、所有者が同じ位置に書き込んでいる最中に読み込んでいる可能性があるため、
+    /// crossbeam-dequeと同様に、最適化で読み込みが省略されたり分割されたりしないように`read_volatile`で読み込む。
+    ///
+    /// 安全性: 戻り値は、`top`の`compare_exchange`に成功した場合だけ、初期化された値として扱わなければならない。
+    unsafe fn read(&self, index: usize) -> MaybeUninit<T> {
+        unsafe { self.slot(index).read_volatile() }
+    }
+}
+
+struct Inner<T> {
+    /// `steal`で次に取り出す要素のインデックス
+    top: AtomicUsize,
+    /// 次に`push`する要素のインデックス
+    bottom: AtomicUsize,
+    buffer: AtomicPtr<Buffer<T>>,
+    /// 拡張する前の古いバッファ（所有者だけがアクセスする）
+    retired: UnsafeCell<Vec<*mut Buffer<T>>>,
+}
+
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let top = *self.top.get_mut();
+        let bottom = *self.bottom.get_mut();
+        let buffer = unsafe { Box::from_raw(*self.buffer.get_mut()) };
+        // 残っている要素をドロップする。
+        let mut i = top;
+        while i != bottom {
+            unsafe { buffer.read(i).assume_init_drop() };
+            i = i.wrapping_add(1);
+        }
+        // 古いバッファの要素は、現在のバッファの要素のコピーであるため、ドロップせずにメモリだけを解放する。
+        for retired in self.retired.get_mut().drain(..) {
+            drop(unsafe { Box::from_raw(retired) });
+        }
+    }
+}
+
+/// 両端キューの所有者
+///
+/// `push`と`pop`は1つのスレッドだけが呼び出せるように、`Worker`は`Sync`を実装しない。
+/// 別のスレッドにムーブすることはできる。
+pub struct Worker<T> {
+    inner: Arc<Inner<T>>,
+    _not_sync: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: Send> Send for Worker<T> {}
+
+/// 両端キューからタスクを盗むハンドル
+pub struct Stealer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// 空の両端キューを作成し、所有者と`steal`するハンドルを返す。
+pub fn deque<T>() -> (Worker<T>, Stealer<T>) {
+    let inner = Arc::new(Inner {
+        top: AtomicUsize::new(0),
+        bottom: AtomicUsize::new(0),
+        buffer: AtomicPtr::new(Buffer::alloc(INITIAL_CAPACITY)),
+        retired: UnsafeCell::new(Vec::new()),
+    });
+    (
+        Worker {
+            inner: Arc::clone(&inner),
+            _not_sync: PhantomData,
+        },
+        Stealer { inner },
+    )
+}
+
+impl<T> Worker<T> {
+    /// 下端に要素を追加する。
+    pub fn push(&self, value: T) {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        // `steal`がインクリメントした`top`と同期して、取り出された位置を再利用できることを確認する。
+        let top = inner.top.load(Ordering::Acquire);
+        let mut buffer = inner.buffer.load(Ordering::Relaxed);
+        if bottom.wrapping_sub(top) >= unsafe { (*buffer).capacity() } {
+            buffer = unsafe { self.grow(buffer, top, bottom) };
+        }
+        unsafe { (*buffer).write(bottom, MaybeUninit::new(value)) };
+        // `bottom`を`Relaxed`でストアする前に、要素への書き込みを公開する。
+        // `steal`は`bottom`を`Acquire`で読み込むため、このフェンスと同期する。
+        fence(Ordering::Release);
+        inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    /// 容量が2倍のバッファに`top`から`bottom`までの要素をコピーして公開し、新しいバッファを返す。
+    ///
+    /// 安全性: 所有者だけが呼び出せる。`old`は現在のバッファでなければならない。
+    unsafe fn grow(&self, old: *mut Buffer<T>, top: usize, bottom: usize) -> *mut Buffer<T> {
+        let inner = &*self.inner;
+        let new = Buffer::alloc(unsafe { (*old).capacity() } * 2);
+        // 1段階目: 新しいバッファに要素をコピーする。
+        let mut i = top;
+        while i != bottom {
+            unsafe { (*new).write(i, (*old).read(i)) };
+            i = i.wrapping_add(1);
+        }
+        // 2段階目: 新しいバッファを公開する。
+        // バッファのポインタを変更するのは所有者だけであるため、`compare_exchange`は必要ない。
+        // `Release`により、`Acquire`でポインタを読み込んだ`steal`は、コピーした要素を観測できる。
+        inner.buffer.store(new, Ordering::Release);
+        // 古いバッファを読み込んでいる`steal`が残っている可能性があるため、ドロップされるまで保持する。
+        unsafe { (*inner.retired.get()).push(old) };
+        new
+    }
+
+    /// 下端から要素を取り出す。
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed).wrapping_sub(1);
+        let buffer = inner.buffer.load(Ordering::Relaxed);
+        // 先に`bottom`をデクリメントして、`steal`が最後の要素を取り出せないようにする。
+        inner.bottom.store(bottom, Ordering::Relaxed);
+        // `bottom`のストアと`top`のロードの順序が入れ替わると、`steal`と同じ要素を取り出してしまうため、
+        // `SeqCst`フェンスで順序を保証する（`steal`も`top`と`bottom`の間に`SeqCst`フェンスを置く）。
+        fence(Ordering::SeqCst);
+        let top = inner.top.load(Ordering::Relaxed);
+
+        let len = bottom.wrapping_sub(top) as isize;
+        if len < 0 {
+            // 空だったため、`bottom`を元に戻す。
+            inner
+                .bottom
+                .store(bottom.wrapping_add(1), Ordering::Relaxed);
+            return None;
+        }
+        let value = unsafe { (*buffer).read(bottom) };
+        if len > 0 {
+            // 2つ以上残っていたため、`steal`と競合しない。
+            return Some(unsafe { value.assume_init() });
+        }
+
+        // 最後の1つは、`steal`と`top`のインクリメントを競う。
+        let won = inner
+            .top
+            .compare_exchange(
+                top,
+                top.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Relaxed);
+        // 競争に負けた場合は、`steal`がすでに取り出しているため、コピーを破棄する。
+        won.then(|| unsafe { value.assume_init() })
+    }
+
+    /// 要素がない場合は`true`を返す。
+    pub fn is_empty(&self) -> bool {
+        let bottom = self.inner.bottom.load(Ordering::Relaxed);
+        let top = self.inner.top.load(Ordering::Relaxed);
+        bottom.wrapping_sub(top) as isize <= 0
+    }
+
+    /// この両端キューから`steal`するハンドルを作成する。
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Stealer<T> {
+    /// 上端から要素を取り出す。
+    ///
+    /// 他のスレッドと競合した場合は再試行し、空の場合だけ`None`を返す。
+    pub fn steal(&self) -> Option<T> {
+        let inner = &*self.inner;
+        loop {
+            let top = inner.top.load(Ordering::Acquire);
+            fence(Ordering::SeqCst);
+            // `push`のReleaseフェンスと同期して、`bottom`より前の要素への書き込みを観測する。
+            let bottom = inner.bottom.load(Ordering::Acquire);
+            if bottom.wrapping_sub(top) as isize <= 0 {
+                return None;
+            }
+
+            // `grow`が公開したバッファへの書き込みを観測するため、`Acquire`で読み込む。
+            let buffer = inner.buffer.load(Ordering::Acquire);
+            let value = unsafe { (*buffer).read(top) };
+            if inner
+                .top
+                .compare_exchange(
+                    top,
+                    top.wrapping_add(1),
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(unsafe { value.assume_init() });
+            }
+            // 他の`steal`か、最後の要素を取り出す`pop`に負けたため、コピーを破棄して再試行する。
+        }
+    }
+}
+
+fn main() {
+    let (worker, stealer) = deque();
+    for i in 0..10 {
+        worker.push(i);
+    }
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            // 上端（古い順）から盗む。
+            while let Some(task) = stealer.steal() {
+                println!("stolen: {task}");
+            }
+        });
+    });
+    while let Some(task) = worker.pop() {
+        println!("popped: {task}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn pop_is_lifo_and_steal_is_fifo() {
+        let (worker, stealer) = deque();
+        // 初期容量を超えて積み、バッファを拡張させる。
+        for i in 0..100 {
+            worker.push(i);
+        }
+        assert_eq!(stealer.steal(), Some(0));
+        assert_eq!(stealer.steal(), Some(1));
+        assert_eq!(worker.pop(), Some(99));
+        assert_eq!(worker.pop(), Some(98));
+
+        let mut rest = Vec::new();
+        while let Some(i) = worker.pop() {
+            rest.push(i);
+        }
+        assert_eq!(rest, (2..98).rev().collect::<Vec<_>>());
+        assert!(worker.is_empty());
+        assert_eq!(worker.pop(), None);
+        assert_eq!(stealer.steal(), None);
+
+        // 空の状態で`pop`しても、その後の`push`と`steal`は正しく動作するはず。
+        worker.push(100);
+        assert_eq!(stealer.steal(), Some(100));
+    }
+
+    #[test]
+    fn every_task_runs_exactly_once_with_eight_stealers() {
+        const TASKS: usize = 10_000;
+        const STEALERS: usize = 8;
+        let (worker, stealer) = deque();
+        let done = AtomicBool::new(false);
+        let mut executed = std::thread::scope(|s| {
+            let threads = (0..STEALERS)
+                .map(|_| {
+                    let stealer = stealer.clone();
+                    let done = &done;
+                    s.spawn(move || {
+                        let mut executed = Vec::new();
+                        loop {
+                            // `push`が終わったことを確認してから`steal`し、空ならば終了する。
+                            let finished = done.load(Ordering::Acquire);
+                            match stealer.steal() {
+                                Some(task) => executed.push(task),
+                                None if finished => return executed,
+                                None => std::thread::yield_now(),
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            // 所有者は、積みながらときどき自分でも取り出す。
+            let mut executed = Vec::new();
+            for task in 0..TASKS {
+                worker.push(task);
+                if task.is_multiple_of(3)
+                    && let Some(task) = worker.pop()
+                {
+                    executed.push(task);
+                }
+            }
+            done.store(true, Ordering::Release);
+            while let Some(task) = worker.pop() {
+                executed.push(task);
+            }
+            for t in threads {
+                executed.extend(t.join().unwrap());
+            }
+            executed
+        });
+        // すべてのタスクが、ちょうど1回ずつ実行されたはず。
+        executed.sort_unstable();
+        assert_eq!(executed, (0..TASKS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn remaining_tasks_are_dropped_once() {
+        static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct DetectDrop;
+
+        impl Drop for DetectDrop {
+            fn drop(&mut self) {
+                NUM_DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let (worker, stealer) = deque();
+        // 2回拡張させる。
+        for _ in 0..INITIAL_CAPACITY * 4 {
+            worker.push(DetectDrop);
+        }
+        drop(stealer.steal());
+        drop(worker.pop());
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 2);
+        drop(worker);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), 2);
+        // 古いバッファのコピーはドロップされず、残っている要素だけが1回ずつドロップされるはず。
+        drop(stealer);
+        assert_eq!(NUM_DROPS.load(Ordering::Relaxed), INITIAL_CAPACITY * 4);
+    }
+}
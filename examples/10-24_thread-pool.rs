@@ -0,0 +1,153 @@
+//! # スレッドプール
+//!
+//! あらかじめ起動した`n`個のワーカースレッドで、送信されたタスクを実行する。
+//! タスクは09-02のチャネル（09-01-02のミューテックスと09-02の条件変数で実装したもの）で送信し、
+//! 各ワーカーは`receive`でタスクを待機して実行する。
+//!
+//! チャネルには`Option<Job>`を送信する。
+//! `shutdown`は、ワーカーの数だけ`None`を送信して終了を通知し、すべてのワーカーを`join`する。
+//! キューは先入れ先出しであるため、`None`より前に送信されたタスクは、すべて実行されてから終了する。
+//!
+//! タスクがパニックした場合は、そのタスクを実行していたワーカーだけが終了する。
+//!
+//! 09-02と10-09のファイルを`#[path]`でモジュールとして読み込み、09-02のチャネルと10-09のカウントダウンラッチを
+//! そのまま使用する。
+//! そのため、`cargo test --example 10-24_thread-pool`は、09-02、09-01-02、10-09のテストも実行する。
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// 09-02のチャネル（09-01-02のミューテックスと09-02の条件変数）
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+#[allow(dead_code)]
+#[path = "09-02_condvar.rs"]
+mod condvar;
+
+/// 10-09のカウントダウンラッチ
+#[allow(dead_code)]
+#[path = "10-09_count-down-latch.rs"]
+mod latch;
+
+use condvar::Channel;
+use latch::CountDownLatch;
+
+/// ワーカーが実行するタスク
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    /// タスクを送信するチャネル（`None`はワーカーに終了を通知する）
+    sender: Arc<Channel<Option<Job>>>,
+}
+
+impl ThreadPool {
+    /// `n`個のワーカースレッドを起動する。
+    ///
+    /// # パニック
+    ///
+    /// `n`が0の場合はパニックする。
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0, "thread pool requires at least one worker");
+        let sender = Arc::new(Channel::<Option<Job>>::new());
+        let workers = (0..n)
+            .map(|_| {
+                let receiver = Arc::clone(&sender);
+                std::thread::spawn(move || {
+                    while let Some(job) = receiver.receive() {
+                        job();
+                    }
+                })
+            })
+            .collect();
+        Self { workers, sender }
+    }
+
+    /// タスクを送信する。タスクは、いずれかのワーカーで実行される。
+    pub fn submit<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender.send(Some(Box::new(f)));
+    }
+
+    /// すでに送信されたタスクをすべて実行してから、ワーカーを終了させる。
+    pub fn shutdown(mut self) {
+        self.join_workers();
+    }
+
+    fn join_workers(&mut self) {
+        // 各ワーカーは`None`を1つ受信して終了するため、ワーカーの数だけ送信する。
+        for _ in &self.workers {
+            self.sender.send(None);
+        }
+        for worker in self.workers.drain(..) {
+            // タスクがパニックしたワーカーは、すでに終了している。
+            let _ = worker.join();
+        }
+    }
+}
+
+/// `shutdown`を呼び出さずにドロップした場合も、ワーカーを終了させる。
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.join_workers();
+    }
+}
+
+fn main() {
+    let pool = ThreadPool::new(4);
+    let latch = Arc::new(CountDownLatch::new(8));
+    for i in 0..8 {
+        let latch = Arc::clone(&latch);
+        pool.submit(move || {
+            println!("task {i} on {:?}", std::thread::current().id());
+            latch.count_down();
+        });
+    }
+    latch.wait();
+    pool.shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn runs_thousand_tasks() {
+        const TASKS: usize = 1000;
+        let pool = ThreadPool::new(4);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let latch = Arc::new(CountDownLatch::new(TASKS as u32));
+        for _ in 0..TASKS {
+            let counter = Arc::clone(&counter);
+            let latch = Arc::clone(&latch);
+            pool.submit(move || {
+                counter.fetch_add(1, Ordering::Relaxed);
+                latch.count_down();
+            });
+        }
+        // ラッチの`count_down`はReleaseであるため、待機が解除された時点で、すべてのインクリメントを観測できるはず。
+        latch.wait();
+        assert_eq!(counter.load(Ordering::Relaxed), TASKS);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn shutdown_runs_pending_tasks_first() {
+        let pool = ThreadPool::new(2);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let counter = Arc::clone(&counter);
+            pool.submit(move || {
+                std::thread::sleep(Duration::from_millis(5));
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        // `None`は送信済みのタスクの後に受信されるため、すべてのタスクが実行されてから終了するはず。
+        pool.shutdown();
+        assert_eq!(counter.load(Ordering::Relaxed), 10);
+        // ワーカーが終了したため、タスクが保持していた`Arc`はすべてドロップされているはず。
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+}
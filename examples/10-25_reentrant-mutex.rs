@@ -0,0 +1,248 @@
+//! # 再入可能なミューテックス
+//!
+//! 09-01-02のミューテックスは、ロックを保持しているスレッドが同じミューテックスを再びロックすると、
+//! 自分自身の解放を待機してデッドロックする。
+//! コールバックを呼び出すAPIなどでは、コールバックの中から同じミューテックスをロックすることがある。
+//!
+//! `ReentrantMutex<T>`は、ロックを保持しているスレッド（所有者）のIDと再帰の深さを記録し、
+//! 所有者が再びロックした場合は、futexの状態を変更せずに深さだけを増やす。
+//! 深さが0に戻ったときに、09-01-02と同じ状態遷移でロックを解放する。
+//!
+//! 同じスレッドが複数のガードを同時に保持できるため、ガードから`&mut T`を得られると、
+//! 同じ値への可変参照が複数存在してしまう。
+//! そのため、ガードは`&T`だけを提供し（標準ライブラリの`ReentrantLock`と同じ設計）、
+//! 値を変更する場合は`ReentrantMutex<RefCell<T>>`のように内部可変性を使用する。
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use atomic_wait::{wait, wake_one};
+
+/// 所有者がいないことを表すスレッドID
+const NO_OWNER: usize = 0;
+
+/// スレッドIDを割り当てるカウンター（0は`NO_OWNER`であるため、1から割り当てる）
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    /// スレッドごとに1回だけ割り当てて、キャッシュしたスレッドID
+    static THREAD_ID: usize = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 現在のスレッドのIDを返す。
+///
+/// 終了したスレッドのIDを再利用しないため、異なるスレッドが同じIDを持つことはない。
+fn current_thread_id() -> usize {
+    THREAD_ID.with(|id| *id)
+}
+
+pub struct ReentrantMutex<T> {
+    /// 09-01-02と同じ状態
+    ///
+    /// 0: ロックされていない状態
+    /// 1: ロックされており、待機中のスレッドがない状態
+    /// 2: ロックされており、待機中のスレッドがある状態
+    state: AtomicU32,
+    /// ロックを保持しているスレッドのID（ロックされていない場合は`NO_OWNER`）
+    ///
+    /// 自分のIDを書き込むのは自分自身だけであるため、`Relaxed`で読み込んでも、自分がロックを保持しているかを
+    /// 正しく判定できる。
+    owner: AtomicUsize,
+    /// 再帰の深さ（所有者だけがアクセスする）
+    depth: UnsafeCell<usize>,
+    value: T,
+}
+
+// ガードは`&T`だけを提供し、同時に`&T`にアクセスするのは所有者のスレッドだけであるため、`T: Sync`は不要である。
+unsafe impl<T> Sync for ReentrantMutex<T> where T: Send {}
+
+/// ガードは、ロックしたスレッドで解放する必要があるため、`Send`を実装しない。
+///
+/// ガードは`&T`だけを提供する（`DerefMut`を実装しない）ため、値を変更することはできない。
+/// どちらも`tests`の`assert_not_impl_any!`で確認している。
+pub struct ReentrantMutexGuard<'a, T> {
+    mutex: &'a ReentrantMutex<T>,
+    /// 生ポインターを保持して、`Send`と`Sync`を実装しないようにする。
+    _not_send: PhantomData<*const ()>,
+}
+
+unsafe impl<T> Sync for ReentrantMutexGuard<'_, T> where T: Sync {}
+
+impl<T> Deref for ReentrantMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.mutex.value
+    }
+}
+
+impl<T> ReentrantMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            owner: AtomicUsize::new(NO_OWNER),
+            depth: UnsafeCell::new(0),
+            value,
+        }
+    }
+
+    /// ロックを獲得する。現在のスレッドがすでにロックを保持している場合は、待機せずに深さを増やす。
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        let id = current_thread_id();
+        if self.owner.load(Ordering::Relaxed) == id {
+            // 安全性: 所有者であるため、`depth`にアクセスするのは現在のスレッドだけである。
+            let depth = unsafe { &mut *self.depth.get() };
+            *depth = depth.checked_add(1).expect("lock depth overflow");
+        } else {
+            if self
+                .state
+                .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                lock_contented(&self.state);
+            }
+            self.owner.store(id, Ordering::Relaxed);
+            // 安全性: ロックを獲得したため、`depth`にアクセスするのは現在のスレッドだけである。
+            unsafe { *self.depth.get() = 1 };
+        }
+        ReentrantMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Drop for ReentrantMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mutex = self.mutex;
+        // 安全性: ガードは`Send`を実装しないため、ドロップするのはロックを保持しているスレッドである。
+        let depth = unsafe { &mut *mutex.depth.get() };
+        *depth -= 1;
+        if *depth == 0 {
+            // ロックを解放する前に所有者を消去する。
+            // 解放した後に他のスレッドがロックを獲得して、所有者を書き込むことがあるためである。
+            mutex.owner.store(NO_OWNER, Ordering::Relaxed);
+            if mutex.state.swap(0, Ordering::Release) == 2 {
+                wake_one(&mutex.state);
+            }
+        }
+    }
+}
+
+/// 09-01-02の競合したときにロックを獲得する処理
+fn lock_contented(state: &AtomicU32) {
+    let mut spin_count = 0;
+    while state.load(Ordering::Relaxed) == 1 && spin_count < 100 {
+        spin_count += 1;
+        std::hint::spin_loop();
+    }
+
+    if state
+        .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok()
+    {
+        return;
+    }
+
+    while state.swap(2, Ordering::Acquire) != 0 {
+        wait(state, 2);
+    }
+}
+
+/// コールバックの中から、同じミューテックスをロックする例
+fn visit(log: &ReentrantMutex<Cell<u32>>, depth: u32) {
+    let guard = log.lock();
+    guard.set(guard.get() + 1);
+    if depth > 0 {
+        visit(log, depth - 1);
+    }
+}
+
+fn main() {
+    let log = ReentrantMutex::new(Cell::new(0));
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| visit(&log, 9));
+        }
+    });
+    println!("visits: {}", log.into_inner().get());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use static_assertions::{assert_impl_all, assert_not_impl_any};
+    use std::cell::RefCell;
+    use std::ops::DerefMut;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    // ガードから`&mut T`を得られないため、`*guard += 1`のような変更はコンパイルエラーになるはず。
+    assert_not_impl_any!(ReentrantMutexGuard<'static, u32>: DerefMut);
+    // ガードはロックしたスレッドで解放する必要があるため、`Send`ではないはず。
+    assert_not_impl_any!(ReentrantMutexGuard<'static, u32>: Send);
+    assert_impl_all!(ReentrantMutexGuard<'static, u32>: Sync);
+
+    #[test]
+    fn nested_lock_on_same_thread() {
+        let mutex = ReentrantMutex::new(RefCell::new(Vec::new()));
+        let first = mutex.lock();
+        first.borrow_mut().push(1);
+        let second = mutex.lock();
+        second.borrow_mut().push(2);
+        let third = mutex.lock();
+        third.borrow_mut().push(3);
+        // 同じスレッドであれば、深さ3までロックしてもデッドロックしないはず。
+        assert_eq!(*first.borrow(), [1, 2, 3]);
+        drop(third);
+        drop(second);
+        drop(first);
+        // すべてのガードをドロップした後は、再びロックできるはず。
+        assert_eq!(*mutex.lock().borrow(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn other_thread_waits_until_all_guards_drop() {
+        let mutex = ReentrantMutex::new(0);
+        let acquired = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let first = mutex.lock();
+            let second = mutex.lock();
+            let third = mutex.lock();
+            s.spawn(|| {
+                let _guard = mutex.lock();
+                acquired.store(true, Ordering::Relaxed);
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::Relaxed));
+            drop(third);
+            drop(second);
+            // 1つでもガードが残っている間は、他のスレッドはロックを獲得できないはず。
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::Relaxed));
+            drop(first);
+        });
+        assert!(acquired.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn counts_nested_visits_from_many_threads() {
+        let log = ReentrantMutex::new(Cell::new(0));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        visit(&log, 4);
+                    }
+                });
+            }
+        });
+        // 各スレッドは、100回 × 深さ5回インクリメントするはず。
+        assert_eq!(log.into_inner().get(), 4 * 100 * 5);
+    }
+}
@@ -0,0 +1,349 @@
+//! # バージョン付きのアトミックなポインタ
+//!
+//! `compare_exchange`でポインタだけを比較すると、ポインタがAからBに変更され、再びAに戻された場合に、
+//! 変更されていないものとして成功してしまう（ABA問題）。
+//! 例えば、Treiberスタックの`pop`が先頭のノードAとその次のノードBを読み込んだ後、他のスレッドがAとBを
+//! ポップしてからAだけを再びプッシュすると、`head`をAからBに変更する`compare_exchange`が成功し、
+//! すでにポップされたBがスタックの先頭になる。
+//! 10-11や10-14は、ノードを再利用しないことでABA問題を避けているが、ノードを再利用するフリーリストなどでは避けられない。
+//!
+//! `TaggedAtomicPtr<T>`は、ポインタと、変更するたびに増やすバージョンの組を1つの128ビットの値として格納し、
+//! 両方を同時に比較する。
+//! ポインタがAに戻っても、バージョンが異なるため`compare_exchange`は失敗する。
+//!
+//! `AtomicU128`は安定版のRustでは使用できないため、x86-64では`cmpxchg16b`命令をインラインアセンブリで
+//! 直接使用する。それ以外のアーキテクチャでは、ミューテックスで保護する（ロックフリーではない）。
+//! いずれの場合も、すべての操作は`SeqCst`と同等の順序を持つ。
+use std::marker::PhantomData;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// 2つの`u64`の組を、1つの値としてアトミックに比較交換する。
+#[cfg(target_arch = "x86_64")]
+mod pair {
+    use std::arch::asm;
+    use std::cell::UnsafeCell;
+
+    /// `cmpxchg16b`は、16バイトにアラインされたメモリを要求する。
+    #[repr(C, align(16))]
+    pub struct AtomicPair(UnsafeCell<[u64; 2]>);
+
+    // `cmpxchg16b`以外の方法ではアクセスしないため、複数のスレッドで共有できる。
+    unsafe impl Sync for AtomicPair {}
+
+    impl AtomicPair {
+        pub const fn new(value: (u64, u64)) -> Self {
+            Self(UnsafeCell::new([value.0, value.1]))
+        }
+
+        pub fn load(&self) -> (u64, u64) {
+            // 失敗した場合は現在の値を返し、成功した場合は同じ値を書き込むだけであるため、読み込みとして使用できる。
+            match self.compare_exchange((0, 0), (0, 0)) {
+                Ok(value) | Err(value) => value,
+            }
+        }
+
+        /// 現在の値が`current`と等しい場合は`new`に置き換える。いずれの場合も、直前の値を返す。
+        pub fn compare_exchange(
+            &self,
+            current: (u64, u64),
+            new: (u64, u64),
+        ) -> Result<(u64, u64), (u64, u64)> {
+            let previous_low: u64;
+            let previous_high: u64;
+            let succeeded: u8;
+            // 安全性: ポインタは16バイトにアラインされた有効なメモリを指す。
+            // `rbx`はLLVMが予約しているため、`cmpxchg16b`の前後で別のレジスタと交換する。
+            // `lock`プレフィックスにより、メモリバリアとしても機能する。
+            unsafe {
+                asm!(
+                    "xchg {new_low}, rbx",
+                    "lock cmpxchg16b xmmword ptr [{ptr}]",
+                    "setz {succeeded}",
+                    "mov rbx, {new_low}",
+                    ptr = in(reg) self.0.get(),
+                    new_low = inout(reg) new.0 => _,
+                    succeeded = out(reg_byte) succeeded,
+                    inout("rax") current.0 => previous_low,
+                    inout("rdx") current.1 => previous_high,
+                    in("rcx") new.1,
+                    options(nostack),
+                );
+            }
+            let previous = (previous_low, previous_high);
+            if succeeded != 0 {
+                Ok(previous)
+            } else {
+                Err(previous)
+            }
+        }
+    }
+}
+
+/// 128ビットの比較交換命令がないアーキテクチャでは、ミューテックスで保護する。
+#[cfg(not(target_arch = "x86_64"))]
+mod pair {
+    use std::sync::Mutex;
+
+    pub struct AtomicPair(Mutex<(u64, u64)>);
+
+    impl AtomicPair {
+        pub const fn new(value: (u64, u64)) -> Self {
+            Self(Mutex::new(value))
+        }
+
+        pub fn load(&self) -> (u64, u64) {
+            *self.0.lock().unwrap()
+        }
+
+        pub fn compare_exchange(
+            &self,
+            current: (u64, u64),
+            new: (u64, u64),
+        ) -> Result<(u64, u64), (u64, u64)> {
+            let mut value = self.0.lock().unwrap();
+            let previous = *value;
+            if previous == current {
+                *value = new;
+                Ok(previous)
+            } else {
+                Err(previous)
+            }
+        }
+    }
+}
+
+use pair::AtomicPair;
+
+/// ポインタ（下位64ビット）とバージョン（上位64ビット）の組を格納するアトミックなポインタ
+///
+/// `AtomicPtr<T>`と同様に、ポインタが指す値にはアクセスしないため、`T`にかかわらず`Send`かつ`Sync`である。
+pub struct TaggedAtomicPtr<T> {
+    inner: AtomicPair,
+    _marker: PhantomData<*mut T>,
+}
+
+unsafe impl<T> Send for TaggedAtomicPtr<T> {}
+unsafe impl<T> Sync for TaggedAtomicPtr<T> {}
+
+fn to_bits<T>(ptr: *mut T) -> u64 {
+    ptr.expose_provenance() as u64
+}
+
+fn from_bits<T>(bits: u64) -> *mut T {
+    ptr::with_exposed_provenance_mut(bits as usize)
+}
+
+impl<T> TaggedAtomicPtr<T> {
+    pub fn new(ptr: *mut T, version: u64) -> Self {
+        Self {
+            inner: AtomicPair::new((to_bits(ptr), version)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// ポインタとバージョンの組を読み込む。
+    ///
+    /// 空のスタックの先頭のように、ヌルポインタも格納できるため、`NonNull`ではなく`*mut T`を返す。
+    pub fn load(&self) -> (*mut T, u64) {
+        let (ptr, version) = self.inner.load();
+        (from_bits(ptr), version)
+    }
+
+    /// ポインタとバージョンの両方が等しい場合だけ、`new_ptr`と`new_version`に置き換える。
+    ///
+    /// 失敗した場合は、現在のポインタとバージョンを返す。
+    pub fn compare_exchange(
+        &self,
+        current_ptr: *mut T,
+        current_version: u64,
+        new_ptr: *mut T,
+        new_version: u64,
+    ) -> Result<(), (*mut T, u64)> {
+        self.inner
+            .compare_exchange(
+                (to_bits(current_ptr), current_version),
+                (to_bits(new_ptr), new_version),
+            )
+            .map(|_| ())
+            .map_err(|(ptr, version)| (from_bits(ptr), version))
+    }
+}
+
+/// `TreiberStack`に格納するノード
+pub struct Node<T> {
+    pub value: T,
+    next: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// ノードを所有せず、呼び出し側が用意したノードをプッシュとポップで再利用するTreiberスタック
+///
+/// フリーリストのように、ポップしたノードを再びプッシュするため、ABA問題が発生する。
+/// `head`をバージョン付きのポインタとし、変更するたびにバージョンを増やすことで、ABA問題を防ぐ。
+/// ノードは解放されないため、ポップしたスレッドが、他のスレッドがすでにポップしたノードの`next`を
+/// 読み込んでも問題ない。`next`は他のスレッドが同時に書き込むため、アトミック変数にしている。
+pub struct TreiberStack<T> {
+    head: TaggedAtomicPtr<Node<T>>,
+}
+
+impl<T> TreiberStack<T> {
+    pub fn new() -> Self {
+        Self {
+            head: TaggedAtomicPtr::new(ptr::null_mut(), 0),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `node`はスタックより長く生存し、このスタックにも他のスタックにも含まれていてはならない。
+    pub unsafe fn push(&self, node: NonNull<Node<T>>) {
+        let (mut head, mut version) = self.head.load();
+        loop {
+            // 安全性: 呼び出し側が、ノードが生存していることを保証する。
+            unsafe { node.as_ref() }.next.store(head, Ordering::Relaxed);
+            match self
+                .head
+                .compare_exchange(head, version, node.as_ptr(), version.wrapping_add(1))
+            {
+                Ok(()) => return,
+                Err((h, v)) => (head, version) = (h, v),
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<NonNull<Node<T>>> {
+        loop {
+            let (head, version) = self.head.load();
+            let node = NonNull::new(head)?;
+            // 安全性: プッシュしたノードは、スタックより長く生存する。
+            let next = unsafe { node.as_ref() }.next.load(Ordering::Relaxed);
+            // 読み込んだ後に他のスレッドが`node`をポップして再びプッシュしていれば、ポインタが同じでも
+            // バージョンが異なるため失敗する。
+            if self
+                .head
+                .compare_exchange(head, version, next, version.wrapping_add(1))
+                .is_ok()
+            {
+                return Some(node);
+            }
+        }
+    }
+}
+
+impl<T> Default for TreiberStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn main() {
+    // 4つのバッファを再利用するフリーリスト
+    let buffers = (0..4).map(Node::new).collect::<Vec<_>>();
+    let free_list = TreiberStack::new();
+    for buffer in &buffers {
+        unsafe { free_list.push(NonNull::from(buffer)) };
+    }
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..10_000 {
+                    if let Some(buffer) = free_list.pop() {
+                        std::hint::black_box(unsafe { buffer.as_ref() }.value);
+                        unsafe { free_list.push(buffer) };
+                    }
+                }
+            });
+        }
+    });
+    let remaining = std::iter::from_fn(|| free_list.pop())
+        .map(|node| unsafe { node.as_ref() }.value)
+        .collect::<Vec<_>>();
+    println!("buffers: {remaining:?}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versioned_compare_exchange_detects_aba() {
+        let nodes = [Node::new('a'), Node::new('b'), Node::new('c')];
+        let stack = TreiberStack::new();
+        for node in nodes.iter().rev() {
+            unsafe { stack.push(NonNull::from(node)) };
+        }
+
+        // スレッド1: `pop`の途中で、先頭のノードAと次のノードBを読み込む。
+        let (a, version) = stack.head.load();
+        let b = unsafe { &*a }.next.load(Ordering::Relaxed);
+
+        // スレッド2: AとBをポップし、Aだけを再びプッシュする。
+        let popped_a = stack.pop().unwrap();
+        let popped_b = stack.pop().unwrap();
+        unsafe { stack.push(popped_a) };
+        assert_eq!(popped_b.as_ptr(), b);
+        // ポインタだけを比較すると、スタックは変更されていないように見えるはず。
+        assert_eq!(stack.head.load().0, a);
+
+        // スレッド1: バージョンが異なるため、ポップ済みのBを先頭にする`compare_exchange`は失敗するはず。
+        assert_eq!(
+            stack.head.compare_exchange(a, version, b, version + 1),
+            Err((a, version + 3))
+        );
+        let remaining = std::iter::from_fn(|| stack.pop())
+            .map(|node| unsafe { node.as_ref() }.value)
+            .collect::<String>();
+        assert_eq!(remaining, "ac");
+    }
+
+    #[test]
+    fn load_returns_pointer_and_version() {
+        let mut value = 1;
+        let ptr = TaggedAtomicPtr::new(&mut value as *mut i32, u64::MAX);
+        assert_eq!(ptr.load(), (&mut value as *mut i32, u64::MAX));
+        assert!(
+            ptr.compare_exchange(&mut value, u64::MAX, ptr::null_mut(), 0)
+                .is_ok()
+        );
+        assert_eq!(ptr.load(), (ptr::null_mut(), 0));
+    }
+
+    #[test]
+    fn free_list_keeps_every_node_under_contention() {
+        const NODES: usize = 8;
+        let nodes = (0..NODES).map(Node::new).collect::<Vec<_>>();
+        let stack = TreiberStack::new();
+        for node in &nodes {
+            unsafe { stack.push(NonNull::from(node)) };
+        }
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..20_000 {
+                        // ポップと再プッシュを繰り返して、ABA問題が発生しやすい状況を作る。
+                        let first = stack.pop();
+                        let second = stack.pop();
+                        for node in [second, first].into_iter().flatten() {
+                            unsafe { stack.push(node) };
+                        }
+                    }
+                });
+            }
+        });
+        // ノードが失われたり、重複したりしていないはず。
+        let mut remaining = std::iter::from_fn(|| stack.pop())
+            .map(|node| unsafe { node.as_ref() }.value)
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, (0..NODES).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,223 @@
+//! # モニター
+//!
+//! 条件変数は、常に同じミューテックスと組み合わせて使用するため、`(Mutex<T>, Condvar)`のタプルを
+//! `Arc`で共有することが多いが、ミューテックスと条件変数を別々に扱う必要があり冗長である。
+//!
+//! `Monitor<T>`は、09-01-02のミューテックスと09-02の条件変数を1つにまとめたものである。
+//! `lock`が返す`MonitorGuard`から、保護している値へのアクセス、待機（`wait`）、通知（`notify_one`と
+//! `notify_all`）のすべてを行える。
+//! `wait`はガードを`&mut self`で受け取り、ロックを解放して待機した後、同じガードでロックを獲得し直すため、
+//! `let mut guard = monitor.lock(); while 条件 { guard.wait(); }`のように書ける。
+//!
+//! 複数のスレッドで共有する場合は、`Arc<Monitor<T>>`を`clone`する。
+//!
+//! 09-02のファイルを`#[path]`でモジュールとして読み込み、09-02が読み込んでいる09-01-02のミューテックスと
+//! 09-02の条件変数をそのまま使用する。
+//! そのため、`cargo test --example 10-27_monitor`は、09-02と09-01-02のテストも実行する。
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 09-01-02のミューテックスと09-02の条件変数
+// 読み込んだファイルの`main`など、この例では使用しない項目があるため`dead_code`を許可する。
+#[allow(dead_code)]
+#[path = "09-02_condvar.rs"]
+mod condvar;
+
+use condvar::{Condvar, Mutex, MutexGuard};
+
+/// ミューテックスと、そのミューテックスと組み合わせて使用する条件変数
+pub struct Monitor<T> {
+    mutex: Mutex<T>,
+    condvar: Condvar,
+}
+
+/// ロックを保持していることを表すガード
+///
+/// `MutexGuard`と同様に値にアクセスでき、さらに待機と通知を行える。
+pub struct MonitorGuard<'a, T> {
+    /// ミューテックスのガード（`wait`の間だけ`Condvar::wait`に渡すため`None`になる）
+    guard: Option<MutexGuard<'a, T>>,
+    condvar: &'a Condvar,
+}
+
+impl<T> Monitor<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            mutex: Mutex::new(value),
+            condvar: Condvar::new(),
+        }
+    }
+
+    pub fn lock(&self) -> MonitorGuard<'_, T> {
+        MonitorGuard {
+            guard: Some(self.mutex.lock()),
+            condvar: &self.condvar,
+        }
+    }
+
+    /// ロックを獲得せずに、待機しているスレッドのうち1つを起床させる。
+    pub fn notify_one(&self) {
+        self.condvar.notify_one();
+    }
+
+    /// ロックを獲得せずに、待機しているすべてのスレッドを起床させる。
+    pub fn notify_all(&self) {
+        self.condvar.notify_all();
+    }
+
+    pub fn into_inner(self) -> T {
+        self.mutex.into_inner()
+    }
+}
+
+impl<T> MonitorGuard<'_, T> {
+    /// ロックを解放して通知を待機し、起床した後に再びロックを獲得する。
+    ///
+    /// 09-02の`Condvar::wait(guard)`にミューテックスのガードを渡し、返されたガードを保持し直す。
+    /// そのため、呼び出し側はガードを消費せずに、このガードのまま待機できる。
+    /// 通知されていなくても戻ることがある（スプリアスウェイクアップ）ため、呼び出し側はループ内で
+    /// 条件を再評価しなければならない。
+    pub fn wait(&mut self) {
+        // `Condvar::wait`は、1つのモニターの中で常に同じミューテックスと組み合わせて使用するため、パニックしない。
+        let guard = self.guard.take().unwrap();
+        self.guard = Some(self.condvar.wait(guard));
+    }
+
+    /// 待機しているスレッドのうち、1つを起床させる。
+    ///
+    /// 起床したスレッドは、このガードがドロップされるか、`wait`でロックを解放するまで、ロックを獲得できない。
+    pub fn notify_one(&self) {
+        self.condvar.notify_one();
+    }
+
+    /// 待機しているすべてのスレッドを起床させる。
+    pub fn notify_all(&self) {
+        self.condvar.notify_all();
+    }
+}
+
+impl<T> Deref for MonitorGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for MonitorGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+/// 01-08-02の例を`Monitor`で書き直したもの
+fn main() {
+    let queue = Arc::new(Monitor::new(VecDeque::new()));
+
+    let consumer = {
+        let queue = Arc::clone(&queue);
+        std::thread::spawn(move || {
+            for _ in 0..5 {
+                let mut q = queue.lock();
+                while q.is_empty() {
+                    q.wait();
+                }
+                dbg!(q.pop_front().unwrap());
+            }
+        })
+    };
+
+    for i in 0..5 {
+        queue.lock().push_back(i);
+        queue.notify_one();
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    consumer.join().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn reimplements_01_08_02_example() {
+        // 01-08-02と同じく、キューが空の間は待機し、要素が追加されたら取り出す。
+        let queue = Monitor::new(VecDeque::new());
+        let received = std::thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                (0..10)
+                    .map(|_| {
+                        let mut q = queue.lock();
+                        while q.is_empty() {
+                            q.wait();
+                        }
+                        q.pop_front().unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for i in 0..10 {
+                let mut q = queue.lock();
+                q.push_back(i);
+                q.notify_one();
+                drop(q);
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            consumer.join().unwrap()
+        });
+        // 追加した順にすべての要素を取り出すはず。
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+        assert!(queue.into_inner().is_empty());
+    }
+
+    #[test]
+    fn notify_all_wakes_every_waiter() {
+        let ready = Monitor::new(false);
+        let woken = AtomicU32::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut ready = ready.lock();
+                    while !*ready {
+                        ready.wait();
+                    }
+                    woken.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(woken.load(Ordering::Relaxed), 0);
+            let mut guard = ready.lock();
+            *guard = true;
+            guard.notify_all();
+        });
+        // 1回の`notify_all`で、すべてのスレッドが戻るはず。
+        assert_eq!(woken.into_inner(), 4);
+    }
+
+    #[test]
+    fn shared_through_cloned_arc() {
+        let counter = Arc::new(Monitor::new(0));
+        let handles = (0..4)
+            .map(|_| {
+                let counter = Arc::clone(&counter);
+                std::thread::spawn(move || {
+                    let mut count = counter.lock();
+                    *count += 1;
+                    count.notify_all();
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut count = counter.lock();
+        while *count < 4 {
+            count.wait();
+        }
+        // `Arc`を`clone`して共有したモニターで、すべてのスレッドのインクリメントを待機できるはず。
+        assert_eq!(*count, 4);
+        drop(count);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
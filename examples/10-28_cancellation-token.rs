@@ -0,0 +1,158 @@
+//! # キャンセルトークン
+//!
+//! 02-01-01の停止フラグ（`static STOP: AtomicBool`）を、協調的なキャンセルのための型にまとめたものである。
+//!
+//! - `cancel`: キャンセルを通知する。2回目以降の呼び出しは何もしない。
+//! - `is_cancelled`: 02-01-01の`STOP.load`と同様に、処理の区切りでキャンセルされたかどうかを確認する。
+//! - `wait_for_cancellation`: キャンセルされるまでfutexで待機する。
+//!
+//! `clone`は`Arc`を複製するだけであり、複製したトークンは同じキャンセル状態を共有する。
+//! そのため、02-01-01のように静的変数にしなくても、複数のスレッドで使用できる。
+//!
+//! `child_token`は、親がキャンセルされるとキャンセルされるが、自身をキャンセルしても親には影響しない
+//! 子トークンを作成する。例えば、スレッドプール全体のトークンから、ワーカーごとの子トークンを作成すると、
+//! 1つのワーカーだけを停止することも、すべてのワーカーを停止することもできる。
+//! 親は子を`Weak`で保持し、キャンセルされたときに子をキャンセルする（子の待機しているスレッドも起床させる）。
+//! 子は親を`Arc`で保持し、ドロップされるときに親の一覧から自身を取り除く。
+//!
+//! トークンの実装は、09-01-02の`Mutex::lock_cancellable`と共有するため`shared/cancellation.rs`にある。
+//! `lock_cancellable`は、ミューテックスのfutexで待機する前にそのアドレスをトークンに登録するため、
+//! 親トークンをキャンセルすると、子トークンでロックを待機しているスレッドも起床する。
+use std::time::Duration;
+
+#[path = "shared/cancellation.rs"]
+mod cancellation;
+
+use cancellation::CancellationToken;
+
+/// 02-01-01の例を、`CancellationToken`と複数のワーカーで書き直したもの
+fn main() {
+    let token = CancellationToken::new();
+    let workers = (0..4)
+        .map(|i| {
+            let token = token.child_token();
+            std::thread::spawn(move || {
+                let mut count = 0;
+                while !token.is_cancelled() {
+                    some_work();
+                    count += 1;
+                }
+                println!("worker {i}: {count} iterations");
+            })
+        })
+        .collect::<Vec<_>>();
+
+    std::thread::sleep(Duration::from_millis(100));
+    token.cancel();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+fn some_work() {
+    std::thread::sleep(Duration::from_millis(1));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Instant;
+
+    #[test]
+    fn cancelling_parent_cancels_child() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+        assert!(!child.is_cancelled());
+        parent.cancel();
+        // 子と、子の子もキャンセルされるはず。
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        // キャンセルされた後に作成した子は、最初からキャンセルされているはず。
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[test]
+    fn cancelling_child_does_not_cancel_parent() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let sibling = parent.child_token();
+        child.cancel();
+        assert!(child.is_cancelled());
+        // 親と兄弟はキャンセルされないはず。
+        assert!(!parent.is_cancelled());
+        assert!(!sibling.is_cancelled());
+    }
+
+    #[test]
+    fn clones_share_state_and_dropped_children_are_forgotten() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        for _ in 0..100 {
+            drop(token.child_token());
+        }
+        // ドロップされた子は、親の一覧から取り除かれるはず。
+        assert_eq!(token.child_count(), 0);
+        clone.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn wait_for_cancellation_returns_immediately_if_cancelled() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let start = Instant::now();
+        token.wait_for_cancellation();
+        token.child_token().wait_for_cancellation();
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn wait_for_cancellation_is_woken_through_parent() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| child.wait_for_cancellation());
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            // 親をキャンセルすると、子で待機しているスレッドも起床するはず。
+            parent.cancel();
+            waiter.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn thread_pool_shuts_down_cleanly() {
+        let pool = CancellationToken::new();
+        let done = AtomicU32::new(0);
+        let stopped = std::thread::scope(|s| {
+            let workers = (0..4)
+                .map(|_| {
+                    let token = pool.child_token();
+                    let done = &done;
+                    s.spawn(move || {
+                        while !token.is_cancelled() {
+                            done.fetch_add(1, Ordering::Relaxed);
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            // 仕事がないワーカーは、キャンセルされるまで待機する。
+            let idle = pool.child_token();
+            let idle = s.spawn(move || idle.wait_for_cancellation());
+            std::thread::sleep(Duration::from_millis(50));
+            let start = Instant::now();
+            pool.cancel();
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            idle.join().unwrap();
+            start.elapsed()
+        });
+        // すべてのワーカーが仕事をした後、キャンセルから短い時間で終了するはず。
+        assert!(done.into_inner() > 0);
+        assert!(stopped < Duration::from_secs(1), "{stopped:?}");
+    }
+}
@@ -0,0 +1,197 @@
+//! # 定期実行タスク
+//!
+//! メトリクスの書き出しのように、一定の間隔で処理を実行するバックグラウンドスレッドである。
+//!
+//! スレッドは、次に実行する時刻まで`park_timeout`で待機し、時刻になったら`f`を呼び出す。
+//! `park_timeout`は、`unpark`されていなくても戻ることがある（スプリアスウェイクアップ）ため、戻るたびに
+//! 停止の要求、即時実行の要求、現在時刻の順に確認する。
+//!
+//! - `stop`: 停止フラグ（02-01-01と同じ`AtomicBool`）を設定して`unpark`し、スレッドの終了を待つ。
+//! - `trigger_now`: 即時実行フラグを設定して`unpark`し、次の時刻を待たずに`f`を呼び出させる。
+//!
+//! `f`の実行中に`unpark`された場合は、許可トークンが残るため、次の`park_timeout`がすぐに戻り、
+//! 要求は失われない。
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// タスクのスレッドと、それを操作するハンドルが共有するフラグ
+struct Flags {
+    stop: AtomicBool,
+    trigger: AtomicBool,
+}
+
+/// 一定の間隔で`f`を呼び出すバックグラウンドスレッドのハンドル
+///
+/// ドロップされた場合も、`stop`と同様にスレッドを停止して終了を待つ。
+pub struct PeriodicTask {
+    flags: Arc<Flags>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicTask {
+    /// `interval`ごとに`f`を呼び出すスレッドを起動する。
+    ///
+    /// 最初の呼び出しは、起動してから`interval`が経過した後である。
+    /// `f`の実行に`interval`より長くかかった場合は、遅れを取り戻すために連続して呼び出さず、
+    /// 実行が終わった時刻から`interval`後に次を呼び出す。
+    pub fn new<F>(f: F, interval: Duration) -> PeriodicTask
+    where
+        F: Fn() + Send + 'static,
+    {
+        let flags = Arc::new(Flags {
+            stop: AtomicBool::new(false),
+            trigger: AtomicBool::new(false),
+        });
+        let thread = {
+            let flags = Arc::clone(&flags);
+            std::thread::spawn(move || run(&flags, f, interval))
+        };
+        PeriodicTask {
+            flags,
+            thread: Some(thread),
+        }
+    }
+
+    /// 次の時刻を待たずに、`f`を1回呼び出させる。
+    ///
+    /// `f`が呼び出される前に複数回呼び出した場合は、まとめて1回だけ呼び出す。
+    /// 定期的な呼び出しの時刻は変わらない。
+    pub fn trigger_now(&self) {
+        self.flags.trigger.store(true, Ordering::Release);
+        self.thread().unpark();
+    }
+
+    /// スレッドを停止し、終了するまで待つ。
+    ///
+    /// `f`の実行中であれば、実行が終わってから停止する。
+    /// `f`がパニックしてスレッドが終了していた場合は、そのパニックを呼び出し側で再開する。
+    pub fn stop(mut self) {
+        if let Err(panic) = self.shutdown() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    fn thread(&self) -> &std::thread::Thread {
+        // `thread`が`None`になるのは`shutdown`の後だけであり、`stop`と`drop`は`self`を消費する。
+        self.thread.as_ref().unwrap().thread()
+    }
+
+    /// 停止フラグを設定してスレッドを起床させ、終了を待つ。
+    fn shutdown(&mut self) -> std::thread::Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+        self.flags.stop.store(true, Ordering::Release);
+        thread.thread().unpark();
+        thread.join()
+    }
+}
+
+impl Drop for PeriodicTask {
+    fn drop(&mut self) {
+        // ドロップ中にパニックを再開すると異常終了する可能性があるため、`f`のパニックは無視する。
+        let _ = self.shutdown();
+    }
+}
+
+/// タスクのスレッドで実行するループ
+fn run(flags: &Flags, f: impl Fn(), interval: Duration) {
+    let mut next = Instant::now() + interval;
+    loop {
+        if flags.stop.load(Ordering::Acquire) {
+            return;
+        }
+        let now = Instant::now();
+        if flags.trigger.swap(false, Ordering::Acquire) {
+            f();
+        } else if now >= next {
+            f();
+            next = Instant::now() + interval;
+        } else {
+            std::thread::park_timeout(next - now);
+        }
+    }
+}
+
+fn main() {
+    let task = PeriodicTask::new(|| println!("tick"), Duration::from_millis(100));
+    std::thread::sleep(Duration::from_millis(350));
+    println!("trigger");
+    task.trigger_now();
+    std::thread::sleep(Duration::from_millis(50));
+    task.stop();
+    println!("stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    fn counting_task(interval: Duration) -> (PeriodicTask, Arc<AtomicU32>) {
+        let count = Arc::new(AtomicU32::new(0));
+        let task = {
+            let count = Arc::clone(&count);
+            PeriodicTask::new(
+                move || {
+                    count.fetch_add(1, Ordering::Relaxed);
+                },
+                interval,
+            )
+        };
+        (task, count)
+    }
+
+    #[test]
+    fn fires_about_n_times_in_n_intervals() {
+        let (task, count) = counting_task(Duration::from_millis(20));
+        std::thread::sleep(Duration::from_millis(20 * 10 + 10));
+        task.stop();
+        // 10回の間隔で約10回呼び出されるはず（スケジューリングの遅れで少なくなることはある）。
+        let count = count.load(Ordering::Relaxed);
+        assert!((5..=11).contains(&count), "{count}");
+    }
+
+    #[test]
+    fn stop_terminates_promptly_and_cleanly() {
+        let (task, count) = counting_task(Duration::from_secs(10));
+        let start = Instant::now();
+        task.stop();
+        // 次の時刻を待たずに停止し、`f`は一度も呼び出されないはず。
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+
+        let (task, count) = counting_task(Duration::from_millis(5));
+        std::thread::sleep(Duration::from_millis(50));
+        task.stop();
+        // 停止した後は、呼び出されないはず。
+        let stopped = count.load(Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(count.load(Ordering::Relaxed), stopped);
+    }
+
+    #[test]
+    fn trigger_now_runs_without_waiting_for_interval() {
+        let (task, count) = counting_task(Duration::from_secs(10));
+        task.trigger_now();
+        let start = Instant::now();
+        while count.load(Ordering::Relaxed) == 0 {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            std::thread::yield_now();
+        }
+        // 即時実行の要求1回につき、1回だけ呼び出されるはず。
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        drop(task);
+    }
+
+    #[test]
+    #[should_panic(expected = "task failed")]
+    fn stop_resumes_panic_from_task() {
+        let task = PeriodicTask::new(|| panic!("task failed"), Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(50));
+        task.stop();
+    }
+}
@@ -0,0 +1,91 @@
+//! # バックオフ
+//!
+//! 競合しているときの待機方針（指数バックオフ）である。
+//!
+//! 毎回1回だけ`spin_loop`してすぐに再試行すると、競合しているすべてのスレッドがロックの
+//! キャッシュラインを奪い合うため、スループットが大きく低下する。
+//! そこで、`snooze`を呼び出すたびにスピン回数を1、2、4、8回と倍増させ、スピンを使い切った後の
+//! `YIELD_LIMIT`回は`std::thread::yield_now`でCPUを他のスレッド（ロックを保持しているスレッドなど）に譲る。
+//!
+//! それでも競合が解消しない場合は、`completed`が`true`を返す。
+//! futexで待機できる呼び出し側（09-01-02のミューテックス）は、スピンをやめてfutexで待機（ブロック）する。
+//! スピンロックのように待機する手段がない場合は、そのまま`snooze`を呼び出し続けてよく、以降の`snooze`は
+//! `PARK_TIMEOUT`の間スレッドを一時停止（`park_timeout`）する。
+//! 起床させるスレッドがいないため、時間が経過するまで停止し続ける（`unpark`されて早く戻ることもある）。
+//!
+//! 04-02、04-03、04-03-01、04-03-02、04-04のスピンロック、09-01-02のミューテックス、10-04のキューロック、
+//! 10-11のキューは、`#[path]`でこのファイルを読み込み、同じ`Backoff`を使用する。
+//! 待機方針を調整する場合は、このファイルの定数だけを変更すること。
+
+// 読み込む例によって、使用しない項目があるため。
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+/// `Backoff::new`がスピンする`spin_loop`の合計の回数（1、2、4、8回と倍増させる）
+pub const SPIN_LIMIT: u32 = 1 + 2 + 4 + 8;
+/// スピンを使い切った後に、`yield_now`する回数
+pub const YIELD_LIMIT: u32 = 8;
+/// `completed`が`true`を返した後の`snooze`で、スレッドを一時停止する時間
+pub const PARK_TIMEOUT: Duration = Duration::from_micros(50);
+
+pub struct Backoff {
+    /// 次にスピンする回数の指数
+    step: u32,
+    /// 残りの`spin_loop`の回数
+    spin_left: u32,
+    /// `yield_now`した回数
+    yields: u32,
+}
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Self::with_spin_limit(SPIN_LIMIT)
+    }
+
+    /// `spin_loop`の合計の回数を`spin_limit`にしたバックオフを作成する。
+    ///
+    /// 09-01-02のミューテックスのように、スピンする回数を調整できるロックで使用する。
+    /// `0`の場合はスピンせず、最初の`snooze`から`yield_now`する。
+    pub const fn with_spin_limit(spin_limit: u32) -> Self {
+        Self {
+            step: 0,
+            spin_left: spin_limit,
+            yields: 0,
+        }
+    }
+
+    /// 次の`snooze`でスピンする回数を返す。
+    /// `None`の場合はスピンせずに`yield_now`するか、一時停止する。
+    pub fn spins(&self) -> Option<u32> {
+        // 指数が大きくなっても、残りの回数で頭打ちにするため、シフトの桁あふれは起こらない。
+        (self.spin_left > 0).then(|| (1 << self.step.min(31)).min(self.spin_left))
+    }
+
+    /// 競合の度合いに応じて、スピンするか、`yield_now`するか、一時停止する。
+    pub fn snooze(&mut self) {
+        if let Some(spins) = self.spins() {
+            for _ in 0..spins {
+                std::hint::spin_loop();
+            }
+            self.spin_left -= spins;
+            self.step += 1;
+        } else if self.yields < YIELD_LIMIT {
+            std::thread::yield_now();
+            self.yields += 1;
+        } else {
+            std::thread::park_timeout(PARK_TIMEOUT);
+        }
+    }
+
+    /// スピンと`yield_now`を使い切り、待機（ブロック）に切り替えるべき場合は`true`を返す。
+    pub fn completed(&self) -> bool {
+        self.spin_left == 0 && self.yields >= YIELD_LIMIT
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,205 @@
+//! # キャンセルトークン
+//!
+//! 02-01-01の停止フラグに、待機中のスレッドを起床させる機能を加えたものである。
+//!
+//! - `cancel`: キャンセルを通知する。2回目以降の呼び出しは何もしない。
+//! - `is_cancelled`: 処理の区切りでキャンセルされたかどうかを確認する。
+//! - `wait_for_cancellation`: キャンセルされるまでfutexで待機する。
+//! - `child_token`: 親がキャンセルされるとキャンセルされる子トークンを作成する。
+//!
+//! 他のプリミティブのfutexで待機するスレッドは、待機する前にそのfutexのアドレスを`register`で登録する。
+//! `cancel`は、登録されたすべてのアドレスに対して`wake_all`を呼び出し、待機しているスレッドを起床させる。
+//!
+//! 09-01-02の`Mutex::lock_cancellable`と10-28は、`#[path]`でこのファイルを読み込み、同じトークンを使用する。
+//! 09-01-02のミューテックスのstateと同じ型のアドレスを登録できるように、アトミック型は`shared/sync.rs`から使用する。
+
+// 読み込む例によって、使用しない項目があるため。
+#![allow(dead_code)]
+
+use std::sync::{Arc, Mutex, Weak};
+
+// 09-01-02も`shared/sync.rs`を読み込むため、同じファイルを2回読み込むことを許可する。
+#[allow(clippy::duplicate_mod)]
+#[path = "sync.rs"]
+mod sync;
+
+use sync::{AtomicU32, Ordering, wait, wake_all, yield_now};
+
+const ACTIVE: u32 = 0;
+const CANCELLED: u32 = 1;
+
+/// 複製したトークンが共有する状態
+struct CancellationInner {
+    /// `ACTIVE`または`CANCELLED`（`wait_for_cancellation`が待機するfutexのワード）
+    state: AtomicU32,
+    /// 親トークン（`child_token`で作成した場合のみ）
+    parent: Option<Arc<CancellationInner>>,
+    /// 子トークン
+    ///
+    /// ドロップされた子トークンの状態を親が生存させ続けないように、`Weak`で保持する。
+    children: Mutex<Vec<Weak<CancellationInner>>>,
+    /// 待機しているスレッドが登録した、futexのアドレス（同じアドレスが複数回登録される場合がある）
+    ///
+    /// 登録を解除するまで待機しているスレッドは戻らないため、登録されたアドレスのアトミック変数は有効である。
+    registrations: Mutex<Vec<usize>>,
+}
+
+impl CancellationInner {
+    fn new(parent: Option<Arc<CancellationInner>>) -> Self {
+        Self {
+            state: AtomicU32::new(ACTIVE),
+            parent,
+            children: Mutex::new(Vec::new()),
+            registrations: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        // `cancel`の`Release`と同期し、キャンセルする前の書き込みを観測できるようにする。
+        self.state.load(Ordering::Acquire) == CANCELLED
+    }
+
+    fn cancel(&self) {
+        if self.state.swap(CANCELLED, Ordering::Release) == CANCELLED {
+            return;
+        }
+        wake_all(&self.state);
+        self.wake_registered();
+        // stateを変更した後に一覧を取り出すため、`child_token`が一覧に追加した子は、ここでキャンセルされるか、
+        // `child_token`が親のキャンセルを観測してキャンセルする。
+        let children = std::mem::take(&mut *self.children.lock().unwrap());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+
+    /// 登録されたアドレスがなくなる（すべてのスレッドが戻る）まで、`wake_all`を繰り返す。
+    ///
+    /// 待機しているスレッドがキャンセルを確認してからfutexで待機するまでの間に`wake_all`を呼び出すと、
+    /// そのスレッドは起床しないためである。
+    fn wake_registered(&self) {
+        loop {
+            // 登録と確認は`registrations`のロックで同期される。
+            // このロックより後に登録したスレッドは、登録した後の確認でキャンセルを観測するため、待機しない。
+            let registrations = self.registrations.lock().unwrap();
+            if registrations.is_empty() {
+                return;
+            }
+            for &address in registrations.iter() {
+                // 安全性: 登録したスレッドは、このロックを獲得して登録を解除するまで戻らないため、
+                // アドレスのアトミック変数は有効である。
+                wake_all(unsafe { &*(address as *const AtomicU32) });
+            }
+            drop(registrations);
+            yield_now();
+        }
+    }
+}
+
+impl Drop for CancellationInner {
+    fn drop(&mut self) {
+        // ドロップされた子（自身を含む）の`Weak`を、親の一覧から取り除く。
+        if let Some(parent) = &self.parent {
+            parent
+                .children
+                .lock()
+                .unwrap()
+                .retain(|child| child.strong_count() > 0);
+        }
+    }
+}
+
+/// 協調的なキャンセルを通知するトークン
+///
+/// `clone`で作成したトークンは、同じキャンセル状態を共有する。
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<CancellationInner>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(CancellationInner::new(None)),
+        }
+    }
+
+    /// キャンセルされたかどうかを返す。
+    ///
+    /// 親がキャンセルされた場合は、親の`cancel`が子をキャンセルしてから戻るため、親の`cancel`から戻った後は
+    /// `true`を返す。
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.is_cancelled()
+    }
+
+    /// キャンセルし、`wait_for_cancellation`で待機しているスレッドと、登録されたアドレスで待機している
+    /// スレッドをすべて起床させる。
+    ///
+    /// 子トークン（子の子も含む）もキャンセルする。すでにキャンセルされている場合は何もしない。
+    /// 登録されたアドレスで待機しているスレッドが、すべて登録を解除するまで戻らない。
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    /// キャンセルされるまで待機する。すでにキャンセルされている場合は、すぐに戻る。
+    pub fn wait_for_cancellation(&self) {
+        while !self.inner.is_cancelled() {
+            wait(&self.inner.state, ACTIVE);
+        }
+    }
+
+    /// このトークンがキャンセルされるとキャンセルされる子トークンを作成する。
+    ///
+    /// 子トークンをキャンセルしても、このトークンはキャンセルされない。
+    /// このトークンがすでにキャンセルされている場合は、キャンセルされた子トークンを返す。
+    pub fn child_token(&self) -> CancellationToken {
+        let child = Arc::new(CancellationInner::new(Some(Arc::clone(&self.inner))));
+        self.inner
+            .children
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&child));
+        // 一覧に追加する前に親がキャンセルされていた場合は、親の`cancel`が子を見つけられないため、ここでキャンセルする。
+        // 親の`cancel`はstateを変更してから一覧をロックするため、ロックを解放した後に読み込めば、必ず観測できる。
+        if self.inner.is_cancelled() {
+            child.cancel();
+        }
+        CancellationToken { inner: child }
+    }
+
+    /// 生存している子トークンの数を返す（診断用）。
+    pub fn child_count(&self) -> usize {
+        self.inner.children.lock().unwrap().len()
+    }
+
+    /// 登録されているアドレスの数を返す（診断用）。
+    pub fn registration_count(&self) -> usize {
+        self.inner.registrations.lock().unwrap().len()
+    }
+
+    /// `futex`で待機する前に、そのアドレスを登録する。
+    ///
+    /// 登録した後にキャンセルを確認し、キャンセルされていない場合だけ待機すること。
+    /// 待機を終えたら、戻る前に必ず`unregister`で登録を解除すること（`cancel`は解除されるまで戻らない）。
+    pub fn register(&self, futex: &AtomicU32) {
+        self.inner
+            .registrations
+            .lock()
+            .unwrap()
+            .push(futex as *const AtomicU32 as usize);
+    }
+
+    /// `register`で登録したアドレスの登録を1つ解除する。
+    pub fn unregister(&self, futex: &AtomicU32) {
+        let address = futex as *const AtomicU32 as usize;
+        let mut registrations = self.inner.registrations.lock().unwrap();
+        let index = registrations.iter().position(|&a| a == address).unwrap();
+        registrations.swap_remove(index);
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,268 @@
+//! # エポックベースのメモリ回収
+//!
+//! 10-13で説明した、グローバルなエポックとスレッドごとのローカルなエポックによるメモリ回収の実装である。
+//! ノードにアクセスする前に`EpochGuard::pin`でスレッドを固定し、取り除いたノードは`EpochGuard::defer_drop`で
+//! 解放を延期する。
+//!
+//! 10-11のMichael-Scottキューと、10-13のTreiberスタックおよび`ReadMostly<T>`は、`#[path]`でこのファイルを
+//! 読み込み、同じ回収の仕組みを使用する。
+
+// 読み込む例によって、使用しない項目があるため。
+#![allow(dead_code)]
+
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering, fence};
+use std::sync::{Arc, Mutex};
+
+/// ローカルなエポックに設定する、スレッドが固定されていることを示すビット
+const PINNED: usize = 1;
+
+/// この回数だけスレッドを固定するたびに、エポックを進めて、回収待ちのノードを解放する。
+const COLLECT_INTERVAL: usize = 128;
+
+/// すべてのスレッドで共有する、グローバルなエポックと参加しているスレッドの一覧
+pub struct Collector {
+    global_epoch: AtomicUsize,
+    participants: Mutex<Vec<Arc<Participant>>>,
+    /// 終了したスレッドが解放できなかった、回収待ちのノード
+    orphans: Mutex<Vec<(usize, Deferred)>>,
+}
+
+static COLLECTOR: Collector = Collector {
+    global_epoch: AtomicUsize::new(0),
+    participants: Mutex::new(Vec::new()),
+    orphans: Mutex::new(Vec::new()),
+};
+
+/// 参加しているスレッドごとの状態
+struct Participant {
+    /// ローカルなエポックを1ビット左にシフトし、固定されている場合は`PINNED`を設定した値
+    state: AtomicUsize,
+}
+
+/// 解放を延期したノード
+struct Deferred {
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+}
+
+/// `defer_drop`の安全性の契約により、どのスレッドで解放しても問題ない。
+unsafe impl Send for Deferred {}
+
+impl Deferred {
+    fn new<T>(ptr: *mut T) -> Self {
+        unsafe fn drop_box<T>(ptr: *mut ()) {
+            drop(unsafe { Box::from_raw(ptr.cast::<T>()) });
+        }
+        Self {
+            ptr: ptr.cast(),
+            drop: drop_box::<T>,
+        }
+    }
+
+    fn run(self) {
+        unsafe { (self.drop)(self.ptr) }
+    }
+}
+
+/// スレッドローカルな状態
+struct Local {
+    participant: Arc<Participant>,
+    /// 入れ子になった`pin`の数
+    pin_count: Cell<usize>,
+    /// `pin`を呼び出した回数
+    pins: Cell<usize>,
+    /// 取り除いたときのグローバルなエポックと、回収待ちのノード
+    garbage: RefCell<Vec<(usize, Deferred)>>,
+}
+
+thread_local! {
+    static LOCAL: Local = Local::register();
+}
+
+impl Collector {
+    /// すべての固定されているスレッドが、現在のエポックに追い付いていれば、エポックを1つ進める。
+    ///
+    /// 進めた後（または進められなかった場合は現在）のグローバルなエポックを返す。
+    fn try_advance(&self) -> usize {
+        let global = self.global_epoch.load(Ordering::Relaxed);
+        // `pin`側のSeqCstフェンスと組み合わせることで、固定されたスレッドのローカルなエポックの
+        // 書き込みと、グローバルなエポックの読み込みの順序を保証する。
+        fence(Ordering::SeqCst);
+        for participant in self.participants.lock().unwrap().iter() {
+            let state = participant.state.load(Ordering::Relaxed);
+            if state & PINNED != 0 && state >> 1 != global {
+                return global;
+            }
+        }
+        fence(Ordering::Acquire);
+        match self.global_epoch.compare_exchange(
+            global,
+            global + 1,
+            Ordering::Release,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => global + 1,
+            Err(current) => current,
+        }
+    }
+
+    /// 終了したスレッドから引き継いだ、回収待ちのノードのうち、解放できるものを解放する。
+    fn collect_orphans(&self, global: usize) {
+        let ready: Vec<_> = {
+            let mut orphans = self.orphans.lock().unwrap();
+            let (ready, pending) = std::mem::take(&mut *orphans)
+                .into_iter()
+                .partition(|(epoch, _)| epoch + 2 <= global);
+            *orphans = pending;
+            ready
+        };
+        for (_, deferred) in ready {
+            deferred.run();
+        }
+    }
+}
+
+impl Local {
+    fn register() -> Self {
+        let participant = Arc::new(Participant {
+            state: AtomicUsize::new(0),
+        });
+        COLLECTOR
+            .participants
+            .lock()
+            .unwrap()
+            .push(Arc::clone(&participant));
+        Self {
+            participant,
+            pin_count: Cell::new(0),
+            pins: Cell::new(0),
+            garbage: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn pin(&self) {
+        let count = self.pin_count.get();
+        self.pin_count.set(count + 1);
+        if count != 0 {
+            return;
+        }
+        let mut global = COLLECTOR.global_epoch.load(Ordering::Relaxed);
+        loop {
+            self.participant
+                .state
+                .store(global << 1 | PINNED, Ordering::Relaxed);
+            // ローカルなエポックの書き込みを、他のスレッドの`try_advance`が観測する前に、
+            // グローバルなエポックが進んでいた場合は、新しいエポックで固定し直す。
+            fence(Ordering::SeqCst);
+            let current = COLLECTOR.global_epoch.load(Ordering::Relaxed);
+            if current == global {
+                break;
+            }
+            global = current;
+        }
+
+        let pins = self.pins.get() + 1;
+        self.pins.set(pins);
+        if pins.is_multiple_of(COLLECT_INTERVAL) {
+            self.collect();
+        }
+    }
+
+    fn unpin(&self) {
+        let count = self.pin_count.get() - 1;
+        self.pin_count.set(count);
+        if count == 0 {
+            // Releaseにより、固定されている間のノードへのアクセスは、エポックを進めたスレッドが
+            // ノードを解放するより前に行われる。
+            let state = self.participant.state.load(Ordering::Relaxed);
+            self.participant
+                .state
+                .store(state & !PINNED, Ordering::Release);
+        }
+    }
+
+    /// エポックを進めることを試み、解放できる回収待ちのノードを解放する。
+    fn collect(&self) {
+        let global = COLLECTOR.try_advance();
+        let ready: Vec<_> = {
+            let mut garbage = self.garbage.borrow_mut();
+            let (ready, pending) = std::mem::take(&mut *garbage)
+                .into_iter()
+                .partition(|(epoch, _)| epoch + 2 <= global);
+            *garbage = pending;
+            ready
+        };
+        // ノードのドロップ中に、このスレッドの状態にアクセスしても問題ないように、借用を解除してから解放する。
+        for (_, deferred) in ready {
+            deferred.run();
+        }
+        COLLECTOR.collect_orphans(global);
+    }
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        // スレッドが終了する場合は、参加しているスレッドの一覧から削除し、解放できなかったノードを
+        // 他のスレッドに引き継ぐ。
+        COLLECTOR
+            .participants
+            .lock()
+            .unwrap()
+            .retain(|p| !Arc::ptr_eq(p, &self.participant));
+        COLLECTOR
+            .orphans
+            .lock()
+            .unwrap()
+            .append(self.garbage.get_mut());
+    }
+}
+
+/// スレッドが固定されていることを表すガード
+///
+/// ガードが存在する間に読み込んだノードは、解放されない。
+/// スレッドローカルな状態を参照するため、他のスレッドに送ることはできない。
+pub struct EpochGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl EpochGuard {
+    /// 現在のスレッドを固定する。
+    pub fn pin() -> Self {
+        LOCAL.with(Local::pin);
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+
+    /// `ptr`が指す`Box`の解放を、他のスレッドがアクセスしなくなるまで延期する。
+    ///
+    /// # Safety
+    ///
+    /// - `ptr`は`Box::into_raw`で作成したポインタでなければならない。
+    /// - `ptr`はデータ構造から取り除かれており、これから固定するスレッドが読み込めてはならない。
+    /// - `T`のドロップは、後で任意のスレッドで実行しても問題があってはならない。
+    pub unsafe fn defer_drop<T>(&self, ptr: *mut T) {
+        let epoch = COLLECTOR.global_epoch.load(Ordering::Relaxed);
+        LOCAL.with(|local| {
+            local.garbage.borrow_mut().push((epoch, Deferred::new(ptr)));
+        });
+    }
+
+    /// エポックを進めることを試み、解放できる回収待ちのノードを解放する。
+    pub fn flush(&self) {
+        LOCAL.with(Local::collect);
+    }
+}
+
+/// 現在のグローバルなエポックを返す。
+pub fn global_epoch() -> usize {
+    COLLECTOR.global_epoch.load(Ordering::Relaxed)
+}
+
+impl Drop for EpochGuard {
+    fn drop(&mut self) {
+        LOCAL.with(Local::unpin);
+    }
+}
@@ -0,0 +1,64 @@
+//! # アトミック型の切り替え
+//!
+//! `--cfg loom`を指定した場合はloomのアトミック型を、それ以外は標準ライブラリのアトミック型を使用する。
+//! 06-03の`Arc`と09-01-02のミューテックスは、`#[path]`でこのファイルを読み込み、10-21はそれらをloomで検査する。
+//! `shared/cancellation.rs`も、09-01-02のstateと同じ型のアドレスを登録するために、このファイルを読み込む。
+//!
+//! loomのアトミック型は、作成するときに実行中のモデルに登録するため、`const fn`で作成できない。
+//! そこで、アトミック型を作成する関数は`const_fn!`で定義し、`--cfg loom`を指定した場合だけ`const`を外す。
+//! 作成を遅延させると、作成したスレッドと他のスレッドのアクセスに先行発生関係がなくなり、loomが誤りとして
+//! 検出するためである。
+//!
+//! loomのアトミック型はfutexで待機できないため、`wait`はCPUを譲ってから値を再確認させ、`wake_one`と
+//! `wake_all`は何もしない。
+//! loomは、スピンループの中で`spin_loop`や`yield_now`を呼び出すと、他のスレッドに切り替えるため、
+//! これらもloomの関数に置き換える。
+
+// 読み込む例によって、使用しない項目があるため。
+#![allow(dead_code, unused_imports, unused_macros)]
+
+#[cfg(not(loom))]
+pub use std::hint::spin_loop;
+#[cfg(not(loom))]
+pub use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence};
+#[cfg(not(loom))]
+pub use std::thread::yield_now;
+
+#[cfg(not(loom))]
+pub use atomic_wait::{wait, wake_all, wake_one};
+
+#[cfg(loom)]
+pub use loom::hint::spin_loop;
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering, fence};
+#[cfg(loom)]
+pub use loom::thread::yield_now;
+
+/// `a`が`expected`と等しい場合は、CPUを譲ってから戻り、呼び出し側に値を再確認させる。
+#[cfg(loom)]
+pub fn wait(a: &AtomicU32, expected: u32) {
+    if a.load(Ordering::Relaxed) == expected {
+        loom::thread::yield_now();
+    }
+}
+
+#[cfg(loom)]
+pub fn wake_one(_a: &AtomicU32) {}
+
+#[cfg(loom)]
+pub fn wake_all(_a: &AtomicU32) {}
+
+/// `const fn`を定義する。`--cfg loom`を指定した場合は、loomのアトミック型を作成できるように`const`を外す。
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $name:ident $($rest:tt)*) => {
+        $(#[$attr])*
+        #[cfg(not(loom))]
+        $vis const fn $name $($rest)*
+
+        $(#[$attr])*
+        #[cfg(loom)]
+        $vis fn $name $($rest)*
+    };
+}
+
+pub(crate) use const_fn;